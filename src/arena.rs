@@ -0,0 +1,94 @@
+use decompress::{DecompressStarted, MarkerData, MAX_COMPONENTS};
+use marker::Marker;
+use arrayvec::ArrayVec;
+use std::ops::Range;
+
+/// Scratch space for decoding many images one after another without allocating
+///
+/// Pixels, raw data planes and copies of markers are kept in the arena until `reset()`,
+/// which empties it, but keeps the memory. After the first few images buffers are large enough,
+/// and decoding doesn't allocate any more (libjpeg's own memory aside).
+///
+/// ```rust,ignore
+/// let mut arena = Arena::new();
+/// for file in files {
+///     arena.reset();
+///     let mut dinfo = Decompress::new_mem(&file)?.rgb()?;
+///     let pixels = arena.read_scanlines(&mut dinfo).unwrap();
+/// }
+/// ```
+#[derive(Default)]
+pub struct Arena {
+    pixels: Vec<u8>,
+    planes: Vec<Vec<u8>>,
+    marker_data: Vec<u8>,
+    markers: Vec<(Marker, Range<usize>)>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena::default()
+    }
+
+    /// Forget contents of the previous image. Memory is kept for the next one.
+    pub fn reset(&mut self) {
+        self.pixels.clear();
+        for plane in &mut self.planes {
+            plane.clear();
+        }
+        self.marker_data.clear();
+        self.markers.clear();
+    }
+
+    /// Bytes reserved by the arena
+    pub fn capacity(&self) -> usize {
+        self.pixels.capacity() +
+        self.planes.iter().map(|p| p.capacity()).sum::<usize>() +
+        self.marker_data.capacity()
+    }
+
+    /// All remaining lines as interleaved bytes, like `read_scanlines_flat()`
+    pub fn read_scanlines<'a>(&'a mut self, dinfo: &mut DecompressStarted) -> Option<&'a [u8]> {
+        self.pixels.clear();
+        if dinfo.read_scanlines_into(&mut self.pixels) {
+            Some(&self.pixels)
+        } else {
+            None
+        }
+    }
+
+    /// One plane per component, like `read_raw_data()`
+    pub fn read_raw_data<'a>(&'a mut self, dinfo: &mut DecompressStarted) -> &'a [Vec<u8>] {
+        let num_components = dinfo.components().len();
+        assert!(num_components <= MAX_COMPONENTS);
+        while self.planes.len() < num_components {
+            self.planes.push(Vec::new());
+        }
+        for plane in &mut self.planes[..num_components] {
+            plane.clear();
+        }
+        {
+            let mut planes = self.planes[..num_components].iter_mut()
+                .collect::<ArrayVec<[_; MAX_COMPONENTS]>>();
+            dinfo.read_raw_data(&mut planes);
+        }
+        &self.planes[..num_components]
+    }
+
+    /// Keep copies of markers, so that they can be used after the decoder is gone
+    pub fn copy_markers<'m, I: IntoIterator<Item = MarkerData<'m>>>(&mut self, markers: I) {
+        for m in markers {
+            let start = self.marker_data.len();
+            self.marker_data.extend_from_slice(m.data);
+            self.markers.push((m.marker, start..self.marker_data.len()));
+        }
+    }
+
+    /// Markers saved with `copy_markers()`
+    pub fn markers(&self) -> impl Iterator<Item = MarkerData<'_>> {
+        self.markers.iter().map(move |&(marker, ref range)| MarkerData {
+            marker,
+            data: &self.marker_data[range.clone()],
+        })
+    }
+}
@@ -0,0 +1,48 @@
+//! Integration with the `ndarray` crate. Images are arrays of height × width × components.
+use compress::{Compress, CompressStarted};
+use decompress::DecompressStarted;
+use ndarray::{Array3, ArrayView3};
+use std::borrow::Cow;
+use std::io;
+
+impl<'src> DecompressStarted<'src> {
+    /// All remaining lines as an array of `[height, width, components]` shape
+    ///
+    /// Components are in the output color space, e.g. 3 after `rgb()`, 1 after `grayscale()`.
+    pub fn read_array(&mut self) -> Option<Array3<u8>> {
+        let (width, components) = (self.width(), self.output_components());
+        let mut pixels = Vec::new();
+        if !self.read_scanlines_into(&mut pixels) {
+            return None;
+        }
+        let height = pixels.len() / (width * components);
+        Array3::from_shape_vec((height, width, components), pixels).ok()
+    }
+}
+
+impl Compress {
+    /// Compress the array of `[height, width, components]` shape into a new file, using the current settings
+    ///
+    /// Number of components must match the input color space.
+    pub fn compress_array(&mut self, array: ArrayView3<'_, u8>) -> io::Result<Vec<u8>> {
+        let (height, width, _) = array.dim();
+        self.set_size(width, height);
+        self.encode_to_vec_with(true, |started| started.write_array(array))
+    }
+}
+
+impl<'a> CompressStarted<'a> {
+    /// Write lines from an array of `[lines, width, components]` shape
+    ///
+    /// Arrays in standard (row-major) layout are written without copying.
+    pub fn write_array(&mut self, array: ArrayView3<'_, u8>) -> bool {
+        let (_, width, components) = array.dim();
+        assert_eq!(self.image_width(), width, "Array width doesn't match");
+        assert_eq!(self.input_components(), components, "Number of components doesn't match the input color space");
+        let pixels = match array.as_slice() {
+            Some(pixels) => Cow::Borrowed(pixels),
+            None => Cow::Owned(array.iter().cloned().collect::<Vec<u8>>()),
+        };
+        self.write_scanlines(&pixels)
+    }
+}
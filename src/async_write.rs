@@ -0,0 +1,70 @@
+use compress::{Compress, CompressStarted};
+use dest::BufferDest;
+use tokio::io::AsyncWrite;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Future that writes compressed data to an `AsyncWrite`, see `CompressStarted::flush_to()`
+#[must_use = "futures do nothing unless polled"]
+pub struct FlushTo<'w, W: 'w + ?Sized> {
+    writer: &'w mut W,
+    // Data stays in the compressor's buffer until it's written, so dropping the future loses nothing
+    dest: &'w mut BufferDest,
+}
+
+impl Compress {
+    /// Write data from the buffer set with `set_buffer_dest()` to `writer`, e.g. the end of the file after `finish_compress()`
+    pub fn flush_to<'w, W: AsyncWrite + Unpin + ?Sized>(&'w mut self, writer: &'w mut W) -> FlushTo<'w, W> {
+        FlushTo {
+            dest: self.buffer_dest_mut(),
+            writer,
+        }
+    }
+}
+
+impl<'a> CompressStarted<'a> {
+    /// Write data compressed so far to `writer`, e.g. a socket
+    ///
+    /// Use it with `set_buffer_dest()`: when the buffer is full, `write_scanlines()` stops and returns `false`,
+    /// so the data can be sent before compressing more lines. Memory use stays bounded, and a slow
+    /// client slows down compression instead of making output pile up.
+    ///
+    /// ```rust,ignore
+    /// cinfo.set_buffer_dest(64 * 1024);
+    /// let mut started = cinfo.start_compress();
+    /// while started.next_scanline() < height {
+    ///     // The first write (with headers) can't stop early, so write a few lines at a time
+    ///     let next = started.next_scanline();
+    ///     let end = (next + 16).min(height);
+    ///     started.write_scanlines(&pixels[next * row_bytes..end * row_bytes]);
+    ///     started.flush_to(&mut socket).await?;
+    /// }
+    /// started.finish_compress();
+    /// cinfo.flush_to(&mut socket).await?;
+    /// ```
+    pub fn flush_to<'w, W: AsyncWrite + Unpin + ?Sized>(&'w mut self, writer: &'w mut W) -> FlushTo<'w, W> {
+        FlushTo {
+            dest: self.buffer_dest_mut(),
+            writer,
+        }
+    }
+}
+
+impl<'w, W: AsyncWrite + Unpin + ?Sized> Future for FlushTo<'w, W> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.dest.pending().is_empty() {
+            match Pin::new(&mut *this.writer).poll_write(cx, this.dest.pending()) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(len)) => this.dest.consume(len),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Pin::new(&mut *this.writer).poll_flush(cx)
+    }
+}
@@ -0,0 +1,86 @@
+use decompress::DecompressStarted;
+#[cfg(feature = "futures")]
+use futures_core::Stream;
+use std::io;
+#[cfg(feature = "futures")]
+use std::pin::Pin;
+#[cfg(feature = "futures")]
+use std::task::{Context, Poll};
+
+/// Consecutive lines of the image, see `DecompressStarted::row_bands()`
+#[derive(Debug, Clone)]
+pub struct RowBand {
+    /// Index of the first line in the band
+    pub y: usize,
+    /// Number of lines in the band. The last band may be shorter than the others.
+    pub height: usize,
+    /// Tightly packed pixels of all lines of the band
+    pub pixels: Vec<u8>,
+}
+
+/// Decodes the image one band of lines at a time, as they're requested
+///
+/// It's an `Iterator`, and with the `futures` feature also a `Stream`, so a slow consumer
+/// applies backpressure: nothing is decoded until the next band is polled.
+/// After the last band, decompression is finished.
+pub struct RowBands<'src> {
+    dinfo: Option<DecompressStarted<'src>>,
+    band_height: usize,
+    y: usize,
+}
+
+impl<'src> DecompressStarted<'src> {
+    /// Decode the remaining lines in bands of `band_height` lines
+    pub fn row_bands(self, band_height: usize) -> RowBands<'src> {
+        assert!(band_height > 0);
+        RowBands {
+            dinfo: Some(self),
+            band_height,
+            y: 0,
+        }
+    }
+}
+
+impl<'src> RowBands<'src> {
+    fn next_band(&mut self) -> Option<io::Result<RowBand>> {
+        let done = match self.dinfo {
+            Some(ref dinfo) => self.y >= dinfo.height(),
+            None => return None,
+        };
+        if done {
+            let finished = self.dinfo.take().map_or(false, |dinfo| dinfo.finish_decompress());
+            return if finished { None } else {
+                Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete")))
+            };
+        }
+
+        let dinfo = self.dinfo.as_mut().unwrap();
+        let mut pixels = Vec::with_capacity(self.band_height * dinfo.width() * dinfo.output_components());
+        if !dinfo.read_lines_into(&mut pixels, self.band_height) {
+            self.dinfo = None;
+            return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG decoder didn't return all lines")));
+        }
+        let height = pixels.len() / (dinfo.width() * dinfo.output_components());
+        let band = RowBand { y: self.y, height, pixels };
+        self.y += height;
+        Some(Ok(band))
+    }
+}
+
+impl<'src> Iterator for RowBands<'src> {
+    type Item = io::Result<RowBand>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_band()
+    }
+}
+
+/// Bands are decoded synchronously in `poll_next()`, so it's always ready
+#[cfg(feature = "futures")]
+impl<'src> Stream for RowBands<'src> {
+    type Item = io::Result<RowBand>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().next_band())
+    }
+}
@@ -0,0 +1,125 @@
+use compress::Compress;
+use decompress::Decompress;
+use colorspace::ColorSpace;
+use errormgr::panic_to_error;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// A JPEG file to process, see `run()`
+pub enum Input {
+    #[cfg(not(target_arch = "wasm32"))]
+    Path(PathBuf),
+    Data(Vec<u8>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<PathBuf> for Input {
+    fn from(path: PathBuf) -> Input {
+        Input::Path(path)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<'a> From<&'a Path> for Input {
+    fn from(path: &'a Path) -> Input {
+        Input::Path(path.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for Input {
+    fn from(data: Vec<u8>) -> Input {
+        Input::Data(data)
+    }
+}
+
+/// State of one worker thread, kept between the images it processes
+pub struct Worker {
+    compress: Option<(ColorSpace, Compress)>,
+}
+
+impl Worker {
+    /// Compressor reused for all images of this worker, which avoids setting up libjpeg every time
+    ///
+    /// It's created again only if `color_space` changes. Settings from the previous image
+    /// are kept, so set all that matter (size, quality, etc.) every time.
+    pub fn compress(&mut self, color_space: ColorSpace) -> &mut Compress {
+        let reuse = match self.compress {
+            Some((cs, _)) => cs == color_space,
+            None => false,
+        };
+        if !reuse {
+            self.compress = Some((color_space, Compress::new(color_space)));
+        }
+        &mut self.compress.as_mut().unwrap().1
+    }
+}
+
+/// Results of `run()`, in order of completion
+///
+/// Each item has the index of the input it came from.
+pub struct Results<T> {
+    receiver: Receiver<(usize, io::Result<T>)>,
+}
+
+impl<T> Iterator for Results<T> {
+    type Item = (usize, io::Result<T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Process many JPEG files on `threads` threads
+///
+/// `job` gets a reader for every input, and returns its result (e.g. a compressed thumbnail).
+/// Inputs are read lazily, as workers become free. Errors and panics of `job` are reported
+/// as errors of the file that caused them, and don't stop the batch. Fatal libjpeg errors
+/// (e.g. of corrupt JPEG data) can't be caught, and abort the process.
+///
+/// ```rust,ignore
+/// let thumbnails = batch::run(paths, 4, |worker, dinfo| { ... });
+/// for (index, result) in thumbnails { ... }
+/// ```
+pub fn run<I, F, T>(inputs: I, threads: usize, job: F) -> Results<T>
+    where I: IntoIterator, I::Item: Into<Input>, I::IntoIter: Send + 'static,
+          F: Fn(&mut Worker, Decompress) -> io::Result<T> + Send + Sync + 'static,
+          T: Send + 'static
+{
+    assert!(threads > 0);
+    let inputs = Arc::new(Mutex::new(inputs.into_iter().enumerate()));
+    let job = Arc::new(job);
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..threads {
+        let inputs = inputs.clone();
+        let job = job.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut worker = Worker { compress: None };
+            loop {
+                let next = match inputs.lock() {
+                    Ok(mut inputs) => inputs.next(),
+                    Err(_) => None,
+                };
+                let (index, input) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    match input.into() {
+                        #[cfg(not(target_arch = "wasm32"))]
+                        Input::Path(path) => job(&mut worker, Decompress::new_path(path)?),
+                        Input::Data(data) => job(&mut worker, Decompress::new_mem(&data)?),
+                    }
+                })).unwrap_or_else(|payload| Err(panic_to_error(payload)));
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    Results { receiver }
+}
@@ -0,0 +1,122 @@
+//! C interface to the one-shot functions, for programs not written in Rust. Requires the `capi` feature.
+//!
+//! Build a shared library with `cargo rustc --release --features capi --crate-type cdylib`.
+//!
+//! ```c
+//! uint8_t *mozjpeg_decode_rgb(const uint8_t *jpeg, size_t jpeg_len, size_t *width, size_t *height, size_t *out_len);
+//! uint8_t *mozjpeg_encode_rgb(const uint8_t *pixels, size_t width, size_t height, float quality, size_t *out_len);
+//! uint8_t *mozjpeg_encode_gray(const uint8_t *pixels, size_t width, size_t height, float quality, size_t *out_len);
+//! uint8_t *mozjpeg_optimize(const uint8_t *jpeg, size_t jpeg_len, float max_quality, size_t target_size, size_t *out_len);
+//! void mozjpeg_free(uint8_t *data, size_t len);
+//! ```
+//!
+//! Functions return `NULL` on errors, including data that isn't JPEG and unsupported files.
+//! Fatal libjpeg errors (e.g. of corrupt JPEG data) abort the process, because they can't unwind
+//! out of libjpeg's error handler.
+//! Returned buffers must be freed with `mozjpeg_free()`, passing the length from `out_len`.
+use errormgr::catch_panics;
+use oneshot::{decode_rgb, encode_rgb, encode_gray};
+use optimize::{optimize, OptimizeOptions};
+use std::io;
+use std::ptr;
+use std::slice;
+
+/// Decode JPEG data to RGB pixels, 3 bytes per pixel. See `decode_rgb()`.
+///
+/// # Safety
+///
+/// `jpeg` must point to `jpeg_len` readable bytes, and the other pointers must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mozjpeg_decode_rgb(jpeg: *const u8, jpeg_len: usize, width: *mut usize, height: *mut usize, out_len: *mut usize) -> *mut u8 {
+    if jpeg.is_null() || width.is_null() || height.is_null() || out_len.is_null() {
+        return ptr::null_mut();
+    }
+    let jpeg = slice::from_raw_parts(jpeg, jpeg_len);
+    match decode_rgb(jpeg) {
+        Ok((pixels, w, h)) => {
+            *width = w;
+            *height = h;
+            into_raw(pixels, out_len)
+        },
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Compress RGB pixels, 3 bytes per pixel, with quality in 1-100 range. See `encode_rgb()`.
+///
+/// # Safety
+///
+/// `pixels` must point to `width * height * 3` readable bytes, and `out_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mozjpeg_encode_rgb(pixels: *const u8, width: usize, height: usize, quality: f32, out_len: *mut usize) -> *mut u8 {
+    encode(encode_rgb, 3, pixels, width, height, quality, out_len)
+}
+
+/// Compress grayscale pixels, 1 byte per pixel, with quality in 1-100 range. See `encode_gray()`.
+///
+/// # Safety
+///
+/// `pixels` must point to `width * height` readable bytes, and `out_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mozjpeg_encode_gray(pixels: *const u8, width: usize, height: usize, quality: f32, out_len: *mut usize) -> *mut u8 {
+    encode(encode_gray, 1, pixels, width, height, quality, out_len)
+}
+
+/// Make a JPEG file smaller. See `optimize()`.
+///
+/// `max_quality` of 0 or less keeps the original quality, and `target_size` of 0 sets no size limit.
+///
+/// # Safety
+///
+/// `jpeg` must point to `jpeg_len` readable bytes, and `out_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mozjpeg_optimize(jpeg: *const u8, jpeg_len: usize, max_quality: f32, target_size: usize, out_len: *mut usize) -> *mut u8 {
+    if jpeg.is_null() || out_len.is_null() {
+        return ptr::null_mut();
+    }
+    let jpeg = slice::from_raw_parts(jpeg, jpeg_len);
+    let options = OptimizeOptions {
+        max_quality: if max_quality > 0. { Some(max_quality) } else { None },
+        target_size: if target_size > 0 { Some(target_size) } else { None },
+        ..OptimizeOptions::default()
+    };
+    match catch_panics(|| optimize(jpeg, &options)) {
+        Ok(data) => into_raw(data, out_len),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Free a buffer returned by any of the functions above. `NULL` is ignored.
+///
+/// # Safety
+///
+/// `data` and `len` must come from one of these functions, and the buffer can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mozjpeg_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// `encode_rgb()` or `encode_gray()`
+type EncodeFn = fn(&[u8], usize, usize, f32) -> io::Result<Vec<u8>>;
+
+unsafe fn encode(encode: EncodeFn, components: usize, pixels: *const u8, width: usize, height: usize, quality: f32, out_len: *mut usize) -> *mut u8 {
+    let len = width.checked_mul(height).and_then(|px| px.checked_mul(components));
+    let len = match len {
+        Some(len) if !pixels.is_null() && !out_len.is_null() => len,
+        _ => return ptr::null_mut(),
+    };
+    let pixels = slice::from_raw_parts(pixels, len);
+    match encode(pixels, width, height, quality) {
+        Ok(data) => into_raw(data, out_len),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// The length is exact, so that `mozjpeg_free()` can rebuild the `Box`
+unsafe fn into_raw(data: Vec<u8>, out_len: *mut usize) -> *mut u8 {
+    let data = data.into_boxed_slice();
+    *out_len = data.len();
+    Box::into_raw(data) as *mut u8
+}
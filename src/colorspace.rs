@@ -1,8 +1,45 @@
 pub use ffi::J_COLOR_SPACE as ColorSpace;
+use std::error;
+use std::fmt;
+
+/// Names used by `ColorSpaceExt::name()` and `parse_color_space()`
+const NAMES: [(ColorSpace, &str); 17] = [
+    (ColorSpace::JCS_UNKNOWN, "unknown"),
+    (ColorSpace::JCS_GRAYSCALE, "gray"),
+    (ColorSpace::JCS_RGB, "rgb"),
+    (ColorSpace::JCS_YCbCr, "ycbcr"),
+    (ColorSpace::JCS_CMYK, "cmyk"),
+    (ColorSpace::JCS_YCCK, "ycck"),
+    (ColorSpace::JCS_EXT_RGB, "ext-rgb"),
+    (ColorSpace::JCS_EXT_RGBX, "rgbx"),
+    (ColorSpace::JCS_EXT_BGR, "bgr"),
+    (ColorSpace::JCS_EXT_BGRX, "bgrx"),
+    (ColorSpace::JCS_EXT_XBGR, "xbgr"),
+    (ColorSpace::JCS_EXT_XRGB, "xrgb"),
+    (ColorSpace::JCS_EXT_RGBA, "rgba"),
+    (ColorSpace::JCS_EXT_BGRA, "bgra"),
+    (ColorSpace::JCS_EXT_ABGR, "abgr"),
+    (ColorSpace::JCS_EXT_ARGB, "argb"),
+    (ColorSpace::JCS_RGB565, "rgb565"),
+];
 
 pub trait ColorSpaceExt {
     /// Number of channels (including unused alpha) in this color space
     fn num_components(&self) -> usize;
+
+    /// Bytes of one decoded pixel. Same as `num_components()`, except for `JCS_RGB565`, which is packed in 2 bytes.
+    fn bytes_per_pixel(&self) -> usize;
+
+    /// `true` for the `JCS_EXT_RGBA` family. libjpeg fills alpha with 255.
+    fn has_alpha(&self) -> bool;
+
+    /// `true` for libjpeg-turbo's `JCS_EXT_*` channel orders of RGB
+    fn is_extended_rgb(&self) -> bool;
+
+    /// Lowercase name, e.g. `"ycbcr"`, that `parse_color_space()` accepts
+    ///
+    /// `ColorSpace` is defined in `mozjpeg-sys`, so it can't implement `Display` and `FromStr` in this crate.
+    fn name(&self) -> &'static str;
 }
 
 impl ColorSpaceExt for ColorSpace {
@@ -27,8 +64,61 @@ impl ColorSpaceExt for ColorSpace {
             ColorSpace::JCS_RGB565 => 3,
         }
     }
+
+    fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            ColorSpace::JCS_RGB565 => 2,
+            _ => self.num_components(),
+        }
+    }
+
+    fn has_alpha(&self) -> bool {
+        matches!(*self,
+            ColorSpace::JCS_EXT_RGBA | ColorSpace::JCS_EXT_BGRA |
+            ColorSpace::JCS_EXT_ABGR | ColorSpace::JCS_EXT_ARGB)
+    }
+
+    fn is_extended_rgb(&self) -> bool {
+        matches!(*self,
+            ColorSpace::JCS_EXT_RGB | ColorSpace::JCS_EXT_RGBX | ColorSpace::JCS_EXT_BGR |
+            ColorSpace::JCS_EXT_BGRX | ColorSpace::JCS_EXT_XBGR | ColorSpace::JCS_EXT_XRGB |
+            ColorSpace::JCS_EXT_RGBA | ColorSpace::JCS_EXT_BGRA | ColorSpace::JCS_EXT_ABGR |
+            ColorSpace::JCS_EXT_ARGB)
+    }
+
+    fn name(&self) -> &'static str {
+        NAMES.iter().find(|&&(cs, _)| cs == *self).map(|&(_, name)| name).unwrap()
+    }
+}
+
+/// Color space from a name, e.g. from the command line
+///
+/// Accepts names from `ColorSpaceExt::name()`, case-insensitive, and also `"grayscale"`, `"grey"` and `"yuv"`.
+pub fn parse_color_space(name: &str) -> Result<ColorSpace, ParseColorSpaceError> {
+    let lower = name.to_ascii_lowercase();
+    let lower = match &lower[..] {
+        "grayscale" | "grey" => "gray",
+        "yuv" => "ycbcr",
+        other => other,
+    };
+    NAMES.iter().find(|&&(_, n)| n == lower).map(|&(cs, _)| cs)
+        .ok_or_else(|| ParseColorSpaceError { name: name.to_string() })
+}
+
+/// Error of `parse_color_space()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseColorSpaceError {
+    name: String,
+}
+
+impl fmt::Display for ParseColorSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown color space '{}' (expected e.g. rgb, ycbcr, cmyk or gray)", self.name)
+    }
 }
 
+impl error::Error for ParseColorSpaceError {}
+
 #[test]
 fn test() {
     use ffi;
@@ -36,3 +126,20 @@ fn test() {
     assert_eq!(3, ffi::J_COLOR_SPACE::JCS_RGB.num_components());
     assert_eq!(1, ffi::J_COLOR_SPACE::JCS_GRAYSCALE.num_components());
 }
+
+#[test]
+fn names() {
+    for &(cs, name) in NAMES.iter() {
+        assert_eq!(name, cs.name());
+        assert_eq!(Ok(cs), parse_color_space(name));
+    }
+    assert_eq!(Ok(ColorSpace::JCS_GRAYSCALE), parse_color_space("Grey"));
+    assert_eq!(Ok(ColorSpace::JCS_YCbCr), parse_color_space("YCbCr"));
+    assert!(parse_color_space("hsv").is_err());
+
+    assert!(ColorSpace::JCS_EXT_BGRA.has_alpha() && ColorSpace::JCS_EXT_BGRA.is_extended_rgb());
+    assert!(!ColorSpace::JCS_EXT_BGRX.has_alpha());
+    assert!(!ColorSpace::JCS_RGB.is_extended_rgb());
+    assert_eq!(2, ColorSpace::JCS_RGB565.bytes_per_pixel());
+    assert_eq!(4, ColorSpace::JCS_EXT_XRGB.bytes_per_pixel());
+}
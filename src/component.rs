@@ -15,6 +15,27 @@ pub trait CompInfoExt {
 
     // Quantization table, if available
     fn qtable(&self) -> Option<QTable>;
+
+    /// Index of the quantization table (0-3) used by this component
+    fn quant_table_slot(&self) -> usize;
+
+    /// Number of DCT blocks per row, without padding to MCU
+    fn blocks_per_row(&self) -> usize;
+    /// Number of DCT blocks per column, without padding to MCU
+    fn blocks_per_column(&self) -> usize;
+
+    /// Number of rows of raw data, padded to whole iMCU rows, as read by `read_raw_data()` and written by `write_raw_data()`
+    fn padded_rows(&self) -> usize;
+
+    /// Width and height in samples, after downsampling and without padding
+    ///
+    /// libjpeg sets it when compression or decompression starts, and it includes scaling when decoding.
+    fn downsampled_size(&self) -> (usize, usize);
+
+    /// Width and height of one decoded block, in samples (8 unless decoding at a smaller scale)
+    ///
+    /// libjpeg sets it when decompression starts.
+    fn dct_scaled_size(&self) -> usize;
 }
 
 impl CompInfoExt for CompInfo {
@@ -30,6 +51,31 @@ impl CompInfoExt for CompInfo {
         })
     }
 
+    fn quant_table_slot(&self) -> usize {
+        self.quant_tbl_no as usize
+    }
+
+    fn blocks_per_row(&self) -> usize {
+        self.width_in_blocks as usize
+    }
+
+    fn blocks_per_column(&self) -> usize {
+        self.height_in_blocks as usize
+    }
+
+    fn padded_rows(&self) -> usize {
+        let v_samp_factor = self.v_samp_factor as usize;
+        (self.blocks_per_column() + v_samp_factor - 1) / v_samp_factor * v_samp_factor * DCTSIZE
+    }
+
+    fn downsampled_size(&self) -> (usize, usize) {
+        (self.downsampled_width as usize, self.downsampled_height as usize)
+    }
+
+    fn dct_scaled_size(&self) -> usize {
+        self.DCT_scaled_size as usize
+    }
+
     fn sampling(&self) -> (u8, u8) {
         (self.h_samp_factor as u8, self.v_samp_factor as u8)
     }
@@ -44,3 +90,21 @@ impl CompInfoExt for CompInfo {
         self.height_in_blocks as usize * DCTSIZE
     }
 }
+
+#[test]
+fn geometry() {
+    let mut c: CompInfo = unsafe { ::std::mem::zeroed() };
+    c.h_samp_factor = 1;
+    c.v_samp_factor = 2;
+    c.width_in_blocks = 3;
+    c.height_in_blocks = 5;
+    c.quant_tbl_no = 1;
+    assert_eq!(1, c.quant_table_slot());
+    assert_eq!((3, 5), (c.blocks_per_row(), c.blocks_per_column()));
+    assert_eq!(48, c.padded_rows());
+    c.downsampled_width = 23;
+    c.downsampled_height = 40;
+    c.DCT_scaled_size = 4;
+    assert_eq!((23, 40), c.downsampled_size());
+    assert_eq!(4, c.dct_scaled_size());
+}
@@ -5,62 +5,317 @@ use errormgr::PanicingErrorMgr;
 use component::CompInfoExt;
 use component::CompInfo;
 use marker::Marker;
+use dest::BufferDest;
+use dest::WriterDest;
+#[cfg(feature = "memmap2")]
+use dest::MmapDest;
+use transform::Blocks;
+use index::scan_index;
+use scan_script::ScanScript;
+use limits::{self, Limits};
+use jfif::JfifThumbnail;
+use defaults;
+use ext;
+use pool::IdleList;
 use colorspace::ColorSpace;
 use colorspace::ColorSpaceExt;
+use pixel::{self, Pixel};
+use qtable;
 use qtable::QTable;
+use decompress::{Decompress, MarkerData, subsampling};
+#[cfg(feature = "perceptual")]
+use metrics;
 use ffi;
 use ffi::JPEG_LIB_VERSION;
 use ffi::J_INT_PARAM;
 use ffi::J_BOOLEAN_PARAM;
+use ffi::J_FLOAT_PARAM;
 use ffi::jpeg_compress_struct;
+use ffi::jpeg_common_struct;
 use ffi::boolean;
 use ffi::DCTSIZE;
 use ffi::JDIMENSION;
-use std::os::raw::{c_int, c_uint, c_ulong, c_uchar};
-use libc::free;
-use libc::c_void;
+use std::os::raw::{c_int, c_uint, c_ulong, c_uchar, c_void};
+use free;
 use arrayvec::ArrayVec;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 use std::slice;
 use std::mem;
 use std::ptr;
-use std::cmp::min;
+use std::cmp::{min, max};
+use std::io;
+use std::io::{Read, Write};
+#[cfg(feature = "memmap2")]
+use std::fs::File;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
 
 const MAX_MCU_HEIGHT: usize = 16;
 const MAX_COMPONENTS: usize = 4;
+const MAX_SAMP_FACTOR: usize = 4;
+const MAX_BLOCKS_IN_MCU: usize = 10;
+// Quantization and Huffman tables each have 4 slots
+const NUM_TABLE_SLOTS: usize = 4;
+const JPEG_MAX_DIMENSION: usize = 65500;
 
 /// Create a new JPEG file from pixels
 ///
 /// Wrapper for `jpeg_compress_struct`
+///
+/// Output is deterministic: the same pixels and settings always give byte-identical files,
+/// regardless of thread or run, since none of MozJPEG's optimizations depend on timing or randomness.
+/// See `set_deterministic()` for output that also doesn't depend on the CPU's SIMD support or the number of threads.
 pub struct Compress {
-    cinfo: jpeg_compress_struct,
+    pub(crate) cinfo: jpeg_compress_struct,
     own_err: Box<ErrorMgr>,
     outbuffer: *mut c_uchar,
     outsize: c_ulong,
+    bottom_up: bool,
+    // `None` keeps the defaults: off for `set_quality()`, on for `set_luma_qtable()` and `set_chroma_qtable()`
+    force_baseline: Option<bool>,
+    bounded_memory: bool,
+    progress: Option<Box<ProgressMgr>>,
+    buffer_dest: Option<Box<BufferDest>>,
+    writer_dest: Option<Box<WriterDest>>,
+    #[cfg(feature = "memmap2")]
+    mmap_dest: Option<Box<MmapDest>>,
+    // libjpeg keeps a pointer to it while compressing
+    scan_script: Vec<ffi::jpeg_scan_info>,
+    limits: Limits,
+    // Written with `write_marker()` since `start_compress()`, for `Limits::max_marker_bytes`
+    marker_bytes: usize,
+    // libjpeg keeps a pointer to it while writing coefficients
+    coef_arrays: Vec<*mut ffi::jvirt_barray_control>,
+    // Compressors from a `Pool` go back to it when dropped
+    pool: Option<Arc<IdleList<Compress>>>,
+    // libjpeg's memory destination, detached from the previous user of a pooled compressor
+    spare_mem_dest: *mut ffi::jpeg_destination_mgr,
+    jfif_thumbnail: Option<JfifThumbnail>,
+    deterministic: bool,
+}
+
+/// Progress of compression, see `Compress::set_progress_callback()`
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    /// Work units completed in the current pass
+    pub pass_counter: i64,
+    /// Total work units in the current pass
+    pub pass_limit: i64,
+    pub completed_passes: i32,
+    /// May grow during compression
+    pub total_passes: i32,
+}
+
+impl Progress {
+    /// Estimate of overall progress, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        if self.total_passes <= 0 || self.pass_limit <= 0 {
+            return 0.;
+        }
+        (self.completed_passes as f64 + self.pass_counter as f64 / self.pass_limit as f64) / self.total_passes as f64
+    }
 }
 
-#[derive(Copy,Clone)]
+/// Panic payload of compression cancelled by the progress callback
+///
+/// It's raised when the libjpeg call that ran the callback returns, see `Compress::set_progress_callback()`.
+#[derive(Debug)]
+pub struct Cancelled;
+
+/// Invalid compression settings, found before libjpeg starts. See `Compress::validate()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// Width or height is 0 or larger than 65500
+    InvalidSize { width: usize, height: usize },
+    /// Number of input components doesn't match the input color space
+    InputComponents { color_space: ColorSpace, expected: usize, got: usize },
+    /// libjpeg can't convert input in the first color space to the second (JPEG) one
+    UnsupportedConversion { from: ColorSpace, to: ColorSpace },
+    /// Sampling factors of the component are not in 1-4 range
+    InvalidSamplingFactor { component: usize, h_samp_factor: i32, v_samp_factor: i32 },
+    /// Sampling factors of the component don't evenly divide the largest ones
+    FractionalSampling { component: usize },
+    /// Sampling factors add up to more than 10 blocks per MCU
+    TooManyBlocksInMcu { blocks: usize },
+    /// The option needs a buffer for the whole image, which `set_bounded_memory()` doesn't allow
+    NeedsWholeImageBuffer { option: &'static str },
+    /// The scan script breaks libjpeg's rules, see `ScanScript::validate()`
+    InvalidScanScript { scan: usize, reason: &'static str },
+    /// The value is over the limit set with `Compress::set_limits()` or `DecompressConfig::with_limits()`
+    LimitExceeded { limit: &'static str, value: usize, max: usize },
+    /// There's no component with this index
+    InvalidComponent { component: usize, num_components: usize },
+    /// The quantization (`"quant"`), `"dc"` or `"ac"` Huffman table slot is not in 0-3 range,
+    /// or no quantization table has been set in the slot
+    InvalidTableSlot { component: usize, table: &'static str, slot: usize },
+    /// The JFIF thumbnail doesn't match its size or doesn't fit in a marker
+    InvalidThumbnail { reason: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::InvalidSize { width, height } =>
+                write!(f, "Image size {}x{} is invalid (must be 1-{})", width, height, JPEG_MAX_DIMENSION),
+            ConfigError::InputComponents { color_space, expected, got } =>
+                write!(f, "Input color space {:?} has {} components, but input has {}", color_space, expected, got),
+            ConfigError::UnsupportedConversion { from, to } =>
+                write!(f, "Can't convert input color space {:?} to JPEG color space {:?}", from, to),
+            ConfigError::InvalidSamplingFactor { component, h_samp_factor, v_samp_factor } =>
+                write!(f, "Sampling factors {}x{} of component {} are invalid (must be 1-{})", h_samp_factor, v_samp_factor, component, MAX_SAMP_FACTOR),
+            ConfigError::FractionalSampling { component } =>
+                write!(f, "Sampling factors of component {} don't evenly divide the largest sampling factors", component),
+            ConfigError::TooManyBlocksInMcu { blocks } =>
+                write!(f, "Sampling factors need {} blocks per MCU (max is {})", blocks, MAX_BLOCKS_IN_MCU),
+            ConfigError::NeedsWholeImageBuffer { option } =>
+                write!(f, "{} buffers the whole image, which is not allowed in bounded memory mode", option),
+            ConfigError::InvalidScanScript { scan, reason } =>
+                write!(f, "Scan {} of the scan script is invalid: {}", scan, reason),
+            ConfigError::LimitExceeded { limit, value, max } =>
+                write!(f, "{} is over the limit ({} > {})", limit, value, max),
+            ConfigError::InvalidComponent { component, num_components } =>
+                write!(f, "Component {} doesn't exist (there are {})", component, num_components),
+            ConfigError::InvalidTableSlot { component, table, slot } =>
+                write!(f, "The {} table slot {} of component {} is invalid or empty", table, slot, component),
+            ConfigError::InvalidThumbnail { reason } =>
+                write!(f, "JFIF thumbnail is invalid: {}", reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+#[repr(C)]
+struct ProgressMgr {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_progress_mgr,
+    callback: Box<dyn FnMut(&Progress) -> bool>,
+    // `Cancelled` or the callback's own panic. It can't unwind through libjpeg, so it's raised
+    // by `CompressStarted` when libjpeg returns.
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+extern "C" fn progress_monitor(cinfo: &mut jpeg_common_struct) {
+    unsafe {
+        let progress_mgr = &mut *(cinfo.progress as *mut ProgressMgr);
+        if progress_mgr.panic.is_some() {
+            return;
+        }
+        // c_long is only 32-bit on Windows
+        #[allow(clippy::unnecessary_cast)]
+        let progress = Progress {
+            pass_counter: progress_mgr.mgr.pass_counter as i64,
+            pass_limit: progress_mgr.mgr.pass_limit as i64,
+            completed_passes: progress_mgr.mgr.completed_passes,
+            total_passes: progress_mgr.mgr.total_passes,
+        };
+        let callback = &mut progress_mgr.callback;
+        progress_mgr.panic = match panic::catch_unwind(AssertUnwindSafe(|| callback(&progress))) {
+            Ok(true) => None,
+            Ok(false) => Some(Box::new(Cancelled)),
+            Err(payload) => Some(payload),
+        };
+    }
+}
+
+/// How DC coefficients are split into scans in progressive mode (`JINT_DC_SCAN_OPT_MODE`)
+///
+/// Some decoders can't handle DC scans with all components interleaved.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ScanMode {
+    /// One DC scan for all components
     AllComponentsTogether = 0,
+    /// One DC scan per component
     ScanPerComponent = 1,
+    /// Pick the smaller of one scan for all components, or one scan for luma and one for chroma (the default)
     Auto = 2,
 }
 
+/// How components of a sequential file are arranged in scans, see `Compress::set_scan_layout()`
+///
+/// Some hardware decoders support only one of these layouts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ScanLayout {
+    /// One scan with blocks of all components interleaved in MCUs (the usual baseline layout)
+    Interleaved,
+    /// One scan per component, each with all of its blocks in order
+    NonInterleaved,
+}
+
+/// Preset for all of mozjpeg's settings, see `Compress::set_compress_profile()`
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CompressProfile {
+    /// Progressive, with trellis quantization and all other mozjpeg extensions (the default)
+    MaxCompression,
+    /// Baseline libjpeg v6 defaults, without slow mozjpeg extensions
+    Fastest,
+}
+
+/// Optimize for a quality metric, like cjpeg's `-tune-*` options
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Tune {
+    /// `-tune-psnr`
+    Psnr,
+    /// `-tune-ssim`
+    Ssim,
+    /// `-tune-ms-ssim`
+    MsSsim,
+    /// `-tune-hvs-psnr` (the default)
+    HvsPsnr,
+}
+
 impl Compress {
     /// Compress image using input in this colorspace
     ///
     /// By default errors cause panic and unwind through the C code,
     /// which strictly speaking is not guaranteed to work in Rust (but seems to work fine, at least in x86/64).
+    ///
+    /// The error manager, limits and progressive mode can be changed for all new instances with `set_defaults()`.
     pub fn new(color_space: ColorSpace) -> Compress {
-        Compress::new_err(<ErrorMgr as PanicingErrorMgr>::new(), color_space)
+        Compress::new_err(defaults::error_mgr(&defaults::defaults()), color_space)
+    }
+
+    /// Compress image with 1-4 arbitrary components, stored without any color conversion
+    ///
+    /// The file will have `JCS_UNKNOWN` color space. Use `Decompress::passthrough()` to read it back.
+    pub fn new_unknown(num_components: usize) -> Compress {
+        assert!((1..=MAX_COMPONENTS).contains(&num_components), "Unsupported number of components: {}", num_components);
+        Compress::new_err_components(defaults::error_mgr(&defaults::defaults()), ColorSpace::JCS_UNKNOWN, num_components)
     }
 
     pub fn new_err(err: ErrorMgr, color_space: ColorSpace) -> Compress {
+        Compress::new_err_components(err, color_space, color_space.num_components())
+    }
+
+    fn new_err_components(err: ErrorMgr, color_space: ColorSpace, input_components: usize) -> Compress {
         unsafe {
             let mut newself = Compress{
                 cinfo: mem::zeroed(),
                 own_err: Box::new(err),
                 outbuffer: ::std::ptr::null_mut(),
                 outsize: 0,
+                bottom_up: false,
+                force_baseline: None,
+                bounded_memory: false,
+                progress: None,
+                buffer_dest: None,
+                writer_dest: None,
+                #[cfg(feature = "memmap2")]
+                mmap_dest: None,
+                scan_script: Vec::new(),
+                limits: Limits::default(),
+                marker_bytes: 0,
+                coef_arrays: Vec::new(),
+                pool: None,
+                spare_mem_dest: ptr::null_mut(),
+                jfif_thumbnail: None,
+                deterministic: false,
             };
 
             newself.cinfo.common.err = &mut *newself.own_err;
@@ -69,125 +324,373 @@ impl Compress {
             ffi::jpeg_CreateCompress(&mut newself.cinfo, JPEG_LIB_VERSION, s);
 
             newself.cinfo.in_color_space = color_space;
-            newself.cinfo.input_components = color_space.num_components() as c_int;
-            ffi::jpeg_set_defaults(&mut newself.cinfo);
+            newself.cinfo.input_components = input_components as c_int;
+            ext::jpeg_set_defaults(&mut newself.cinfo);
+            defaults::apply_to_compress(&defaults::defaults(), &mut newself);
 
             newself
         }
     }
 
-    pub fn start_compress(&mut self) {
+    /// Set up a compressor from a `Pool` as if it was created with `new(color_space)`
+    pub(crate) fn reset(&mut self, color_space: ColorSpace, pool: Arc<IdleList<Compress>>) {
         unsafe {
-            ffi::jpeg_start_compress(&mut self.cinfo, true as boolean);
+            self.cinfo.in_color_space = color_space;
+            self.cinfo.input_components = color_space.num_components() as c_int;
+            ext::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_COMPRESS_PROFILE,
+                ffi::JINT_COMPRESS_PROFILE_VALUE::JCP_MAX_COMPRESSION as c_int);
+            // jpeg_set_defaults() makes quantization tables before it resets this, so it must be as in a new object
+            ext::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX, 0);
+            ext::jpeg_set_defaults(&mut self.cinfo);
         }
+        defaults::apply_to_compress(&defaults::defaults(), self);
+        self.deterministic = false;
+        self.pool = Some(pool);
     }
 
-    pub fn write_marker(&mut self, marker: Marker, data: &[u8]) {
+    /// Move libjpeg's state out of this compressor, so that it can be reused by a `Pool`
+    ///
+    /// Only for `drop()`: afterwards this compressor shares `cinfo` with the returned one, and must not use it.
+    fn take_idle(&mut self) -> Compress {
         unsafe {
-            ffi::jpeg_write_marker(&mut self.cinfo, marker.into(), data.as_ptr(), data.len() as c_uint);
-
+            ffi::jpeg_abort_compress(&mut self.cinfo);
         }
-    }
-
-    /// Expose components for modification
-    pub fn components_mut(&mut self) -> &mut [CompInfo] {
+        // Destinations owned by Rust are freed, but libjpeg's own one can be reused by `set_mem_dest()`
+        if self.take_rust_dest() {
+            self.cinfo.dest = ptr::null_mut();
+        }
+        let spare_mem_dest = if self.cinfo.dest.is_null() { self.spare_mem_dest } else { self.cinfo.dest };
+        self.cinfo.dest = ptr::null_mut();
+        self.cinfo.common.progress = ptr::null_mut();
+        self.progress = None;
+        self.coef_arrays.clear();
         unsafe {
-            slice::from_raw_parts_mut(self.cinfo.comp_info, self.cinfo.num_components as usize)
+            Compress {
+                cinfo: ptr::read(&self.cinfo),
+                own_err: mem::replace(&mut self.own_err, Box::new(mem::zeroed())),
+                outbuffer: ptr::null_mut(),
+                outsize: 0,
+                bottom_up: false,
+                force_baseline: None,
+                bounded_memory: false,
+                progress: None,
+                buffer_dest: None,
+                writer_dest: None,
+                #[cfg(feature = "memmap2")]
+                mmap_dest: None,
+                scan_script: Vec::new(),
+                limits: Limits::default(),
+                marker_bytes: 0,
+                coef_arrays: Vec::new(),
+                pool: None,
+                spare_mem_dest,
+                jfif_thumbnail: None,
+                deterministic: false,
+            }
         }
     }
 
-    pub fn components(&self) -> &[CompInfo] {
-        unsafe {
-            slice::from_raw_parts(self.cinfo.comp_info, self.cinfo.num_components as usize)
-        }
+    /// Start compressing an image. Settings can't be changed until it's finished or aborted.
+    ///
+    /// Panics if settings are invalid (see `validate()`). Use `try_start_compress()` to get the error instead.
+    pub fn start_compress(&mut self) -> CompressStarted<'_> {
+        self.start(true)
     }
 
-    fn can_write_more_lines(&self) -> bool {
-        self.cinfo.next_scanline < self.cinfo.image_height
+    /// Like `start_compress()`, but returns an error instead of panicking if settings are invalid
+    pub fn try_start_compress(&mut self) -> Result<CompressStarted<'_>, ConfigError> {
+        self.validate()?;
+        Ok(self.start(true))
     }
 
-    /// Returns true if all lines in image_src (not necessarily all lines of the image) were written
-    pub fn write_scanlines(&mut self, image_src: &[u8]) -> bool {
-        assert_eq!(0, self.cinfo.raw_data_in);
-        assert!(self.cinfo.input_components > 0);
-        assert!(self.cinfo.image_width > 0);
-
-        let byte_width = self.cinfo.image_width as usize * self.cinfo.input_components as usize;
-        for rows in image_src.chunks(MAX_MCU_HEIGHT * byte_width) {
-            let mut row_pointers = ArrayVec::<[_; MAX_MCU_HEIGHT]>::new();
-            for row in rows.chunks(byte_width) {
-                debug_assert!(row.len() == byte_width);
-                row_pointers.push(row.as_ptr());
-            }
+    fn start(&mut self, write_all_tables: bool) -> CompressStarted<'_> {
+        if let Err(err) = self.validate() {
+            panic!("Invalid compression settings: {}", err);
+        }
+        self.marker_bytes = 0;
+        if self.deterministic {
+            self.cinfo.dct_method = ffi::J_DCT_METHOD::JDCT_ISLOW;
+        }
+        let thumbnail = match self.jfif_thumbnail {
+            Some(ref thumbnail) if 0 != self.cinfo.write_JFIF_header => {
+                // Extension segments are defined in JFIF 1.02
+                self.cinfo.JFIF_minor_version = self.cinfo.JFIF_minor_version.max(2);
+                Some(thumbnail.segment())
+            },
+            _ => None,
+        };
+        unsafe {
+            ffi::jpeg_start_compress(&mut self.cinfo, write_all_tables as boolean);
+        }
+        // Aborts the image if the destination failed
+        let mut started = CompressStarted { comp: self };
+        started.comp.resume_callback_panic();
+        if let Some(segment) = thumbnail {
+            // Must immediately follow the JFIF header
+            started.write_marker(Marker::APP(0), &segment);
+        }
+        started
+    }
 
-            unsafe {
-                let rows_written = ffi::jpeg_write_scanlines(&mut self.cinfo, row_pointers.as_ptr(), row_pointers.len() as u32) as usize;
-                if rows_written < row_pointers.len() {
-                    return false;
+    /// Start writing quantized DCT coefficients instead of pixels, for lossless transcoding
+    ///
+    /// Size, components and quantization tables must be set already (e.g. with `jpeg_copy_critical_parameters()`).
+    /// There must be `Blocks` for every component, padded to whole iMCUs.
+    pub(crate) fn start_compress_coefficients(&mut self, blocks: &[Blocks]) -> CompressStarted<'_> {
+        assert_eq!(self.cinfo.num_components as usize, blocks.len());
+        self.marker_bytes = 0;
+        unsafe {
+            // Coefficients are already quantized, and trellis passes can't run when transcoding
+            ext::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, false as boolean);
+            let mem = &*self.cinfo.common.mem;
+            let request = mem.request_virt_barray.unwrap();
+            let access = mem.access_virt_barray.unwrap();
+
+            let v_samp_factors = self.components().iter().map(|c| c.v_samp_factor as JDIMENSION).collect::<Vec<_>>();
+            self.coef_arrays = blocks.iter().zip(v_samp_factors).map(|(b, v_samp_factor)| {
+                request(&mut self.cinfo.common, ffi::JPOOL_IMAGE, true as boolean,
+                    b.width as JDIMENSION, b.height as JDIMENSION, v_samp_factor)
+            }).collect();
+            ffi::jpeg_write_coefficients(&mut self.cinfo, self.coef_arrays.as_mut_ptr());
+
+            for (&array, b) in self.coef_arrays.iter().zip(blocks) {
+                for y in 0..b.height {
+                    let row = *access(&mut self.cinfo.common, array, y as JDIMENSION, 1, true as boolean);
+                    slice::from_raw_parts_mut(row, b.width).copy_from_slice(&b.blocks[y * b.width .. (y + 1) * b.width]);
                 }
             }
         }
-        return true;
+        let started = CompressStarted { comp: self };
+        started.comp.resume_callback_panic();
+        started
     }
 
-    pub fn write_raw_data(&mut self, image_src: &[&[u8]]) -> bool {
-        if 0 == self.cinfo.raw_data_in {
-            panic!("Raw data not set");
+    /// Check size, color spaces, sampling factors and table slots before starting compression
+    ///
+    /// libjpeg would otherwise report these errors only once compression has started.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let width = self.cinfo.image_width as usize;
+        let height = self.cinfo.image_height as usize;
+        if width == 0 || height == 0 || width > JPEG_MAX_DIMENSION || height > JPEG_MAX_DIMENSION {
+            return Err(ConfigError::InvalidSize { width, height });
         }
-
-        let mcu_height = self.cinfo.max_v_samp_factor as usize * DCTSIZE;
-        if mcu_height > MAX_MCU_HEIGHT {
-            panic!("Subsampling factor too large");
+        self.limits.check_size(width, height)?;
+
+        let in_color_space = self.cinfo.in_color_space;
+        let jpeg_color_space = self.cinfo.jpeg_color_space;
+        let got = self.cinfo.input_components as usize;
+        let expected = if in_color_space == ColorSpace::JCS_UNKNOWN {
+            if (1..=MAX_COMPONENTS).contains(&got) {got} else {MAX_COMPONENTS}
+        } else {
+            in_color_space.num_components()
+        };
+        if got != expected {
+            return Err(ConfigError::InputComponents { color_space: in_color_space, expected, got });
         }
-        assert!(mcu_height > 0);
 
-        let num_components = self.components().len();
-        if num_components > MAX_COMPONENTS || num_components > image_src.len() {
-            panic!("Too many components: declared {}, got {}", num_components, image_src.len());
+        let is_rgb = |cs| matches!(cs,
+            ColorSpace::JCS_RGB | ColorSpace::JCS_EXT_RGB | ColorSpace::JCS_EXT_RGBX |
+            ColorSpace::JCS_EXT_BGR | ColorSpace::JCS_EXT_BGRX | ColorSpace::JCS_EXT_XBGR |
+            ColorSpace::JCS_EXT_XRGB | ColorSpace::JCS_EXT_RGBA | ColorSpace::JCS_EXT_BGRA |
+            ColorSpace::JCS_EXT_ABGR | ColorSpace::JCS_EXT_ARGB);
+        // Same as conversions in jccolor.c
+        let can_convert = match jpeg_color_space {
+            ColorSpace::JCS_GRAYSCALE => in_color_space == ColorSpace::JCS_GRAYSCALE ||
+                in_color_space == ColorSpace::JCS_YCbCr || is_rgb(in_color_space),
+            ColorSpace::JCS_RGB => is_rgb(in_color_space),
+            ColorSpace::JCS_YCbCr => in_color_space == ColorSpace::JCS_YCbCr || is_rgb(in_color_space),
+            ColorSpace::JCS_CMYK => in_color_space == ColorSpace::JCS_CMYK,
+            ColorSpace::JCS_YCCK => in_color_space == ColorSpace::JCS_CMYK || in_color_space == ColorSpace::JCS_YCCK,
+            _ => in_color_space == jpeg_color_space && self.cinfo.num_components == self.cinfo.input_components,
+        };
+        if !can_convert {
+            return Err(ConfigError::UnsupportedConversion { from: in_color_space, to: jpeg_color_space });
         }
 
-        for (ci, comp_info) in self.components().iter().enumerate() {
-            if comp_info.row_stride() * comp_info.col_stride() > image_src[ci].len() {
-                panic!("Bitmap too small. Expected {}x{}, got {}", comp_info.row_stride(), comp_info.col_stride(), image_src[ci].len());
+        let components = self.components();
+        for (component, c) in components.iter().enumerate() {
+            if c.h_samp_factor < 1 || c.h_samp_factor > MAX_SAMP_FACTOR as c_int ||
+               c.v_samp_factor < 1 || c.v_samp_factor > MAX_SAMP_FACTOR as c_int {
+                return Err(ConfigError::InvalidSamplingFactor { component, h_samp_factor: c.h_samp_factor, v_samp_factor: c.v_samp_factor });
+            }
+        }
+        for (component, c) in components.iter().enumerate() {
+            self.check_table_slots(component, c)?;
+        }
+        let max_h = components.iter().map(|c| c.h_samp_factor).max().unwrap_or(1);
+        let max_v = components.iter().map(|c| c.v_samp_factor).max().unwrap_or(1);
+        if 0 == self.cinfo.raw_data_in {
+            // libjpeg can only downsample by integer ratios
+            if let Some(component) = components.iter().position(|c| max_h % c.h_samp_factor != 0 || max_v % c.v_samp_factor != 0) {
+                return Err(ConfigError::FractionalSampling { component });
+            }
+        }
+        if components.len() > 1 {
+            let blocks = components.iter().map(|c| (c.h_samp_factor * c.v_samp_factor) as usize).sum();
+            if blocks > MAX_BLOCKS_IN_MCU {
+                return Err(ConfigError::TooManyBlocksInMcu { blocks });
+            }
+        }
+        if let Some(script) = self.scan_script() {
+            script.validate(components.len())?;
+            self.limits.check_scans(script.scans().len())?;
+        }
+        if let Some(option) = self.whole_image_option() {
+            if self.bounded_memory {
+                return Err(ConfigError::NeedsWholeImageBuffer { option });
+            }
+            self.limits.check_memory(limits::coefficient_buffer_size(width, height, components))?;
+        }
+        // Written by `start_compress()`, before any of the user's markers
+        if let Some(ref thumbnail) = self.jfif_thumbnail {
+            if 0 != self.cinfo.write_JFIF_header {
+                self.limits.check_marker_bytes(thumbnail.segment_len())?;
             }
         }
+        Ok(())
+    }
 
-        let mut start_row = self.cinfo.next_scanline as usize;
-        while self.can_write_more_lines() {
-            unsafe {
-                let mut row_ptrs = [[ptr::null::<u8>(); MAX_MCU_HEIGHT]; MAX_COMPONENTS];
-                let mut comp_ptrs = [ptr::null::<*const u8>(); MAX_COMPONENTS];
+    /// Start compression of an abbreviated datastream, which omits tables that have already been
+    /// written with `write_tables()` (or marked as such with `suppress_tables()`)
+    ///
+    /// With `optimize_coding` enabled Huffman tables are computed for each image, so they're always written.
+    pub fn start_compress_abbreviated(&mut self) -> CompressStarted<'_> {
+        self.start(false)
+    }
 
-                for (ci, comp_info) in self.components().iter().enumerate() {
+    /// Write a tables-only datastream with the current quantization and Huffman tables
+    ///
+    /// Use `set_mem_dest()` before and `data_to_vec()` after, like for an image.
+    /// Changing quality or tables afterwards marks them as not written again.
+    pub fn write_tables(&mut self) {
+        unsafe {
+            ffi::jpeg_write_tables(&mut self.cinfo);
+        }
+        self.resume_callback_panic();
+    }
 
-                    let row_stride = comp_info.row_stride();
+    /// Mark all current tables as already written (`true`), so that `start_compress_abbreviated()` omits them,
+    /// or as not written yet (`false`)
+    pub fn suppress_tables(&mut self, suppress: bool) {
+        unsafe {
+            ffi::jpeg_suppress_tables(&mut self.cinfo, suppress as boolean);
+        }
+    }
 
-                    let input_height = image_src[ci].len() / row_stride;
+    /// Call `callback` periodically during compression. It can return `false` to cancel compression.
+    ///
+    /// The callback is called from inside libjpeg, which can't be unwound, so cancelling takes effect
+    /// when the libjpeg call running it returns: `write_scanlines()` and `write_raw_data()` stop
+    /// after the current group of rows, and `finish_compress()` after libjpeg finishes its passes.
+    /// Then the call panics with `Cancelled` as the payload (a panic of the callback itself is
+    /// raised the same way). Catch it with `catch_unwind()`. The unfinished image is aborted when
+    /// `CompressStarted` is dropped, so the compressor can be reused.
+    pub fn set_progress_callback<F: FnMut(&Progress) -> bool + 'static>(&mut self, callback: F) {
+        let mut progress_mgr = Box::new(ProgressMgr {
+            mgr: unsafe { mem::zeroed() },
+            callback: Box::new(callback),
+            panic: None,
+        });
+        progress_mgr.mgr.progress_monitor = Some(progress_monitor);
+        self.cinfo.common.progress = &mut progress_mgr.mgr;
+        self.progress = Some(progress_mgr);
+    }
 
-                    let comp_start_row = start_row * comp_info.v_samp_factor as usize / self.cinfo.max_v_samp_factor as usize;
-                    let comp_height = min(input_height - comp_start_row, DCTSIZE * comp_info.v_samp_factor as usize);
-                    assert!(comp_height >= 8);
+    /// Expose components for modification
+    pub fn components_mut(&mut self) -> &mut [CompInfo] {
+        unsafe {
+            slice::from_raw_parts_mut(self.cinfo.comp_info, self.cinfo.num_components as usize)
+        }
+    }
 
-                    for ri in 0..comp_height {
-                        let start_offset = (comp_start_row + ri) * row_stride;
-                        row_ptrs[ci][ri] = image_src[ci][start_offset .. start_offset + row_stride].as_ptr();
-                    }
-                    for ri in comp_height..mcu_height {
-                        row_ptrs[ci][ri] = ptr::null();
-                    }
-                    comp_ptrs[ci] = row_ptrs[ci].as_ptr();
-                }
+    pub fn components(&self) -> &[CompInfo] {
+        unsafe {
+            slice::from_raw_parts(self.cinfo.comp_info, self.cinfo.num_components as usize)
+        }
+    }
 
-                let rows_written = ffi::jpeg_write_raw_data(&mut self.cinfo, comp_ptrs.as_ptr(), mcu_height as u32) as usize;
-                if 0 == rows_written {
-                    return false;
-                }
-                start_row += rows_written;
-            }
+    /// Set how many luma pixels each sample of the component covers, horizontally and vertically (1-4)
+    ///
+    /// E.g. `(2, 2)` for luma and `(1, 1)` for both chroma components is 4:2:0. Factors of all components
+    /// are checked together by `validate()`, since they must evenly divide the largest ones.
+    pub fn set_sampling_factors(&mut self, component: usize, h: u8, v: u8) -> Result<(), ConfigError> {
+        self.check_component(component)?;
+        if h < 1 || h as usize > MAX_SAMP_FACTOR || v < 1 || v as usize > MAX_SAMP_FACTOR {
+            return Err(ConfigError::InvalidSamplingFactor { component, h_samp_factor: h.into(), v_samp_factor: v.into() });
+        }
+        let c = &mut self.components_mut()[component];
+        c.h_samp_factor = h.into();
+        c.v_samp_factor = v.into();
+        Ok(())
+    }
+
+    /// Quantize the component with the table in `slot` (0-3). The slot must already have a table.
+    ///
+    /// By default luma uses slot 0, and chroma slot 1.
+    pub fn set_quant_table_slot(&mut self, component: usize, slot: usize) -> Result<(), ConfigError> {
+        self.check_component(component)?;
+        if slot >= NUM_TABLE_SLOTS || self.cinfo.quant_tbl_ptrs[slot].is_null() {
+            return Err(ConfigError::InvalidTableSlot { component, table: "quant", slot });
+        }
+        self.components_mut()[component].quant_tbl_no = slot as c_int;
+        Ok(())
+    }
+
+    /// Encode the component with the DC and AC Huffman tables in slots `dc` and `ac` (0-3)
+    ///
+    /// libjpeg defines tables in slots 0 and 1. With `set_optimize_coding(true)` tables are computed for any slot.
+    pub fn set_huffman_table_slots(&mut self, component: usize, dc: usize, ac: usize) -> Result<(), ConfigError> {
+        self.check_component(component)?;
+        if dc >= NUM_TABLE_SLOTS {
+            return Err(ConfigError::InvalidTableSlot { component, table: "dc", slot: dc });
+        }
+        if ac >= NUM_TABLE_SLOTS {
+            return Err(ConfigError::InvalidTableSlot { component, table: "ac", slot: ac });
+        }
+        let c = &mut self.components_mut()[component];
+        c.dc_tbl_no = dc as c_int;
+        c.ac_tbl_no = ac as c_int;
+        Ok(())
+    }
+
+    fn check_component(&self, component: usize) -> Result<(), ConfigError> {
+        let num_components = self.components().len();
+        if component >= num_components {
+            return Err(ConfigError::InvalidComponent { component, num_components });
+        }
+        Ok(())
+    }
+
+    fn check_table_slots(&self, component: usize, c: &CompInfo) -> Result<(), ConfigError> {
+        let quant = c.quant_tbl_no as usize;
+        if quant >= NUM_TABLE_SLOTS || self.cinfo.quant_tbl_ptrs[quant].is_null() {
+            return Err(ConfigError::InvalidTableSlot { component, table: "quant", slot: quant });
+        }
+        // Optimized Huffman tables are generated, others must have been set
+        let needs_huff_tables = 0 == self.cinfo.optimize_coding && 0 == self.cinfo.arith_code;
+        let dc = c.dc_tbl_no as usize;
+        if dc >= NUM_TABLE_SLOTS || (needs_huff_tables && self.cinfo.dc_huff_tbl_ptrs[dc].is_null()) {
+            return Err(ConfigError::InvalidTableSlot { component, table: "dc", slot: dc });
         }
-        return true;
+        let ac = c.ac_tbl_no as usize;
+        if ac >= NUM_TABLE_SLOTS || (needs_huff_tables && self.cinfo.ac_huff_tbl_ptrs[ac].is_null()) {
+            return Err(ConfigError::InvalidTableSlot { component, table: "ac", slot: ac });
+        }
+        Ok(())
     }
 
+    /// Rows given to `write_scanlines` are in bottom-to-top order (as in BMP files or OpenGL readback)
+    ///
+    /// Rows of each buffer are written last to first. If the image is written in several calls,
+    /// pass chunks starting from the end of the bottom-up buffer.
+    pub fn set_bottom_up(&mut self, bottom_up: bool) {
+        self.bottom_up = bottom_up;
+    }
+
+    /// Set the color space of the JPEG file (not the input)
+    ///
+    /// For `JCS_CMYK` input, use `JCS_YCCK` to store it as YCCK, which usually compresses better.
+    /// Both CMYK and YCCK files get an Adobe marker, which decoders need to tell them apart.
     pub fn set_color_space(&mut self, color_space: ColorSpace) {
         self.cinfo.input_components = color_space.num_components() as c_int;
         unsafe {
@@ -200,19 +703,163 @@ impl Compress {
         self.cinfo.image_height = height as JDIMENSION;
     }
 
+    /// Gamma of the input image (`input_gamma`), 1.0 by default
+    ///
+    /// JPEG files have no field for it, so it's not stored in the file,
+    /// and decompressors report their default output gamma instead.
     pub fn set_gamma(&mut self, gamma: f64) {
         self.cinfo.input_gamma = gamma;
     }
 
+    pub fn gamma(&self) -> f64 {
+        self.cinfo.input_gamma
+    }
+
+    /// Make output independent of the CPU's SIMD support and of the number of threads
+    ///
+    /// Encoding is deterministic anyway, but some code paths aren't the same on every machine:
+    /// libjpeg-turbo picks SIMD code at run time, and its floating-point DCT rounds differently than the C code.
+    /// When this is on, the slow integer DCT (`JDCT_ISLOW`), whose SIMD and C versions give identical results,
+    /// is used regardless of other settings, and `encode_parallel()` splits images into bands of a fixed height
+    /// rather than one band per thread.
+    ///
+    /// Files from different builds of libjpeg may still differ, e.g. if trellis quantization's floating-point math
+    /// is compiled differently. Off by default.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
     pub fn set_optimize_scans(&mut self, opt: bool) {
         unsafe {
-            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, opt as boolean);
+            ext::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, opt as boolean);
         }
         if !opt {
             self.cinfo.scan_info = ptr::null();
         }
     }
 
+    /// Reject images that are too large, and limit libjpeg's memory use. See `Limits`.
+    ///
+    /// Size, memory and scan limits are checked by `validate()`. Markers over `max_marker_bytes`
+    /// make `CompressStarted::write_marker()` panic.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Use a custom sequence of scans, e.g. for progressive mode with a different order of coefficients
+    ///
+    /// The script is checked against the number of components of the JPEG color space, so set the color space first.
+    /// It disables `set_optimize_scans()`, which would generate its own script. Progressive mode is enabled
+    /// if the script is progressive.
+    pub fn set_scan_script(&mut self, script: &ScanScript) -> Result<(), ConfigError> {
+        script.validate(self.cinfo.num_components as usize)?;
+        self.set_optimize_scans(false);
+        self.scan_script = script.to_ffi();
+        self.cinfo.scan_info = self.scan_script.as_ptr();
+        self.cinfo.num_scans = self.scan_script.len() as c_int;
+        Ok(())
+    }
+
+    /// The scan script that will be used, either set with `set_scan_script()` or by progressive mode.
+    /// `None` means a single sequential scan of all components, or a script chosen by `set_optimize_scans()`.
+    pub fn scan_script(&self) -> Option<ScanScript> {
+        if self.cinfo.scan_info.is_null() || self.cinfo.num_scans <= 0 {
+            return None;
+        }
+        let script = ScanScript::from_ffi(unsafe {
+            slice::from_raw_parts(self.cinfo.scan_info, self.cinfo.num_scans as usize)
+        });
+        // Then scan_info has candidate scans to choose from, not a script
+        match unsafe { ext::get_bool_param(&self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS) } {
+            Some(true) => None,
+            Some(false) => Some(script),
+            // Candidate scans aren't a valid script
+            None => Some(script).filter(|s| s.validate(self.cinfo.num_components as usize).is_ok()),
+        }
+    }
+
+    /// Embed a thumbnail in a JFIF extension segment, right after the JFIF header
+    ///
+    /// It's written only in JFIF files, i.e. YCbCr and grayscale ones. It counts towards `Limits::max_marker_bytes`,
+    /// which `validate()` checks.
+    pub fn set_jfif_thumbnail(&mut self, thumbnail: Option<JfifThumbnail>) -> Result<(), ConfigError> {
+        if let Some(ref thumbnail) = thumbnail {
+            thumbnail.validate()?;
+        }
+        self.jfif_thumbnail = thumbnail;
+        Ok(())
+    }
+
+    /// Adobe APP14 marker is written by default for CMYK and YCCK files
+    pub fn set_write_adobe_marker(&mut self, write: bool) {
+        self.cinfo.write_Adobe_marker = write as boolean;
+    }
+
+    /// Write a restart marker every `mcus` MCUs, or none if 0 (the default)
+    ///
+    /// Restart markers let decoders resynchronize after corrupted data, and decode segments of the file independently.
+    /// See `data_to_vec_with_restarts()` to find where they are.
+    pub fn set_restart_interval(&mut self, mcus: u16) {
+        self.cinfo.restart_interval = mcus as c_uint;
+        self.cinfo.restart_in_rows = 0;
+    }
+
+    /// Write a restart marker at the start of every `rows` rows of MCUs, or none if 0
+    ///
+    /// Overrides `set_restart_interval()`.
+    pub fn set_restart_in_rows(&mut self, rows: u16) {
+        self.cinfo.restart_in_rows = rows as c_int;
+    }
+
+    /// Compress in a single pass, with memory use independent of image height
+    ///
+    /// libjpeg then keeps only one iMCU row (8 or 16 lines) of input at a time, plus the output
+    /// (use `set_buffer_dest()` or a writer to bound that too). This disables progressive mode,
+    /// Huffman table optimization and trellis quantization, since they need all DCT coefficients of the image.
+    /// Files are larger, but decoders can show them as they arrive.
+    ///
+    /// While it's on, `validate()` and `try_start_compress()` reject settings that need the whole image,
+    /// e.g. a later call to `set_progressive_mode()`.
+    pub fn set_bounded_memory(&mut self, bounded: bool) {
+        self.bounded_memory = bounded;
+        if bounded {
+            self.set_optimize_coding(false);
+            self.set_optimize_scans(false);
+            self.cinfo.num_scans = 0;
+            unsafe {
+                ext::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, false as boolean);
+                ext::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC, false as boolean);
+            }
+        }
+    }
+
+    /// The first enabled option that makes libjpeg buffer coefficients of the whole image
+    fn whole_image_option(&self) -> Option<&'static str> {
+        if 0 != self.cinfo.optimize_coding {
+            return Some("optimize_coding");
+        }
+        if !self.cinfo.scan_info.is_null() && self.cinfo.num_scans > 1 {
+            return Some("Progressive mode");
+        }
+        // Settings that can't be read (without mozjpeg's extensions) can't be turned off either
+        let is_on = |param| unsafe { ext::get_bool_param(&self.cinfo, param) } == Some(true);
+        if is_on(J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS) {
+            return Some("optimize_scans");
+        }
+        if is_on(J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT) || is_on(J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC) {
+            return Some("Trellis quantization");
+        }
+        None
+    }
+
     /// Set to `false` to make files larger for no reason
     pub fn set_optimize_coding(&mut self, opt: bool) {
         self.cinfo.optimize_coding = opt as boolean;
@@ -220,7 +867,7 @@ impl Compress {
 
     pub fn set_use_scans_in_trellis(&mut self, opt: bool) {
         unsafe {
-            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_USE_SCANS_IN_TRELLIS, opt as boolean);
+            ext::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_USE_SCANS_IN_TRELLIS, opt as boolean);
         }
     }
 
@@ -231,18 +878,73 @@ impl Compress {
         }
     }
 
+    /// Write a single scan, undoing `set_progressive_mode()` and mozjpeg's scan optimization
+    pub(crate) fn set_sequential_mode(&mut self) {
+        self.set_optimize_scans(false);
+        self.cinfo.scan_info = ptr::null();
+        self.cinfo.num_scans = 0;
+    }
+
+    /// Write a sequential file with the layout of scans, undoing progressive mode and `set_scan_script()`
+    ///
+    /// Set the color space first, since `NonInterleaved` makes a scan for each of its components.
+    /// In progressive mode, use `set_scan_optimization_mode()` or `set_scan_script()` instead.
+    pub fn set_scan_layout(&mut self, layout: ScanLayout) {
+        match layout {
+            ScanLayout::Interleaved => self.set_sequential_mode(),
+            ScanLayout::NonInterleaved => {
+                let script = ScanScript::non_interleaved(self.cinfo.num_components as usize);
+                self.set_scan_script(&script).expect("valid sequential script");
+            },
+        }
+    }
+
+    /// Keeps other settings. If progressive mode is on, its scan script is regenerated,
+    /// but a script set with `set_scan_script()` or `set_scan_layout()` is kept as-is.
     pub fn set_scan_optimization_mode(&mut self, mode: ScanMode) {
         unsafe {
-            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_DC_SCAN_OPT_MODE, mode as c_int);
-            ffi::jpeg_set_defaults(&mut self.cinfo);
+            ext::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_DC_SCAN_OPT_MODE, mode as c_int);
+            // Scripts of progressive mode are allocated by libjpeg, custom ones are in `scan_script`
+            if !self.cinfo.scan_info.is_null() && self.cinfo.scan_info != self.scan_script.as_ptr() {
+                ffi::jpeg_simple_progression(&mut self.cinfo);
+            }
+        }
+    }
+
+    /// Selects base quantization tables and trellis weights for the metric
+    ///
+    /// Call before `set_quality()`, which generates the quantization tables.
+    pub fn set_tune(&mut self, tune: Tune) {
+        let (base_tbl, scale1, scale2, weight_tbl) = match tune {
+            Tune::Psnr => (1, 9.0, 0.0, false),
+            Tune::Ssim => (1, 11.5, 12.75, false),
+            Tune::MsSsim => (3, 12.0, 13.0, true),
+            Tune::HvsPsnr => (3, 14.75, 16.5, true),
+        };
+        unsafe {
+            ext::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX, base_tbl);
+            ext::jpeg_c_set_float_param(&mut self.cinfo, J_FLOAT_PARAM::JFLOAT_LAMBDA_LOG_SCALE1, scale1);
+            ext::jpeg_c_set_float_param(&mut self.cinfo, J_FLOAT_PARAM::JFLOAT_LAMBDA_LOG_SCALE2, scale2);
+            ext::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_USE_LAMBDA_WEIGHT_TBL, weight_tbl as boolean);
         }
     }
 
     /// Reset to libjpeg v6 settings
     pub fn set_fastest_defaults(&mut self) {
+        self.set_compress_profile(CompressProfile::Fastest);
+    }
+
+    /// Reset all settings to defaults of the profile
+    ///
+    /// Call it first, since it overwrites quality, tables and other settings.
+    pub fn set_compress_profile(&mut self, profile: CompressProfile) {
+        let value = match profile {
+            CompressProfile::MaxCompression => ffi::JINT_COMPRESS_PROFILE_VALUE::JCP_MAX_COMPRESSION,
+            CompressProfile::Fastest => ffi::JINT_COMPRESS_PROFILE_VALUE::JCP_FASTEST,
+        };
         unsafe {
-            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_COMPRESS_PROFILE, ffi::JINT_COMPRESS_PROFILE_VALUE::JCP_FASTEST as c_int);
-            ffi::jpeg_set_defaults(&mut self.cinfo);
+            ext::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_COMPRESS_PROFILE, value as c_int);
+            ext::jpeg_set_defaults(&mut self.cinfo);
         }
     }
 
@@ -252,29 +954,278 @@ impl Compress {
 
     pub fn set_quality(&mut self, quality: f32) {
         unsafe {
-            ffi::jpeg_set_quality(&mut self.cinfo, quality as c_int, false as boolean);
+            ffi::jpeg_set_quality(&mut self.cinfo, quality as c_int, self.force_baseline.unwrap_or(false) as boolean);
         }
     }
 
-    pub fn set_luma_qtable(&mut self, qtable: &QTable) {
+    /// Limit quantization table values to 8 bits (255), as required by baseline JPEG
+    ///
+    /// Only low qualities need larger values, and some old decoders can't read them.
+    /// Applies to tables set afterwards (by `set_quality()`, `set_luma_qtable()`, etc.).
+    /// By default it's off for `set_quality()` and `set_default_qtables_scaled()`,
+    /// and on for `set_luma_qtable()` and `set_chroma_qtable()`.
+    pub fn set_force_baseline(&mut self, force_baseline: bool) {
+        self.force_baseline = Some(force_baseline);
+    }
+
+    /// Compress the whole image at the highest quality that fits in `max_bytes`
+    ///
+    /// `image_src` must contain all lines of the image, as for `write_scanlines`.
+    /// The image is compressed several times, bisecting over quality,
+    /// which replaces any quantization tables set before.
+    ///
+    /// Returns the JPEG file and the quality used, or `None` if even quality 1 is too large.
+    pub fn target_size(&mut self, image_src: &[u8], max_bytes: usize) -> Option<(Vec<u8>, f32)> {
+        let mut low = 1;
+        let mut high = 100;
+        let mut best = None;
+        while low <= high {
+            let quality = (low + high) / 2;
+            let data = self.compress_with_quality(image_src, quality as f32);
+            if data.len() <= max_bytes {
+                best = Some((data, quality as f32));
+                low = quality + 1;
+            } else {
+                high = quality - 1;
+            }
+        }
+        best
+    }
+
+    /// Compress the whole image once for each of the `qualities`, e.g. for responsive images
+    ///
+    /// `image_src` must contain all lines of the image, as for `write_scanlines`.
+    /// The source only needs to be decoded/scaled once, and all other settings are shared by the outputs.
+    /// This replaces any quantization tables set before.
+    ///
+    /// Returns JPEG files in the same order as `qualities`.
+    pub fn quality_ladder(&mut self, image_src: &[u8], qualities: &[f32]) -> Vec<Vec<u8>> {
+        qualities.iter().map(|&quality| self.compress_with_quality(image_src, quality)).collect()
+    }
+
+    /// Compress the whole image at the lowest quality that still looks like the original
+    ///
+    /// `image_src` must contain all lines of the image, as for `write_scanlines`.
+    /// Candidates are decoded and compared with `image_src` using SSIM, and `max_dssim` is the largest
+    /// acceptable difference, expressed as `metrics::dssim()` (e.g. `0.001` is hardly noticeable).
+    /// This replaces any quantization tables set before.
+    ///
+    /// Returns the JPEG file and the quality used. If no quality is good enough, quality 100 is used.
+    #[cfg(feature = "perceptual")]
+    pub fn perceptual_quality(&mut self, image_src: &[u8], max_dssim: f64) -> (Vec<u8>, f32) {
+        let width = self.cinfo.image_width as usize;
+        let height = self.cinfo.image_height as usize;
+        let channels = self.cinfo.input_components as usize;
+
+        let mut low = 1;
+        let mut high = 100;
+        let mut best = None;
+        while low <= high {
+            let quality = (low + high) / 2;
+            let data = self.compress_with_quality(image_src, quality as f32);
+            let decoded = self.decode_in_input_color_space(&data);
+            let dssim = metrics::dssim(metrics::ssim(image_src, &decoded, width, height, channels));
+            if dssim <= max_dssim {
+                best = Some((data, quality as f32));
+                high = quality - 1;
+            } else {
+                low = quality + 1;
+            }
+        }
+        best.unwrap_or_else(|| (self.compress_with_quality(image_src, 100.), 100.))
+    }
+
+    #[cfg(feature = "perceptual")]
+    fn decode_in_input_color_space(&self, jpeg: &[u8]) -> Vec<u8> {
+        let dinfo = Decompress::new_mem(jpeg).unwrap();
+        let mut dinfo = match self.cinfo.in_color_space {
+            ColorSpace::JCS_UNKNOWN => dinfo.passthrough(),
+            color_space => dinfo.start_as_color_space(color_space),
+        }.unwrap();
+        let pixels = dinfo.read_scanlines_bytes().unwrap();
+        dinfo.finish_decompress();
+        pixels
+    }
+
+    fn compress_with_quality(&mut self, image_src: &[u8], quality: f32) -> Vec<u8> {
+        self.set_quality(quality);
+        self.set_mem_dest();
+        let mut started = self.start_compress();
+        assert!(started.write_scanlines(image_src));
+        started.finish_compress();
+        self.data_to_vec().unwrap()
+    }
+
+    /// Set the default luma and chroma quantization tables, each scaled by its own factor
+    ///
+    /// Like libjpeg's `jpeg_default_qtables()` with `q_scale_factor`. Base tables are the ones selected by `set_tune()`.
+    /// Scale factors are percentages (100 = base table as-is, 50 = quality 75 in libjpeg's scale).
+    /// See `qtable::quality_to_scale_factor()` to convert from quality.
+    pub fn set_default_qtables_scaled(&mut self, luma_scale_factor: i32, chroma_scale_factor: i32) {
         unsafe {
-            ffi::jpeg_add_quant_table(&mut self.cinfo, 0, qtable.as_ptr(), 100, 1);
+            // libjpeg's standard tables are the first ones
+            let base_index = ext::get_int_param(&self.cinfo, J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX).unwrap_or(0);
+            let (luma, chroma) = qtable::mozjpeg_base_tables(base_index as usize).expect("Invalid base table index");
+            ffi::jpeg_add_quant_table(&mut self.cinfo, 0, luma.as_ptr(), luma_scale_factor as c_int, self.force_baseline.unwrap_or(false) as boolean);
+            ffi::jpeg_add_quant_table(&mut self.cinfo, 1, chroma.as_ptr(), chroma_scale_factor as c_int, self.force_baseline.unwrap_or(false) as boolean);
         }
     }
 
+    pub fn set_luma_qtable(&mut self, qtable: &QTable) {
+        let force_baseline = self.force_baseline.unwrap_or(true);
+        self.add_qtable(0, qtable, force_baseline);
+    }
+
     pub fn set_chroma_qtable(&mut self, qtable: &QTable) {
+        let force_baseline = self.force_baseline.unwrap_or(true);
+        self.add_qtable(1, qtable, force_baseline);
+    }
+
+    fn add_qtable(&mut self, slot: usize, qtable: &QTable, force_baseline: bool) {
         unsafe {
-            ffi::jpeg_add_quant_table(&mut self.cinfo, 1, qtable.as_ptr(), 100, 1);
+            ffi::jpeg_add_quant_table(&mut self.cinfo, slot as c_int, qtable.as_ptr(), 100, force_baseline as boolean);
+        }
+    }
+
+    /// Match quality of the source file by using its quantization tables
+    ///
+    /// Each coefficient gets the coarser of the source's and the currently set tables,
+    /// so recompression never keeps more detail than the original had. Call it after `set_quality()`.
+    /// Luma table is taken from the first component of the source, and chroma from the second
+    /// (or the first too, if the source is grayscale).
+    pub fn set_qtables_from(&mut self, source: &Decompress) {
+        let source_tables = source.qtables();
+        for slot in 0..2 {
+            let source_table = match source_tables.get(slot).or_else(|| source_tables.first()) {
+                Some(Some(t)) => t,
+                _ => continue,
+            };
+            let mut table = QTable { coeffs: source_table.coeffs };
+            if let Some(current) = unsafe { self.cinfo.quant_tbl_ptrs[slot].as_ref() } {
+                for (out, &q) in table.coeffs.iter_mut().zip(current.quantval.iter()) {
+                    *out = max(*out, q as c_uint);
+                }
+            }
+            // Tables of `set_quality()` may need more than 8 bits
+            let force_baseline = self.force_baseline.unwrap_or(false);
+            self.add_qtable(slot, &table, force_baseline);
         }
     }
 
     pub fn set_mem_dest(&mut self) {
         self.free_mem_dest();
+        if self.take_rust_dest() {
+            self.cinfo.dest = ptr::null_mut();
+        }
+        if self.cinfo.dest.is_null() {
+            self.cinfo.dest = mem::replace(&mut self.spare_mem_dest, ptr::null_mut());
+        }
         unsafe {
             ffi::jpeg_mem_dest(&mut self.cinfo, &mut self.outbuffer, &mut self.outsize);
         }
     }
 
+    /// Write output to an internal buffer, which is emptied with `flush()`. Allows streaming the file as it's produced.
+    ///
+    /// If the buffer of `buffer_size` bytes fills up during `write_scanlines()` or `write_raw_data()`,
+    /// libjpeg suspends and the call returns `false`. Call `flush()`, then write the remaining lines
+    /// again, starting from `next_scanline()`. Other calls, and the first write which includes headers,
+    /// grow the buffer instead.
+    ///
+    /// Output is produced gradually only in single-pass mode (`set_optimize_coding(false)` and
+    /// no progressive mode). Otherwise everything is written in `finish_compress()`.
+    pub fn set_buffer_dest(&mut self, buffer_size: usize) {
+        self.free_mem_dest();
+        self.keep_mem_dest();
+        let mut dest = BufferDest::new(buffer_size);
+        self.cinfo.dest = dest.as_mgr();
+        self.buffer_dest = Some(dest);
+    }
+
+    /// Write output to `writer` as it's produced, in chunks of 64KB. It's flushed when compression finishes.
+    ///
+    /// An I/O error discards the rest of the output, and the call that was writing (e.g. `finish_compress()`)
+    /// panics after libjpeg returns.
+    pub fn set_writer_dest<W: Write + 'static>(&mut self, writer: W) {
+        self.free_mem_dest();
+        self.keep_mem_dest();
+        let mut dest = WriterDest::new(Box::new(writer));
+        self.cinfo.dest = dest.as_mgr();
+        self.writer_dest = Some(dest);
+    }
+
+    /// Write output to the standard output, e.g. in a command-line filter. See `set_writer_dest()`.
+    pub fn set_stdout_dest(&mut self) {
+        self.set_writer_dest(io::stdout());
+    }
+
+    /// Write output directly into `file`, which is memory-mapped, and truncated to the final length when compression finishes
+    ///
+    /// Avoids copying large outputs through a `Vec`. The file is grown if the image doesn't fit in
+    /// `size_hint` bytes. Requires the `memmap2` feature.
+    #[cfg(feature = "memmap2")]
+    pub fn set_mmap_dest(&mut self, file: &File, size_hint: usize) -> io::Result<()> {
+        let mut dest = MmapDest::new(file, size_hint)?;
+        self.free_mem_dest();
+        self.keep_mem_dest();
+        self.cinfo.dest = dest.as_mgr();
+        self.mmap_dest = Some(dest);
+        Ok(())
+    }
+
+    /// Before switching to a destination managed by Rust, keep libjpeg's one for `set_mem_dest()`
+    fn keep_mem_dest(&mut self) {
+        if !self.take_rust_dest() && !self.cinfo.dest.is_null() {
+            self.spare_mem_dest = self.cinfo.dest;
+        }
+        self.cinfo.dest = ptr::null_mut();
+    }
+
+    /// Free destinations managed by Rust. Returns `true` if there was one.
+    fn take_rust_dest(&mut self) -> bool {
+        #[cfg(feature = "memmap2")]
+        let had_mmap = self.mmap_dest.take().is_some();
+        #[cfg(not(feature = "memmap2"))]
+        let had_mmap = false;
+        let had_writer = self.writer_dest.take().is_some();
+        self.buffer_dest.take().is_some() || had_writer || had_mmap
+    }
+
+    /// Take compressed data written so far to the buffer set with `set_buffer_dest()`
+    ///
+    /// All complete MCUs are included, except a few bits that are still being entropy-coded.
+    pub fn flush(&mut self) -> Vec<u8> {
+        self.buffer_dest.as_mut().expect("flush() requires set_buffer_dest()").take_data()
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn buffer_dest_mut(&mut self) -> &mut BufferDest {
+        self.buffer_dest.as_mut().expect("flush_to() requires set_buffer_dest()")
+    }
+
+    /// Raise the panic (or I/O error of the destination) that a callback couldn't unwind through libjpeg,
+    /// after libjpeg has returned
+    fn resume_callback_panic(&mut self) {
+        if let Some(payload) = self.progress.as_mut().and_then(|p| p.panic.take()) {
+            panic::resume_unwind(payload);
+        }
+        #[cfg(feature = "memmap2")]
+        if let Some(err) = self.mmap_dest.as_mut().and_then(|dest| dest.take_error()) {
+            panic!("Can't write the output file: {}", err);
+        }
+        if let Some(err) = self.writer_dest.as_mut().and_then(|dest| dest.take_error()) {
+            panic!("Can't write JPEG data: {}", err);
+        }
+    }
+
+    fn set_can_suspend(&mut self, can_suspend: bool) {
+        // The first write emits headers, and libjpeg can't suspend while writing markers
+        let can_suspend = can_suspend && self.cinfo.next_scanline > 0;
+        if let Some(ref mut dest) = self.buffer_dest {
+            dest.can_suspend = can_suspend;
+        }
+    }
+
     fn free_mem_dest(&mut self) {
         if !self.outbuffer.is_null() {
             unsafe {
@@ -285,12 +1236,6 @@ impl Compress {
         }
     }
 
-    pub fn finish_compress(&mut self) {
-        unsafe {
-            ffi::jpeg_finish_compress(&mut self.cinfo);
-        }
-    }
-
     pub fn data_as_mut_slice(&mut self) -> Result<&[u8],()> {
         if self.outbuffer.is_null() || 0 == self.outsize {
             return Err(());
@@ -310,11 +1255,385 @@ impl Compress {
             return res;
         }
     }
+
+    /// Like `data_to_vec()`, but also returns byte offsets of all restart markers in the file
+    ///
+    /// Enable restart markers with `set_restart_interval()` or `set_restart_in_rows()`.
+    /// Compress tightly packed pixels into a new file in memory, using the current size and settings
+    pub(crate) fn encode_to_vec(&mut self, pixels: &[u8]) -> io::Result<Vec<u8>> {
+        self.encode_to_vec_with(true, |started| started.write_scanlines(pixels))
+    }
+
+    /// Like `encode_to_vec()`, but all lines are written by `write`.
+    /// If `write_all_tables` is `false`, the file is abbreviated as with `start_compress_abbreviated()`.
+    pub(crate) fn encode_to_vec_with<F>(&mut self, write_all_tables: bool, write: F) -> io::Result<Vec<u8>>
+        where F: FnOnce(&mut CompressStarted<'_>) -> bool
+    {
+        self.set_mem_dest();
+        self.validate().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        {
+            let mut started = self.start(write_all_tables);
+            if !write(&mut started) {
+                return Err(io::Error::new(io::ErrorKind::Other, "Not all lines were written"));
+            }
+            started.finish_compress();
+        }
+        self.data_to_vec().map_err(|_| io::Error::new(io::ErrorKind::Other, "No data"))
+    }
+
+    pub fn data_to_vec_with_restarts(&mut self) -> io::Result<(Vec<u8>, Vec<usize>)> {
+        let data = self.data_to_vec().map_err(|_| io::Error::new(io::ErrorKind::Other, "No data"))?;
+        let offsets = scan_index(&data)?.iter()
+            .flat_map(|scan| scan.intervals[1..].iter().map(|interval| interval.start))
+            .collect();
+        Ok((data, offsets))
+    }
+}
+
+/// Compressor that has started writing an image. See `Compress::start_compress()`.
+///
+/// Dropping it without `finish_compress()` aborts the image, and the `Compress` can be reused.
+pub struct CompressStarted<'a> {
+    comp: &'a mut Compress,
+}
+
+impl<'a> CompressStarted<'a> {
+    pub fn write_marker(&mut self, marker: Marker, data: &[u8]) {
+        self.comp.marker_bytes += data.len();
+        if let Err(err) = self.comp.limits.check_marker_bytes(self.comp.marker_bytes) {
+            panic!("Can't write marker: {}", err);
+        }
+        unsafe {
+            ffi::jpeg_write_marker(&mut self.comp.cinfo, marker.into(), data.as_ptr(), data.len() as c_uint);
+
+        }
+        self.comp.resume_callback_panic();
+    }
+
+    /// Write markers of another file, except JFIF and Adobe headers that libjpeg has already written
+    pub(crate) fn copy_markers<'m, I: Iterator<Item = MarkerData<'m>>>(&mut self, markers: I) {
+        let write_jfif = 0 != self.comp.cinfo.write_JFIF_header;
+        let write_adobe = 0 != self.comp.cinfo.write_Adobe_marker;
+        for marker in markers {
+            if write_jfif && marker.marker == Marker::APP(0) && marker.data.starts_with(b"JFIF") {
+                continue;
+            }
+            if write_adobe && marker.marker == Marker::APP(14) && marker.data.starts_with(b"Adobe") {
+                continue;
+            }
+            self.write_marker(marker.marker, marker.data);
+        }
+    }
+
+    /// Write EXIF and ICC profile markers of the source file, so that recompression doesn't lose them
+    ///
+    /// The source must be read with these markers enabled, e.g. `Decompress::with_markers(ALL_MARKERS)`.
+    /// Call it before writing scanlines:
+    ///
+    /// ```rust,ignore
+    /// started.copy_exif_and_icc(dinfo.markers());
+    /// ```
+    pub fn copy_exif_and_icc<'m, I: Iterator<Item = MarkerData<'m>>>(&mut self, markers: I) {
+        for marker in markers {
+            let is_exif = marker.marker == Marker::APP(1) && marker.data.starts_with(b"Exif\0");
+            let is_icc = marker.marker == Marker::APP(2) && marker.data.starts_with(b"ICC_PROFILE\0");
+            if is_exif || is_icc {
+                self.write_marker(marker.marker, marker.data);
+            }
+        }
+    }
+
+    pub fn components(&self) -> &[CompInfo] {
+        self.comp.components()
+    }
+
+    fn can_write_more_lines(&self) -> bool {
+        self.comp.cinfo.next_scanline < self.comp.cinfo.image_height
+    }
+
+    /// Returns true if all lines in image_src (not necessarily all lines of the image) were written
+    pub fn write_scanlines(&mut self, image_src: &[u8]) -> bool {
+        let byte_width = self.row_byte_width();
+        assert_eq!(0, image_src.len() % byte_width, "Rows must be exactly {} bytes wide", byte_width);
+        self.write_scanlines_with_stride(image_src, byte_width)
+    }
+
+    /// Like `write_scanlines`, but with pixels of a type that matches the input color space, e.g. `RGB8` for `JCS_RGB`
+    ///
+    /// Panics if the color space is different.
+    pub fn write_scanlines_as<P: Pixel>(&mut self, pixels: &[P]) -> bool {
+        self.assert_pixel_type::<P>();
+        let bytes = unsafe {
+            slice::from_raw_parts(pixels.as_ptr() as *const u8, mem::size_of_val(pixels))
+        };
+        self.write_scanlines(bytes)
+    }
+
+    /// Like `write_scanlines`, but rows start every `stride` bytes
+    ///
+    /// Allows writing framebuffers with padded rows, or a sub-rectangle of a larger image,
+    /// without copying. Bytes between the end of a row and the start of the next are ignored,
+    /// and the last row doesn't need to be padded to the full stride.
+    pub fn write_scanlines_with_stride(&mut self, image_src: &[u8], stride: usize) -> bool {
+        let byte_width = self.row_byte_width();
+        assert!(stride >= byte_width, "Stride {} is smaller than row width {}", stride, byte_width);
+
+        let last_row_len = image_src.len() % stride;
+        assert!(last_row_len == 0 || last_row_len >= byte_width, "Last row is truncated: got {} bytes, expected {}", last_row_len, byte_width);
+        let num_rows = image_src.len() / stride + if last_row_len > 0 {1} else {0};
+
+        let bottom_up = self.comp.bottom_up;
+        self.write_row_pointers((0..num_rows)
+            .map(|i| if bottom_up {num_rows - 1 - i} else {i})
+            .map(|i| image_src[i * stride .. i * stride + byte_width].as_ptr()))
+    }
+
+    /// Write rows as they're produced by an iterator, without holding the whole image in memory
+    ///
+    /// Each row must be at least `width * components` bytes long. `set_bottom_up()` doesn't apply here.
+    /// Returns true if all rows were written.
+    pub fn write_rows<'r, I: IntoIterator<Item = &'r [u8]>>(&mut self, rows: I) -> bool {
+        let byte_width = self.row_byte_width();
+        self.write_row_pointers(rows.into_iter().map(|row| {
+            assert!(row.len() >= byte_width, "Row too short. Expected {} bytes, got {}", byte_width, row.len());
+            row.as_ptr()
+        }))
+    }
+
+    /// Read tightly packed pixels from `reader` until all remaining lines of the image are written
+    ///
+    /// Pixels must be in the input color space and size this compressor was set up with.
+    /// Only a few rows are buffered at a time, so this works for piping in arbitrarily large images.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` for `set_buffer_dest()`, because rows that were read
+    /// couldn't be written again after it suspends.
+    pub fn write_scanlines_from_reader<R: Read>(&mut self, mut reader: R) -> io::Result<()> {
+        if self.comp.buffer_dest.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "write_scanlines_from_reader() can't write to a suspending set_buffer_dest()"));
+        }
+        let byte_width = self.row_byte_width();
+        let mut buffer = vec![0; MAX_MCU_HEIGHT * byte_width];
+        while self.can_write_more_lines() {
+            let rows = min(MAX_MCU_HEIGHT, (self.comp.cinfo.image_height - self.comp.cinfo.next_scanline) as usize);
+            let chunk = &mut buffer[..rows * byte_width];
+            reader.read_exact(chunk)?;
+            if !self.write_row_pointers(chunk.chunks(byte_width).map(|row| row.as_ptr())) {
+                return Err(io::Error::new(io::ErrorKind::Other, "JPEG encoder didn't accept all lines"));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(any(feature = "imgref", feature = "ndarray"))]
+    pub(crate) fn image_width(&self) -> usize {
+        self.comp.cinfo.image_width as usize
+    }
+
+    #[cfg(feature = "ndarray")]
+    pub(crate) fn input_components(&self) -> usize {
+        self.comp.cinfo.input_components as usize
+    }
+
+    pub(crate) fn assert_pixel_type<P: Pixel>(&self) {
+        let color_space = self.comp.cinfo.in_color_space;
+        assert!(pixel::same_layout(P::COLOR_SPACE, color_space), "Pixel type is for {:?}, but input is {:?}", P::COLOR_SPACE, color_space);
+    }
+
+    fn row_byte_width(&self) -> usize {
+        assert_eq!(0, self.comp.cinfo.raw_data_in);
+        assert!(self.comp.cinfo.input_components > 0);
+        assert!(self.comp.cinfo.image_width > 0);
+
+        self.comp.cinfo.image_width as usize * self.comp.cinfo.input_components as usize
+    }
+
+    /// Rows must be at least `row_byte_width()` long
+    fn write_row_pointers<I: Iterator<Item = *const u8>>(&mut self, rows: I) -> bool {
+        self.comp.set_can_suspend(true);
+        let res = self.write_row_pointers_suspendable(rows);
+        self.comp.set_can_suspend(false);
+        res
+    }
+
+    fn write_row_pointers_suspendable<I: Iterator<Item = *const u8>>(&mut self, mut rows: I) -> bool {
+        loop {
+            let row_pointers = rows.by_ref().take(MAX_MCU_HEIGHT).collect::<ArrayVec<[_; MAX_MCU_HEIGHT]>>();
+            if row_pointers.is_empty() {
+                return true;
+            }
+
+            unsafe {
+                let rows_written = ffi::jpeg_write_scanlines(&mut self.comp.cinfo, row_pointers.as_ptr(), row_pointers.len() as u32) as usize;
+                self.comp.resume_callback_panic();
+                if rows_written < row_pointers.len() {
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Write rows of 8-bit single-channel pixels, exactly `width` bytes per row
+    ///
+    /// The compressor must have been created with `ColorSpace::JCS_GRAYSCALE`.
+    pub fn write_gray_scanlines(&mut self, image_src: &[u8]) -> bool {
+        assert_eq!(ColorSpace::JCS_GRAYSCALE, self.comp.cinfo.in_color_space, "Input color space is not grayscale");
+        assert_eq!(1, self.comp.cinfo.input_components);
+        self.write_scanlines(image_src)
+    }
+
+    pub fn write_raw_data(&mut self, image_src: &[&[u8]]) -> bool {
+        self.comp.set_can_suspend(true);
+        let res = self.write_raw_data_suspendable(image_src);
+        self.comp.set_can_suspend(false);
+        res
+    }
+
+    fn write_raw_data_suspendable(&mut self, image_src: &[&[u8]]) -> bool {
+        if 0 == self.comp.cinfo.raw_data_in {
+            panic!("Raw data not set");
+        }
+
+        let mcu_height = self.comp.cinfo.max_v_samp_factor as usize * DCTSIZE;
+        if mcu_height > MAX_MCU_HEIGHT {
+            panic!("Subsampling factor too large");
+        }
+        assert!(mcu_height > 0);
+
+        let num_components = self.components().len();
+        if num_components > MAX_COMPONENTS || num_components > image_src.len() {
+            panic!("Too many components: declared {}, got {}", num_components, image_src.len());
+        }
+
+        for (ci, comp_info) in self.components().iter().enumerate() {
+            if comp_info.row_stride() * comp_info.col_stride() > image_src[ci].len() {
+                panic!("Bitmap too small. Expected {}x{}, got {}", comp_info.row_stride(), comp_info.col_stride(), image_src[ci].len());
+            }
+        }
+
+        let mut start_row = self.comp.cinfo.next_scanline as usize;
+        while self.can_write_more_lines() {
+            unsafe {
+                let mut row_ptrs = [[ptr::null::<u8>(); MAX_MCU_HEIGHT]; MAX_COMPONENTS];
+                let mut comp_ptrs = [ptr::null::<*const u8>(); MAX_COMPONENTS];
+
+                for (ci, comp_info) in self.components().iter().enumerate() {
+
+                    let row_stride = comp_info.row_stride();
+
+                    let input_height = image_src[ci].len() / row_stride;
+
+                    let comp_start_row = start_row * comp_info.v_samp_factor as usize / self.comp.cinfo.max_v_samp_factor as usize;
+                    let comp_height = min(input_height - comp_start_row, DCTSIZE * comp_info.v_samp_factor as usize);
+                    assert!(comp_height >= 8);
+
+                    for (ri, row) in row_ptrs[ci].iter_mut().enumerate().take(comp_height) {
+                        let start_offset = (comp_start_row + ri) * row_stride;
+                        *row = image_src[ci][start_offset .. start_offset + row_stride].as_ptr();
+                    }
+                    for row in row_ptrs[ci].iter_mut().take(mcu_height).skip(comp_height) {
+                        *row = ptr::null();
+                    }
+                    comp_ptrs[ci] = row_ptrs[ci].as_ptr();
+                }
+
+                let rows_written = ffi::jpeg_write_raw_data(&mut self.comp.cinfo, comp_ptrs.as_ptr(), mcu_height as u32) as usize;
+                self.comp.resume_callback_panic();
+                if 0 == rows_written {
+                    return false;
+                }
+                start_row += rows_written;
+            }
+        }
+        true
+    }
+
+    /// Index of the next line to write
+    pub fn next_scanline(&self) -> usize {
+        self.comp.cinfo.next_scanline as usize
+    }
+
+    /// Take compressed data written so far. See `Compress::set_buffer_dest()`.
+    pub fn flush(&mut self) -> Vec<u8> {
+        self.comp.flush()
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn buffer_dest_mut(&mut self) -> &mut BufferDest {
+        self.comp.buffer_dest_mut()
+    }
+
+    /// Finish the file. Get it with `Compress::data_to_vec()` or `Compress::flush()`.
+    pub fn finish_compress(self) {
+        unsafe {
+            ffi::jpeg_finish_compress(&mut self.comp.cinfo);
+        }
+        self.comp.resume_callback_panic();
+        // Only a reference; there's nothing to abort anymore
+        mem::forget(self);
+    }
+
+    /// Abandon the image being compressed, keeping all settings (size, quality, tables, etc.)
+    ///
+    /// Same as dropping it. Call `set_mem_dest()` and `start_compress()` to compress another image with the same settings.
+    pub fn abort_compress(self) {
+    }
+}
+
+impl fmt::Debug for Compress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.debug_fields(&mut f.debug_struct("Compress")).finish()
+    }
+}
+
+impl<'a> fmt::Debug for CompressStarted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.comp.debug_fields(&mut f.debug_struct("CompressStarted"))
+            .field("next_scanline", &self.comp.cinfo.next_scanline)
+            .finish()
+    }
+}
+
+impl Compress {
+    fn debug_fields<'a, 'b, 'c>(&self, d: &'c mut fmt::DebugStruct<'a, 'b>) -> &'c mut fmt::DebugStruct<'a, 'b> {
+        let progressive = match self.scan_script() {
+            Some(script) => script.is_progressive(),
+            // mozjpeg's candidate scans for `set_optimize_scans()`
+            None => !self.cinfo.scan_info.is_null() && self.cinfo.num_scans > 1,
+        };
+        d.field("width", &self.cinfo.image_width)
+            .field("height", &self.cinfo.image_height)
+            .field("in_color_space", &self.cinfo.in_color_space)
+            .field("color_space", &self.cinfo.jpeg_color_space)
+            .field("subsampling", &subsampling(self.components()))
+            .field("progressive", &progressive)
+    }
+}
+
+impl<'a> Drop for CompressStarted<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            // libjpeg's memory destination updates outbuffer only when it's terminated,
+            // and may have reallocated the buffer since it started
+            if !self.comp.outbuffer.is_null() {
+                if let Some(term) = (*self.comp.cinfo.dest).term_destination {
+                    term(&mut self.comp.cinfo);
+                }
+            }
+            ffi::jpeg_abort_compress(&mut self.comp.cinfo);
+        }
+        // Now it's the current buffer, with incomplete data
+        self.comp.free_mem_dest();
+    }
 }
 
 impl Drop for Compress {
     fn drop(&mut self) {
         self.free_mem_dest();
+        if let Some(pool) = self.pool.take() {
+            let idle = self.take_idle();
+            pool.put(idle);
+            return;
+        }
         unsafe {
             ffi::jpeg_destroy_compress(&mut self.cinfo);
         }
@@ -345,24 +1664,42 @@ fn write_mem() {
         c.h_samp_factor = samp;
     }
 
-    cinfo.start_compress();
+    let mut started = cinfo.start_compress();
 
-    cinfo.write_marker(Marker::APP(2), "Hello World".as_bytes());
+    started.write_marker(Marker::APP(2), "Hello World".as_bytes());
 
-    assert_eq!(24, cinfo.components()[0].row_stride());
-    assert_eq!(40, cinfo.components()[0].col_stride());
-    assert_eq!(16, cinfo.components()[1].row_stride());
-    assert_eq!(24, cinfo.components()[1].col_stride());
-    assert_eq!(16, cinfo.components()[2].row_stride());
-    assert_eq!(24, cinfo.components()[2].col_stride());
+    assert_eq!(24, started.components()[0].row_stride());
+    assert_eq!(40, started.components()[0].col_stride());
+    assert_eq!(16, started.components()[1].row_stride());
+    assert_eq!(24, started.components()[1].col_stride());
+    assert_eq!(16, started.components()[2].row_stride());
+    assert_eq!(24, started.components()[2].col_stride());
 
-    let bitmaps = cinfo.components().iter().map(|c|{
+    let bitmaps = started.components().iter().map(|c|{
         vec![128u8; c.row_stride() * c.col_stride()]
     }).collect::<Vec<_>>();
 
-    assert!(cinfo.write_raw_data(&bitmaps.iter().map(|c|&c[..]).collect::<Vec<_>>()));
+    assert!(started.write_raw_data(&bitmaps.iter().map(|c|&c[..]).collect::<Vec<_>>()));
 
-    cinfo.finish_compress();
+    started.finish_compress();
 
     cinfo.data_to_vec().unwrap();
 }
+
+#[test]
+fn input_gamma() {
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    assert_eq!(1.0, cinfo.gamma());
+    cinfo.set_gamma(2.2);
+    assert_eq!(2.2, cinfo.gamma());
+
+    cinfo.set_size(8, 8);
+    cinfo.set_mem_dest();
+    let mut started = cinfo.start_compress();
+    assert!(started.write_scanlines(&[128; 8 * 8 * 3]));
+    started.finish_compress();
+    let jpeg = cinfo.data_to_vec().unwrap();
+
+    // Not stored in the file
+    assert_eq!(1.0, Decompress::new_mem(&jpeg).unwrap().gamma());
+}
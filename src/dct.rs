@@ -0,0 +1,98 @@
+//! 8×8 forward and inverse DCT, for inspecting and editing blocks from `Decompress::read_coefficients()`
+//!
+//! This is the exact floating-point DCT from the JPEG standard, not libjpeg's fast integer approximations,
+//! so results may differ from decoded pixels by a rounding step. Blocks and quantization tables are in natural
+//! (not zigzag) order, same as `transform::Blocks` and `QTable::as_slice()`.
+use ffi::JBLOCK;
+use qtable::QTable;
+use std::f32::consts::PI;
+
+/// cos((2x + 1)uπ/16), scaled by C(u)/2 where C(0) = 1/√2, so that rows and columns can be transformed separately
+fn basis() -> [[f32; 8]; 8] {
+    let mut basis = [[0.; 8]; 8];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let scale = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+        for (x, b) in row.iter_mut().enumerate() {
+            *b = scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.).cos();
+        }
+    }
+    basis
+}
+
+/// DCT of 8×8 pixels (row by row), level-shifted by 128 as in JPEG. Coefficients are not quantized.
+pub fn forward(pixels: &[u8; 64]) -> [f32; 64] {
+    let basis = basis();
+    let mut rows = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| basis[u][x] * (pixels[y * 8 + x] as f32 - 128.)).sum();
+        }
+    }
+    let mut out = [0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| basis[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+    out
+}
+
+/// Inverse DCT of unquantized coefficients to 8×8 pixels, rounded and clamped to 0-255
+pub fn inverse(coefs: &[f32; 64]) -> [u8; 64] {
+    let basis = basis();
+    let mut cols = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            cols[y * 8 + u] = (0..8).map(|v| basis[v][y] * coefs[v * 8 + u]).sum();
+        }
+    }
+    let mut out = [0u8; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let px: f32 = (0..8).map(|u| basis[u][x] * cols[y * 8 + u]).sum();
+            out[y * 8 + x] = (px + 128.).round().clamp(0., 255.) as u8;
+        }
+    }
+    out
+}
+
+/// Divide coefficients by the quantization table, as stored in JPEG files
+pub fn quantize(coefs: &[f32; 64], qtable: &QTable) -> JBLOCK {
+    let mut block = [0; 64];
+    for ((out, &c), &q) in block.iter_mut().zip(coefs.iter()).zip(qtable.as_slice()) {
+        *out = (c / q as f32).round() as i16;
+    }
+    block
+}
+
+/// Multiply quantized coefficients by the quantization table
+pub fn dequantize(block: &JBLOCK, qtable: &QTable) -> [f32; 64] {
+    let mut coefs = [0.; 64];
+    for ((out, &c), &q) in coefs.iter_mut().zip(block.iter()).zip(qtable.as_slice()) {
+        *out = c as f32 * q as f32;
+    }
+    coefs
+}
+
+/// Pixels of a quantized block, e.g. one from `Blocks::get()`
+pub fn block_to_pixels(block: &JBLOCK, qtable: &QTable) -> [u8; 64] {
+    inverse(&dequantize(block, qtable))
+}
+
+/// Quantized block of 8×8 pixels, e.g. to replace one with `Blocks::get_mut()`
+pub fn pixels_to_block(pixels: &[u8; 64], qtable: &QTable) -> JBLOCK {
+    quantize(&forward(pixels), qtable)
+}
+
+#[test]
+fn roundtrip() {
+    let mut pixels = [0u8; 64];
+    for (i, px) in pixels.iter_mut().enumerate() {
+        *px = (i * 37 % 256) as u8;
+    }
+    assert_eq!(&pixels[..], &inverse(&forward(&pixels))[..]);
+
+    let flat = forward(&[138; 64]);
+    assert!((flat[0] - 80.).abs() < 0.001);
+    assert!(flat[1..].iter().all(|c| c.abs() < 0.001));
+}
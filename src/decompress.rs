@@ -1,10 +1,8 @@
 //! You don't need to use this module directly.
 //!
 //! See `mozjpeg::Decompress` struct instead.
-extern crate libc;
 extern crate mozjpeg_sys as ffi;
 
-use libc::fdopen;
 use marker::Marker;
 use errormgr::ErrorMgr;
 use errormgr::PanicingErrorMgr;
@@ -13,24 +11,50 @@ use component::CompInfo;
 use colorspace::ColorSpace;
 use colorspace::ColorSpaceExt;
 use vec::VecUninitExtender;
+use transform::Blocks;
+use qtable::QTable;
+use pool::IdleList;
+#[cfg(not(target_arch = "wasm32"))]
+use source::FileSource;
+use source::ReaderSource;
+use source::{check_signature, end_input_after_error};
+#[cfg(feature = "bytes")]
+use source::BufSource;
+#[cfg(feature = "bytes")]
+use bytes::Buf;
+use histogram::Histogram;
+use limits::{self, Limits};
+use flavor::Flavor;
+use defaults;
+use compress::ConfigError;
+use pixel::{self, Pixel};
 use self::ffi::JPEG_LIB_VERSION;
 use self::ffi::J_COLOR_SPACE as COLOR_SPACE;
 use self::ffi::jpeg_decompress_struct;
 use self::ffi::DCTSIZE;
-use self::libc::{size_t, c_void, c_int, c_ulong, c_uchar};
+use self::ffi::JDIMENSION;
+use self::ffi::boolean;
+use std::os::raw::{c_void, c_int, c_uint, c_ulong, c_uchar};
 use std::marker::PhantomData;
 use std::slice;
 use std::mem;
 use std::ptr;
 use std::cmp::min;
-#[cfg(unix)]
-use std::os::unix::io::AsRawFd;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io;
+use std::io::{Read, Write};
+use std::hash::Hasher;
+use std::fmt;
 use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "memmap2")]
+use memmap2;
 
-const MAX_MCU_HEIGHT: usize = 16;
-const MAX_COMPONENTS: usize = 4;
+pub(crate) const MAX_MCU_HEIGHT: usize = 16;
+pub(crate) const MAX_COMPONENTS: usize = 4;
+// Enough for the "Exif" signature
+const EXIF_MARKER_LENGTH: c_uint = 5;
 
 /// Empty list of markers
 ///
@@ -50,7 +74,8 @@ pub const ALL_MARKERS: &'static [Marker] = &[
 ];
 
 /// Algorithm for the DCT step.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DctMethod {
     /// slow but accurate integer algorithm
     IntegerSlow,
@@ -63,7 +88,8 @@ pub enum DctMethod {
 /// Use `Decompress` static methods instead of creating this directly
 pub struct DecompressConfig<'markers> {
     save_markers: &'markers [Marker],
-    err: Option<ErrorMgr>
+    err: Option<ErrorMgr>,
+    limits: Limits,
 }
 
 impl<'markers> DecompressConfig<'markers> {
@@ -72,15 +98,18 @@ impl<'markers> DecompressConfig<'markers> {
         DecompressConfig {
             err: None,
             save_markers: NO_MARKERS,
+            limits: defaults::defaults().limits,
         }
     }
 
     #[inline]
     fn create<'a>(self) -> Decompress<'a> {
-        let mut d = Decompress::new_err(self.err.unwrap_or_else(|| <ErrorMgr as PanicingErrorMgr>::new()));
+        let mut d = Decompress::new_err(self.err.unwrap_or_else(|| defaults::error_mgr(&defaults::defaults())));
+        d.set_limits(self.limits);
         for &marker in self.save_markers {
             d.save_marker(marker);
         }
+        d.save_exif_marker(self.save_markers);
         d
     }
 
@@ -96,17 +125,28 @@ impl<'markers> DecompressConfig<'markers> {
         self
     }
 
+    /// Reject files that are too large or complex, and limit libjpeg's memory use. See `Limits`.
+    ///
+    /// Size, markers and memory are checked when the header is read, and fail with `ErrorKind::InvalidData`.
+    /// The scan limit is enforced while decoding: libjpeg stops reading the file, and starting
+    /// decompression fails with the same kind of error.
     #[inline]
-    #[cfg(unix)]
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn from_path<P: AsRef<Path>>(self, path: P) -> io::Result<Decompress<'static>> {
         self.from_file(File::open(path)?)
     }
 
     #[inline]
-    #[cfg(unix)]
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn from_file(self, file: File) -> io::Result<Decompress<'static>> {
         let mut d = self.create();
-        d.set_file_src(Box::new(file))?;
+        d.set_file_src(file)?;
         d.read_header()?;
         Ok(d)
     }
@@ -114,7 +154,71 @@ impl<'markers> DecompressConfig<'markers> {
     #[inline]
     pub fn from_mem<'src>(self, mem: &'src [u8]) -> io::Result<Decompress<'src>> {
         let mut d = self.create();
-        d.set_mem_src(mem);
+        d.set_mem_src(mem)?;
+        d.read_header()?;
+        Ok(d)
+    }
+
+    /// Map the file into memory and decode it from there, without copying. Requires the `memmap2` feature.
+    ///
+    /// This is faster than `from_path()` for large files, especially ones already in the OS cache.
+    /// The file must not be modified while it's being decoded, because the mapping would change too.
+    #[inline]
+    #[cfg(feature = "memmap2")]
+    pub fn from_mmap<P: AsRef<Path>>(self, path: P) -> io::Result<Decompress<'static>> {
+        let file = File::open(path)?;
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        self.from_owned(map)
+    }
+
+    /// Decode a file that has been read into memory, taking ownership of the buffer
+    ///
+    /// ```rust,ignore
+    /// let dinfo = Decompress::with_markers(ALL_MARKERS).from_vec(fs::read(path)?)?;
+    /// ```
+    #[inline]
+    pub fn from_vec(self, data: Vec<u8>) -> io::Result<Decompress<'static>> {
+        self.from_owned(data)
+    }
+
+    /// Decode data owned (or shared) by the decompressor, e.g. `Vec<u8>`, `Arc<[u8]>` or `Bytes`,
+    /// so that it doesn't borrow anything
+    #[inline]
+    pub fn from_owned<D: AsRef<[u8]> + 'static>(self, data: D) -> io::Result<Decompress<'static>> {
+        let mut d = self.create();
+        d.set_owned_src(Box::new(data))?;
+        d.read_header()?;
+        Ok(d)
+    }
+
+    /// Decode data as it's read from `reader`, without reading it all into memory first
+    ///
+    /// Prefer `from_path()` or `from_mem()` when possible, because unlike them it can't skip unneeded data.
+    /// I/O errors are returned while the header is read. Later they're raised as a panic
+    /// by the method that was reading (e.g. `read_scanlines_flat()`), after libjpeg returns.
+    #[inline]
+    pub fn from_reader<R: Read + 'static>(self, reader: R) -> io::Result<Decompress<'static>> {
+        let mut d = self.create();
+        d.set_reader_src(Box::new(reader));
+        d.read_header()?;
+        Ok(d)
+    }
+
+    /// Decode data from the standard input, e.g. in a command-line filter. See `from_reader()`.
+    #[inline]
+    pub fn from_stdin(self) -> io::Result<Decompress<'static>> {
+        self.from_reader(io::stdin())
+    }
+
+    /// Decode data split into chunks, e.g. a body received by `hyper`, without joining them first.
+    /// Requires the `bytes` feature.
+    ///
+    /// Contiguous buffers like `Bytes` work with `from_owned()` too.
+    #[inline]
+    #[cfg(feature = "bytes")]
+    pub fn from_buf<B: Buf + 'static>(self, buf: B) -> io::Result<Decompress<'static>> {
+        let mut d = self.create();
+        d.set_buf_src(Box::new(buf));
         d.read_header()?;
         Ok(d)
     }
@@ -128,11 +232,91 @@ impl<'markers> DecompressConfig<'markers> {
 /// let d = Decompress::new_path("image.jpg");
 /// ```
 pub struct Decompress<'src> {
-    cinfo: jpeg_decompress_struct,
+    pub(crate) cinfo: jpeg_decompress_struct,
     own_error: Box<ErrorMgr>,
-    own_file: Option<Box<File>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    own_source: Option<Box<FileSource>>,
+    own_reader: Option<Box<ReaderSource>>,
+    own_data: Option<Box<dyn AsRef<[u8]>>>,
+    #[cfg(feature = "bytes")]
+    own_buf: Option<Box<BufSource>>,
     // Informs the borrow checker that the memory given in src must outlive the `jpeg_decompress_struct`
     _mem_marker: PhantomData<&'src [u8]>,
+    limits: Limits,
+    scan_limit: Option<Box<ScanLimitMgr>>,
+    // APP1 is saved only for `flavor()`
+    hide_app1: bool,
+    // Decompressors from a `Pool` go back to it when dropped
+    pool: Option<Arc<IdleList<Decompress<'static>>>>,
+}
+
+/// Progress monitor that stops decoding files with too many scans, see `Limits::max_scans`
+#[repr(C)]
+struct ScanLimitMgr {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_progress_mgr,
+    max_scans: usize,
+    // Set instead of panicking, which can't unwind through libjpeg. Returned by `callback_error()`.
+    exceeded: Option<ConfigError>,
+}
+
+extern "C" fn scan_limit_monitor(cinfo: &mut ffi::jpeg_common_struct) {
+    unsafe {
+        let mgr = &mut *(cinfo.progress as *mut ScanLimitMgr);
+        let cinfo = &mut *(cinfo as *mut ffi::jpeg_common_struct as *mut jpeg_decompress_struct);
+        let scans = cinfo.input_scan_number as usize;
+        if scans > mgr.max_scans && mgr.exceeded.is_none() {
+            mgr.exceeded = Some(ConfigError::LimitExceeded { limit: "max_scans", value: scans, max: mgr.max_scans });
+            // libjpeg stops reading at the end of the current scan
+            end_input_after_error(cinfo, false);
+        }
+    }
+}
+
+/// Compile-time check of `DecompressStarted::read_pixels::<N>()`
+struct PixelSize<const N: usize>;
+
+impl<const N: usize> PixelSize<N> {
+    const VALID: () = assert!(N >= 1 && N <= MAX_COMPONENTS, "Pixels must have 1-4 components");
+}
+
+/// Size of pixels from `DecompressStarted::read_scanlines_flat()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLayout {
+    pub width: usize,
+    /// Number of lines read
+    pub height: usize,
+    /// Bytes per pixel
+    pub components: usize,
+}
+
+impl ImageLayout {
+    /// Bytes per line
+    pub fn row_len(&self) -> usize {
+        self.width * self.components
+    }
+}
+
+/// Columns decoded after `DecompressStarted::crop()`
+///
+/// libjpeg starts the region at an iMCU boundary, so it may begin before the requested column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropAlignment {
+    /// First decoded column
+    pub x: usize,
+    /// Number of decoded columns, which is the new `width()`
+    pub width: usize,
+    /// Pixels at the start of each line that are before the requested column
+    pub skip: usize,
+    /// Requested width, which follows the skipped pixels
+    pub requested_width: usize,
+}
+
+impl CropAlignment {
+    /// Part of a decoded line (with `pixel_size` items per pixel) that is in the requested region
+    pub fn trim<'a, T>(&self, line: &'a [T], pixel_size: usize) -> &'a [T] {
+        &line[self.skip * pixel_size..(self.skip + self.requested_width) * pixel_size]
+    }
 }
 
 /// Marker type and data slice returned by `MarkerIter`
@@ -144,23 +328,57 @@ pub struct MarkerData<'a> {
 /// See `Decompress.markers()`
 pub struct MarkerIter<'a> {
     marker_list: *mut ffi::jpeg_marker_struct,
+    // APP1 saved only for `flavor()`, not requested by the user
+    hide_app1: bool,
     _uhh: ::std::marker::PhantomData<MarkerData<'a>>,
 }
 
 impl<'a> Iterator for MarkerIter<'a> {
     type Item = MarkerData<'a>;
     fn next(&mut self) -> Option<MarkerData<'a>> {
-        if self.marker_list.is_null() {
-            return None;
-        }
-        unsafe {
-            let ref last = *self.marker_list;
-            self.marker_list = last.next;
-            Some(MarkerData {
-                marker: last.marker.into(),
-                data: ::std::slice::from_raw_parts(last.data, last.data_length as usize),
-            })
+        while !self.marker_list.is_null() {
+            unsafe {
+                let last = &*self.marker_list;
+                self.marker_list = last.next;
+                let marker = last.marker.into();
+                if self.hide_app1 && marker == Marker::APP(1) {
+                    continue;
+                }
+                return Some(MarkerData {
+                    marker,
+                    data: ::std::slice::from_raw_parts(last.data, last.data_length as usize),
+                });
+            }
         }
+        None
+    }
+}
+
+/// Same as `Decompress::new_mem()`
+impl<'src> TryFrom<&'src [u8]> for Decompress<'src> {
+    type Error = io::Error;
+
+    fn try_from(mem: &'src [u8]) -> io::Result<Self> {
+        Decompress::new_mem(mem)
+    }
+}
+
+/// Same as `Decompress::new_path()`
+#[cfg(not(target_arch = "wasm32"))]
+impl<'a> TryFrom<&'a Path> for Decompress<'static> {
+    type Error = io::Error;
+
+    fn try_from(path: &'a Path) -> io::Result<Self> {
+        Decompress::new_path(path)
+    }
+}
+
+/// Same as `Decompress::new_vec()`
+impl TryFrom<Vec<u8>> for Decompress<'static> {
+    type Error = io::Error;
+
+    fn try_from(data: Vec<u8>) -> io::Result<Self> {
+        Decompress::new_vec(data)
     }
 }
 
@@ -175,16 +393,22 @@ impl<'src> Decompress<'src> {
         Self::config().with_markers(save_markers)
     }
 
+    /// See `DecompressConfig::with_limits()`
     #[inline]
-    #[cfg(unix)]
+    pub fn with_limits(limits: Limits) -> DecompressConfig<'static> {
+        Self::config().with_limits(limits)
+    }
+
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
     /// Decode file at path
     pub fn new_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         Self::config().from_path(path)
     }
 
-    /// Decode an already-opened file
+    /// Decode an already-opened file, starting from its current position
     #[inline]
-    #[cfg(unix)]
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn new_file(file: File) -> io::Result<Self> {
         Self::config().from_file(file)
     }
@@ -194,22 +418,69 @@ impl<'src> Decompress<'src> {
         Self::config().from_mem(mem)
     }
 
+    /// Decode a memory-mapped file. See `DecompressConfig::from_mmap()`.
+    #[inline]
+    #[cfg(feature = "memmap2")]
+    pub fn new_mmap<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::config().from_mmap(path)
+    }
+
+    /// Decode a file that has been read into memory, taking ownership of the buffer
+    #[inline]
+    pub fn new_vec(data: Vec<u8>) -> io::Result<Self> {
+        Self::config().from_vec(data)
+    }
+
+    /// Decode data owned by the decompressor. See `DecompressConfig::from_owned()`.
+    #[inline]
+    pub fn new_owned<D: AsRef<[u8]> + 'static>(data: D) -> io::Result<Self> {
+        Self::config().from_owned(data)
+    }
+
+    /// Decode data read from `reader`. See `DecompressConfig::from_reader()`.
+    #[inline]
+    pub fn new_reader<R: Read + 'static>(reader: R) -> io::Result<Self> {
+        Self::config().from_reader(reader)
+    }
+
+    /// Decode data from the standard input. See `DecompressConfig::from_reader()`.
+    #[inline]
+    pub fn new_stdin() -> io::Result<Self> {
+        Self::config().from_stdin()
+    }
+
+    /// Decode data split into chunks. See `DecompressConfig::from_buf()`.
+    #[inline]
+    #[cfg(feature = "bytes")]
+    pub fn new_buf<B: Buf + 'static>(buf: B) -> io::Result<Self> {
+        Self::config().from_buf(buf)
+    }
+
     #[inline]
     fn config() -> DecompressConfig<'static> {
         DecompressConfig::new()
     }
 
-    fn new_err(err: ErrorMgr) -> Self {
+    pub(crate) fn new_err(err: ErrorMgr) -> Self {
         unsafe {
             let mut newself = Decompress {
                 cinfo: mem::zeroed(),
                 own_error: Box::new(err),
-                own_file: None,
+                #[cfg(not(target_arch = "wasm32"))]
+                own_source: None,
+                own_reader: None,
+                own_data: None,
+                #[cfg(feature = "bytes")]
+                own_buf: None,
                 _mem_marker: PhantomData,
+                limits: Limits::default(),
+                scan_limit: None,
+                hide_app1: false,
+                pool: None,
             };
             newself.cinfo.common.err = &mut *newself.own_error;
 
-            let s = mem::size_of_val(&newself.cinfo) as size_t;
+            let s = mem::size_of_val(&newself.cinfo) as usize;
             ffi::jpeg_CreateDecompress(&mut newself.cinfo, JPEG_LIB_VERSION, s);
 
             newself
@@ -228,54 +499,221 @@ impl<'src> Decompress<'src> {
         }
     }
 
-    #[cfg(unix)]
-    fn set_file_src(&mut self, file: Box<File>) -> io::Result<()> {
-        unsafe {
-            let fh = fdopen(file.as_raw_fd(), b"rb".as_ptr() as *const _);
-            if fh.is_null() {
-                return Err(io::Error::last_os_error());
+    #[cfg(not(target_arch = "wasm32"))]
+    fn set_file_src(&mut self, file: File) -> io::Result<()> {
+        let mut src = FileSource::new(file)?;
+        self.cinfo.src = src.as_mgr();
+        self.own_source = Some(src);
+        Ok(())
+    }
+
+    fn set_reader_src(&mut self, reader: Box<dyn Read>) {
+        let mut src = ReaderSource::new(reader);
+        self.cinfo.src = src.as_mgr();
+        self.own_reader = Some(src);
+    }
+
+    #[cfg(feature = "bytes")]
+    fn set_buf_src(&mut self, buf: Box<dyn Buf>) {
+        let mut src = BufSource::new(buf);
+        self.cinfo.src = src.as_mgr();
+        self.own_buf = Some(src);
+    }
+
+    fn set_owned_src(&mut self, data: Box<dyn AsRef<[u8]>>) -> io::Result<()> {
+        {
+            // The data is on the heap, so it doesn't move with the box
+            let mem = (*data).as_ref();
+            check_signature(mem)?;
+            unsafe {
+                ffi::jpeg_mem_src(&mut self.cinfo, mem.as_ptr(), mem.len() as c_ulong);
             }
-            ffi::jpeg_stdio_src(&mut self.cinfo, fh)
         }
-        self.own_file = Some(file);
+        self.own_data = Some(data);
         Ok(())
     }
 
-    fn set_mem_src(&mut self, file: &'src [u8]) {
+    fn set_mem_src(&mut self, file: &'src [u8]) -> io::Result<()> {
+        check_signature(file)?;
         unsafe {
             ffi::jpeg_mem_src(&mut self.cinfo, file.as_ptr(), file.len() as c_ulong);
         }
+        Ok(())
+    }
+
+    /// Read a new file with a decompressor from a `Pool`, which has been aborted before
+    ///
+    /// Defaults are applied again, as for a new `Decompress`.
+    pub(crate) fn reuse<'a>(mut self, save_markers: &[Marker], mem: &'a [u8], pool: Arc<IdleList<Decompress<'static>>>) -> io::Result<Decompress<'a>> {
+        let defaults = defaults::defaults();
+        // `cinfo` keeps pointing to the same box
+        *self.own_error = defaults::error_mgr(&defaults);
+        self.set_limits(defaults.limits);
+        for &marker in ALL_MARKERS {
+            if save_markers.contains(&marker) {
+                self.save_marker(marker);
+            } else {
+                unsafe {
+                    ffi::jpeg_save_markers(&mut self.cinfo, marker.into(), 0);
+                }
+            }
+        }
+        self.save_exif_marker(save_markers);
+        self.pool = Some(pool);
+        // Only the lifetime of the source changes
+        let mut d: Decompress<'a> = unsafe { mem::transmute(self) };
+        d.set_mem_src(mem)?;
+        d.read_header()?;
+        Ok(d)
     }
 
     /// Result here is mostly useless, because it will panic if the file is invalid
     fn read_header(&mut self) -> io::Result<()> {
         let res = unsafe { ffi::jpeg_read_header(&mut self.cinfo, 0) };
+        self.callback_error()?;
         if res == 1 {
-            return Ok(());
+            self.check_limits().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
         } else {
-            return Err(io::Error::new(io::ErrorKind::Other, format!("JPEG err {}", res)));
+            Err(io::Error::new(io::ErrorKind::Other, format!("JPEG err {}", res)))
+        }
+    }
+
+    /// Error that a callback (e.g. a read error of the source) couldn't return through libjpeg
+    ///
+    /// The callback ends the input instead, and the error is taken here after libjpeg returns.
+    fn callback_error(&mut self) -> io::Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(err) = self.own_source.as_mut().and_then(|src| src.take_error()) {
+            return Err(err);
+        }
+        if let Some(err) = self.own_reader.as_mut().and_then(|src| src.take_error()) {
+            return Err(err);
+        }
+        #[cfg(feature = "bytes")]
+        if let Some(err) = self.own_buf.as_mut().and_then(|src| src.take_error()) {
+            return Err(err);
+        }
+        if let Some(err) = self.scan_limit.as_mut().and_then(|mgr| mgr.exceeded.take()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        Ok(())
+    }
+
+    /// Like `callback_error()`, for methods that can't return errors
+    fn expect_callbacks(&mut self) {
+        if let Err(err) = self.callback_error() {
+            panic!("Can't read JPEG data: {}", err);
         }
     }
 
+    fn set_limits(&mut self, limits: Limits) {
+        self.scan_limit = limits.max_scans.map(|max_scans| {
+            let mut mgr = Box::new(ScanLimitMgr {
+                mgr: unsafe { mem::zeroed() },
+                max_scans,
+                exceeded: None,
+            });
+            mgr.mgr.progress_monitor = Some(scan_limit_monitor);
+            mgr
+        });
+        self.cinfo.common.progress = match self.scan_limit {
+            Some(ref mut mgr) => &mut mgr.mgr,
+            None => ptr::null_mut(),
+        };
+        self.limits = limits;
+    }
+
+    fn check_limits(&self) -> Result<(), ConfigError> {
+        self.limits.check_size(self.width(), self.height())?;
+        // Saved markers may be truncated, so count their full length
+        let mut marker_bytes = 0;
+        let mut marker_list = self.cinfo.marker_list;
+        while let Some(m) = unsafe { marker_list.as_ref() } {
+            if !(self.hide_app1 && Marker::from(m.marker) == Marker::APP(1)) {
+                marker_bytes += m.original_length as usize;
+            }
+            marker_list = m.next;
+        }
+        self.limits.check_marker_bytes(marker_bytes)?;
+        // Other files are decoded a few rows at a time
+        if 0 != unsafe { ffi::jpeg_has_multiple_scans(&self.cinfo) } {
+            self.check_memory()?;
+        }
+        Ok(())
+    }
+
+    fn check_memory(&self) -> Result<(), ConfigError> {
+        self.limits.check_memory(limits::coefficient_buffer_size(self.width(), self.height(), self.components()))
+    }
+
     pub fn color_space(&self) -> COLOR_SPACE {
         self.cinfo.jpeg_color_space
     }
 
+    /// Output gamma. It's not stored in JPEG files, so it's always libjpeg's default 1.0
     pub fn gamma(&self) -> f64 {
         self.cinfo.output_gamma
     }
 
+    /// Quantization table used by each component, as defined in the file's header
+    ///
+    /// Unlike `CompInfoExt::qtable()`, this is available before decompression starts.
+    pub fn qtables(&self) -> Vec<Option<QTable>> {
+        self.components().iter().map(|c| {
+            let tbl = self.cinfo.quant_tbl_ptrs.get(c.quant_tbl_no as usize).cloned().unwrap_or(ptr::null_mut());
+            unsafe {tbl.as_ref()}.map(|q_in| {
+                let mut qtable = QTable {
+                    coeffs: [0; 64],
+                };
+                for (out, q) in qtable.coeffs.iter_mut().zip(q_in.quantval.iter()) {
+                    *out = *q as u32;
+                }
+                qtable
+            })
+        }).collect()
+    }
+
     /// Markers are available only if you enable them via `with_markers()`
     pub fn markers(&self) -> MarkerIter {
         MarkerIter {
             marker_list: self.cinfo.marker_list,
+            hide_app1: self.hide_app1,
             _uhh: PhantomData,
         }
     }
 
+    /// Whether the file is JFIF, EXIF, Adobe or has none of these markers
+    ///
+    /// Works without `with_markers()`: libjpeg parses JFIF and Adobe markers itself,
+    /// and the start of EXIF markers is always kept.
+    pub fn flavor(&self) -> Flavor {
+        let exif = Flavor::from_markers(MarkerIter {
+            marker_list: self.cinfo.marker_list,
+            hide_app1: false,
+            _uhh: PhantomData,
+        }).exif;
+        Flavor {
+            jfif: self.cinfo.saw_JFIF_marker != 0,
+            exif,
+            adobe_transform: if self.cinfo.saw_Adobe_marker != 0 { Some(self.cinfo.Adobe_transform) } else { None },
+        }
+    }
+
+    /// Keep enough of APP1 for `flavor()`, unless it's already saved
+    fn save_exif_marker(&mut self, save_markers: &[Marker]) {
+        self.hide_app1 = !save_markers.contains(&Marker::APP(1));
+        if self.hide_app1 {
+            unsafe {
+                ffi::jpeg_save_markers(&mut self.cinfo, Marker::APP(1).into(), EXIF_MARKER_LENGTH);
+            }
+        }
+    }
+
     fn save_marker(&mut self, marker: Marker) {
+        // Longer markers would fail the limit anyway, so don't keep more of them
+        let length_limit = self.limits.max_marker_bytes.map_or(0xFFFF, |max| max.clamp(1, 0xFFFF));
         unsafe {
-            ffi::jpeg_save_markers (&mut self.cinfo, marker.into(), 0xFFFF);
+            ffi::jpeg_save_markers (&mut self.cinfo, marker.into(), length_limit as c_uint);
         }
     }
 
@@ -317,6 +755,37 @@ impl<'src> Decompress<'src> {
         return DecompressStarted::start_decompress(self);
     }
 
+    /// Start decompression with conversion to any color space libjpeg can convert to
+    pub(crate) fn start_as_color_space(mut self, color_space: ColorSpace) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = color_space;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with conversion to the color space of the pixel type, e.g. `start_as::<RGB8>()`
+    ///
+    /// Read the pixels with `read_scanlines_as()`.
+    pub fn start_as<P: Pixel>(self) -> io::Result<DecompressStarted<'src>> {
+        self.start_as_color_space(P::COLOR_SPACE)
+    }
+
+    /// Start decompression with conversion to CMYK.
+    ///
+    /// Works for both CMYK and YCCK files.
+    pub fn cmyk(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_CMYK;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression without any color conversion, treating all components as opaque data
+    ///
+    /// This is needed for files with `JCS_UNKNOWN` color space (see `Compress::new_unknown()`),
+    /// which would otherwise be guessed to be YCbCr or RGB.
+    pub fn passthrough(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.jpeg_color_space = ffi::J_COLOR_SPACE::JCS_UNKNOWN;
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_UNKNOWN;
+        DecompressStarted::start_decompress(self)
+    }
+
     /// Selects the algorithm used for the DCT step.
     pub fn dct_method(&mut self, method: DctMethod) {
         self.cinfo.dct_method = match method {
@@ -363,6 +832,32 @@ impl<'src> Decompress<'src> {
         }
     }
 
+    /// Read quantized DCT coefficients of all components, without decoding pixels
+    ///
+    /// Pixels can't be read afterwards. Use `dct::block_to_pixels()` with `qtables()` to see the blocks.
+    pub fn read_coefficients(&mut self) -> io::Result<Vec<Blocks>> {
+        self.check_memory().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        unsafe {
+            let arrays = ffi::jpeg_read_coefficients(&mut self.cinfo);
+            self.callback_error()?;
+            if arrays.is_null() {
+                return Err(io::Error::new(io::ErrorKind::Other, "Can't read coefficients"));
+            }
+            let access = (*self.cinfo.common.mem).access_virt_barray.unwrap();
+            let sizes = self.components().iter()
+                .map(|c| (c.width_in_blocks as usize, c.height_in_blocks as usize))
+                .collect::<Vec<_>>();
+            Ok(sizes.into_iter().enumerate().map(|(ci, (width, height))| {
+                let mut blocks = Blocks::new(width, height);
+                for y in 0..height {
+                    let row = *access(&mut self.cinfo.common, *arrays.add(ci), y as JDIMENSION, 1, false as boolean);
+                    blocks.blocks[y * width .. (y + 1) * width].copy_from_slice(slice::from_raw_parts(row, width));
+                }
+                blocks
+            }).collect())
+        }
+    }
+
     /// Rescales the output image by `numerator / 8` during decompression.
     /// `numerator` must be between 1 and 16. 
     /// Thus setting a value of `8` will result in an unscaled image.
@@ -380,17 +875,25 @@ pub enum Format<'a> {
     CMYK(DecompressStarted<'a>),
 }
 
+/// See `DecompressStarted::inspect_rows()`
+type RowCallback<'src> = Box<dyn FnMut(&[u8]) + 'src>;
+
 /// See methods on `Decompress`
 pub struct DecompressStarted<'src> {
     dec: Decompress<'src>,
+    histogram: Option<Histogram>,
+    row_callbacks: Vec<RowCallback<'src>>,
 }
 
 impl<'src> DecompressStarted<'src> {
     fn start_decompress(mut dec: Decompress<'src>) -> io::Result<Self> {
         let res = unsafe { ffi::jpeg_start_decompress(&mut dec.cinfo) };
+        dec.callback_error()?;
         if 0 != res {
             Ok(DecompressStarted {
-                dec
+                dec,
+                histogram: None,
+                row_callbacks: Vec::new(),
             })
         } else {
             Err(io::Error::new(io::ErrorKind::Other, format!("JPEG err {}", res)))
@@ -401,14 +904,67 @@ impl<'src> DecompressStarted<'src> {
         self.dec.out_color_space()
     }
 
-    fn read_more_chunks(&self) -> bool {
+    /// Markers are available only if you enable them via `with_markers()`
+    pub fn markers(&self) -> MarkerIter<'_> {
+        self.dec.markers()
+    }
+
+    /// Whether the file is JFIF, EXIF, Adobe or has none of these markers
+    pub fn flavor(&self) -> Flavor {
+        self.dec.flavor()
+    }
+
+    /// Bytes per pixel in output scanlines: 1 for color-quantized (palettized) output,
+    /// otherwise `out_color_components()` (including padding of e.g. `JCS_EXT_RGBX`)
+    pub fn output_components(&self) -> usize {
+        self.dec.cinfo.output_components as usize
+    }
+
+    /// Channels of `color_space()`, regardless of color quantization
+    pub fn out_color_components(&self) -> usize {
+        self.dec.cinfo.out_color_components as usize
+    }
+
+    /// Number of lines libjpeg prefers to decode per call (usually 1 or 2, at most 4)
+    ///
+    /// Buffers for `read_scanlines*` are filled most efficiently in multiples of this height.
+    pub fn rec_outbuf_height(&self) -> usize {
+        self.dec.cinfo.rec_outbuf_height as usize
+    }
+
+    pub(crate) fn read_more_chunks(&self) -> bool {
         self.dec.cinfo.output_scanline < self.dec.cinfo.output_height
     }
 
     pub fn read_raw_data(&mut self, image_dest: &mut [&mut Vec<u8>]) {
-        while self.read_more_chunks() {
-            self.read_raw_data_chunk(image_dest);
+        while self.read_next_imcu_row(image_dest) {}
+    }
+
+    /// Lines in one iMCU row of raw data: 8 × the largest vertical sampling factor
+    pub fn imcu_height(&self) -> usize {
+        self.dec.cinfo.max_v_samp_factor as usize * DCTSIZE
+    }
+
+    /// Number of iMCU rows in the image, which is how many times `read_next_imcu_row()` returns `true`
+    pub fn total_imcu_rows(&self) -> usize {
+        self.dec.cinfo.total_iMCU_rows as usize
+    }
+
+    /// Index of the next iMCU row to read. Equals `total_imcu_rows()` when all have been read.
+    pub fn imcu_row(&self) -> usize {
+        self.dec.cinfo.output_scanline as usize / self.imcu_height()
+    }
+
+    /// Append one iMCU row of raw data of every component (see `read_raw_data()`),
+    /// so that it can be processed before decoding the next one
+    ///
+    /// Returns `false`, without reading anything, if all rows have been read.
+    pub fn read_next_imcu_row(&mut self, image_dest: &mut [&mut Vec<u8>]) -> bool {
+        if !self.read_more_chunks() {
+            return false;
         }
+        self.read_raw_data_chunk(image_dest);
+        true
     }
 
     fn read_raw_data_chunk(&mut self, image_dest: &mut [&mut Vec<u8>]) {
@@ -444,6 +1000,7 @@ impl<'src> DecompressStarted<'src> {
             }
 
             let lines_read = ffi::jpeg_read_raw_data(&mut self.dec.cinfo, comp_ptrs.as_mut_ptr(), mcu_height as u32) as usize;
+            self.dec.expect_callbacks();
 
             assert_eq!(lines_read, mcu_height); // Partial reads would make subsampled height tricky to define
         }
@@ -457,28 +1014,199 @@ impl<'src> DecompressStarted<'src> {
         self.dec.cinfo.output_height as usize
     }
 
+    /// All remaining lines as pixels of type `T`, which must have the size of one pixel
+    ///
+    /// Only the size is checked, so it's easy to pick a type with the wrong layout.
+    #[deprecated(note = "use read_scanlines_flat() or read_scanlines_as()")]
     pub fn read_scanlines<T: Copy>(&mut self) -> Option<Vec<T>> {
-        let num_components = self.color_space().num_components();
+        let num_components = self.dec.cinfo.output_components as usize;
         assert_eq!(num_components, mem::size_of::<T>());
+        self.read_scanlines_items(1)
+    }
+
+    /// All remaining lines as pixels of a type that matches the output color space (e.g. after `start_as::<P>()`)
+    ///
+    /// Panics if the color space is different.
+    pub fn read_scanlines_as<P: Pixel>(&mut self) -> Option<Vec<P>> {
+        let color_space = self.dec.out_color_space();
+        assert!(pixel::same_layout(P::COLOR_SPACE, color_space), "Pixel type is for {:?}, but output is {:?}", P::COLOR_SPACE, color_space);
+        self.read_scanlines_items(1)
+    }
+
+    /// All remaining lines as arrays of `N` bytes per pixel, e.g. `read_pixels::<3>()` for RGB
+    ///
+    /// `N` is checked at compile time to be a valid number of components (1-4),
+    /// and at run time to match the output color space. Panics if it doesn't.
+    pub fn read_pixels<const N: usize>(&mut self) -> Option<Vec<[u8; N]>> {
+        let () = PixelSize::<N>::VALID;
+        let num_components = self.dec.cinfo.output_components as usize;
+        assert_eq!(num_components, N, "Output has {} components per pixel", num_components);
+        self.read_scanlines_items(1)
+    }
+
+    /// All remaining lines as interleaved bytes, and their size
+    pub fn read_scanlines_flat(&mut self) -> Option<(Vec<u8>, ImageLayout)> {
+        let components = self.dec.cinfo.output_components as usize;
         let width = self.width();
-        let height = self.height();
-        let mut image_dst:Vec<T> = Vec::with_capacity(self.height() * width);
+        let pixels = self.read_scanlines_bytes()?;
+        let height = if width > 0 { pixels.len() / (width * components) } else { 0 };
+        Some((pixels, ImageLayout { width, height, components }))
+    }
+
+    /// All remaining lines as interleaved bytes
+    pub(crate) fn read_scanlines_bytes(&mut self) -> Option<Vec<u8>> {
+        let num_components = self.dec.cinfo.output_components as usize;
+        self.read_scanlines_items(num_components)
+    }
+
+    /// All remaining lines as interleaved bytes, appended to `dest`
+    ///
+    /// Reuses capacity of `dest`, e.g. a buffer from `BufferPool`. Returns `false` on error.
+    pub fn read_scanlines_into(&mut self, dest: &mut Vec<u8>) -> bool {
+        let num_components = self.dec.cinfo.output_components as usize;
+        self.read_scanlines_items_into(dest, num_components, usize::MAX)
+    }
+
+    /// Write all remaining lines as tightly packed pixels to `writer`
+    ///
+    /// Only a few rows are buffered at a time, so this works for images too large to decode into memory,
+    /// e.g. when writing to a temporary file or another encoder.
+    pub fn read_scanlines_to_writer<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
+        let num_components = self.dec.cinfo.output_components as usize;
+        let mut buffer = Vec::with_capacity(MAX_MCU_HEIGHT * self.width() * num_components);
+        while self.read_more_chunks() {
+            buffer.clear();
+            if !self.read_lines_into(&mut buffer, MAX_MCU_HEIGHT) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "JPEG decoder didn't return all lines"));
+            }
+            writer.write_all(&buffer)?;
+        }
+        writer.flush()
+    }
+
+    /// Count values of pixels in lines read from now on, e.g. for auto-levels, without another pass over the image
+    ///
+    /// Applies to `read_scanlines()` and other methods that return pixels (not raw data).
+    pub fn collect_histogram(&mut self) {
+        self.histogram = Some(Histogram::new(self.dec.cinfo.output_components as usize));
+    }
+
+    /// Histogram of pixels read so far, if enabled with `collect_histogram()`
+    pub fn histogram(&self) -> Option<&Histogram> {
+        self.histogram.as_ref()
+    }
+
+    /// Feed bytes of every line read from now on to `hasher`, e.g. to deduplicate images by their pixels
+    ///
+    /// Pass `&mut hasher` to keep using it afterwards. For other kinds of hashes (e.g. `Digest`), use `inspect_rows()`.
+    ///
+    /// ```rust,ignore
+    /// let mut hasher = DefaultHasher::new();
+    /// dinfo.hash_rows(&mut hasher);
+    /// ```
+    pub fn hash_rows<H: Hasher + 'src>(&mut self, mut hasher: H) {
+        self.inspect_rows(move |row| hasher.write(row));
+    }
+
+    /// Call `callback` with bytes of every line read from now on, as soon as it's decoded
+    ///
+    /// Applies to `read_scanlines()` and other methods that return pixels (not raw data).
+    pub fn inspect_rows<F: FnMut(&[u8]) + 'src>(&mut self, callback: F) {
+        self.row_callbacks.push(Box::new(callback));
+    }
+
+    /// Up to `max_lines` lines as interleaved bytes, appended to `dest`
+    pub(crate) fn read_lines_into(&mut self, dest: &mut Vec<u8>, max_lines: usize) -> bool {
+        let num_components = self.dec.cinfo.output_components as usize;
+        self.read_scanlines_items_into(dest, num_components, max_lines)
+    }
+
+    /// Decode only columns from `x` to `x + width`, for all following lines
+    ///
+    /// libjpeg may widen the region to start at an iMCU boundary. Returns the actual start and width,
+    /// which are also the new `width()`. Must be called before reading any lines.
+    /// See `crop()` for how many pixels to trim.
+    pub fn crop_scanline(&mut self, x: usize, width: usize) -> (usize, usize) {
+        assert!(x + width <= self.width() && width > 0, "Crop outside the image");
+        let mut xoffset = x as JDIMENSION;
+        let mut width = width as JDIMENSION;
         unsafe {
-            image_dst.extend_uninit(height * width);
+            ffi::jpeg_crop_scanline(&mut self.dec.cinfo, &mut xoffset, &mut width);
+        }
+        (xoffset as usize, width as usize)
+    }
+
+    /// Same as `crop_scanline()`, but also returns where the requested columns are in decoded lines
+    pub fn crop(&mut self, x: usize, width: usize) -> CropAlignment {
+        let (crop_x, crop_width) = self.crop_scanline(x, width);
+        CropAlignment {
+            x: crop_x,
+            width: crop_width,
+            skip: x - crop_x,
+            requested_width: width,
+        }
+    }
 
-            while self.read_more_chunks() {
-                let start_line = self.dec.cinfo.output_scanline as usize;
-                let rest:&mut [T] = &mut image_dst[width * start_line ..];
-                let rows = (&mut rest.as_mut_ptr()) as *mut *mut T;
+    /// Skip `lines` lines without fully decoding them. Returns the number of lines skipped.
+    pub fn skip_scanlines(&mut self, lines: usize) -> usize {
+        let skipped = unsafe {
+            ffi::jpeg_skip_scanlines(&mut self.dec.cinfo, lines as JDIMENSION) as usize
+        };
+        self.dec.expect_callbacks();
+        skipped
+    }
+
+    /// `items_per_pixel` of `T` make one pixel
+    fn read_scanlines_items<T: Copy>(&mut self, items_per_pixel: usize) -> Option<Vec<T>> {
+        let mut image_dst = Vec::new();
+        if self.read_scanlines_items_into(&mut image_dst, items_per_pixel, usize::MAX) {
+            Some(image_dst)
+        } else {
+            None
+        }
+    }
+
+    fn read_scanlines_items_into<T: Copy>(&mut self, image_dst: &mut Vec<T>, items_per_pixel: usize, max_lines: usize) -> bool {
+        let row_len = self.width() * items_per_pixel;
+        let first_line = self.dec.cinfo.output_scanline as usize;
+        let end_line = first_line + min(max_lines, self.height() - first_line);
+        let height = self.height();
+        let start = image_dst.len();
+        unsafe {
+            image_dst.extend_uninit((end_line - first_line) * row_len);
+
+            // Several rows per call amortize the per-call overhead of libjpeg (it outputs up to `rec_outbuf_height` rows at once)
+            let mut row_ptrs = [ptr::null_mut::<T>(); MAX_MCU_HEIGHT];
+            while (self.dec.cinfo.output_scanline as usize) < end_line {
+                let line = self.dec.cinfo.output_scanline as usize - first_line;
+                let band = min(MAX_MCU_HEIGHT, end_line - first_line - line);
+                let rest: &mut [T] = &mut image_dst[start + row_len * line ..];
+                for (i, row_ptr) in row_ptrs[..band].iter_mut().enumerate() {
+                    *row_ptr = rest.as_mut_ptr().add(i * row_len);
+                }
 
-                let rows_read = ffi::jpeg_read_scanlines(&mut self.dec.cinfo, rows as *mut *mut u8, 1) as usize;
-                debug_assert_eq!(start_line + rows_read, self.dec.cinfo.output_scanline as usize, "wat {}/{} at {}", rows_read, height, start_line);
+                let rows_read = ffi::jpeg_read_scanlines(&mut self.dec.cinfo, row_ptrs.as_mut_ptr() as *mut *mut u8, band as u32) as usize;
+                self.dec.expect_callbacks();
+                debug_assert_eq!(first_line + line + rows_read, self.dec.cinfo.output_scanline as usize, "wat {}/{} at {}", rows_read, height, line);
                 if 0 == rows_read {
-                    return None;
+                    image_dst.set_len(start + row_len * line);
+                    return false;
+                }
+                if self.histogram.is_some() || !self.row_callbacks.is_empty() {
+                    let row_bytes = row_len * mem::size_of::<T>();
+                    let rows = slice::from_raw_parts(rest.as_ptr() as *const u8, rows_read * row_bytes);
+                    for row in rows.chunks(row_bytes) {
+                        if let Some(ref mut histogram) = self.histogram {
+                            histogram.add(row);
+                        }
+                        for callback in &mut self.row_callbacks {
+                            callback(row);
+                        }
+                    }
                 }
             }
         }
-        return Some(image_dst);
+        true
     }
 
     pub fn components(&self) -> &[CompInfo] {
@@ -490,15 +1218,75 @@ impl<'src> DecompressStarted<'src> {
     }
 
     pub fn finish_decompress(mut self) -> bool {
-        unsafe {
+        let finished = unsafe {
             0 != ffi::jpeg_finish_decompress(&mut self.dec.cinfo)
-        }
+        };
+        self.dec.expect_callbacks();
+        finished
+    }
+}
+
+impl<'src> fmt::Debug for Decompress<'src> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decompress")
+            .field("width", &self.cinfo.image_width)
+            .field("height", &self.cinfo.image_height)
+            .field("color_space", &self.cinfo.jpeg_color_space)
+            .field("out_color_space", &self.cinfo.out_color_space)
+            .field("subsampling", &subsampling(self.components()))
+            .field("multiple_scans", &(0 != unsafe { ffi::jpeg_has_multiple_scans(&self.cinfo) }))
+            .finish()
     }
 }
 
+impl<'src> fmt::Debug for DecompressStarted<'src> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cinfo = &self.dec.cinfo;
+        f.debug_struct("DecompressStarted")
+            .field("width", &cinfo.image_width)
+            .field("height", &cinfo.image_height)
+            .field("output_width", &cinfo.output_width)
+            .field("output_height", &cinfo.output_height)
+            .field("color_space", &cinfo.jpeg_color_space)
+            .field("out_color_space", &cinfo.out_color_space)
+            .field("subsampling", &subsampling(self.components()))
+            .field("multiple_scans", &(0 != unsafe { ffi::jpeg_has_multiple_scans(cinfo) }))
+            .field("output_scanline", &cinfo.output_scanline)
+            .finish()
+    }
+}
+
+/// Horizontal and vertical sampling factors of each component
+pub(crate) fn subsampling(components: &[CompInfo]) -> Vec<(i32, i32)> {
+    components.iter().map(|c| (c.h_samp_factor, c.v_samp_factor)).collect()
+}
 
 impl<'src> Drop for Decompress<'src> {
     fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            unsafe {
+                ffi::jpeg_abort_decompress(&mut self.cinfo);
+                // The progress monitor of `Limits` is dropped with this struct
+                self.cinfo.common.progress = ptr::null_mut();
+                pool.put(Decompress {
+                    // Moved out, this decompressor returns below without destroying it
+                    cinfo: ptr::read(&self.cinfo),
+                    own_error: mem::replace(&mut self.own_error, Box::new(mem::zeroed())),
+                    #[cfg(not(target_arch = "wasm32"))]
+                    own_source: None,
+                    own_reader: None,
+                    own_data: None,
+                    #[cfg(feature = "bytes")]
+                    own_buf: None,
+                    _mem_marker: PhantomData,
+                    limits: Limits::default(),
+                    scan_limit: None,
+                    hide_app1: false,
+                    pool: None,
+                });
+            }
+            return;
+        }
         unsafe {
             ffi::jpeg_destroy_decompress(&mut self.cinfo);
         }
@@ -563,7 +1351,6 @@ fn read_file() {
 }
 
 #[test]
-#[cfg(unix)]
 fn no_markers() {
     use std::fs::File;
     use std::io::Read;
@@ -596,10 +1383,11 @@ fn read_file_rgb() {
     assert_eq!(ColorSpace::JCS_RGB, dinfo.color_space());
     assert_eq!(dinfo.components().len(), dinfo.color_space().num_components() as usize);
 
-    let bitmap:Vec<(u8,u8,u8)> = dinfo.read_scanlines().unwrap();
-    assert_eq!(bitmap.len(), 45*30);
+    let (bitmap, layout) = dinfo.read_scanlines_flat().unwrap();
+    assert_eq!(ImageLayout { width: 45, height: 30, components: 3 }, layout);
+    assert_eq!(bitmap.len(), 45*30*3);
 
-    assert!(!bitmap.contains(&(0,0,0)));
+    assert!(!bitmap.chunks_exact(3).any(|px| px == [0,0,0]));
 
     assert!(dinfo.finish_decompress());
 }
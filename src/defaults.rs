@@ -0,0 +1,64 @@
+use compress::Compress;
+use errormgr::{ErrorMgr, PanicingErrorMgr};
+use limits::Limits;
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+/// Settings inherited by every new `Compress` and `Decompress`, see `set_defaults()`
+///
+/// Explicit settings (e.g. `DecompressConfig::with_err()` or `Compress::set_limits()`) override them.
+#[derive(Clone, Copy, Default)]
+pub struct Defaults {
+    /// Creates the error manager for instances that aren't given one. `None` is the panicking one.
+    pub error_mgr: Option<fn() -> ErrorMgr>,
+    pub limits: Limits,
+    /// Whether `Compress::new()` enables progressive mode. `None` keeps mozjpeg's default (progressive).
+    pub progressive: Option<bool>,
+}
+
+static GLOBAL: Mutex<Option<Defaults>> = Mutex::new(None);
+
+thread_local! {
+    static THREAD: RefCell<Option<Defaults>> = const { RefCell::new(None) };
+}
+
+/// Install process-wide defaults for instances created from now on, in any thread
+pub fn set_defaults(defaults: Defaults) {
+    *GLOBAL.lock().unwrap_or_else(|e| e.into_inner()) = Some(defaults);
+}
+
+/// Run `f` with defaults that apply only to instances created in this thread, and only until `f` returns
+pub fn with_thread_defaults<T, F: FnOnce() -> T>(defaults: Defaults, f: F) -> T {
+    struct Restore(Option<Defaults>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            THREAD.with(|t| *t.borrow_mut() = previous);
+        }
+    }
+    let _restore = Restore(THREAD.with(|t| t.borrow_mut().replace(defaults)));
+    f()
+}
+
+/// Defaults in effect in this thread: from `with_thread_defaults()`, `set_defaults()`, or the built-in ones
+pub fn defaults() -> Defaults {
+    THREAD.with(|t| *t.borrow())
+        .or_else(|| *GLOBAL.lock().unwrap_or_else(|e| e.into_inner()))
+        .unwrap_or_default()
+}
+
+pub(crate) fn error_mgr(defaults: &Defaults) -> ErrorMgr {
+    match defaults.error_mgr {
+        Some(new) => new(),
+        None => <ErrorMgr as PanicingErrorMgr>::new(),
+    }
+}
+
+pub(crate) fn apply_to_compress(defaults: &Defaults, comp: &mut Compress) {
+    comp.set_limits(defaults.limits);
+    match defaults.progressive {
+        Some(true) => comp.set_progressive_mode(),
+        Some(false) => comp.set_sequential_mode(),
+        None => {},
+    }
+}
@@ -0,0 +1,296 @@
+use ffi;
+use ffi::jpeg_compress_struct;
+use ffi::boolean;
+use std::mem;
+#[cfg(feature = "memmap2")]
+use std::ptr;
+#[cfg(feature = "memmap2")]
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(feature = "memmap2")]
+use memmap2::MmapMut;
+
+/// Destination manager that collects output in a Rust buffer
+///
+/// When `can_suspend` is set and the buffer is full, it makes libjpeg suspend.
+/// Otherwise the buffer grows as needed.
+#[repr(C)]
+pub struct BufferDest {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_destination_mgr,
+    buffer: Vec<u8>,
+    buffer_size: usize,
+    pub can_suspend: bool,
+}
+
+impl BufferDest {
+    pub fn new(buffer_size: usize) -> Box<BufferDest> {
+        let mut dest = Box::new(BufferDest {
+            mgr: unsafe { mem::zeroed() },
+            buffer: vec![0; buffer_size.max(1)],
+            buffer_size: buffer_size.max(1),
+            can_suspend: false,
+        });
+        dest.mgr.init_destination = Some(init_destination);
+        dest.mgr.empty_output_buffer = Some(empty_output_buffer);
+        dest.mgr.term_destination = Some(term_destination);
+        dest.reset();
+        dest
+    }
+
+    pub fn as_mgr(&mut self) -> *mut ffi::jpeg_destination_mgr {
+        &mut self.mgr
+    }
+
+    /// Data written so far. It's removed from the buffer.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        let data = self.pending().to_vec();
+        self.consume(data.len());
+        data
+    }
+
+    /// Data written so far, without removing it from the buffer
+    pub fn pending(&self) -> &[u8] {
+        let used = self.buffer.len() - self.mgr.free_in_buffer;
+        &self.buffer[..used]
+    }
+
+    /// Remove the first `len` bytes of `pending()`, e.g. after they've been written out
+    pub fn consume(&mut self, len: usize) {
+        let used = self.buffer.len() - self.mgr.free_in_buffer;
+        assert!(len <= used);
+        if len == used {
+            // it may have grown when it couldn't suspend
+            self.buffer.truncate(self.buffer_size);
+            self.buffer.shrink_to_fit();
+            self.reset();
+            return;
+        }
+        self.buffer.copy_within(len..used, 0);
+        let left = used - len;
+        self.mgr.next_output_byte = unsafe { self.buffer.as_mut_ptr().add(left) };
+        self.mgr.free_in_buffer = self.buffer.len() - left;
+    }
+
+    fn reset(&mut self) {
+        self.mgr.next_output_byte = self.buffer.as_mut_ptr();
+        self.mgr.free_in_buffer = self.buffer.len();
+    }
+}
+
+extern "C" fn init_destination(cinfo: &mut jpeg_compress_struct) {
+    unsafe {
+        (*(cinfo.dest as *mut BufferDest)).reset();
+    }
+}
+
+extern "C" fn empty_output_buffer(cinfo: &mut jpeg_compress_struct) -> boolean {
+    unsafe {
+        let dest = &mut *(cinfo.dest as *mut BufferDest);
+        if dest.can_suspend {
+            return false as boolean;
+        }
+        // libjpeg calls it only when the buffer is full, and may not have updated free_in_buffer
+        let used = dest.buffer.len();
+        let new_len = used * 2;
+        dest.buffer.resize(new_len, 0);
+        dest.mgr.next_output_byte = dest.buffer.as_mut_ptr().add(used);
+        dest.mgr.free_in_buffer = dest.buffer.len() - used;
+        true as boolean
+    }
+}
+
+extern "C" fn term_destination(_cinfo: &mut jpeg_compress_struct) {
+}
+
+/// Destination manager that passes output to any `Write`, e.g. stdout, whenever its buffer fills up
+#[repr(C)]
+pub struct WriterDest {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_destination_mgr,
+    writer: Box<dyn Write>,
+    buffer: Vec<u8>,
+    // I/O error, which can't unwind through libjpeg. It's raised by `Compress` after libjpeg returns,
+    // and until then output is discarded.
+    error: Option<io::Error>,
+}
+
+impl WriterDest {
+    pub fn new(writer: Box<dyn Write>) -> Box<WriterDest> {
+        let mut dest = Box::new(WriterDest {
+            mgr: unsafe { mem::zeroed() },
+            writer,
+            buffer: vec![0; 64 * 1024],
+            error: None,
+        });
+        dest.mgr.init_destination = Some(init_writer_destination);
+        dest.mgr.empty_output_buffer = Some(empty_writer_output_buffer);
+        dest.mgr.term_destination = Some(term_writer_destination);
+        dest.reset();
+        dest
+    }
+
+    pub fn as_mgr(&mut self) -> *mut ffi::jpeg_destination_mgr {
+        &mut self.mgr
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn reset(&mut self) {
+        self.mgr.next_output_byte = self.buffer.as_mut_ptr();
+        self.mgr.free_in_buffer = self.buffer.len();
+    }
+}
+
+extern "C" fn init_writer_destination(cinfo: &mut jpeg_compress_struct) {
+    unsafe {
+        (*(cinfo.dest as *mut WriterDest)).reset();
+    }
+}
+
+extern "C" fn empty_writer_output_buffer(cinfo: &mut jpeg_compress_struct) -> boolean {
+    unsafe {
+        let dest = &mut *(cinfo.dest as *mut WriterDest);
+        // libjpeg calls it only when the buffer is full, and may not have updated free_in_buffer
+        if dest.error.is_none() {
+            dest.error = dest.writer.write_all(&dest.buffer).err();
+        }
+        dest.reset();
+        true as boolean
+    }
+}
+
+extern "C" fn term_writer_destination(cinfo: &mut jpeg_compress_struct) {
+    unsafe {
+        let dest = &mut *(cinfo.dest as *mut WriterDest);
+        let used = dest.buffer.len() - dest.mgr.free_in_buffer;
+        if dest.error.is_none() {
+            dest.error = dest.writer.write_all(&dest.buffer[..used]).and_then(|_| dest.writer.flush()).err();
+        }
+        dest.reset();
+    }
+}
+
+/// Destination manager that writes into a memory-mapped file
+///
+/// The file is grown when it's full, and truncated to the length of the data at the end.
+#[cfg(feature = "memmap2")]
+#[repr(C)]
+pub struct MmapDest {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_destination_mgr,
+    file: File,
+    map: Option<MmapMut>,
+    size_hint: usize,
+    // I/O error, which can't unwind through libjpeg. It's raised by `Compress` after libjpeg returns,
+    // and until then output goes to `discard`.
+    error: Option<io::Error>,
+    discard: Vec<u8>,
+}
+
+#[cfg(feature = "memmap2")]
+impl MmapDest {
+    pub fn new(file: &File, size_hint: usize) -> io::Result<Box<MmapDest>> {
+        let mut dest = Box::new(MmapDest {
+            mgr: unsafe { mem::zeroed() },
+            file: file.try_clone()?,
+            map: None,
+            size_hint: size_hint.max(4096),
+            error: None,
+            discard: Vec::new(),
+        });
+        dest.mgr.init_destination = Some(init_mmap_destination);
+        dest.mgr.empty_output_buffer = Some(empty_mmap_output_buffer);
+        dest.mgr.term_destination = Some(term_mmap_destination);
+        let size_hint = dest.size_hint;
+        dest.map_from(0, size_hint)?;
+        Ok(dest)
+    }
+
+    pub fn as_mgr(&mut self) -> *mut ffi::jpeg_destination_mgr {
+        &mut self.mgr
+    }
+
+    /// Resize the file to `len` bytes, and continue writing at `used`
+    fn map_from(&mut self, used: usize, len: usize) -> io::Result<()> {
+        // Unmapping keeps the data in the file
+        self.map = None;
+        self.file.set_len(len as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&self.file)? };
+        self.mgr.next_output_byte = unsafe { map.as_mut_ptr().add(used) };
+        self.mgr.free_in_buffer = len - used;
+        self.map = Some(map);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.len())
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Keep the first error, and let libjpeg write the rest of the image into a scratch buffer
+    fn fail(&mut self, err: io::Error) {
+        if self.error.is_none() {
+            self.error = Some(err);
+        }
+        self.discard.resize(4096, 0);
+        self.mgr.next_output_byte = self.discard.as_mut_ptr();
+        self.mgr.free_in_buffer = self.discard.len();
+    }
+}
+
+#[cfg(feature = "memmap2")]
+extern "C" fn init_mmap_destination(cinfo: &mut jpeg_compress_struct) {
+    unsafe {
+        let dest = &mut *(cinfo.dest as *mut MmapDest);
+        // The destination may be reused for another image, which overwrites the file
+        let len = if dest.map.is_some() { dest.len() } else { dest.size_hint };
+        if let Err(err) = dest.map_from(0, len) {
+            dest.fail(err);
+        }
+    }
+}
+
+#[cfg(feature = "memmap2")]
+extern "C" fn empty_mmap_output_buffer(cinfo: &mut jpeg_compress_struct) -> boolean {
+    unsafe {
+        let dest = &mut *(cinfo.dest as *mut MmapDest);
+        if dest.error.is_some() {
+            let len = dest.discard.len();
+            dest.mgr.next_output_byte = dest.discard.as_mut_ptr();
+            dest.mgr.free_in_buffer = len;
+            return true as boolean;
+        }
+        // libjpeg calls it only when the buffer is full, and may not have updated free_in_buffer
+        let used = dest.len();
+        if let Err(err) = dest.map_from(used, used * 2) {
+            dest.fail(err);
+        }
+        true as boolean
+    }
+}
+
+#[cfg(feature = "memmap2")]
+extern "C" fn term_mmap_destination(cinfo: &mut jpeg_compress_struct) {
+    unsafe {
+        let dest = &mut *(cinfo.dest as *mut MmapDest);
+        if dest.error.is_some() {
+            return;
+        }
+        let used = dest.len() - dest.mgr.free_in_buffer;
+        let res = match dest.map.take() {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }.and_then(|_| dest.file.set_len(used as u64));
+        dest.mgr.next_output_byte = ptr::null_mut();
+        dest.mgr.free_in_buffer = 0;
+        if let Err(err) = res {
+            dest.error = Some(err);
+        }
+    }
+}
@@ -3,7 +3,10 @@ extern crate mozjpeg_sys as ffi;
 
 pub use self::ffi::jpeg_error_mgr as ErrorMgr;
 use self::ffi::jpeg_common_struct;
+use ::std::any::Any;
+use ::std::io;
 use ::std::mem;
+use ::std::panic::{self, AssertUnwindSafe};
 
 pub trait PanicingErrorMgr {
     fn new() -> ErrorMgr {
@@ -32,3 +35,20 @@ pub trait PanicingErrorMgr {
 }
 
 impl PanicingErrorMgr for ErrorMgr {}
+
+/// Return panics of this crate (e.g. invalid settings, or I/O errors raised after libjpeg returns) as errors
+///
+/// Fatal libjpeg errors can't be caught here: `panic_error_exit()` is called by C code,
+/// and a panic can't unwind out of it, so the process aborts.
+pub(crate) fn catch_panics<T, F: FnOnce() -> io::Result<T>>(f: F) -> io::Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(panic_to_error(payload)))
+}
+
+pub(crate) fn panic_to_error(payload: Box<dyn Any + Send>) -> io::Error {
+    let msg = match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => payload.downcast_ref::<&str>().map(|s| s.to_string()).unwrap_or_else(|| "Unknown error".into()),
+    };
+    io::Error::new(io::ErrorKind::Other, msg)
+}
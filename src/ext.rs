@@ -0,0 +1,65 @@
+//! mozjpeg's extension parameters, which don't exist in libjpeg-turbo
+//!
+//! With the `libjpeg-turbo` feature these functions aren't linked. Setters do nothing, and getters
+//! return `None`, since the bundled mozjpeg may still be linked, and then its defaults are in effect.
+use ffi;
+use ffi::jpeg_compress_struct;
+use ffi::{boolean, J_BOOLEAN_PARAM, J_FLOAT_PARAM, J_INT_PARAM};
+use std::os::raw::c_int;
+use std::ptr;
+
+/// `false` when built with the `libjpeg-turbo` feature, where mozjpeg-only settings (trellis quantization,
+/// `set_tune()`, `set_optimize_scans()`, `CompressProfile::MaxCompression`, etc.) have no effect
+pub fn has_mozjpeg_extensions() -> bool {
+    cfg!(not(feature = "libjpeg-turbo"))
+}
+
+#[cfg(not(feature = "libjpeg-turbo"))]
+pub(crate) use ffi::{jpeg_c_set_bool_param, jpeg_c_set_int_param, jpeg_c_set_float_param, jpeg_float_quality_scaling};
+
+/// Value of a boolean parameter, or `None` if the library can't be asked
+#[cfg(not(feature = "libjpeg-turbo"))]
+pub(crate) unsafe fn get_bool_param(cinfo: &jpeg_compress_struct, param: J_BOOLEAN_PARAM) -> Option<bool> {
+    Some(0 != ffi::jpeg_c_get_bool_param(cinfo, param))
+}
+
+/// Value of an int parameter, or `None` if the library can't be asked
+#[cfg(not(feature = "libjpeg-turbo"))]
+pub(crate) unsafe fn get_int_param(cinfo: &jpeg_compress_struct, param: J_INT_PARAM) -> Option<c_int> {
+    Some(ffi::jpeg_c_get_int_param(cinfo, param))
+}
+
+/// libjpeg-turbo's defaults are sequential, while mozjpeg may leave a progressive script
+pub(crate) unsafe fn jpeg_set_defaults(cinfo: &mut jpeg_compress_struct) {
+    ffi::jpeg_set_defaults(cinfo);
+    if !has_mozjpeg_extensions() {
+        cinfo.scan_info = ptr::null();
+        cinfo.num_scans = 0;
+    }
+}
+
+#[cfg(feature = "libjpeg-turbo")]
+pub(crate) unsafe fn jpeg_c_set_bool_param(_: &mut jpeg_compress_struct, _: J_BOOLEAN_PARAM, _: boolean) {}
+
+#[cfg(feature = "libjpeg-turbo")]
+pub(crate) unsafe fn get_bool_param(_: &jpeg_compress_struct, _: J_BOOLEAN_PARAM) -> Option<bool> {
+    None
+}
+
+#[cfg(feature = "libjpeg-turbo")]
+pub(crate) unsafe fn jpeg_c_set_int_param(_: &mut jpeg_compress_struct, _: J_INT_PARAM, _: c_int) {}
+
+#[cfg(feature = "libjpeg-turbo")]
+pub(crate) unsafe fn get_int_param(_: &jpeg_compress_struct, _: J_INT_PARAM) -> Option<c_int> {
+    None
+}
+
+#[cfg(feature = "libjpeg-turbo")]
+pub(crate) unsafe fn jpeg_c_set_float_param(_: &mut jpeg_compress_struct, _: J_FLOAT_PARAM, _: f32) {}
+
+/// Same as libjpeg's `jpeg_quality_scaling()`, without rounding to an integer
+#[cfg(feature = "libjpeg-turbo")]
+pub(crate) unsafe fn jpeg_float_quality_scaling(quality: f32) -> f32 {
+    let quality = if quality <= 0. { 1. } else { quality.min(100.) };
+    if quality < 50. { 5000. / quality } else { 200. - quality * 2. }
+}
@@ -0,0 +1,51 @@
+use decompress::MarkerData;
+use marker::Marker;
+
+/// Kind of JPEG file, from its application markers. See `Decompress::flavor()`.
+///
+/// Files may have several of these markers, e.g. cameras often write both JFIF and EXIF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flavor {
+    /// JFIF `APP0` marker, which means the file is YCbCr or grayscale
+    pub jfif: bool,
+    /// EXIF `APP1` marker, with camera metadata and orientation
+    pub exif: bool,
+    /// Transform flag of Adobe's `APP14` marker: 0 for RGB or CMYK, 1 for YCbCr, 2 for YCCK.
+    /// Adobe applications write CMYK inverted (0 is full ink).
+    pub adobe_transform: Option<u8>,
+}
+
+impl Flavor {
+    /// Classify saved markers, e.g. from `Decompress::markers()` with `APP(0)`, `APP(1)` and `APP(14)` saved
+    pub fn from_markers<'a, I: IntoIterator<Item = MarkerData<'a>>>(markers: I) -> Flavor {
+        let mut flavor = Flavor::default();
+        for m in markers {
+            match m.marker {
+                Marker::APP(0) if m.data.starts_with(b"JFIF\0") => flavor.jfif = true,
+                Marker::APP(1) if m.data.starts_with(b"Exif\0") => flavor.exif = true,
+                // "Adobe", version, flags0, flags1, transform
+                Marker::APP(14) if m.data.starts_with(b"Adobe") && m.data.len() >= 12 => {
+                    flavor.adobe_transform = Some(m.data[11]);
+                },
+                _ => {},
+            }
+        }
+        flavor
+    }
+
+    /// None of these markers, e.g. a frame of an MJPEG stream
+    pub fn is_raw(&self) -> bool {
+        !self.jfif && !self.exif && self.adobe_transform.is_none()
+    }
+}
+
+#[test]
+fn from_markers() {
+    let adobe = b"Adobe\0\x64\x80\0\0\0\x02";
+    let flavor = Flavor::from_markers(vec![
+        MarkerData { marker: Marker::APP(0), data: b"JFIF\0\x01\x02" },
+        MarkerData { marker: Marker::APP(14), data: adobe },
+    ]);
+    assert_eq!(Flavor { jfif: true, exif: false, adobe_transform: Some(2) }, flavor);
+    assert!(Flavor::from_markers(vec![MarkerData { marker: Marker::APP(1), data: b"http://ns.adobe.com/xap/1.0/\0" }]).is_raw());
+}
@@ -0,0 +1,274 @@
+use compress::Compress;
+use colorspace::ColorSpace;
+use colorspace::ColorSpaceExt;
+use decompress::Decompress;
+use errormgr::catch_panics;
+use std::io;
+
+/// Encode a sequence of same-sized frames with shared tables, e.g. for MJPEG
+///
+/// Quality and tables are set once, and each frame is compressed in a single pass
+/// (baseline, no Huffman or trellis optimization), so that per-frame cost is minimal.
+///
+/// The first frame is a complete JPEG file. Subsequent frames are abbreviated datastreams
+/// that omit the tables, unless disabled with `set_abbreviated_frames(false)`.
+/// Decoders can get the tables from the first frame or from `tables()`.
+pub struct FrameEncoder {
+    compress: Compress,
+    frame_len: usize,
+    frames: usize,
+    abbreviated: bool,
+}
+
+impl FrameEncoder {
+    pub fn new(color_space: ColorSpace, width: usize, height: usize, quality: f32) -> FrameEncoder {
+        let mut compress = Compress::new(color_space);
+        compress.set_fastest_defaults();
+        compress.set_optimize_coding(false);
+        compress.set_size(width, height);
+        compress.set_quality(quality);
+        FrameEncoder {
+            compress,
+            frame_len: width * height * color_space.num_components(),
+            frames: 0,
+            abbreviated: true,
+        }
+    }
+
+    /// If `false`, every frame is a complete JPEG file with its own tables
+    pub fn set_abbreviated_frames(&mut self, abbreviated: bool) {
+        self.abbreviated = abbreviated;
+    }
+
+    /// Number of frames encoded so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Tables-only datastream for decoding abbreviated frames
+    pub fn tables(&mut self) -> io::Result<Vec<u8>> {
+        let compress = &mut self.compress;
+        catch_panics(|| {
+            compress.set_mem_dest();
+            compress.write_tables();
+            compress.data_to_vec().map_err(|_| io::Error::new(io::ErrorKind::Other, "No data"))
+        })
+    }
+
+    /// Encode tightly packed pixels of one frame
+    pub fn encode_frame(&mut self, pixels: &[u8]) -> io::Result<Vec<u8>> {
+        if pixels.len() != self.frame_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Expected {} bytes of a frame, got {}", self.frame_len, pixels.len())));
+        }
+        let abbreviated = self.frames > 0 && self.abbreviated;
+        let compress = &mut self.compress;
+        let frame = catch_panics(|| {
+            compress.encode_to_vec_with(!abbreviated, |started| started.write_scanlines(pixels))
+        })?;
+        self.frames += 1;
+        Ok(frame)
+    }
+}
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOS: u8 = 0xDA;
+const DQT: u8 = 0xDB;
+const DHT: u8 = 0xC4;
+
+/// Iterator over frames of a raw MJPEG stream (concatenated JPEG files, e.g. from a webcam or an AVI file)
+///
+/// Frames are found by their SOI and EOI markers, and anything between frames is skipped.
+/// Frames may omit quantization or Huffman tables, in which case the most recent tables
+/// from an earlier frame or a tables-only datastream (like `FrameEncoder::tables()`) are used.
+/// Frames without any Huffman tables, as usual in AVI files, are decoded with the standard tables.
+///
+/// A truncated frame at the end of the stream is an error.
+pub struct MjpegFrames<'a> {
+    data: &'a [u8],
+    pos: usize,
+    dqt: Vec<u8>,
+    dht: Vec<u8>,
+}
+
+impl<'a> MjpegFrames<'a> {
+    pub fn new(data: &'a [u8]) -> MjpegFrames<'a> {
+        MjpegFrames {
+            data,
+            pos: 0,
+            dqt: Vec::new(),
+            dht: Vec::new(),
+        }
+    }
+
+    /// Use tables from a tables-only datastream for frames that don't have their own
+    pub fn with_tables(mut self, tables: &[u8]) -> io::Result<Self> {
+        let mut frame = Frame::parse(tables, 0)?;
+        if frame.has_image {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a tables-only datastream"));
+        }
+        self.remember_tables(&mut frame);
+        Ok(self)
+    }
+
+    fn remember_tables(&mut self, frame: &mut Frame) {
+        if !frame.dqt.is_empty() {
+            self.dqt = std::mem::take(&mut frame.dqt);
+        }
+        if !frame.dht.is_empty() {
+            self.dht = std::mem::take(&mut frame.dht);
+        }
+    }
+}
+
+impl<'a> Iterator for MjpegFrames<'a> {
+    type Item = io::Result<Decompress<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.data[self.pos..].windows(2).position(|m| m == [0xFF, SOI])? + self.pos;
+            let mut frame = match Frame::parse(self.data, start) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    self.pos = self.data.len();
+                    return Some(Err(err));
+                },
+            };
+            self.pos = frame.end;
+            let missing_dqt = frame.dqt.is_empty();
+            let missing_dht = frame.dht.is_empty();
+            self.remember_tables(&mut frame);
+            if !frame.has_image {
+                continue;
+            }
+
+            let data = &self.data[start..frame.end];
+            let spliced_dqt = if missing_dqt { &self.dqt[..] } else { &[][..] };
+            let spliced_dht = if missing_dht { &self.dht[..] } else { &[][..] };
+            if spliced_dqt.is_empty() && spliced_dht.is_empty() {
+                return Some(Decompress::new_mem(data));
+            }
+            let mut complete = Vec::with_capacity(data.len() + spliced_dqt.len() + spliced_dht.len());
+            complete.extend_from_slice(&data[..2]);
+            complete.extend_from_slice(spliced_dqt);
+            complete.extend_from_slice(spliced_dht);
+            complete.extend_from_slice(&data[2..]);
+            return Some(Decompress::new_vec(complete));
+        }
+    }
+}
+
+/// Extent of one datastream, and copies of its table segments
+struct Frame {
+    end: usize,
+    has_image: bool,
+    dqt: Vec<u8>,
+    dht: Vec<u8>,
+}
+
+impl Frame {
+    /// `start` is the position of SOI
+    fn parse(data: &[u8], start: usize) -> io::Result<Frame> {
+        let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated MJPEG frame");
+        if data.get(start..start + 2) != Some(&[0xFF, SOI][..]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a JPEG datastream"));
+        }
+        let mut frame = Frame { end: 0, has_image: false, dqt: Vec::new(), dht: Vec::new() };
+        let mut pos = start + 2;
+        loop {
+            let marker = *data.get(pos + 1).ok_or_else(truncated)?;
+            if data[pos] != 0xFF || marker == 0xFF {
+                // Fill bytes
+                pos += 1;
+                continue;
+            }
+            if marker == EOI {
+                frame.end = pos + 2;
+                return Ok(frame);
+            }
+            let len = data.get(pos + 2..pos + 4).ok_or_else(truncated)?;
+            let end = pos + 2 + ((len[0] as usize) << 8 | len[1] as usize);
+            let segment = data.get(pos..end).ok_or_else(truncated)?;
+            match marker {
+                DQT => frame.dqt.extend_from_slice(segment),
+                DHT => frame.dht.extend_from_slice(segment),
+                SOS => frame.has_image = true,
+                _ => {},
+            }
+            pos = end;
+            if marker == SOS {
+                // Entropy-coded data ends at a marker other than a stuffed zero or a restart marker
+                loop {
+                    let next = data.get(pos..pos + 2).ok_or_else(truncated)?;
+                    if next[0] == 0xFF && next[1] != 0 && !(0xD0..=0xD7).contains(&next[1]) {
+                        break;
+                    }
+                    pos += 1;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn frames() {
+    use decompress::Decompress;
+
+    let mut enc = FrameEncoder::new(ColorSpace::JCS_RGB, 32, 24, 75.);
+    let tables = enc.tables().unwrap();
+    assert!(!tables.is_empty());
+
+    let frame = vec![100; 32*24*3];
+    let first = enc.encode_frame(&frame).unwrap();
+    let second = enc.encode_frame(&frame).unwrap();
+    assert_eq!(2, enc.frames());
+    assert!(second.len() < first.len());
+
+    let dinfo = Decompress::new_mem(&first).unwrap();
+    assert_eq!((32, 24), dinfo.size());
+
+    enc.set_abbreviated_frames(false);
+    assert_eq!(first, enc.encode_frame(&frame).unwrap());
+    assert_eq!(io::ErrorKind::InvalidInput, enc.encode_frame(&frame[1..]).unwrap_err().kind());
+}
+
+#[test]
+fn mjpeg_frames() {
+    let mut enc = FrameEncoder::new(ColorSpace::JCS_RGB, 16, 8, 75.);
+    let tables = enc.tables().unwrap();
+    let mut stream = b"garbage".to_vec();
+    for i in 0..3 {
+        stream.extend(enc.encode_frame(&[i * 50; 16*8*3]).unwrap());
+        stream.extend_from_slice(&[0, 0]);
+    }
+
+    let frames = MjpegFrames::new(&stream).collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!(3, frames.len());
+    for (i, dinfo) in frames.into_iter().enumerate() {
+        let mut dinfo = dinfo.rgb().unwrap();
+        let (pixels, _) = dinfo.read_scanlines_flat().unwrap();
+        assert!((pixels[0] as i32 - i as i32 * 50).abs() <= 2);
+    }
+
+    // Only abbreviated frames, with tables given separately
+    let abbreviated = &stream[stream.windows(2).rposition(|m| m == [0xFF, SOI]).unwrap()..];
+    let mut frames = MjpegFrames::new(abbreviated).with_tables(&tables).unwrap();
+    assert_eq!((16, 8), frames.next().unwrap().unwrap().size());
+    assert!(frames.next().is_none());
+
+    // AVI-style frame without Huffman tables. They must be the standard ones, but the feature
+    // can't turn off trellis quantization of the bundled mozjpeg, which optimizes them.
+    #[cfg(not(feature = "libjpeg-turbo"))]
+    {
+        enc.set_abbreviated_frames(false);
+        let first = enc.encode_frame(&[100; 16*8*3]).unwrap();
+        let dht = first.windows(2).position(|m| m == [0xFF, DHT]).unwrap();
+        let sos = first.windows(2).position(|m| m == [0xFF, SOS]).unwrap();
+        let avi = [&first[..dht], &first[sos..]].concat();
+        let mut dinfo = MjpegFrames::new(&avi).next().unwrap().unwrap().rgb().unwrap();
+        assert_eq!(100, dinfo.read_scanlines_flat().unwrap().0[0]);
+    }
+
+    assert!(MjpegFrames::new(&stream[..stream.len() - 10]).last().unwrap().is_err());
+}
@@ -0,0 +1,78 @@
+/// Counts of every value of every channel of decoded pixels, see `DecompressStarted::collect_histogram()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Number of pixels with each value, for each channel (e.g. R, G, B)
+    pub channels: Vec<[u32; 256]>,
+}
+
+impl Histogram {
+    pub(crate) fn new(num_channels: usize) -> Self {
+        Histogram {
+            channels: vec![[0; 256]; num_channels],
+        }
+    }
+
+    /// Add interleaved pixels
+    pub(crate) fn add(&mut self, pixels: &[u8]) {
+        match self.channels.len() {
+            // Common cases are unrolled
+            3 => {
+                let (c0, rest) = self.channels.split_at_mut(1);
+                let (c1, c2) = rest.split_at_mut(1);
+                for px in pixels.chunks_exact(3) {
+                    c0[0][px[0] as usize] += 1;
+                    c1[0][px[1] as usize] += 1;
+                    c2[0][px[2] as usize] += 1;
+                }
+            },
+            1 => for &v in pixels {
+                self.channels[0][v as usize] += 1;
+            },
+            n => for px in pixels.chunks_exact(n) {
+                for (counts, &v) in self.channels.iter_mut().zip(px) {
+                    counts[v as usize] += 1;
+                }
+            },
+        }
+    }
+
+    /// Number of pixels counted
+    pub fn pixels(&self) -> usize {
+        self.channels.first().map_or(0, |c| c.iter().map(|&n| n as usize).sum())
+    }
+
+    /// Lowest value of the channel, or `None` if no pixels were counted
+    pub fn min(&self, channel: usize) -> Option<u8> {
+        self.channels[channel].iter().position(|&n| n > 0).map(|v| v as u8)
+    }
+
+    /// Highest value of the channel, or `None` if no pixels were counted
+    pub fn max(&self, channel: usize) -> Option<u8> {
+        self.channels[channel].iter().rposition(|&n| n > 0).map(|v| v as u8)
+    }
+
+    /// Average value of the channel
+    pub fn mean(&self, channel: usize) -> f64 {
+        let counts = &self.channels[channel];
+        let total: u64 = counts.iter().map(|&n| n as u64).sum();
+        if total == 0 {
+            return 0.;
+        }
+        counts.iter().enumerate().map(|(v, &n)| v as u64 * n as u64).sum::<u64>() as f64 / total as f64
+    }
+}
+
+#[test]
+fn counts() {
+    let mut h = Histogram::new(3);
+    h.add(&[1, 2, 3, 1, 200, 255]);
+    assert_eq!(2, h.pixels());
+    assert_eq!(2, h.channels[0][1]);
+    assert_eq!((Some(2), Some(200)), (h.min(1), h.max(1)));
+    assert_eq!(129., h.mean(2));
+
+    let mut h = Histogram::new(4);
+    h.add(&[1, 2, 3, 4]);
+    assert_eq!(Some(4), h.max(3));
+    assert_eq!(None, Histogram::new(1).min(0));
+}
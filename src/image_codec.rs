@@ -0,0 +1,139 @@
+//! Integration with the `image` crate
+use errormgr::catch_panics;
+use colorspace::ColorSpace;
+use compress::Compress;
+use decompress::{Decompress, DecompressStarted};
+use marker::Marker;
+use image::{ColorType, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageFormat, ImageResult};
+use image::error::{DecodingError, EncodingError, ParameterError, ParameterErrorKind, UnsupportedError, UnsupportedErrorKind};
+use std::io::{self, Cursor, Write};
+
+/// JPEG decoder for the `image` crate, e.g. `DynamicImage::from_decoder(JpegDecoder::new(&data)?)`
+///
+/// Grayscale files are decoded as `L8`, all others as `Rgb8`. CMYK files are not supported.
+pub struct JpegDecoder<'src> {
+    dinfo: DecompressStarted<'src>,
+    color_type: ColorType,
+    icc_profile: Option<Vec<u8>>,
+}
+
+impl<'src> JpegDecoder<'src> {
+    /// Reads the header. Pixels are decoded by `read_image()`.
+    pub fn new(jpeg: &'src [u8]) -> ImageResult<JpegDecoder<'src>> {
+        let dinfo = catch_panics(|| Decompress::with_markers(&[Marker::APP(2)]).from_mem(jpeg)).map_err(decoding_error)?;
+        let icc_profile = icc_profile(&dinfo);
+        let color_type = match dinfo.color_space() {
+            ColorSpace::JCS_GRAYSCALE => ColorType::L8,
+            ColorSpace::JCS_CMYK | ColorSpace::JCS_YCCK => {
+                return Err(unsupported_color(ExtendedColorType::Cmyk8));
+            },
+            _ => ColorType::Rgb8,
+        };
+        let dinfo = catch_panics(move || if color_type == ColorType::L8 { dinfo.grayscale() } else { dinfo.rgb() }).map_err(decoding_error)?;
+        Ok(JpegDecoder { dinfo, color_type, icc_profile })
+    }
+
+    fn decode(mut self) -> ImageResult<Vec<u8>> {
+        catch_panics(move || {
+            let mut pixels = Vec::new();
+            if !self.dinfo.read_scanlines_into(&mut pixels) || !self.dinfo.finish_decompress() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete"));
+            }
+            Ok(pixels)
+        }).map_err(decoding_error)
+    }
+}
+
+impl<'a, 'src> ImageDecoder<'a> for JpegDecoder<'src> {
+    type Reader = Cursor<Vec<u8>>;
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.dinfo.width() as u32, self.dinfo.height() as u32)
+    }
+
+    fn color_type(&self) -> ColorType {
+        self.color_type
+    }
+
+    fn icc_profile(&mut self) -> Option<Vec<u8>> {
+        self.icc_profile.clone()
+    }
+
+    fn into_reader(self) -> ImageResult<Self::Reader> {
+        Ok(Cursor::new(self.decode()?))
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        assert_eq!(buf.len() as u64, self.total_bytes());
+        buf.copy_from_slice(&self.decode()?);
+        Ok(())
+    }
+}
+
+/// ICC profile split into APP2 markers, in order of their sequence numbers
+fn icc_profile(dinfo: &Decompress) -> Option<Vec<u8>> {
+    let mut chunks = dinfo.markers()
+        .filter(|m| m.marker == Marker::APP(2) && m.data.len() > 14 && m.data.starts_with(b"ICC_PROFILE\0"))
+        .map(|m| (m.data[12], &m.data[14..]))
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(seq, _)| seq);
+    Some(chunks.into_iter().flat_map(|(_, data)| data.iter().cloned()).collect())
+}
+
+/// JPEG encoder for the `image` crate, e.g. `img.write_with_encoder(JpegEncoder::new(file))`
+///
+/// Accepts `L8`, `Rgb8` and `Rgba8` pixels (alpha is ignored). Uses mozjpeg's default (maximum compression) settings.
+pub struct JpegEncoder<W> {
+    writer: W,
+    quality: f32,
+}
+
+impl<W: Write> JpegEncoder<W> {
+    /// Quality 75, like libjpeg's default
+    pub fn new(writer: W) -> JpegEncoder<W> {
+        JpegEncoder::new_with_quality(writer, 75.)
+    }
+
+    /// Quality in 1-100 range
+    pub fn new_with_quality(writer: W, quality: f32) -> JpegEncoder<W> {
+        JpegEncoder { writer, quality }
+    }
+}
+
+impl<W: Write> ImageEncoder for JpegEncoder<W> {
+    fn write_image(mut self, buf: &[u8], width: u32, height: u32, color_type: ColorType) -> ImageResult<()> {
+        let color_space = match color_type {
+            ColorType::L8 => ColorSpace::JCS_GRAYSCALE,
+            ColorType::Rgb8 => ColorSpace::JCS_RGB,
+            ColorType::Rgba8 => ColorSpace::JCS_EXT_RGBA,
+            other => return Err(unsupported_color(other.into())),
+        };
+        if buf.len() as u64 != width as u64 * height as u64 * color_type.bytes_per_pixel() as u64 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)));
+        }
+        let quality = self.quality;
+        let jpeg = catch_panics(|| {
+            let mut cinfo = Compress::new(color_space);
+            cinfo.set_size(width as usize, height as usize);
+            cinfo.set_quality(quality);
+            cinfo.encode_to_vec(buf)
+        }).map_err(|err| ImageError::Encoding(EncodingError::new(ImageFormat::Jpeg.into(), err)))?;
+        self.writer.write_all(&jpeg)?;
+        Ok(())
+    }
+}
+
+fn decoding_error(err: io::Error) -> ImageError {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof | io::ErrorKind::Other =>
+            ImageError::Decoding(DecodingError::new(ImageFormat::Jpeg.into(), err)),
+        _ => ImageError::IoError(err),
+    }
+}
+
+fn unsupported_color(color: ExtendedColorType) -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(ImageFormat::Jpeg.into(), UnsupportedErrorKind::Color(color)))
+}
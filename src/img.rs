@@ -0,0 +1,51 @@
+//! Integration with the `imgref` crate, which keeps width, height and stride together with pixels
+use compress::{Compress, CompressStarted};
+use decompress::Decompress;
+use pixel::Pixel;
+use imgref::{ImgRef, ImgVec};
+use std::io;
+use std::mem;
+use std::slice;
+
+impl<'src> Decompress<'src> {
+    /// Decode the whole image, converted to the color space of the pixel type, e.g. `to_img::<RGB8>()`
+    pub fn to_img<P: Pixel>(self) -> io::Result<ImgVec<P>> {
+        let mut dinfo = self.start_as::<P>()?;
+        let (width, height) = (dinfo.width(), dinfo.height());
+        let pixels = dinfo.read_scanlines_as::<P>()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete"))?;
+        if !dinfo.finish_decompress() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete"));
+        }
+        Ok(ImgVec::new(pixels, width, height))
+    }
+}
+
+impl Compress {
+    /// Compress the image into a new file, using its size and the current settings
+    ///
+    /// Pixel type must match the input color space, e.g. `RGB8` for `Compress::new(JCS_RGB)`.
+    pub fn compress_img<P: Pixel>(&mut self, img: ImgRef<'_, P>) -> io::Result<Vec<u8>> {
+        self.set_size(img.width(), img.height());
+        self.encode_to_vec_with(true, |started| started.write_img(img))
+    }
+}
+
+impl<'a> CompressStarted<'a> {
+    /// Like `write_scanlines_as()`, but the image's stride is respected. Its size must match the size being compressed.
+    pub fn write_img<P: Pixel>(&mut self, img: ImgRef<'_, P>) -> bool {
+        self.assert_pixel_type::<P>();
+        let (width, height, stride) = (img.width(), img.height(), img.stride());
+        assert_eq!(self.image_width(), width, "Image width doesn't match");
+        if height == 0 {
+            return true;
+        }
+        // The buffer may extend beyond the image if it's a sub-image
+        let pixels = &img.buf()[..stride * (height - 1) + width];
+        let pixel_size = mem::size_of::<P>();
+        let bytes = unsafe {
+            slice::from_raw_parts(pixels.as_ptr() as *const u8, mem::size_of_val(pixels))
+        };
+        self.write_scanlines_with_stride(bytes, stride * pixel_size)
+    }
+}
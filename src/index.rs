@@ -0,0 +1,120 @@
+use std::io;
+use std::ops::Range;
+
+/// Location of one scan in a JPEG file, see `scan_index()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scan {
+    /// Bytes from the SOS marker to the end of the scan's entropy-coded data
+    pub range: Range<usize>,
+    /// Entropy-coded data of each restart interval
+    ///
+    /// The first interval starts right after the SOS header, and each following one starts at its RST marker.
+    /// Without restart markers there's one interval for the whole scan.
+    pub intervals: Vec<Range<usize>>,
+}
+
+/// Find byte offsets of scans and restart intervals in a JPEG file, without decoding it
+///
+/// Baseline files have one scan, progressive files have several, so a prefix of the file
+/// up to the end of any scan can be served to get a lower-quality version of the image.
+/// If the file is truncated, the last scan ends at the end of the data.
+pub fn scan_index(jpeg: &[u8]) -> io::Result<Vec<Scan>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a JPEG file"));
+    }
+    let mut scans = Vec::new();
+    let mut pos = 2;
+    while pos + 1 < jpeg.len() {
+        if jpeg[pos] != 0xFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected a marker at {}", pos)));
+        }
+        let marker = jpeg[pos + 1];
+        match marker {
+            // Fill byte
+            0xFF => { pos += 1; continue; },
+            // EOI
+            0xD9 => break,
+            // TEM, RST and SOI have no length
+            0x01 | 0xD0 ..= 0xD8 => { pos += 2; continue; },
+            _ => {},
+        }
+        if pos + 3 >= jpeg.len() {
+            break;
+        }
+        let segment_end = pos + 2 + ((jpeg[pos + 2] as usize) << 8 | jpeg[pos + 3] as usize);
+        if marker != 0xDA {
+            pos = segment_end;
+            continue;
+        }
+
+        // SOS header is followed by entropy-coded data, which ends at the first marker other than RST
+        let start = pos;
+        let mut interval_start = segment_end;
+        let mut intervals = Vec::new();
+        pos = segment_end;
+        while pos < jpeg.len() {
+            if jpeg[pos] != 0xFF || pos + 1 >= jpeg.len() {
+                pos += 1;
+                continue;
+            }
+            match jpeg[pos + 1] {
+                // Stuffed zero and fill bytes are part of the data
+                0x00 | 0xFF => pos += 1,
+                0xD0 ..= 0xD7 => {
+                    intervals.push(interval_start..pos);
+                    interval_start = pos;
+                    pos += 2;
+                },
+                _ => break,
+            }
+        }
+        let end = pos.min(jpeg.len());
+        intervals.push(interval_start..end);
+        scans.push(Scan {
+            range: start..end,
+            intervals,
+        });
+    }
+    Ok(scans)
+}
+
+/// Find a marker segment in the file's header (before the first scan)
+#[cfg(feature = "rayon")]
+pub(crate) fn find_header_segment(jpeg: &[u8], wanted: u8) -> Option<Range<usize>> {
+    let mut pos = 2;
+    while pos + 3 < jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        match marker {
+            0xFF => { pos += 1; continue; },
+            0x01 | 0xD0 ..= 0xD8 => { pos += 2; continue; },
+            0xD9 | 0xDA if marker != wanted => return None,
+            _ => {},
+        }
+        let segment_end = pos + 2 + ((jpeg[pos + 2] as usize) << 8 | jpeg[pos + 3] as usize);
+        if marker == wanted {
+            return if segment_end <= jpeg.len() { Some(pos..segment_end) } else { None };
+        }
+        pos = segment_end;
+    }
+    None
+}
+
+#[test]
+fn restart_intervals() {
+    let jpeg = [
+        0xFF, 0xD8,
+        0xFF, 0xE0, 0, 4, 1, 2,
+        0xFF, 0xDA, 0, 4, 1, 2,
+        0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xD1, 0x78,
+        0xFF, 0xD9,
+    ];
+    let scans = scan_index(&jpeg).unwrap();
+    assert_eq!(vec![Scan {
+        range: 8..24,
+        intervals: vec![14..18, 18..21, 21..24],
+    }], scans);
+
+    let truncated = scan_index(&jpeg[..20]).unwrap();
+    assert_eq!(vec![14..18, 18..20], truncated[0].intervals);
+    assert!(scan_index(b"GIF89a").is_err());
+}
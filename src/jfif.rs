@@ -0,0 +1,118 @@
+use errormgr::catch_panics;
+use compress::{Compress, ConfigError};
+use colorspace::ColorSpace;
+use ffi::boolean;
+use std::io;
+
+/// JFXX extension codes
+const JPEG_THUMBNAIL: u8 = 0x10;
+const RGB_THUMBNAIL: u8 = 0x13;
+
+/// "JFXX\0" and the extension code
+const HEADER_LEN: usize = 6;
+/// Payload of a marker segment, after its 2 length bytes
+const MAX_SEGMENT_LEN: usize = 0xFFFF - 2;
+
+/// Thumbnail in a JFIF extension (`JFXX`) `APP0` segment, see `Compress::set_jfif_thumbnail()`
+///
+/// Most software reads EXIF thumbnails instead. These are for legacy JFIF readers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JfifThumbnail {
+    /// Uncompressed RGB pixels, at most 255×255 and about 21K pixels in total
+    Rgb { width: u8, height: u8, pixels: Vec<u8> },
+    /// Baseline JPEG datastream, up to 64KB, without its own JFIF header
+    Jpeg(Vec<u8>),
+}
+
+impl JfifThumbnail {
+    /// Compress RGB pixels into a `Jpeg` thumbnail
+    pub fn encode_rgb(pixels: &[u8], width: usize, height: usize, quality: f32) -> io::Result<JfifThumbnail> {
+        if pixels.len() != width * height * 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected {}x{}x3 bytes, got {}",
+                width, height, pixels.len())));
+        }
+        catch_panics(|| {
+            let mut comp = Compress::new(ColorSpace::JCS_RGB);
+            comp.set_fastest_defaults();
+            comp.set_sequential_mode();
+            comp.set_force_baseline(true);
+            comp.set_size(width, height);
+            comp.set_quality(quality);
+            // The thumbnail is inside a JFIF file already
+            comp.cinfo.write_JFIF_header = false as boolean;
+            let jpeg = comp.encode_to_vec(pixels)?;
+            let thumbnail = JfifThumbnail::Jpeg(jpeg);
+            thumbnail.validate().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            Ok(thumbnail)
+        })
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let (len, reason) = match *self {
+            JfifThumbnail::Rgb { width, height, ref pixels } => {
+                if pixels.len() != width as usize * height as usize * 3 {
+                    return Err(ConfigError::InvalidThumbnail { reason: "RGB pixels don't match the size" });
+                }
+                (pixels.len() + 2, "RGB thumbnail doesn't fit in a marker")
+            },
+            JfifThumbnail::Jpeg(ref jpeg) => {
+                if !jpeg.starts_with(&[0xFF, 0xD8]) || !jpeg.ends_with(&[0xFF, 0xD9]) {
+                    return Err(ConfigError::InvalidThumbnail { reason: "not a JPEG datastream" });
+                }
+                (jpeg.len(), "JPEG thumbnail doesn't fit in a marker")
+            },
+        };
+        if HEADER_LEN + len > MAX_SEGMENT_LEN {
+            return Err(ConfigError::InvalidThumbnail { reason });
+        }
+        Ok(())
+    }
+
+    /// Length of `segment()`
+    pub(crate) fn segment_len(&self) -> usize {
+        HEADER_LEN + match *self {
+            JfifThumbnail::Rgb { ref pixels, .. } => pixels.len() + 2,
+            JfifThumbnail::Jpeg(ref jpeg) => jpeg.len(),
+        }
+    }
+
+    /// Data of the `APP0` marker
+    pub(crate) fn segment(&self) -> Vec<u8> {
+        let mut data = b"JFXX\0".to_vec();
+        match *self {
+            JfifThumbnail::Rgb { width, height, ref pixels } => {
+                data.extend_from_slice(&[RGB_THUMBNAIL, width, height]);
+                data.extend_from_slice(pixels);
+            },
+            JfifThumbnail::Jpeg(ref jpeg) => {
+                data.push(JPEG_THUMBNAIL);
+                data.extend_from_slice(jpeg);
+            },
+        }
+        data
+    }
+}
+
+#[test]
+fn segment() {
+    let rgb = JfifThumbnail::Rgb { width: 2, height: 1, pixels: vec![1, 2, 3, 4, 5, 6] };
+    assert_eq!(Ok(()), rgb.validate());
+    assert_eq!(b"JFXX\0\x13\x02\x01\x01\x02\x03\x04\x05\x06", &rgb.segment()[..]);
+
+    let short = JfifThumbnail::Rgb { width: 2, height: 2, pixels: vec![0; 6] };
+    assert!(short.validate().is_err());
+    let large = JfifThumbnail::Rgb { width: 255, height: 255, pixels: vec![0; 255 * 255 * 3] };
+    assert!(large.validate().is_err());
+    assert!(JfifThumbnail::Jpeg(b"GIF89a".to_vec()).validate().is_err());
+
+    assert_eq!(rgb.segment().len(), rgb.segment_len());
+    assert!(JfifThumbnail::encode_rgb(&[200; 8 * 8 * 3], 8, 7, 80.).is_err());
+    assert!(JfifThumbnail::encode_rgb(&[], 0, 0, 80.).is_err());
+
+    let jpeg = JfifThumbnail::encode_rgb(&[200; 8 * 8 * 3], 8, 8, 80.).unwrap();
+    assert_eq!(Ok(()), jpeg.validate());
+    assert_eq!(jpeg.segment().len(), jpeg.segment_len());
+    if let JfifThumbnail::Jpeg(ref data) = jpeg {
+        assert!(!data.windows(4).any(|w| w == b"JFIF"));
+    }
+}
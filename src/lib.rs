@@ -1,19 +1,73 @@
 #![allow(unused_attributes)]
 #![allow(unused_imports)]
 
+#[cfg(not(target_arch = "wasm32"))]
 extern crate libc;
 extern crate arrayvec;
 extern crate mozjpeg_sys as ffi;
-
-pub use compress::Compress;
-pub use compress::ScanMode;
+#[cfg(feature = "rgb")]
+extern crate rgb;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "memmap2")]
+extern crate memmap2;
+#[cfg(feature = "image")]
+extern crate image;
+#[cfg(feature = "imgref")]
+extern crate imgref;
+#[cfg(feature = "ndarray")]
+extern crate ndarray;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "futures")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+pub use compress::{Compress, CompressStarted, ConfigError};
+pub use compress::{ScanMode, ScanLayout};
+pub use compress::{Progress, Cancelled};
+pub use compress::CompressProfile;
+pub use compress::Tune;
+pub use settings::{CompressSettings, DecompressSettings, Preset};
+pub use frame::{FrameEncoder, MjpegFrames};
+pub use optimize::{optimize, OptimizeOptions};
+pub use index::{scan_index, Scan};
+pub use scan_script::{ScanScript, ScanInfo};
+pub use limits::Limits;
+pub use flavor::Flavor;
+pub use jfif::JfifThumbnail;
+pub use ext::has_mozjpeg_extensions;
+pub use defaults::{Defaults, set_defaults, with_thread_defaults, defaults};
+pub use thumbnail::{thumbnail, smart_thumbnail, Thumbnail, ThumbnailSource};
+pub use oneshot::{decode_rgb, encode_rgb, encode_gray};
+#[cfg(not(target_arch = "wasm32"))]
+pub use oneshot::decode_path_rgb;
+pub use tiles::{decode_tiles, decode_region, Tile};
+pub use pyramid::{pyramid, Level};
+pub use histogram::Histogram;
+pub use bands::{RowBand, RowBands};
+pub use reader::PixelReader;
+pub use pool::{BufferPool, PooledBuffer, Pool};
+pub use arena::Arena;
+#[cfg(feature = "async")]
+pub use async_write::FlushTo;
+#[cfg(feature = "image")]
+pub use image_codec::{JpegDecoder, JpegEncoder};
+#[cfg(feature = "rayon")]
+pub use parallel::{decode_parallel, encode_parallel};
 pub use decompress::{Decompress, NO_MARKERS, ALL_MARKERS};
-pub use decompress::{Format, DctMethod};
+pub use decompress::{Format, DctMethod, CropAlignment, ImageLayout};
 pub use component::CompInfo;
 pub use component::CompInfoExt;
 pub use colorspace::ColorSpace;
 pub use colorspace::ColorSpaceExt;
+pub use colorspace::{parse_color_space, ParseColorSpaceError};
 pub use marker::Marker;
+pub use pixel::Pixel;
 pub use ffi::DCTSIZE;
 pub use ffi::JPEG_LIB_VERSION;
 use ffi::J_INT_PARAM;
@@ -24,7 +78,13 @@ use ffi::jpeg_common_struct;
 use ffi::boolean;
 
 use std::os::raw::{c_void, c_int, c_ulong, c_uchar};
+#[cfg(not(target_arch = "wasm32"))]
 use libc::free;
+// There's no `libc` crate for wasm32, but libjpeg is linked with a C library that allocates `jpeg_mem_dest()` buffers
+#[cfg(target_arch = "wasm32")]
+extern "C" {
+    fn free(ptr: *mut c_void);
+}
 use std::slice;
 use std::mem;
 use std::ptr;
@@ -36,9 +96,53 @@ mod vec;
 /// Quantization table presets from MozJPEG
 pub mod qtable;
 pub mod decompress;
+pub mod planar;
+pub mod pixels;
+pub mod dct;
+pub mod prelude;
 mod compress;
+mod settings;
+mod dest;
+#[cfg(feature = "async")]
+mod async_write;
+mod source;
+mod frame;
+mod optimize;
+mod index;
+mod scan_script;
+mod limits;
+mod defaults;
+mod flavor;
+mod jfif;
+mod ext;
+mod thumbnail;
+mod oneshot;
+mod tiles;
+mod pyramid;
+mod histogram;
+mod bands;
+mod reader;
+mod pool;
+mod arena;
+#[cfg(feature = "image")]
+mod image_codec;
+#[cfg(feature = "rayon")]
+mod parallel;
+/// Lossless transformations, like `jpegtran`
+pub mod transform;
+/// Processing many files on a pool of threads
+pub mod batch;
+#[cfg(feature = "capi")]
+pub mod capi;
 mod component;
 mod colorspace;
+mod pixel;
+#[cfg(feature = "imgref")]
+mod img;
+#[cfg(feature = "ndarray")]
+mod array;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 
 #[test]
 fn recompress() {
@@ -87,11 +191,11 @@ fn recompress() {
             c.h_samp_factor = *samp;
         }
 
-        cinfo.start_compress();
+        let mut started = cinfo.start_compress();
 
-        assert!(cinfo.write_raw_data(&bitmaps.iter().map(|c|&c[..]).collect::<Vec<_>>()));
+        assert!(started.write_raw_data(&bitmaps.iter().map(|c|&c[..]).collect::<Vec<_>>()));
 
-        cinfo.finish_compress();
+        started.finish_compress();
 
         return cinfo.data_to_vec().unwrap();
     }
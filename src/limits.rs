@@ -0,0 +1,66 @@
+use compress::ConfigError;
+use component::CompInfo;
+use ffi::{DCTSIZE, JBLOCK};
+use std::mem;
+
+/// Resource limits for untrusted images, shared by `Compress::set_limits()` and `DecompressConfig::with_limits()`
+///
+/// `None` means no limit (the default).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Limits {
+    pub max_width: Option<usize>,
+    pub max_height: Option<usize>,
+    /// Width × height
+    pub max_pixels: Option<usize>,
+    /// Memory for whole-image coefficient buffers (of progressive files, or when compressing with
+    /// optimizations). Checked before libjpeg allocates them.
+    pub max_memory: Option<usize>,
+    /// Scans of a progressive file. When compressing, it's checked only for scripts from `set_scan_script()`.
+    pub max_scans: Option<usize>,
+    /// Total size of saved (when decompressing) or written (when compressing) markers
+    pub max_marker_bytes: Option<usize>,
+}
+
+impl Limits {
+    pub(crate) fn check_size(&self, width: usize, height: usize) -> Result<(), ConfigError> {
+        check("max_width", width, self.max_width)?;
+        check("max_height", height, self.max_height)?;
+        check("max_pixels", width.saturating_mul(height), self.max_pixels)
+    }
+
+    pub(crate) fn check_scans(&self, scans: usize) -> Result<(), ConfigError> {
+        check("max_scans", scans, self.max_scans)
+    }
+
+    pub(crate) fn check_marker_bytes(&self, bytes: usize) -> Result<(), ConfigError> {
+        check("max_marker_bytes", bytes, self.max_marker_bytes)
+    }
+
+    /// libjpeg's own `max_memory_to_use` isn't used, because exceeding it is a fatal libjpeg error
+    pub(crate) fn check_memory(&self, bytes: usize) -> Result<(), ConfigError> {
+        check("max_memory", bytes, self.max_memory)
+    }
+}
+
+/// Size of the coefficient buffers for the whole image, padded to whole iMCUs like libjpeg's
+pub(crate) fn coefficient_buffer_size(width: usize, height: usize, components: &[CompInfo]) -> usize {
+    let max_h = components.iter().map(|c| c.h_samp_factor.max(1) as usize).max().unwrap_or(1);
+    let max_v = components.iter().map(|c| c.v_samp_factor.max(1) as usize).max().unwrap_or(1);
+    let blocks = |size: usize, samp: usize, max_samp: usize| {
+        let blocks = (size * samp + max_samp * DCTSIZE - 1) / (max_samp * DCTSIZE);
+        (blocks + samp - 1) / samp * samp
+    };
+    components.iter().map(|c| {
+        let (h, v) = (c.h_samp_factor.max(1) as usize, c.v_samp_factor.max(1) as usize);
+        blocks(width, h, max_h).saturating_mul(blocks(height, v, max_v)).saturating_mul(mem::size_of::<JBLOCK>())
+    }).fold(0, usize::saturating_add)
+}
+
+fn check(limit: &'static str, value: usize, max: Option<usize>) -> Result<(), ConfigError> {
+    match max {
+        Some(max) if value > max => Err(ConfigError::LimitExceeded { limit, value, max }),
+        _ => Ok(()),
+    }
+}
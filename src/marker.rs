@@ -1,12 +1,12 @@
-extern crate libc;
 extern crate mozjpeg_sys as ffi;
 
-use self::libc::c_int;
+use std::os::raw::c_int;
 
 /// Marker number identifier (APP0-APP14 and comment markers)
 ///
 /// For actual contents of markers, see `MarkerData`
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Marker {
     COM,
     APP(u8),
@@ -0,0 +1,143 @@
+//! Image quality metrics, for quality-regression tests and for picking a quality, as `Compress::perceptual_quality()` does
+//!
+//! Images are compared as decoded 8-bit pixels with interleaved channels. Both images must have the same size.
+use oneshot::decode_rgb;
+use std::io;
+
+const WINDOW: usize = 8;
+const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+/// Mean SSIM of two images with the same size and interleaved channels,
+/// computed over 8x8 windows of each channel separately
+///
+/// 1.0 means the images are identical.
+pub fn ssim(a: &[u8], b: &[u8], width: usize, height: usize, channels: usize) -> f64 {
+    assert_eq!(width * height * channels, a.len());
+    assert_eq!(a.len(), b.len());
+
+    let mut sum = 0.;
+    let mut windows = 0;
+    for channel in 0..channels {
+        for y in (0..height).step_by(WINDOW) {
+            for x in (0..width).step_by(WINDOW) {
+                let pixels = (y..(y + WINDOW).min(height)).flat_map(|y| {
+                    (x..(x + WINDOW).min(width)).map(move |x| (y * width + x) * channels + channel)
+                });
+                sum += window_ssim(pixels.map(|i| (a[i] as f64, b[i] as f64)));
+                windows += 1;
+            }
+        }
+    }
+    if windows > 0 { sum / windows as f64 } else { 1. }
+}
+
+/// SSIM expressed as a difference, `1/SSIM - 1`. 0 means identical.
+///
+/// SSIM of 0 or less (unrelated or inverted images) is infinitely different.
+pub fn dssim(ssim: f64) -> f64 {
+    if ssim <= 0. {
+        return f64::INFINITY;
+    }
+    1. / ssim - 1.
+}
+
+/// Peak signal-to-noise ratio in dB of two images of the same length, over all channels
+///
+/// Higher is better. Identical images give infinity.
+pub fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len());
+    if a.is_empty() {
+        return f64::INFINITY;
+    }
+    let sum_sq: f64 = a.iter().zip(b).map(|(&a, &b)| {
+        let diff = a as f64 - b as f64;
+        diff * diff
+    }).sum();
+    let mse = sum_sq / a.len() as f64;
+    10. * (255. * 255. / mse).log10()
+}
+
+/// Result of `compare()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    /// See `psnr()`
+    pub psnr: f64,
+    /// See `ssim()`
+    pub ssim: f64,
+}
+
+impl Comparison {
+    /// See `dssim()`, as used by `Compress::perceptual_quality()`
+    pub fn dssim(&self) -> f64 {
+        dssim(self.ssim)
+    }
+}
+
+/// PSNR and SSIM of two images with the same size and interleaved channels
+pub fn compare(a: &[u8], b: &[u8], width: usize, height: usize, channels: usize) -> Comparison {
+    Comparison {
+        psnr: psnr(a, b),
+        ssim: ssim(a, b, width, height, channels),
+    }
+}
+
+/// Decode two JPEG files (e.g. an original and its re-encoded version) to RGB and compare them
+///
+/// Fails if either file can't be decoded, or if their sizes differ.
+pub fn compare_jpegs(original: &[u8], other: &[u8]) -> io::Result<Comparison> {
+    let (a, width, height) = decode_rgb(original)?;
+    let (b, other_width, other_height) = decode_rgb(other)?;
+    if (width, height) != (other_width, other_height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Images have different sizes"));
+    }
+    Ok(compare(&a, &b, width, height, 3))
+}
+
+fn window_ssim<I: Iterator<Item = (f64, f64)> + Clone>(pixels: I) -> f64 {
+    let (mut sum_a, mut sum_b, mut n) = (0., 0., 0.);
+    for (a, b) in pixels.clone() {
+        sum_a += a;
+        sum_b += b;
+        n += 1.;
+    }
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+
+    let (mut var_a, mut var_b, mut covar) = (0., 0., 0.);
+    for (a, b) in pixels {
+        var_a += (a - mean_a) * (a - mean_a);
+        var_b += (b - mean_b) * (b - mean_b);
+        covar += (a - mean_a) * (b - mean_b);
+    }
+    let (var_a, var_b, covar) = (var_a / n, var_b / n, covar / n);
+
+    ((2. * mean_a * mean_b + C1) * (2. * covar + C2)) /
+    ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+#[test]
+fn ssim_test() {
+    let a = (0..30*20*3).map(|i| (i * 31 % 256) as u8).collect::<Vec<_>>();
+    assert_eq!(1., ssim(&a, &a, 30, 20, 3));
+
+    let b = a.iter().map(|&v| v / 2).collect::<Vec<_>>();
+    let s = ssim(&a, &b, 30, 20, 3);
+    assert!(s > 0. && s < 0.9, "{}", s);
+}
+
+#[test]
+fn psnr_test() {
+    let a = [10u8, 20, 30, 40];
+    assert_eq!(f64::INFINITY, psnr(&a, &a));
+    let b = [11u8, 19, 31, 39];
+    assert!((psnr(&a, &b) - 48.13).abs() < 0.01);
+    assert!(compare(&a, &b, 2, 2, 1).dssim() > 0.);
+}
+
+#[test]
+fn dssim_test() {
+    assert_eq!(0., dssim(1.));
+    assert_eq!(1., dssim(0.5));
+    assert_eq!(f64::INFINITY, dssim(0.));
+    assert_eq!(f64::INFINITY, dssim(-0.2));
+}
@@ -0,0 +1,58 @@
+use errormgr::catch_panics;
+use colorspace::{ColorSpace, ColorSpaceExt};
+use compress::Compress;
+use decompress::Decompress;
+use std::io;
+use std::path::Path;
+
+/// Decode the whole image as RGB pixels, returning pixels, width and height
+///
+/// Pixels are tightly packed, 3 bytes per pixel. Data that isn't JPEG, unsupported files and I/O errors
+/// are reported as errors. Fatal libjpeg errors (e.g. of corrupt JPEG data) abort the process, see README.
+/// Use `Decompress` for more control (scaling, markers, other color spaces, etc.).
+pub fn decode_rgb(jpeg: &[u8]) -> io::Result<(Vec<u8>, usize, usize)> {
+    catch_panics(|| read_rgb(Decompress::new_mem(jpeg)?))
+}
+
+/// Like `decode_rgb()`, but reads the file at `path`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn decode_path_rgb<P: AsRef<Path>>(path: P) -> io::Result<(Vec<u8>, usize, usize)> {
+    catch_panics(|| read_rgb(Decompress::new_path(path)?))
+}
+
+fn read_rgb(dinfo: Decompress) -> io::Result<(Vec<u8>, usize, usize)> {
+    let mut started = dinfo.rgb()?;
+    let (width, height) = (started.width(), started.height());
+    let pixels = started.read_scanlines_bytes()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete"))?;
+    if !started.finish_decompress() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete"));
+    }
+    Ok((pixels, width, height))
+}
+
+/// Compress tightly packed RGB pixels (3 bytes per pixel) with quality in 1-100 range
+///
+/// Uses mozjpeg's defaults: progressive, with optimized Huffman tables and trellis quantization.
+/// Use `Compress` for more control (chroma subsampling, markers, etc.).
+pub fn encode_rgb(pixels: &[u8], width: usize, height: usize, quality: f32) -> io::Result<Vec<u8>> {
+    encode(ColorSpace::JCS_RGB, pixels, width, height, quality)
+}
+
+/// Like `encode_rgb()`, but for grayscale pixels (1 byte per pixel)
+pub fn encode_gray(pixels: &[u8], width: usize, height: usize, quality: f32) -> io::Result<Vec<u8>> {
+    encode(ColorSpace::JCS_GRAYSCALE, pixels, width, height, quality)
+}
+
+fn encode(color_space: ColorSpace, pixels: &[u8], width: usize, height: usize, quality: f32) -> io::Result<Vec<u8>> {
+    if pixels.len() != width * height * color_space.num_components() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected {}x{}x{} bytes, got {}",
+            width, height, color_space.num_components(), pixels.len())));
+    }
+    catch_panics(|| {
+        let mut cinfo = Compress::new(color_space);
+        cinfo.set_size(width, height);
+        cinfo.set_quality(quality);
+        cinfo.encode_to_vec(pixels)
+    })
+}
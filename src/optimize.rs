@@ -0,0 +1,114 @@
+use compress::Compress;
+use decompress::Decompress;
+use colorspace::ColorSpace;
+use marker::Marker;
+use transform::{CopyMarkers, Transform, TransformConfig};
+use std::io;
+
+/// Settings for `optimize()`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct OptimizeOptions {
+    /// Remove EXIF, XMP, comments, etc. The ICC profile is kept, because without it colors could look different.
+    pub strip_metadata: bool,
+    /// Write a progressive file, which is usually smaller
+    pub progressive: bool,
+    /// Recompress at this quality (1-100). `None` keeps the original quality, and the file is optimized losslessly.
+    pub max_quality: Option<f32>,
+    /// Lower the quality until the file fits in this many bytes
+    pub target_size: Option<usize>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        OptimizeOptions {
+            strip_metadata: false,
+            progressive: true,
+            max_quality: None,
+            target_size: None,
+        }
+    }
+}
+
+/// Make a JPEG file smaller, e.g. for serving it on the web
+///
+/// First the file is optimized losslessly (see `transform::optimize_mem()`). If that's not enough
+/// to satisfy `max_quality` or `target_size`, the image is decoded and compressed again.
+/// Recompression never uses finer quantization tables than the original had (see `Compress::set_qtables_from()`),
+/// and if it doesn't make the file smaller, the losslessly optimized file is returned.
+///
+/// Returns an error if the file can't fit in `target_size` even at quality 1.
+pub fn optimize(jpeg: &[u8], options: &OptimizeOptions) -> io::Result<Vec<u8>> {
+    let copy_markers = if options.strip_metadata {
+        CopyMarkers::Only(vec![Marker::APP(2)])
+    } else {
+        CopyMarkers::All
+    };
+    let lossless = TransformConfig::new(Transform::None)
+        .with_progressive(options.progressive)
+        .with_copy_markers(copy_markers.clone())
+        .transform_mem(jpeg)?;
+    let fits = |data: &[u8]| options.target_size.map_or(true, |max_bytes| data.len() <= max_bytes);
+    if options.max_quality.is_none() && fits(&lossless) {
+        return Ok(lossless);
+    }
+
+    let header = Decompress::with_markers(copy_markers.markers()).from_mem(jpeg)?;
+    let color_space = match header.color_space() {
+        ColorSpace::JCS_GRAYSCALE => ColorSpace::JCS_GRAYSCALE,
+        ColorSpace::JCS_CMYK | ColorSpace::JCS_YCCK => ColorSpace::JCS_CMYK,
+        _ => ColorSpace::JCS_RGB,
+    };
+    let (width, height) = header.size();
+    let pixels = {
+        let mut started = Decompress::new_mem(jpeg)?.start_as_color_space(color_space)?;
+        let pixels = started.read_scanlines_bytes()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"))?;
+        started.finish_decompress();
+        pixels
+    };
+
+    let mut comp = Compress::new(color_space);
+    if color_space == ColorSpace::JCS_CMYK {
+        comp.set_color_space(header.color_space());
+    }
+    comp.set_size(width, height);
+    if !options.progressive {
+        comp.set_optimize_scans(false);
+    }
+    let mut encode = |quality: f32| -> Vec<u8> {
+        comp.set_quality(quality);
+        comp.set_qtables_from(&header);
+        comp.set_mem_dest();
+        {
+            let mut started = comp.start_compress();
+            started.copy_markers(header.markers());
+            assert!(started.write_scanlines(&pixels));
+            started.finish_compress();
+        }
+        comp.data_to_vec().unwrap()
+    };
+
+    let max_quality = options.max_quality.unwrap_or(100.).clamp(1., 100.);
+    let lossy = match options.target_size {
+        None => encode(max_quality),
+        Some(max_bytes) => {
+            let mut low = 1;
+            let mut high = max_quality as i32;
+            let mut best = None;
+            while low <= high {
+                let quality = (low + high) / 2;
+                let data = encode(quality as f32);
+                if data.len() <= max_bytes {
+                    best = Some(data);
+                    low = quality + 1;
+                } else {
+                    high = quality - 1;
+                }
+            }
+            best.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Image doesn't fit in the target size"))?
+        },
+    };
+    Ok(if lossless.len() <= lossy.len() && fits(&lossless) { lossless } else { lossy })
+}
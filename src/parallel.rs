@@ -0,0 +1,194 @@
+use compress::Compress;
+use decompress::Decompress;
+use colorspace::ColorSpace;
+use colorspace::ColorSpaceExt;
+use index::{scan_index, find_header_segment};
+use transform::Blocks;
+use ffi::DCTSIZE;
+use rayon;
+use rayon::prelude::*;
+use std::cmp::{min, max};
+use std::io;
+
+/// MCU rows in each band of `encode_parallel()` when `Compress::set_deterministic()` is on
+const DETERMINISTIC_BAND_ROWS: usize = 8;
+
+/// Decode the whole image using multiple threads. Requires the `rayon` feature.
+///
+/// Baseline files with restart markers at starts of MCU rows (see `Compress::set_restart_in_rows()`)
+/// are split into bands of rows, which are decoded independently. Other files are decoded on one thread.
+/// The pixels are the same as from sequential decoding.
+///
+/// Returns width, height and tightly packed pixels in `color_space` (e.g. `JCS_RGB`).
+pub fn decode_parallel(jpeg: &[u8], color_space: ColorSpace) -> io::Result<(usize, usize, Vec<u8>)> {
+    let dinfo = Decompress::new_mem(jpeg)?;
+    let (width, height) = dinfo.size();
+    let bands = split_bands(jpeg, &dinfo);
+
+    let decode = |jpeg: &[u8]| -> io::Result<Vec<u8>> {
+        let mut started = Decompress::new_mem(jpeg)?.start_as_color_space(color_space)?;
+        let pixels = started.read_scanlines_bytes()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"))?;
+        started.finish_decompress();
+        Ok(pixels)
+    };
+    let pixels = match bands {
+        Some(bands) => {
+            let row_len = width * color_space.num_components();
+            bands.par_iter().map(|band| {
+                let pixels = decode(&band.jpeg)?;
+                Ok(pixels[band.skip_rows * row_len .. (band.skip_rows + band.rows) * row_len].to_vec())
+            }).collect::<io::Result<Vec<_>>>()?.concat()
+        },
+        None => decode(jpeg)?,
+    };
+    Ok((width, height, pixels))
+}
+
+/// Standalone JPEG file with a band of MCU rows
+struct Band {
+    jpeg: Vec<u8>,
+    /// Rows at the top decoded only for chroma upsampling
+    skip_rows: usize,
+    /// Rows of the band, after the skipped ones
+    rows: usize,
+}
+
+/// Make a standalone JPEG file for each band of MCU rows, or `None` if the file can't be split
+fn split_bands(jpeg: &[u8], dinfo: &Decompress) -> Option<Vec<Band>> {
+    let sof = find_header_segment(jpeg, 0xC0).or_else(|| find_header_segment(jpeg, 0xC1))?;
+    let dri = find_header_segment(jpeg, 0xDD)?;
+    let sos = find_header_segment(jpeg, 0xDA)?;
+    let restart_interval = (jpeg[dri.start + 4] as usize) << 8 | jpeg[dri.start + 5] as usize;
+    let components = dinfo.components();
+    // Only a single scan with all components interleaved has restart intervals covering whole MCU rows
+    let scans = scan_index(jpeg).ok()?;
+    if restart_interval == 0 || scans.len() != 1 || jpeg[sos.start + 4] as usize != components.len() {
+        return None;
+    }
+    let intervals = &scans[0].intervals;
+
+    let max_v = components.iter().map(|c| c.v_samp_factor as usize).fold(1, max);
+    let (mcu_width, mcu_height) = if components.len() == 1 {
+        (DCTSIZE, DCTSIZE)
+    } else {
+        let max_h = components.iter().map(|c| c.h_samp_factor as usize).fold(1, max);
+        (max_h * DCTSIZE, max_v * DCTSIZE)
+    };
+    let (width, height) = dinfo.size();
+    let mcus_per_row = (width + mcu_width - 1) / mcu_width;
+    let mcu_rows = (height + mcu_height - 1) / mcu_height;
+    if (mcus_per_row * mcu_rows + restart_interval - 1) / restart_interval != intervals.len() {
+        return None;
+    }
+
+    let row_starts = (0..intervals.len()).filter(|&i| i * restart_interval % mcus_per_row == 0).collect::<Vec<_>>();
+    let num_bands = min(row_starts.len(), rayon::current_num_threads());
+    if num_bands < 2 {
+        return None;
+    }
+    // Vertical chroma upsampling blends neighboring rows, so bands of subsampled images are decoded
+    // with one more row of restart intervals above and below, and these rows are dropped
+    let context = if components.len() > 1 && components.iter().any(|c| (c.v_samp_factor as usize) < max_v) { 1 } else { 0 };
+    // Index of the first restart interval, and the first pixel row, of the n-th row start
+    let interval_at = |n: usize| row_starts.get(n).cloned().unwrap_or(intervals.len());
+    let pixel_row_at = |n: usize| min(height, interval_at(n) * restart_interval / mcus_per_row * mcu_height);
+
+    Some((0..num_bands).map(|b| {
+        let (band_start, band_end) = (b * row_starts.len() / num_bands, (b + 1) * row_starts.len() / num_bands);
+        let (start, end) = (band_start.saturating_sub(context), min(row_starts.len(), band_end + context));
+        let (first, end_interval) = (interval_at(start), interval_at(end));
+        let band_height = pixel_row_at(end) - pixel_row_at(start);
+
+        // Same headers, except the height
+        let mut band = Vec::with_capacity(sos.end + intervals[end_interval - 1].end - intervals[first].start + 2);
+        band.extend_from_slice(&jpeg[..sof.start + 5]);
+        band.extend_from_slice(&[(band_height >> 8) as u8, band_height as u8]);
+        band.extend_from_slice(&jpeg[sof.start + 7..sos.end]);
+        for (n, interval) in (first..end_interval).zip(&intervals[first..end_interval]) {
+            // Restart markers must be numbered from the start of the band
+            let data_start = if n > 0 { interval.start + 2 } else { interval.start };
+            if n > first {
+                band.extend_from_slice(&[0xFF, 0xD0 + ((n - first - 1) % 8) as u8]);
+            }
+            band.extend_from_slice(&jpeg[data_start..interval.end]);
+        }
+        band.extend_from_slice(&[0xFF, 0xD9]);
+        Band {
+            jpeg: band,
+            skip_rows: pixel_row_at(band_start) - pixel_row_at(start),
+            rows: pixel_row_at(band_end) - pixel_row_at(band_start),
+        }
+    }).collect())
+}
+
+/// Compress the image using multiple threads. Requires the `rayon` feature.
+///
+/// The image is split into bands of MCU rows, which are compressed independently (including color conversion,
+/// DCT and trellis quantization, which take most of the time). Their DCT coefficients are then joined and
+/// written as one file, so Huffman optimization and progressive mode work as usual.
+///
+/// Bands aren't simply concatenated with restart markers between them: that would need a baseline file
+/// with one set of Huffman tables chosen before any band is compressed, so Huffman optimization
+/// and progressive scans (which are most of mozjpeg's gains over libjpeg) couldn't be used.
+///
+/// Files depend on the number of threads, unless `configure` turns on `Compress::set_deterministic()`.
+///
+/// `configure` is called for every band's `Compress` and the final one, to set quality and other options.
+/// It must set the same options every time. `image_src` contains tightly packed pixels in `color_space`.
+///
+/// Returns an error if the size of `image_src` doesn't match, or if the settings are invalid.
+pub fn encode_parallel<F>(color_space: ColorSpace, width: usize, height: usize, image_src: &[u8], configure: F) -> io::Result<Vec<u8>>
+    where F: Fn(&mut Compress) + Sync
+{
+    let row_len = width * color_space.num_components();
+    if row_len * height != image_src.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected {}x{}x{} bytes, got {}",
+            width, height, color_space.num_components(), image_src.len())));
+    }
+
+    let mut comp = Compress::new(color_space);
+    configure(&mut comp);
+    comp.set_size(width, height);
+    let sampling = comp.components().iter().map(|c| (c.h_samp_factor as usize, c.v_samp_factor as usize)).collect::<Vec<_>>();
+    let max_h = sampling.iter().map(|s| s.0).fold(1, max);
+    let max_v = sampling.iter().map(|s| s.1).fold(1, max);
+    let mcu_rows = (height + max_v * DCTSIZE - 1) / (max_v * DCTSIZE);
+    let band_rows = if comp.deterministic() {
+        DETERMINISTIC_BAND_ROWS
+    } else {
+        (mcu_rows + rayon::current_num_threads() - 1) / rayon::current_num_threads()
+    };
+    let band_height = band_rows * max_v * DCTSIZE;
+
+    let bands = image_src.par_chunks(band_height * row_len).map(|band_src| {
+        let mut band = Compress::new(color_space);
+        configure(&mut band);
+        band.set_size(width, band_src.len() / row_len);
+        // Only coefficients are used, so scans don't matter
+        band.set_optimize_scans(false);
+        let jpeg = band.encode_to_vec(band_src)?;
+        let mut dinfo = Decompress::new_mem(&jpeg)?;
+        dinfo.read_coefficients()
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    // Bands are whole MCU rows, so their blocks can be stacked
+    let mut blocks = sampling.iter().map(|&(h, v)| {
+        let width_in_blocks = (width * h + max_h * DCTSIZE - 1) / (max_h * DCTSIZE);
+        Blocks::new((width_in_blocks + h - 1) / h * h, mcu_rows * v)
+    }).collect::<Vec<_>>();
+    for (b, band) in bands.iter().enumerate() {
+        for ((dst, src), &(_, v)) in blocks.iter_mut().zip(band).zip(&sampling) {
+            let y_offset = b * band_rows * v;
+            for y in 0..min(src.height, dst.height - y_offset) {
+                let dst_row = (y + y_offset) * dst.width;
+                dst.blocks[dst_row .. dst_row + src.width].copy_from_slice(&src.blocks[y * src.width .. (y + 1) * src.width]);
+            }
+        }
+    }
+
+    comp.set_mem_dest();
+    comp.validate().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    comp.start_compress_coefficients(&blocks).finish_compress();
+    comp.data_to_vec().map_err(|_| io::Error::new(io::ErrorKind::Other, "No data"))
+}
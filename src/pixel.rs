@@ -0,0 +1,49 @@
+use colorspace::ColorSpace;
+
+/// Pixel types that can be read and written directly, see `Decompress::start_as()`
+///
+/// Implemented for `u8` (grayscale) and, with the `rgb` feature, for `RGB8`, `RGBA8`, `BGR8`, `BGRA8` and `GRAY8`.
+///
+/// # Safety
+///
+/// Pixels are read as raw bytes: the type must be `#[repr(C)]`,
+/// and have exactly one `u8` per component of `COLOR_SPACE`.
+pub unsafe trait Pixel: Copy {
+    /// libjpeg's color space with the same layout
+    const COLOR_SPACE: ColorSpace;
+}
+
+unsafe impl Pixel for u8 {
+    const COLOR_SPACE: ColorSpace = ColorSpace::JCS_GRAYSCALE;
+}
+
+#[cfg(feature = "rgb")]
+unsafe impl Pixel for ::rgb::RGB8 {
+    const COLOR_SPACE: ColorSpace = ColorSpace::JCS_RGB;
+}
+
+#[cfg(feature = "rgb")]
+unsafe impl Pixel for ::rgb::RGBA8 {
+    const COLOR_SPACE: ColorSpace = ColorSpace::JCS_EXT_RGBA;
+}
+
+#[cfg(feature = "rgb")]
+unsafe impl Pixel for ::rgb::alt::BGR8 {
+    const COLOR_SPACE: ColorSpace = ColorSpace::JCS_EXT_BGR;
+}
+
+#[cfg(feature = "rgb")]
+unsafe impl Pixel for ::rgb::alt::BGRA8 {
+    const COLOR_SPACE: ColorSpace = ColorSpace::JCS_EXT_BGRA;
+}
+
+#[cfg(feature = "rgb")]
+unsafe impl Pixel for ::rgb::alt::Gray<u8> {
+    const COLOR_SPACE: ColorSpace = ColorSpace::JCS_GRAYSCALE;
+}
+
+/// Whether pixels in these color spaces have the same bytes (`JCS_RGB` is `JCS_EXT_RGB` in libjpeg-turbo)
+pub(crate) fn same_layout(a: ColorSpace, b: ColorSpace) -> bool {
+    let normalize = |cs| if cs == ColorSpace::JCS_EXT_RGB { ColorSpace::JCS_RGB } else { cs };
+    normalize(a) == normalize(b)
+}
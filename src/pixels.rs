@@ -0,0 +1,105 @@
+//! Conversions between tightly packed pixel formats, for use around `Decompress` and `Compress`
+//!
+//! All buffers are 8 bits per channel with no padding between rows. Functions panic if a length
+//! isn't a whole number of pixels.
+use planar;
+
+/// Swap the first and third channel of every pixel, converting RGB to BGR and back, or RGBA to BGRA (with `channels` = 4)
+pub fn swap_rb(pixels: &mut [u8], channels: usize) {
+    assert!(channels >= 3, "Needs at least 3 channels");
+    assert_eq!(0, pixels.len() % channels, "Not a whole number of pixels");
+    for px in pixels.chunks_exact_mut(channels) {
+        px.swap(0, 2);
+    }
+}
+
+/// Copy of RGB pixels in BGR order (or BGR in RGB order)
+pub fn rgb_to_bgr(rgb: &[u8]) -> Vec<u8> {
+    let mut bgr = rgb.to_vec();
+    swap_rb(&mut bgr, 3);
+    bgr
+}
+
+/// Append an opaque alpha channel to every pixel, e.g. RGB to RGBA
+pub fn add_alpha(rgb: &[u8]) -> Vec<u8> {
+    assert_eq!(0, rgb.len() % 3, "Not a whole number of pixels");
+    let mut rgba = vec![255; rgb.len() / 3 * 4];
+    for (out, px) in rgba.chunks_exact_mut(4).zip(rgb.chunks_exact(3)) {
+        out[0] = px[0];
+        out[1] = px[1];
+        out[2] = px[2];
+    }
+    rgba
+}
+
+/// Remove the alpha channel, e.g. RGBA to RGB. JPEG has no transparency, so alpha is ignored (not blended).
+pub fn strip_alpha(rgba: &[u8]) -> Vec<u8> {
+    assert_eq!(0, rgba.len() % 4, "Not a whole number of pixels");
+    let mut rgb = vec![0; rgba.len() / 4 * 3];
+    for (out, px) in rgb.chunks_exact_mut(3).zip(rgba.chunks_exact(4)) {
+        out[0] = px[0];
+        out[1] = px[1];
+        out[2] = px[2];
+    }
+    rgb
+}
+
+/// Grayscale to RGB with all channels equal
+pub fn gray_to_rgb(gray: &[u8]) -> Vec<u8> {
+    let mut rgb = vec![0; gray.len() * 3];
+    for (out, &g) in rgb.chunks_exact_mut(3).zip(gray) {
+        out[0] = g;
+        out[1] = g;
+        out[2] = g;
+    }
+    rgb
+}
+
+/// Split interleaved pixels into one plane per channel
+pub fn packed_to_planar(pixels: &[u8], channels: usize) -> Vec<Vec<u8>> {
+    assert!(channels > 0);
+    assert_eq!(0, pixels.len() % channels, "Not a whole number of pixels");
+    let mut planes = vec![vec![0; pixels.len() / channels]; channels];
+    {
+        let mut planes = planes.iter_mut().map(|p| &mut p[..]).collect::<Vec<_>>();
+        planar::deinterleave_planes(pixels, &mut planes);
+    }
+    planes
+}
+
+/// Interleave planes of equal length into packed pixels
+pub fn planar_to_packed(planes: &[&[u8]]) -> Vec<u8> {
+    assert!(!planes.is_empty());
+    let mut pixels = vec![0; planes[0].len() * planes.len()];
+    planar::interleave_planes(planes, &mut pixels);
+    pixels
+}
+
+#[test]
+fn swizzles() {
+    let rgb = [1, 2, 3, 4, 5, 6];
+    assert_eq!(vec![3, 2, 1, 6, 5, 4], rgb_to_bgr(&rgb));
+    let rgba = add_alpha(&rgb);
+    assert_eq!(vec![1, 2, 3, 255, 4, 5, 6, 255], rgba);
+    assert_eq!(&rgb[..], &strip_alpha(&rgba)[..]);
+    let mut bgra = rgba.clone();
+    swap_rb(&mut bgra, 4);
+    assert_eq!(vec![3, 2, 1, 255, 6, 5, 4, 255], bgra);
+    assert_eq!(vec![7, 7, 7, 9, 9, 9], gray_to_rgb(&[7, 9]));
+}
+
+#[test]
+fn planes() {
+    let rgb = (0..30).collect::<Vec<u8>>();
+    let planes = packed_to_planar(&rgb, 3);
+    assert_eq!(&[0, 3, 6], &planes[0][..3]);
+    assert_eq!(&[2, 5, 8], &planes[2][..3]);
+    let planes = planes.iter().map(|p| &p[..]).collect::<Vec<_>>();
+    assert_eq!(rgb, planar_to_packed(&planes));
+
+    let planes = packed_to_planar(&rgb, 2);
+    assert_eq!(&[0, 2, 4], &planes[0][..3]);
+    assert_eq!(&[1, 3, 5], &planes[1][..3]);
+    let planes = planes.iter().map(|p| &p[..]).collect::<Vec<_>>();
+    assert_eq!(rgb, planar_to_packed(&planes));
+}
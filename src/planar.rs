@@ -0,0 +1,241 @@
+//! Conversions between planar and packed YUV layouts
+//!
+//! `I420` is three tightly packed planes: Y, then U (Cb) and V (Cr) at half width and height (rounded up).
+//! `NV12` is the Y plane followed by one plane of interleaved U and V samples.
+//! `YUV` is packed 4:4:4, three bytes per pixel.
+//!
+//! Loops are written over fixed-size chunks, so that the compiler vectorizes them.
+use component::CompInfo;
+use component::CompInfoExt;
+use std::cmp::max;
+
+/// Size of a plane of 4:2:0 chroma for an image of this size
+#[inline]
+pub fn chroma_size(width: usize, height: usize) -> (usize, usize) {
+    ((width + 1) / 2, (height + 1) / 2)
+}
+
+/// Interleave two planes of chroma into one, as in NV12. `uv` must be twice as long as `u` and `v`.
+pub fn interleave_uv(u: &[u8], v: &[u8], uv: &mut [u8]) {
+    assert_eq!(u.len(), v.len());
+    assert_eq!(u.len() * 2, uv.len());
+    const CHUNK: usize = 16;
+    let whole = u.len() / CHUNK * CHUNK;
+    for ((uv, u), v) in uv[..whole * 2].chunks_exact_mut(CHUNK * 2).zip(u.chunks_exact(CHUNK)).zip(v.chunks_exact(CHUNK)) {
+        for i in 0..CHUNK {
+            uv[2 * i] = u[i];
+            uv[2 * i + 1] = v[i];
+        }
+    }
+    for ((uv, &u), &v) in uv[whole * 2..].chunks_exact_mut(2).zip(&u[whole..]).zip(&v[whole..]) {
+        uv[0] = u;
+        uv[1] = v;
+    }
+}
+
+/// Split interleaved chroma, as in NV12, into two planes. `uv` must be twice as long as `u` and `v`.
+pub fn deinterleave_uv(uv: &[u8], u: &mut [u8], v: &mut [u8]) {
+    assert_eq!(u.len(), v.len());
+    assert_eq!(u.len() * 2, uv.len());
+    const CHUNK: usize = 16;
+    let whole = u.len() / CHUNK * CHUNK;
+    for ((uv, u), v) in uv[..whole * 2].chunks_exact(CHUNK * 2).zip(u.chunks_exact_mut(CHUNK)).zip(v.chunks_exact_mut(CHUNK)) {
+        for i in 0..CHUNK {
+            u[i] = uv[2 * i];
+            v[i] = uv[2 * i + 1];
+        }
+    }
+    for ((uv, u), v) in uv[whole * 2..].chunks_exact(2).zip(&mut u[whole..]).zip(&mut v[whole..]) {
+        *u = uv[0];
+        *v = uv[1];
+    }
+}
+
+/// Interleave planes of equal length into `packed`, which must be as long as all of them together
+pub fn interleave_planes(planes: &[&[u8]], packed: &mut [u8]) {
+    let channels = planes.len();
+    assert!(channels > 0);
+    let len = planes[0].len();
+    assert!(planes.iter().all(|p| p.len() == len), "Planes must have the same length");
+    assert_eq!(len * channels, packed.len());
+    if channels == 2 {
+        return interleave_uv(planes[0], planes[1], packed);
+    }
+    for (c, plane) in planes.iter().enumerate() {
+        for (px, &v) in packed.chunks_exact_mut(channels).zip(plane.iter()) {
+            px[c] = v;
+        }
+    }
+}
+
+/// Split `packed` into planes of equal length, one per channel
+pub fn deinterleave_planes(packed: &[u8], planes: &mut [&mut [u8]]) {
+    let channels = planes.len();
+    assert!(channels > 0);
+    let len = planes[0].len();
+    assert!(planes.iter().all(|p| p.len() == len), "Planes must have the same length");
+    assert_eq!(len * channels, packed.len());
+    if channels == 2 {
+        let (u, v) = planes.split_at_mut(1);
+        return deinterleave_uv(packed, u[0], v[0]);
+    }
+    for (c, plane) in planes.iter_mut().enumerate() {
+        for (v, px) in plane.iter_mut().zip(packed.chunks_exact(channels)) {
+            *v = px[c];
+        }
+    }
+}
+
+/// Copy `width`×`height` pixels between planes with different row lengths (e.g. padded ones from `read_raw_data()`)
+pub fn copy_plane(src: &[u8], src_stride: usize, dst: &mut [u8], dst_stride: usize, width: usize, height: usize) {
+    for (dst, src) in dst.chunks_mut(dst_stride).zip(src.chunks(src_stride)).take(height) {
+        dst[..width].copy_from_slice(&src[..width]);
+    }
+}
+
+pub fn i420_to_nv12(i420: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let (y, u, v) = split_i420(i420, width, height);
+    let mut nv12 = vec![0; i420.len()];
+    nv12[..y.len()].copy_from_slice(y);
+    interleave_uv(u, v, &mut nv12[y.len()..]);
+    nv12
+}
+
+pub fn nv12_to_i420(nv12: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let (cw, ch) = chroma_size(width, height);
+    assert_eq!(width * height + 2 * cw * ch, nv12.len(), "NV12 size doesn't match");
+    let mut i420 = vec![0; nv12.len()];
+    let y_len = width * height;
+    i420[..y_len].copy_from_slice(&nv12[..y_len]);
+    let (u, v) = i420[y_len..].split_at_mut(cw * ch);
+    deinterleave_uv(&nv12[y_len..], u, v);
+    i420
+}
+
+/// Packed YUV with chroma duplicated to every pixel
+pub fn i420_to_yuv(i420: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let (y, u, v) = split_i420(i420, width, height);
+    let cw = chroma_size(width, height).0;
+    let mut yuv = vec![0; width * height * 3];
+    for (row, (out, y)) in yuv.chunks_exact_mut(width * 3).zip(y.chunks_exact(width)).enumerate() {
+        let u = &u[row / 2 * cw..][..cw];
+        let v = &v[row / 2 * cw..][..cw];
+        for (x, (out, &y)) in out.chunks_exact_mut(3).zip(y).enumerate() {
+            out[0] = y;
+            out[1] = u[x / 2];
+            out[2] = v[x / 2];
+        }
+    }
+    yuv
+}
+
+/// Packed YUV to I420, averaging chroma of each 2×2 block of pixels
+pub fn yuv_to_i420(yuv: &[u8], width: usize, height: usize) -> Vec<u8> {
+    assert_eq!(width * height * 3, yuv.len(), "YUV size doesn't match");
+    let (cw, ch) = chroma_size(width, height);
+    let y_len = width * height;
+    let mut i420 = vec![0; y_len + 2 * cw * ch];
+    for (y, px) in i420[..y_len].iter_mut().zip(yuv.chunks_exact(3)) {
+        *y = px[0];
+    }
+    let (u, v) = i420[y_len..].split_at_mut(cw * ch);
+    for cy in 0..ch {
+        let rows = [cy * 2, (cy * 2 + 1).min(height - 1)];
+        for cx in 0..cw {
+            let cols = [cx * 2, (cx * 2 + 1).min(width - 1)];
+            let (mut sum_u, mut sum_v) = (2u32, 2u32);
+            for &row in &rows {
+                for &col in &cols {
+                    let px = &yuv[(row * width + col) * 3..];
+                    sum_u += px[1] as u32;
+                    sum_v += px[2] as u32;
+                }
+            }
+            u[cy * cw + cx] = (sum_u / 4) as u8;
+            v[cy * cw + cx] = (sum_v / 4) as u8;
+        }
+    }
+    i420
+}
+
+/// Tightly packed I420 from planes read with `read_raw_data()` from a 4:2:0 file
+pub fn raw_to_i420(planes: &[Vec<u8>], components: &[CompInfo], width: usize, height: usize) -> Vec<u8> {
+    assert_420(components);
+    let (cw, ch) = chroma_size(width, height);
+    let mut i420 = vec![0; width * height + 2 * cw * ch];
+    {
+        let (y, uv) = i420.split_at_mut(width * height);
+        let (u, v) = uv.split_at_mut(cw * ch);
+        copy_plane(&planes[0], components[0].row_stride(), y, width, width, height);
+        copy_plane(&planes[1], components[1].row_stride(), u, cw, cw, ch);
+        copy_plane(&planes[2], components[2].row_stride(), v, cw, cw, ch);
+    }
+    i420
+}
+
+/// Planes for `write_raw_data()` of a 4:2:0 image (use `CompressStarted::components()`)
+///
+/// Padding is filled with copies of edge pixels, which compresses best.
+pub fn i420_to_raw(i420: &[u8], components: &[CompInfo], width: usize, height: usize) -> Vec<Vec<u8>> {
+    assert_420(components);
+    let (y, u, v) = split_i420(i420, width, height);
+    let (cw, ch) = chroma_size(width, height);
+    [(y, width, height), (u, cw, ch), (v, cw, ch)].iter().zip(components).map(|(&(src, w, h), c)| {
+        let stride = c.row_stride();
+        // Whole MCU rows are written
+        let rows = max(h, c.padded_rows());
+        let mut plane = vec![0; stride * rows];
+        for (row, dst) in plane.chunks_exact_mut(stride).enumerate() {
+            let src = &src[row.min(h - 1) * w..][..w];
+            dst[..w].copy_from_slice(src);
+            let last = src[w - 1];
+            for px in &mut dst[w..] {
+                *px = last;
+            }
+        }
+        plane
+    }).collect()
+}
+
+fn split_i420(i420: &[u8], width: usize, height: usize) -> (&[u8], &[u8], &[u8]) {
+    let (cw, ch) = chroma_size(width, height);
+    assert_eq!(width * height + 2 * cw * ch, i420.len(), "I420 size doesn't match");
+    let (y, uv) = i420.split_at(width * height);
+    let (u, v) = uv.split_at(cw * ch);
+    (y, u, v)
+}
+
+fn assert_420(components: &[CompInfo]) {
+    let sampling = components.iter().map(|c| (c.h_samp_factor, c.v_samp_factor)).collect::<Vec<_>>();
+    assert_eq!(&[(2, 2), (1, 1), (1, 1)][..], &sampling[..], "Only 4:2:0 sampling is supported");
+}
+
+#[test]
+fn uv_roundtrip() {
+    let u = (0..37).collect::<Vec<u8>>();
+    let v = (100..137).collect::<Vec<u8>>();
+    let mut uv = vec![0; 74];
+    interleave_uv(&u, &v, &mut uv);
+    assert_eq!(&[0, 100, 1, 101], &uv[..4]);
+    assert_eq!(&[36, 136], &uv[72..]);
+
+    let mut u2 = vec![0; 37];
+    let mut v2 = vec![0; 37];
+    deinterleave_uv(&uv, &mut u2, &mut v2);
+    assert_eq!(u, u2);
+    assert_eq!(v, v2);
+}
+
+#[test]
+fn i420_conversions() {
+    let (width, height) = (5, 3);
+    let i420 = (0..15 + 2 * 6).map(|i| i as u8).collect::<Vec<_>>();
+    let nv12 = i420_to_nv12(&i420, width, height);
+    assert_eq!(&[15, 21, 16, 22], &nv12[15..19]);
+    assert_eq!(i420, nv12_to_i420(&nv12, width, height));
+
+    let yuv = i420_to_yuv(&i420, width, height);
+    assert_eq!(&[0, 15, 21, 1, 15, 21, 2, 16, 22], &yuv[..9]);
+    assert_eq!(&[14, 20, 26], &yuv[42..]);
+    assert_eq!(i420, yuv_to_i420(&yuv, width, height));
+}
@@ -0,0 +1,198 @@
+use compress::Compress;
+use decompress::{Decompress, DecompressStarted, NO_MARKERS};
+use colorspace::ColorSpace;
+use component::{CompInfo, CompInfoExt};
+use marker::Marker;
+use errormgr::{ErrorMgr, PanicingErrorMgr};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Reusable buffers for pixels and raw data planes, for servers that process many images
+///
+/// Buffers are returned to the pool when `PooledBuffer` is dropped. It can be shared between threads.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+/// Empty buffer from `BufferPool`. Derefs to `Vec<u8>`.
+pub struct PooledBuffer<'pool> {
+    buffer: Vec<u8>,
+    pool: &'pool BufferPool,
+}
+
+impl BufferPool {
+    /// Keeps at most `max_buffers` unused buffers
+    pub fn new(max_buffers: usize) -> BufferPool {
+        BufferPool {
+            free: Mutex::new(Vec::new()),
+            max_buffers,
+        }
+    }
+
+    /// An empty buffer with capacity for at least `capacity` bytes
+    pub fn get(&self, capacity: usize) -> PooledBuffer<'_> {
+        let reused = self.free.lock().ok().and_then(|mut free| {
+            // The smallest buffer that's large enough, or else the largest one to grow
+            let best = free.iter().enumerate()
+                .min_by_key(|&(_, b)| if b.capacity() >= capacity { b.capacity() - capacity } else { usize::MAX - b.capacity() })
+                .map(|(i, _)| i);
+            best.map(|i| free.swap_remove(i))
+        });
+        let mut buffer = reused.unwrap_or_default();
+        buffer.reserve(capacity);
+        PooledBuffer {
+            buffer,
+            pool: self,
+        }
+    }
+
+    /// Buffer for all lines of the image, for `DecompressStarted::read_scanlines_into()`
+    pub fn for_scanlines(&self, dinfo: &DecompressStarted) -> PooledBuffer<'_> {
+        self.get(dinfo.width() * dinfo.height() * dinfo.output_components())
+    }
+
+    /// One buffer for each component, for `read_raw_data()` or `write_raw_data()`
+    pub fn for_planes(&self, components: &[CompInfo]) -> Vec<PooledBuffer<'_>> {
+        components.iter().map(|c| self.get(c.row_stride() * c.padded_rows())).collect()
+    }
+
+    /// Number of unused buffers kept
+    pub fn len(&self) -> usize {
+        self.free.lock().map(|free| free.len()).unwrap_or(0)
+    }
+
+    /// No unused buffers are kept
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'pool> PooledBuffer<'pool> {
+    /// Take the buffer out of the pool, e.g. to return it as a result
+    pub fn into_vec(mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.buffer)
+    }
+}
+
+impl<'pool> Deref for PooledBuffer<'pool> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl<'pool> DerefMut for PooledBuffer<'pool> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl<'pool> Drop for PooledBuffer<'pool> {
+    fn drop(&mut self) {
+        if self.buffer.capacity() == 0 {
+            return;
+        }
+        let mut buffer = ::std::mem::take(&mut self.buffer);
+        buffer.clear();
+        if let Ok(mut free) = self.pool.free.lock() {
+            if free.len() < self.pool.max_buffers {
+                free.push(buffer);
+            }
+        }
+    }
+}
+
+/// Warm `Compress` or `Decompress` objects, reused across images to avoid setting up libjpeg every time
+///
+/// Objects taken from the pool return to it when they're dropped, including after errors.
+/// Clones of the pool share the same objects, and it can be used from many threads.
+///
+/// ```rust,ignore
+/// let decoders = Pool::<Decompress>::new(8);
+/// let dinfo = decoders.decompress_mem(&jpeg)?;
+/// ```
+pub struct Pool<T> {
+    list: Arc<IdleList<T>>,
+}
+
+/// Objects waiting in a `Pool`
+pub(crate) struct IdleList<T> {
+    idle: Mutex<Vec<Idle<T>>>,
+    max_idle: usize,
+}
+
+/// Idle libjpeg objects have no callbacks, sources or destinations, and aren't tied to any thread
+struct Idle<T>(T);
+unsafe impl Send for Idle<Compress> {}
+unsafe impl Send for Idle<Decompress<'static>> {}
+
+impl<T> IdleList<T> {
+    pub(crate) fn put(&self, object: T) {
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.max_idle {
+                idle.push(Idle(object));
+            }
+        }
+    }
+
+    fn take(&self) -> Option<T> {
+        self.idle.lock().ok().and_then(|mut idle| idle.pop()).map(|Idle(object)| object)
+    }
+}
+
+impl<T> Pool<T> {
+    /// Keeps at most `max_idle` unused objects
+    pub fn new(max_idle: usize) -> Self {
+        Pool {
+            list: Arc::new(IdleList {
+                idle: Mutex::new(Vec::new()),
+                max_idle,
+            }),
+        }
+    }
+
+    /// Number of unused objects kept
+    pub fn len(&self) -> usize {
+        self.list.idle.lock().map(|idle| idle.len()).unwrap_or(0)
+    }
+
+    /// No unused objects are kept
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Self {
+        Pool {
+            list: self.list.clone(),
+        }
+    }
+}
+
+impl Pool<Compress> {
+    /// Like `Compress::new(color_space)`. All settings are reset to defaults.
+    ///
+    /// As always, set the destination (e.g. `set_mem_dest()`) before compressing.
+    pub fn compress(&self, color_space: ColorSpace) -> Compress {
+        let mut comp = self.list.take().unwrap_or_else(|| Compress::new(color_space));
+        comp.reset(color_space, self.list.clone());
+        comp
+    }
+}
+
+impl Pool<Decompress<'static>> {
+    /// Like `Decompress::new_mem(mem)`
+    pub fn decompress_mem<'src>(&self, mem: &'src [u8]) -> io::Result<Decompress<'src>> {
+        self.decompress_mem_with_markers(NO_MARKERS, mem)
+    }
+
+    /// Like `Decompress::with_markers(save_markers).from_mem(mem)`
+    pub fn decompress_mem_with_markers<'src>(&self, save_markers: &[Marker], mem: &'src [u8]) -> io::Result<Decompress<'src>> {
+        let dinfo = self.list.take().unwrap_or_else(|| Decompress::new_err(<ErrorMgr as PanicingErrorMgr>::new()));
+        dinfo.reuse(save_markers, mem, self.list.clone())
+    }
+}
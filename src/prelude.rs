@@ -0,0 +1,19 @@
+//! Types and traits needed by most programs, so that one import is enough
+//!
+//! ```rust,ignore
+//! use mozjpeg::prelude::*;
+//! ```
+pub use compress::{Compress, CompressStarted, CompressProfile, ConfigError, ScanMode, ScanLayout};
+pub use decompress::{Decompress, DecompressConfig, DecompressStarted, DctMethod, MarkerData, CropAlignment, ImageLayout};
+pub use decompress::{ALL_MARKERS, NO_MARKERS};
+pub use flavor::Flavor;
+pub use jfif::JfifThumbnail;
+pub use colorspace::{ColorSpace, ColorSpaceExt, parse_color_space};
+pub use component::{CompInfo, CompInfoExt};
+pub use marker::Marker;
+pub use pixel::Pixel;
+pub use settings::{CompressSettings, DecompressSettings, Preset};
+pub use limits::Limits;
+pub use oneshot::{decode_rgb, encode_rgb, encode_gray};
+#[cfg(not(target_arch = "wasm32"))]
+pub use oneshot::decode_path_rgb;
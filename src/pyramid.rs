@@ -0,0 +1,78 @@
+use decompress::Decompress;
+use colorspace::ColorSpace;
+use std::io;
+
+/// One image of `pyramid()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    /// The image is scaled to 1/`denominator` of the original size (rounded up)
+    pub denominator: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Tightly packed pixels in the requested color space
+    pub pixels: Vec<u8>,
+}
+
+/// Decode the image at several scales, e.g. `&[1, 4, 8]` for full size, preview and thumbnail
+///
+/// `denominators` must be 1, 2, 4 or 8. The file is decoded only once, at the largest requested scale
+/// (using libjpeg's DCT scaling if that's smaller than the full size), and smaller levels are made
+/// by averaging 2×2 blocks of the previous level. Levels are returned in the order they were requested.
+pub fn pyramid(jpeg: &[u8], color_space: ColorSpace, denominators: &[usize]) -> io::Result<Vec<Level>> {
+    assert!(denominators.iter().all(|&d| d == 1 || d == 2 || d == 4 || d == 8), "Scale must be 1/1, 1/2, 1/4 or 1/8");
+    let largest = match denominators.iter().cloned().min() {
+        Some(d) => d,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut dinfo = Decompress::new_mem(jpeg)?;
+    dinfo.scale((8 / largest) as u8);
+    let mut started = dinfo.start_as_color_space(color_space)?;
+    let pixel_size = started.output_components();
+    let mut level = Level {
+        denominator: largest,
+        width: started.width(),
+        height: started.height(),
+        pixels: started.read_scanlines_bytes()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"))?,
+    };
+    started.finish_decompress();
+
+    let mut levels: Vec<Option<Level>> = denominators.iter().map(|_| None).collect();
+    loop {
+        for (slot, &d) in levels.iter_mut().zip(denominators) {
+            if d == level.denominator && slot.is_none() {
+                *slot = Some(level.clone());
+            }
+        }
+        if level.denominator >= *denominators.iter().max().unwrap() {
+            break;
+        }
+        level = halve(&level, pixel_size);
+    }
+    Ok(levels.into_iter().map(|l| l.unwrap()).collect())
+}
+
+/// Average 2×2 blocks. Odd edges are averaged with themselves.
+fn halve(level: &Level, pixel_size: usize) -> Level {
+    let width = (level.width + 1) / 2;
+    let height = (level.height + 1) / 2;
+    let src_stride = level.width * pixel_size;
+    let mut pixels = Vec::with_capacity(width * height * pixel_size);
+    for y in 0..height {
+        let row0 = &level.pixels[y * 2 * src_stride..][..src_stride];
+        let row1 = &level.pixels[(y * 2 + 1).min(level.height - 1) * src_stride..][..src_stride];
+        for x in 0..width {
+            let x0 = x * 2 * pixel_size;
+            let x1 = (x * 2 + 1).min(level.width - 1) * pixel_size;
+            for c in 0..pixel_size {
+                let sum = row0[x0 + c] as u32 + row0[x1 + c] as u32 + row1[x0 + c] as u32 + row1[x1 + c] as u32;
+                pixels.push(((sum + 2) / 4) as u8);
+            }
+        }
+    }
+    Level {
+        denominator: level.denominator * 2,
+        width, height, pixels,
+    }
+}
@@ -1,11 +1,12 @@
 #![allow(non_upper_case_globals)]
 
-extern crate libc;
 use ::std;
-use libc::c_uint;
+use ffi;
+use ext;
+use std::os::raw::c_uint;
 use std::fmt;
 use std::cmp::{min,max};
-type Coef = libc::c_uint;
+type Coef = c_uint;
 
 pub struct QTable {
     pub(crate) coeffs: [Coef; 64],
@@ -66,6 +67,11 @@ impl QTable {
         }
     }
 
+    /// Coefficients in natural (not zigzag) order
+    pub fn as_slice(&self) -> &[c_uint] {
+        &self.coeffs
+    }
+
     pub unsafe fn as_ptr(&self) -> *const c_uint {
         self.coeffs.as_ptr()
     }
@@ -82,6 +88,26 @@ impl QTable {
     }
 }
 
+/// Convert IJG quality (1-100) to a percentage scaling factor for the base tables
+///
+/// Same curve libjpeg uses in `set_quality()`: 50 is 100% (base tables as-is), 100 is 0%.
+/// The result can be passed to `Compress::set_default_qtables_scaled()`.
+pub fn quality_to_scale_factor(quality: f32) -> f32 {
+    unsafe {
+        ext::jpeg_float_quality_scaling(quality)
+    }
+}
+
+/// Inverse of `quality_to_scale_factor()`. Result is clamped to 1-100.
+pub fn scale_factor_to_quality(scale_factor: f32) -> f32 {
+    let quality = if scale_factor > 100. {
+        5000. / scale_factor
+    } else {
+        (200. - scale_factor) / 2.
+    };
+    quality.clamp(1., 100.)
+}
+
 pub static AnnexK_Luma: QTable = QTable{coeffs:[
     16,  11,  10,  16,  24,  40,  51,  61,
     12,  12,  14,  19,  26,  58,  60,  55,
@@ -221,6 +247,22 @@ pub static PetersonAhumadaWatson: QTable = QTable{coeffs:[
     45, 33, 38, 47, 59, 74, 91, 108
 ]};
 
+/// Luma and chroma tables mozjpeg uses for base table index `JINT_BASE_QUANT_TBL_IDX` (0-8)
+pub fn mozjpeg_base_tables(index: usize) -> Option<(&'static QTable, &'static QTable)> {
+    Some(match index {
+        0 => (&AnnexK_Luma, &AnnexK_Chroma),
+        1 => (&Flat, &Flat),
+        2 => (&MSSSIM_Luma, &MSSSIM_Chroma),
+        3 => (&NRobidoux, &NRobidoux),
+        4 => (&PSNRHVS_Luma, &PSNRHVS_Chroma),
+        5 => (&KleinSilversteinCarney, &KleinSilversteinCarney),
+        6 => (&WatsonTaylorBorthwick, &WatsonTaylorBorthwick),
+        7 => (&AhumadaWatsonPeterson, &AhumadaWatsonPeterson),
+        8 => (&PetersonAhumadaWatson, &PetersonAhumadaWatson),
+        _ => return None,
+    })
+}
+
 pub static ALL_TABLES: [(&'static str, &'static QTable); 12] = [
     ("Annex-K Luma", &AnnexK_Luma),
     ("Annex-K Chroma", &AnnexK_Chroma),
@@ -259,3 +301,16 @@ fn scaling() {
     assert_eq!(QTable{coeffs:[1; 64]}, NRobidoux.scaled(99.9,99.9));
     assert_eq!(QTable{coeffs:[1; 64]}, MSSSIM_Chroma.scaled(99.8,99.8));
 }
+
+#[test]
+fn quality_scale_factor() {
+    assert_eq!(100., quality_to_scale_factor(50.));
+    assert_eq!(0., quality_to_scale_factor(100.));
+    assert_eq!(50., quality_to_scale_factor(75.));
+    assert_eq!(500., quality_to_scale_factor(10.));
+    assert_eq!(5000., quality_to_scale_factor(0.));
+
+    for &q in &[1., 10., 33., 50., 75., 90., 100.] {
+        assert!((q - scale_factor_to_quality(quality_to_scale_factor(q))).abs() < 0.001);
+    }
+}
@@ -0,0 +1,61 @@
+use decompress::{DecompressStarted, MAX_MCU_HEIGHT};
+use std::cmp::min;
+use std::io::{self, Read};
+
+/// Decoded pixels as a `Read` stream, see `DecompressStarted::into_reader()`
+///
+/// Lines are decoded as they're read, a few at a time. Bytes are tightly packed pixels
+/// in the output color space. Decompression is finished when the last byte has been read.
+pub struct PixelReader<'src> {
+    dinfo: Option<DecompressStarted<'src>>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<'src> DecompressStarted<'src> {
+    /// Read the remaining lines through `Read`, e.g. to upload them or pass them to `io::copy()`
+    pub fn into_reader(self) -> PixelReader<'src> {
+        let capacity = MAX_MCU_HEIGHT * self.width() * self.output_components();
+        PixelReader {
+            dinfo: Some(self),
+            buffer: Vec::with_capacity(capacity),
+            pos: 0,
+        }
+    }
+}
+
+impl<'src> PixelReader<'src> {
+    /// Decode the next lines into the buffer. Returns `false` at the end of the image.
+    fn fill_buffer(&mut self) -> io::Result<bool> {
+        self.buffer.clear();
+        self.pos = 0;
+        let more = match self.dinfo {
+            Some(ref dinfo) => dinfo.read_more_chunks(),
+            None => return Ok(false),
+        };
+        if !more {
+            let finished = self.dinfo.take().map_or(false, |dinfo| dinfo.finish_decompress());
+            if !finished {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG data is incomplete"));
+            }
+            return Ok(false);
+        }
+        if !self.dinfo.as_mut().unwrap().read_lines_into(&mut self.buffer, MAX_MCU_HEIGHT) {
+            self.dinfo = None;
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "JPEG decoder didn't return all lines"));
+        }
+        Ok(true)
+    }
+}
+
+impl<'src> Read for PixelReader<'src> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buffer.len() && !self.fill_buffer()? {
+            return Ok(0);
+        }
+        let len = min(buf.len(), self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
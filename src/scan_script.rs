@@ -0,0 +1,254 @@
+use compress::ConfigError;
+use ffi;
+use std::io;
+use std::ops::RangeInclusive;
+use std::os::raw::c_int;
+
+const MAX_COMPS_IN_SCAN: usize = 4;
+// Largest successive approximation bit position for 8-bit samples
+const MAX_AH_AL: u8 = 10;
+
+/// One scan of a JPEG file, see `ScanScript`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanInfo {
+    /// Indices of components in the scan, in increasing order
+    pub components: Vec<usize>,
+    /// First coefficient in zigzag order (`Ss`). DC is coefficient 0.
+    pub spectral_start: u8,
+    /// Last coefficient in zigzag order, inclusive (`Se`)
+    pub spectral_end: u8,
+    /// Bit position sent by the previous scan of these coefficients, or 0 in their first scan (`Ah`)
+    pub approx_high: u8,
+    /// Number of low bits left out of this scan (`Al`)
+    pub approx_low: u8,
+}
+
+/// Sequence of scans that make up the file, e.g. a custom progressive scan script
+///
+/// ```rust,ignore
+/// let script = ScanScript::new()
+///     .add_scan(&[0, 1, 2], 0..=0, 0, 1)
+///     .add_scan(&[0], 1..=63, 0, 0)
+///     .add_scan(&[1], 1..=63, 0, 0)
+///     .add_scan(&[2], 1..=63, 0, 0)
+///     .add_scan(&[0, 1, 2], 0..=0, 1, 0);
+/// compress.set_scan_script(&script)?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScanScript {
+    scans: Vec<ScanInfo>,
+}
+
+impl ScanScript {
+    pub fn new() -> ScanScript {
+        ScanScript::default()
+    }
+
+    /// Append a scan of `components` (indices), with coefficients `spectral` (in zigzag order),
+    /// and successive approximation bit positions `approx_high` and `approx_low`
+    ///
+    /// A sequential (non-progressive) scan is `0..=63` with both bit positions 0.
+    pub fn add_scan(mut self, components: &[usize], spectral: RangeInclusive<u8>, approx_high: u8, approx_low: u8) -> Self {
+        self.scans.push(ScanInfo {
+            components: components.to_vec(),
+            spectral_start: *spectral.start(),
+            spectral_end: *spectral.end(),
+            approx_high,
+            approx_low,
+        });
+        self
+    }
+
+    /// Sequential script with a separate scan for each component, in order
+    pub fn non_interleaved(num_components: usize) -> ScanScript {
+        (0..num_components).fold(ScanScript::new(), |script, c| script.add_scan(&[c], 0..=63, 0, 0))
+    }
+
+    pub fn scans(&self) -> &[ScanInfo] {
+        &self.scans
+    }
+
+    /// `true` if any scan sends only some coefficients or bits
+    pub fn is_progressive(&self) -> bool {
+        self.scans.iter().any(|s| s.spectral_start != 0 || s.spectral_end != 63 || s.approx_high != 0 || s.approx_low != 0)
+    }
+
+    /// Check the script for an image with `num_components` components, using the same rules as libjpeg
+    ///
+    /// In sequential mode every component must be sent in exactly one scan. In progressive mode
+    /// DC and AC coefficients are sent in separate scans, AC scans have one component and follow its DC scan,
+    /// and each successive approximation scan must refine the bit sent by the previous one.
+    pub fn validate(&self, num_components: usize) -> Result<(), ConfigError> {
+        let invalid = |scan, reason| Err(ConfigError::InvalidScanScript { scan, reason });
+        if self.scans.is_empty() {
+            return invalid(0, "there are no scans");
+        }
+        let progressive = self.is_progressive();
+        // Per component and coefficient: last bit position sent, if any
+        let mut last_bitpos = vec![[None::<u8>; 64]; num_components];
+        let mut sent = vec![false; num_components];
+        for (i, scan) in self.scans.iter().enumerate() {
+            let comps = &scan.components;
+            if comps.is_empty() || comps.len() > MAX_COMPS_IN_SCAN {
+                return invalid(i, "a scan must have 1-4 components");
+            }
+            if comps.windows(2).any(|w| w[0] >= w[1]) {
+                return invalid(i, "component indices must be in increasing order");
+            }
+            if comps.iter().any(|&c| c >= num_components) {
+                return invalid(i, "component index is out of range");
+            }
+            if !progressive {
+                if comps.iter().any(|&c| sent[c]) {
+                    return invalid(i, "component is sent more than once in sequential mode");
+                }
+                for &c in comps {
+                    sent[c] = true;
+                }
+                continue;
+            }
+
+            let (ss, se, ah, al) = (scan.spectral_start, scan.spectral_end, scan.approx_high, scan.approx_low);
+            if ss > se || se > 63 || ah > MAX_AH_AL || al > MAX_AH_AL {
+                return invalid(i, "spectral range or bit positions are out of range");
+            }
+            if ss == 0 && se != 0 {
+                return invalid(i, "DC and AC coefficients must be in separate scans");
+            }
+            if ss != 0 && comps.len() != 1 {
+                return invalid(i, "AC scans must have one component");
+            }
+            for &c in comps {
+                if ss != 0 && last_bitpos[c][0].is_none() {
+                    return invalid(i, "AC scan comes before the DC scan of its component");
+                }
+                for last in &mut last_bitpos[c][ss as usize..=se as usize] {
+                    match *last {
+                        None if ah != 0 => return invalid(i, "first scan of a coefficient must have approx_high 0"),
+                        Some(bit) if ah != bit || al + 1 != ah => return invalid(i, "refinement scan doesn't follow the previous scan"),
+                        _ => {},
+                    }
+                    *last = Some(al);
+                }
+                sent[c] = true;
+            }
+        }
+        if sent.iter().any(|&s| !s) {
+            return invalid(self.scans.len(), "a component is never sent");
+        }
+        Ok(())
+    }
+
+    /// Scan structure of an existing JPEG file, read from its SOS headers without decoding
+    pub fn from_jpeg(jpeg: &[u8]) -> io::Result<ScanScript> {
+        let bad = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            return Err(bad("Not a JPEG file"));
+        }
+        // Scans refer to components by their IDs from the SOF header
+        let mut component_ids = Vec::new();
+        let mut scans = Vec::new();
+        let mut pos = 2;
+        while pos + 3 < jpeg.len() {
+            if jpeg[pos] != 0xFF {
+                // Entropy-coded data
+                pos += 1;
+                continue;
+            }
+            let marker = jpeg[pos + 1];
+            match marker {
+                0xD9 => break,
+                0x00 | 0xFF | 0x01 | 0xD0 ..= 0xD8 => { pos += if marker == 0xFF { 1 } else { 2 }; continue; },
+                _ => {},
+            }
+            let len = (jpeg[pos + 2] as usize) << 8 | jpeg[pos + 3] as usize;
+            let segment = jpeg.get(pos + 4 .. pos + 2 + len).ok_or_else(|| bad("Truncated marker"))?;
+            match marker {
+                // SOF markers, except DHT, JPG and DAC
+                0xC0 ..= 0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                    let count = *segment.get(5).ok_or_else(|| bad("Truncated SOF"))? as usize;
+                    component_ids = (0..count).map(|i| segment.get(6 + i * 3).cloned().ok_or_else(|| bad("Truncated SOF")))
+                        .collect::<Result<_, _>>()?;
+                },
+                0xDA => {
+                    let count = *segment.first().ok_or_else(|| bad("Truncated SOS"))? as usize;
+                    let params = segment.get(1 + count * 2 .. 4 + count * 2).ok_or_else(|| bad("Truncated SOS"))?;
+                    let components = (0..count).map(|i| {
+                        let id = segment[1 + i * 2];
+                        component_ids.iter().position(|&c| c == id).ok_or_else(|| bad("Scan refers to an unknown component"))
+                    }).collect::<Result<_, _>>()?;
+                    scans.push(ScanInfo {
+                        components,
+                        spectral_start: params[0],
+                        spectral_end: params[1],
+                        approx_high: params[2] >> 4,
+                        approx_low: params[2] & 15,
+                    });
+                },
+                _ => {},
+            }
+            pos += 2 + len;
+        }
+        Ok(ScanScript { scans })
+    }
+
+    pub(crate) fn from_ffi(scans: &[ffi::jpeg_scan_info]) -> ScanScript {
+        ScanScript {
+            scans: scans.iter().map(|s| ScanInfo {
+                components: s.component_index[..s.comps_in_scan as usize].iter().map(|&c| c as usize).collect(),
+                spectral_start: s.Ss as u8,
+                spectral_end: s.Se as u8,
+                approx_high: s.Ah as u8,
+                approx_low: s.Al as u8,
+            }).collect(),
+        }
+    }
+
+    pub(crate) fn to_ffi(&self) -> Vec<ffi::jpeg_scan_info> {
+        self.scans.iter().map(|s| {
+            let mut component_index = [0; 4];
+            for (out, &c) in component_index.iter_mut().zip(&s.components) {
+                *out = c as c_int;
+            }
+            ffi::jpeg_scan_info {
+                comps_in_scan: s.components.len() as c_int,
+                component_index,
+                Ss: s.spectral_start as c_int,
+                Se: s.spectral_end as c_int,
+                Ah: s.approx_high as c_int,
+                Al: s.approx_low as c_int,
+            }
+        }).collect()
+    }
+}
+
+#[test]
+fn validation() {
+    let dc_first = ScanScript::new().add_scan(&[0, 1, 2], 0..=0, 0, 1);
+    let script = dc_first.clone()
+        .add_scan(&[0], 1..=63, 0, 0)
+        .add_scan(&[1], 1..=63, 0, 0)
+        .add_scan(&[2], 1..=63, 0, 0)
+        .add_scan(&[0, 1, 2], 0..=0, 1, 0);
+    assert!(script.is_progressive());
+    assert_eq!(Ok(()), script.validate(3));
+    assert!(script.validate(2).is_err());
+
+    let bad_refinement = dc_first.clone().add_scan(&[0, 1, 2], 0..=0, 2, 1);
+    assert_eq!(Err(ConfigError::InvalidScanScript { scan: 1, reason: "refinement scan doesn't follow the previous scan" }),
+               bad_refinement.validate(3));
+    assert!(dc_first.clone().add_scan(&[0, 1], 1..=63, 0, 0).validate(3).is_err());
+    assert!(ScanScript::new().add_scan(&[0], 0..=5, 0, 0).validate(1).is_err());
+    let ac_first = ScanScript::new().add_scan(&[0], 1..=63, 0, 0).add_scan(&[0], 0..=0, 0, 0);
+    assert_eq!(Err(ConfigError::InvalidScanScript { scan: 0, reason: "AC scan comes before the DC scan of its component" }),
+               ac_first.validate(1));
+
+    let sequential = ScanScript::new().add_scan(&[0], 0..=63, 0, 0).add_scan(&[1, 2], 0..=63, 0, 0);
+    assert!(!sequential.is_progressive());
+    assert_eq!(Ok(()), sequential.validate(3));
+    assert_eq!(Ok(()), ScanScript::non_interleaved(3).validate(3));
+    assert!(!ScanScript::non_interleaved(3).is_progressive());
+    assert!(sequential.clone().add_scan(&[1], 0..=63, 0, 0).validate(3).is_err());
+    assert!(ScanScript::new().add_scan(&[0], 0..=63, 0, 0).validate(3).is_err());
+    assert!(ScanScript::new().validate(1).is_err());
+}
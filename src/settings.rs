@@ -0,0 +1,167 @@
+use colorspace::ColorSpace;
+use compress::{Compress, CompressProfile, Tune};
+use decompress::{Decompress, DctMethod};
+
+/// Compression settings that can be stored, e.g. loaded from a config file with the `serde` feature
+///
+/// Apply them with `Compress::apply_settings()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CompressSettings {
+    /// Defaults for all other settings
+    pub profile: CompressProfile,
+    /// 1-100
+    pub quality: f32,
+    /// How many luma pixels share one chroma sample, horizontally and vertically: `(2, 2)` is 4:2:0, `(1, 1)` is 4:4:4
+    ///
+    /// Only YCbCr and YCCK files are subsampled.
+    pub chroma_subsampling: (u8, u8),
+    pub progressive: bool,
+    /// Compute optimal Huffman tables for the image
+    pub optimize_coding: bool,
+    /// Metric to optimize quantization for. `None` keeps the profile's default.
+    pub tune: Option<Tune>,
+}
+
+impl Default for CompressSettings {
+    fn default() -> Self {
+        CompressSettings {
+            profile: CompressProfile::MaxCompression,
+            quality: 75.,
+            chroma_subsampling: (2, 2),
+            progressive: true,
+            optimize_coding: true,
+            tune: None,
+        }
+    }
+}
+
+/// Named sets of compression settings, for pipelines migrating from other tools
+///
+/// Quality numbers use the same libjpeg scale as ImageMagick's `convert -quality` and libvips' `Q`,
+/// but files won't be byte-identical: mozjpeg's quantization tables and trellis quantization
+/// give smaller files at a similar visual quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Preset {
+    /// Quality 85, 4:2:0, progressive, optimized Huffman tables.
+    ///
+    /// Like `convert -quality 85 -sampling-factor 4:2:0 -interlace JPEG`,
+    /// or libvips' `jpegsave` with `Q=85, interlace, optimize_coding`.
+    Web85,
+    /// Quality 95, 4:4:4, sequential, tuned for PSNR rather than perceived quality.
+    ///
+    /// Like `convert -quality 95` (ImageMagick doesn't subsample from quality 90),
+    /// or libvips' `jpegsave` with `Q=95, subsample_mode=off, optimize_coding`.
+    ArchivalHigh,
+    /// Quality 75, 4:2:0, sequential, baseline libjpeg without mozjpeg's slow extensions.
+    ///
+    /// Like `vipsthumbnail` with its default `Q=75`.
+    FastPreview,
+}
+
+impl Preset {
+    pub fn settings(self) -> CompressSettings {
+        match self {
+            Preset::Web85 => CompressSettings {
+                quality: 85.,
+                ..CompressSettings::default()
+            },
+            Preset::ArchivalHigh => CompressSettings {
+                quality: 95.,
+                chroma_subsampling: (1, 1),
+                progressive: false,
+                tune: Some(Tune::Psnr),
+                ..CompressSettings::default()
+            },
+            Preset::FastPreview => CompressSettings {
+                profile: CompressProfile::Fastest,
+                quality: 75.,
+                chroma_subsampling: (2, 2),
+                progressive: false,
+                optimize_coding: false,
+                tune: None,
+            },
+        }
+    }
+}
+
+impl From<Preset> for CompressSettings {
+    fn from(preset: Preset) -> Self {
+        preset.settings()
+    }
+}
+
+/// Decompression settings that can be stored, see `CompressSettings`
+///
+/// Apply them with `Decompress::apply_settings()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DecompressSettings {
+    pub dct_method: DctMethod,
+    /// Smooth chroma upsampling
+    pub fancy_upsampling: bool,
+    /// Smooth blockiness of progressive files that haven't been fully loaded
+    pub block_smoothing: bool,
+    /// Scale to `scale / 8` of the size, 1-16
+    pub scale: u8,
+}
+
+impl Default for DecompressSettings {
+    fn default() -> Self {
+        DecompressSettings {
+            dct_method: DctMethod::IntegerSlow,
+            fancy_upsampling: true,
+            block_smoothing: true,
+            scale: 8,
+        }
+    }
+}
+
+impl Compress {
+    /// Reset settings to the profile's defaults, then apply the rest
+    ///
+    /// Size, color spaces and the destination are kept. Call it before setting anything else.
+    pub fn apply_settings(&mut self, settings: &CompressSettings) {
+        self.set_compress_profile(settings.profile);
+        if let Some(tune) = settings.tune {
+            self.set_tune(tune);
+        }
+        self.set_quality(settings.quality);
+        self.set_optimize_coding(settings.optimize_coding);
+        if settings.progressive {
+            self.set_progressive_mode();
+        } else {
+            self.set_sequential_mode();
+        }
+
+        let (h, v) = settings.chroma_subsampling;
+        assert!((1..=4).contains(&h) && (1..=4).contains(&v), "Invalid chroma subsampling {}x{}", h, v);
+        // Other color spaces have no chroma to subsample
+        let has_chroma = matches!(self.cinfo.jpeg_color_space, ColorSpace::JCS_YCbCr | ColorSpace::JCS_YCCK);
+        if has_chroma {
+            for (i, c) in self.components_mut().iter_mut().enumerate() {
+                // Luma, and K of YCCK, have full resolution
+                let (h, v) = if i == 0 || i == 3 { (h, v) } else { (1, 1) };
+                c.h_samp_factor = h.into();
+                c.v_samp_factor = v.into();
+            }
+        }
+    }
+
+    /// Same as `apply_settings(&preset.settings())`
+    pub fn apply_preset(&mut self, preset: Preset) {
+        self.apply_settings(&preset.settings());
+    }
+}
+
+impl<'src> Decompress<'src> {
+    pub fn apply_settings(&mut self, settings: &DecompressSettings) {
+        self.dct_method(settings.dct_method);
+        self.do_fancy_upsampling(settings.fancy_upsampling);
+        self.do_block_smoothing(settings.block_smoothing);
+        self.scale(settings.scale);
+    }
+}
@@ -0,0 +1,393 @@
+#[cfg(feature = "bytes")]
+use bytes::Buf;
+use ffi;
+use ffi::jpeg_decompress_struct;
+use ffi::boolean;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::File;
+use std::io::{self, Read};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::{Seek, SeekFrom};
+use std::mem;
+use std::os::raw::{c_int, c_long};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+const SOI: [u8; 2] = [0xFF, 0xD8];
+static FAKE_EOI: [u8; 2] = [0xFF, 0xD9];
+// A datastream with no image, which libjpeg's header reader accepts without an error
+static FAKE_EMPTY: [u8; 4] = [0xFF, 0xD8, 0xFF, 0xD9];
+
+/// libjpeg's error for data that isn't JPEG is fatal, so the start of the data is checked before libjpeg sees it
+///
+/// Only the bytes that are available are compared, e.g. one byte of a one-byte chunk.
+pub(crate) fn check_signature(data: &[u8]) -> io::Result<()> {
+    if !data.is_empty() && data.iter().zip(&SOI).all(|(a, b)| a == b) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Not a JPEG file"))
+    }
+}
+
+/// Like libjpeg's own sources, warn about a truncated file, and end it with a fake EOI marker
+unsafe fn insert_fake_eoi(cinfo: &mut jpeg_decompress_struct) {
+    let err = &mut *cinfo.common.err;
+    err.msg_code = ffi::JWRN_JPEG_EOF as c_int;
+    if let Some(emit_message) = err.emit_message {
+        emit_message(&mut cinfo.common, -1);
+    }
+    let src = &mut *cinfo.src;
+    src.next_input_byte = FAKE_EOI.as_ptr();
+    src.bytes_in_buffer = 2;
+}
+
+/// End the input after an error that can't unwind through libjpeg. `Decompress` returns it when libjpeg returns.
+///
+/// The rest of the file is treated as truncated, or as empty if libjpeg hasn't read anything yet.
+pub(crate) unsafe fn end_input_after_error(cinfo: &mut jpeg_decompress_struct, at_start: bool) {
+    let src = &mut *cinfo.src;
+    let data: &[u8] = if at_start { &FAKE_EMPTY } else { &FAKE_EOI };
+    src.next_input_byte = data.as_ptr();
+    src.bytes_in_buffer = data.len();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Source manager that reads a file directly, without C stdio
+///
+/// Reads are positioned, so skipped data (e.g. unwanted markers) is not read at all.
+#[repr(C)]
+pub struct FileSource {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_source_mgr,
+    file: File,
+    buffer: Vec<u8>,
+    // Position in the file where the JPEG data starts
+    start: u64,
+    // Position in the file of the end of the buffer
+    offset: u64,
+    // Read error, which can't unwind through libjpeg. It's returned by `Decompress` after libjpeg returns.
+    error: Option<io::Error>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileSource {
+    /// Reading starts at the current position of the file
+    pub fn new(mut file: File) -> io::Result<Box<FileSource>> {
+        let start = file.stream_position()?;
+        let mut src = Box::new(FileSource {
+            mgr: unsafe { mem::zeroed() },
+            file,
+            buffer: vec![0; BUFFER_SIZE],
+            start,
+            offset: start,
+            error: None,
+        });
+        src.mgr.init_source = Some(init_source);
+        src.mgr.fill_input_buffer = Some(fill_input_buffer);
+        src.mgr.skip_input_data = Some(skip_input_data);
+        src.mgr.resync_to_restart = Some(ffi::jpeg_resync_to_restart);
+        src.mgr.term_source = Some(term_source);
+        Ok(src)
+    }
+
+    pub fn as_mgr(&mut self) -> *mut ffi::jpeg_source_mgr {
+        &mut self.mgr
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn read_at(&mut self) -> io::Result<usize> {
+        loop {
+            match read_at(&self.file, &mut self.buffer, self.offset) {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(not(any(unix, windows, target_arch = "wasm32")))]
+fn read_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+extern "C" fn init_source(cinfo: &mut jpeg_decompress_struct) {
+    unsafe {
+        let src = &mut *(cinfo.src as *mut FileSource);
+        src.offset = src.start;
+        src.mgr.bytes_in_buffer = 0;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+extern "C" fn fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
+    unsafe {
+        let src = &mut *(cinfo.src as *mut FileSource);
+        let at_start = src.offset == src.start;
+        let res = src.read_at()
+            .and_then(|len| if at_start { check_signature(&src.buffer[..len]).map(|_| len) } else { Ok(len) });
+        let len = match res {
+            Ok(len) => len,
+            Err(err) => {
+                src.error = Some(err);
+                end_input_after_error(cinfo, at_start);
+                return true as boolean;
+            },
+        };
+        if len > 0 {
+            src.offset += len as u64;
+            src.mgr.next_input_byte = src.buffer.as_ptr();
+            src.mgr.bytes_in_buffer = len;
+        } else {
+            insert_fake_eoi(cinfo);
+        }
+        true as boolean
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+extern "C" fn skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
+    if num_bytes <= 0 {
+        return;
+    }
+    unsafe {
+        let src = &mut *(cinfo.src as *mut FileSource);
+        let num_bytes = num_bytes as usize;
+        if num_bytes <= src.mgr.bytes_in_buffer {
+            src.mgr.next_input_byte = src.mgr.next_input_byte.add(num_bytes);
+            src.mgr.bytes_in_buffer -= num_bytes;
+        } else {
+            // Data past the buffer doesn't need to be read
+            src.offset += (num_bytes - src.mgr.bytes_in_buffer) as u64;
+            src.mgr.bytes_in_buffer = 0;
+        }
+    }
+}
+
+extern "C" fn term_source(_cinfo: &mut jpeg_decompress_struct) {
+}
+
+/// Source manager that reads from any `Read`, e.g. stdin
+///
+/// Unlike `FileSource` it can't seek, so skipped data is read and discarded.
+#[repr(C)]
+pub struct ReaderSource {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_source_mgr,
+    reader: Box<dyn Read>,
+    buffer: Vec<u8>,
+    // Whether libjpeg got any data
+    started: bool,
+    // Read error, which can't unwind through libjpeg. It's returned by `Decompress` after libjpeg returns.
+    error: Option<io::Error>,
+}
+
+impl ReaderSource {
+    pub fn new(reader: Box<dyn Read>) -> Box<ReaderSource> {
+        let mut src = Box::new(ReaderSource {
+            mgr: unsafe { mem::zeroed() },
+            reader,
+            buffer: vec![0; BUFFER_SIZE],
+            started: false,
+            error: None,
+        });
+        src.mgr.init_source = Some(reader_init_source);
+        src.mgr.fill_input_buffer = Some(reader_fill_input_buffer);
+        src.mgr.skip_input_data = Some(reader_skip_input_data);
+        src.mgr.resync_to_restart = Some(ffi::jpeg_resync_to_restart);
+        src.mgr.term_source = Some(term_source);
+        src
+    }
+
+    pub fn as_mgr(&mut self) -> *mut ffi::jpeg_source_mgr {
+        &mut self.mgr
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn read(&mut self) -> io::Result<usize> {
+        loop {
+            match self.reader.read(&mut self.buffer) {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+extern "C" fn reader_init_source(cinfo: &mut jpeg_decompress_struct) {
+    unsafe {
+        let src = &mut *(cinfo.src as *mut ReaderSource);
+        src.mgr.bytes_in_buffer = 0;
+        src.started = false;
+    }
+}
+
+extern "C" fn reader_fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
+    unsafe {
+        let src = &mut *(cinfo.src as *mut ReaderSource);
+        let at_start = !src.started;
+        let res = src.read()
+            .and_then(|len| if at_start { check_signature(&src.buffer[..len]).map(|_| len) } else { Ok(len) });
+        let len = match res {
+            Ok(len) => len,
+            Err(err) => {
+                src.error = Some(err);
+                end_input_after_error(cinfo, at_start);
+                return true as boolean;
+            },
+        };
+        if len > 0 {
+            src.started = true;
+            src.mgr.next_input_byte = src.buffer.as_ptr();
+            src.mgr.bytes_in_buffer = len;
+        } else {
+            insert_fake_eoi(cinfo);
+        }
+        true as boolean
+    }
+}
+
+extern "C" fn reader_skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
+    if num_bytes <= 0 {
+        return;
+    }
+    unsafe {
+        let src = &mut *(cinfo.src as *mut ReaderSource);
+        let num_bytes = num_bytes as usize;
+        if num_bytes <= src.mgr.bytes_in_buffer {
+            src.mgr.next_input_byte = src.mgr.next_input_byte.add(num_bytes);
+            src.mgr.bytes_in_buffer -= num_bytes;
+        } else {
+            let rest = (num_bytes - src.mgr.bytes_in_buffer) as u64;
+            src.mgr.bytes_in_buffer = 0;
+            if let Err(err) = io::copy(&mut (&mut src.reader).take(rest), &mut io::sink()) {
+                src.error = Some(err);
+                end_input_after_error(cinfo, false);
+            }
+        }
+    }
+}
+
+/// Source manager that gives libjpeg chunks of a `Buf` one by one, without copying them into one buffer
+#[cfg(feature = "bytes")]
+#[repr(C)]
+pub struct BufSource {
+    // must be first, libjpeg sees only this part
+    mgr: ffi::jpeg_source_mgr,
+    buf: Box<dyn Buf>,
+    // Length of the chunk given to libjpeg, which is advanced past on the next fill
+    chunk_len: usize,
+    // Whether libjpeg got any data
+    started: bool,
+    // Set when the data isn't JPEG. It's returned by `Decompress` after libjpeg returns.
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "bytes")]
+impl BufSource {
+    pub fn new(buf: Box<dyn Buf>) -> Box<BufSource> {
+        let mut src = Box::new(BufSource {
+            mgr: unsafe { mem::zeroed() },
+            buf,
+            chunk_len: 0,
+            started: false,
+            error: None,
+        });
+        src.mgr.init_source = Some(buf_init_source);
+        src.mgr.fill_input_buffer = Some(buf_fill_input_buffer);
+        src.mgr.skip_input_data = Some(buf_skip_input_data);
+        src.mgr.resync_to_restart = Some(ffi::jpeg_resync_to_restart);
+        src.mgr.term_source = Some(term_source);
+        src
+    }
+
+    pub fn as_mgr(&mut self) -> *mut ffi::jpeg_source_mgr {
+        &mut self.mgr
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn next_chunk(&mut self) {
+        self.buf.advance(self.chunk_len);
+        self.chunk_len = 0;
+    }
+}
+
+#[cfg(feature = "bytes")]
+extern "C" fn buf_init_source(cinfo: &mut jpeg_decompress_struct) {
+    unsafe {
+        let src = &mut *(cinfo.src as *mut BufSource);
+        src.mgr.bytes_in_buffer = 0;
+        src.started = false;
+    }
+}
+
+#[cfg(feature = "bytes")]
+extern "C" fn buf_fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
+    unsafe {
+        let src = &mut *(cinfo.src as *mut BufSource);
+        src.next_chunk();
+        if !src.started {
+            src.started = true;
+            if let Err(err) = check_signature(src.buf.chunk()) {
+                src.error = Some(err);
+                end_input_after_error(cinfo, true);
+                return true as boolean;
+            }
+        }
+        if src.buf.has_remaining() {
+            let chunk = src.buf.chunk();
+            src.chunk_len = chunk.len();
+            src.mgr.next_input_byte = chunk.as_ptr();
+            src.mgr.bytes_in_buffer = chunk.len();
+        } else {
+            insert_fake_eoi(cinfo);
+        }
+        true as boolean
+    }
+}
+
+#[cfg(feature = "bytes")]
+extern "C" fn buf_skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
+    if num_bytes <= 0 {
+        return;
+    }
+    unsafe {
+        let src = &mut *(cinfo.src as *mut BufSource);
+        let num_bytes = num_bytes as usize;
+        if num_bytes <= src.mgr.bytes_in_buffer {
+            src.mgr.next_input_byte = src.mgr.next_input_byte.add(num_bytes);
+            src.mgr.bytes_in_buffer -= num_bytes;
+        } else {
+            // Skipped chunks are never given to libjpeg
+            let skip = num_bytes - src.mgr.bytes_in_buffer;
+            src.next_chunk();
+            let skip = skip.min(src.buf.remaining());
+            src.buf.advance(skip);
+            src.mgr.bytes_in_buffer = 0;
+        }
+    }
+}
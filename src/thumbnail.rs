@@ -0,0 +1,187 @@
+use errormgr::catch_panics;
+use decompress::{Decompress, DctMethod};
+use index::scan_index;
+use marker::Marker;
+use std::io;
+
+/// Decode a small RGB version of the image as fast as possible
+///
+/// Picks the smallest of 1/8, 1/4, 1/2 and 1/1 scales at which the larger side of the image is still
+/// at least `max_dim` pixels (or the full size, if the image is smaller), and decodes with the fast
+/// integer DCT and without smooth chroma upsampling. At 1/8 scale only the DC coefficients are used.
+/// The result may be up to twice as large as `max_dim`, so resize it further if you need exact dimensions.
+///
+/// Returns pixels, width and height.
+pub fn thumbnail(jpeg: &[u8], max_dim: usize) -> io::Result<(Vec<u8>, usize, usize)> {
+    let mut dinfo = Decompress::new_mem(jpeg)?;
+    let (width, height) = dinfo.size();
+    let larger = width.max(height);
+    let numerator = [1, 2, 4].iter().cloned()
+        .find(|&n| (larger * n + 7) / 8 >= max_dim)
+        .unwrap_or(8);
+    dinfo.scale(numerator as u8);
+    dinfo.dct_method(DctMethod::IntegerFast);
+    dinfo.do_fancy_upsampling(false);
+    dinfo.do_block_smoothing(false);
+
+    let mut started = dinfo.rgb()?;
+    let (width, height) = (started.width(), started.height());
+    let pixels = started.read_scanlines_bytes()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"))?;
+    started.finish_decompress();
+    Ok((pixels, width, height))
+}
+
+/// Where `smart_thumbnail()` got the image from, from the cheapest to the most expensive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailSource {
+    /// Thumbnail embedded in the EXIF metadata
+    Exif,
+    /// Main image decoded at `scale / 8` of its size, using libjpeg's DCT scaling
+    ScaledDecode { scale: u8 },
+    /// Main image decoded at full size
+    FullDecode,
+}
+
+/// Result of `smart_thumbnail()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// Tightly packed RGB pixels
+    pub pixels: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub source: ThumbnailSource,
+}
+
+/// Make an RGB thumbnail whose larger side is exactly `max_dim` pixels (or the full image, if it's smaller)
+///
+/// Uses the cheapest source that is at least as large as the result: the EXIF thumbnail if it has the same
+/// aspect ratio as the image, otherwise the smallest scaled decode (in 1/8 steps), otherwise a full decode.
+/// The source is then downscaled by averaging pixels. Unlike `thumbnail()`, decoding uses the accurate
+/// DCT and smooth upsampling, since the image is resized anyway.
+pub fn smart_thumbnail(jpeg: &[u8], max_dim: usize) -> io::Result<Thumbnail> {
+    assert!(max_dim > 0);
+    let mut dinfo = Decompress::with_markers(&[Marker::APP(1)]).from_mem(jpeg)?;
+    let (width, height) = dinfo.size();
+    let target = fit(width, height, max_dim);
+
+    let exif = dinfo.markers()
+        .filter(|m| m.marker == Marker::APP(1) && m.data.starts_with(b"Exif\0\0"))
+        .filter_map(|m| exif_thumbnail(&m.data[6..]))
+        // libjpeg's errors are fatal, so thumbnails with broken markers are skipped before decoding.
+        // The main image may still be fine.
+        .find(|thumb| scan_index(thumb).map_or(false, |scans| !scans.is_empty()));
+    if let Some(thumb) = exif {
+        let from_exif = catch_panics(|| {
+            let thumb_dinfo = Decompress::new_mem(thumb)?;
+            let (w, h) = thumb_dinfo.size();
+            let same_aspect = {
+                let (tw, th) = fit(w, h, max_dim);
+                (tw as isize - target.0 as isize).abs() <= 1 && (th as isize - target.1 as isize).abs() <= 1
+            };
+            if w >= target.0 && h >= target.1 && same_aspect {
+                let (pixels, w, h) = decode_rgb(thumb_dinfo)?;
+                return Ok(Some(resized(pixels, w, h, target, ThumbnailSource::Exif)));
+            }
+            Ok(None)
+        });
+        if let Ok(Some(thumbnail)) = from_exif {
+            return Ok(thumbnail);
+        }
+    }
+
+    let larger = width.max(height);
+    let scale = (1..8).find(|&n| (larger * n + 7) / 8 >= max_dim).unwrap_or(8);
+    dinfo.scale(scale as u8);
+    let source = if scale < 8 { ThumbnailSource::ScaledDecode { scale: scale as u8 } } else { ThumbnailSource::FullDecode };
+    let (pixels, w, h) = decode_rgb(dinfo)?;
+    Ok(resized(pixels, w, h, target, source))
+}
+
+/// Size with the larger side `max_dim`, unless the image is smaller
+fn fit(width: usize, height: usize, max_dim: usize) -> (usize, usize) {
+    let larger = width.max(height);
+    if larger <= max_dim {
+        return (width, height);
+    }
+    let scaled = |side: usize| ((side * max_dim + larger / 2) / larger).max(1);
+    (scaled(width), scaled(height))
+}
+
+fn decode_rgb(dinfo: Decompress) -> io::Result<(Vec<u8>, usize, usize)> {
+    let mut started = dinfo.rgb()?;
+    let (width, height) = (started.width(), started.height());
+    let pixels = started.read_scanlines_bytes()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"))?;
+    started.finish_decompress();
+    Ok((pixels, width, height))
+}
+
+/// Downscale by averaging the source pixels covered by each output pixel
+fn resized(pixels: Vec<u8>, width: usize, height: usize, (new_width, new_height): (usize, usize), source: ThumbnailSource) -> Thumbnail {
+    if (width, height) == (new_width, new_height) {
+        return Thumbnail { pixels, width, height, source };
+    }
+    let range = |i: usize, new: usize, old: usize| (i * old / new)..((i + 1) * old / new).max(i * old / new + 1);
+    let mut out = Vec::with_capacity(new_width * new_height * 3);
+    for y in 0..new_height {
+        let rows = range(y, new_height, height);
+        for x in 0..new_width {
+            let cols = range(x, new_width, width);
+            let mut sum = [0usize; 3];
+            for row in rows.clone() {
+                for px in pixels[(row * width + cols.start) * 3..(row * width + cols.end) * 3].chunks_exact(3) {
+                    sum[0] += px[0] as usize;
+                    sum[1] += px[1] as usize;
+                    sum[2] += px[2] as usize;
+                }
+            }
+            let count = rows.len() * cols.len();
+            out.extend(sum.iter().map(|&s| ((s + count / 2) / count) as u8));
+        }
+    }
+    Thumbnail { pixels: out, width: new_width, height: new_height, source }
+}
+
+/// JPEG thumbnail from IFD1 of EXIF data (the TIFF structure after `Exif\0\0`)
+fn exif_thumbnail(tiff: &[u8]) -> Option<&[u8]> {
+    let big_endian = match tiff.get(..4)? {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| tiff.get(pos..pos + 2).map(|b| if big_endian { (b[0] as usize) << 8 | b[1] as usize } else { (b[1] as usize) << 8 | b[0] as usize });
+    let u32_at = |pos: usize| tiff.get(pos..pos + 4).map(|b| {
+        let b = if big_endian { [b[0], b[1], b[2], b[3]] } else { [b[3], b[2], b[1], b[0]] };
+        (b[0] as usize) << 24 | (b[1] as usize) << 16 | (b[2] as usize) << 8 | b[3] as usize
+    });
+
+    let ifd0 = u32_at(4)?;
+    let ifd1 = u32_at(ifd0 + 2 + u16_at(ifd0)? * 12)?;
+    if ifd1 == 0 {
+        return None;
+    }
+    let (mut offset, mut len) = (None, None);
+    for i in 0..u16_at(ifd1)? {
+        let entry = ifd1 + 2 + i * 12;
+        match u16_at(entry)? {
+            // JPEGInterchangeFormat and JPEGInterchangeFormatLength
+            0x0201 => offset = u32_at(entry + 8),
+            0x0202 => len = u32_at(entry + 8),
+            _ => {},
+        }
+    }
+    let offset = offset?;
+    tiff.get(offset..offset.checked_add(len?)?)
+}
+
+#[test]
+fn exif_ifd1() {
+    let mut tiff = b"MM\0*\0\0\0\x08\0\0\0\0\0\x0e".to_vec();
+    tiff.extend_from_slice(b"\0\x02\x02\x01\0\x04\0\0\0\x01\0\0\0\x2c\x02\x02\0\x04\0\0\0\x01\0\0\0\x03\0\0\0\0");
+    tiff.extend_from_slice(b"abcd");
+    assert_eq!(Some(&b"abc"[..]), exif_thumbnail(&tiff));
+    assert_eq!(None, exif_thumbnail(&tiff[..20]));
+    assert_eq!((100, 67), fit(300, 200, 100));
+    assert_eq!((30, 20), fit(30, 20, 100));
+}
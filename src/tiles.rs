@@ -0,0 +1,78 @@
+use decompress::Decompress;
+use colorspace::ColorSpace;
+use std::io;
+
+/// Part of the image decoded by `decode_tiles()` or `decode_region()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tile {
+    /// Position of the top left corner in the image
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Tightly packed pixels in the requested color space
+    pub pixels: Vec<u8>,
+}
+
+/// Decode the image in `tile_size`×`tile_size` tiles, e.g. for map viewers and deep-zoom generators
+///
+/// Tiles are passed to `callback` row by row, left to right. Only one row of tiles is kept in memory.
+/// Tiles at the right and bottom edges are smaller if the image size isn't a multiple of `tile_size`.
+pub fn decode_tiles<F>(jpeg: &[u8], color_space: ColorSpace, tile_size: usize, mut callback: F) -> io::Result<()>
+    where F: FnMut(Tile)
+{
+    assert!(tile_size > 0);
+    let mut started = Decompress::new_mem(jpeg)?.start_as_color_space(color_space)?;
+    let width = started.width();
+    let height = started.height();
+    let pixel_size = started.output_components();
+    let mut band = Vec::with_capacity(width * tile_size * pixel_size);
+    for y in (0..height).step_by(tile_size) {
+        band.clear();
+        if !started.read_lines_into(&mut band, tile_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"));
+        }
+        let band_height = band.len() / (width * pixel_size);
+        for x in (0..width).step_by(tile_size) {
+            let tile_width = tile_size.min(width - x);
+            let mut pixels = Vec::with_capacity(tile_width * band_height * pixel_size);
+            for row in band.chunks_exact(width * pixel_size) {
+                pixels.extend_from_slice(&row[x * pixel_size..(x + tile_width) * pixel_size]);
+            }
+            callback(Tile {
+                x, y,
+                width: tile_width,
+                height: band_height,
+                pixels,
+            });
+        }
+    }
+    started.finish_decompress();
+    Ok(())
+}
+
+/// Decode only a rectangle of the image
+///
+/// Lines above the region are skipped, and columns outside it aren't fully decoded (see `crop_scanline()`),
+/// so this is much faster than decoding the whole image for small regions.
+pub fn decode_region(jpeg: &[u8], color_space: ColorSpace, x: usize, y: usize, width: usize, height: usize) -> io::Result<Tile> {
+    let mut started = Decompress::new_mem(jpeg)?.start_as_color_space(color_space)?;
+    if width == 0 || height == 0 || x + width > started.width() || y + height > started.height() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Region is outside the image"));
+    }
+    let crop = started.crop(x, width);
+    let pixel_size = started.output_components();
+    if started.skip_scanlines(y) != y {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"));
+    }
+    let mut lines = Vec::with_capacity(crop.width * height * pixel_size);
+    if !started.read_lines_into(&mut lines, height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Can't decode the image"));
+    }
+    // The cropped lines may start earlier than requested
+    let mut pixels = Vec::with_capacity(width * height * pixel_size);
+    for row in lines.chunks_exact(crop.width * pixel_size) {
+        pixels.extend_from_slice(crop.trim(row, pixel_size));
+    }
+    Ok(Tile { x, y, width, height, pixels })
+}
@@ -0,0 +1,641 @@
+use compress::{Compress, ScanMode};
+use decompress::Decompress;
+use decompress::{ALL_MARKERS, NO_MARKERS};
+use colorspace::ColorSpace;
+use colorspace::ColorSpaceExt;
+use marker::Marker;
+use component::CompInfoExt;
+use ffi;
+use ffi::JBLOCK;
+use ffi::DCTSIZE;
+use ffi::JDIMENSION;
+use std::cmp::{min, max};
+use std::io;
+
+/// Lossless operation on the image, same as `jpegtran` options
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Transform {
+    None,
+    /// Mirror left-right (`-flip horizontal`)
+    FlipHorizontal,
+    /// Mirror top-bottom (`-flip vertical`)
+    FlipVertical,
+    /// Mirror across the top-left to bottom-right diagonal (`-transpose`)
+    Transpose,
+    /// Mirror across the top-right to bottom-left diagonal (`-transverse`)
+    Transverse,
+    /// Rotate clockwise (`-rotate 90`)
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    /// Equivalent to transposing, and then mirroring the result horizontally and/or vertically
+    fn steps(self) -> (bool, bool, bool) {
+        match self {
+            Transform::None => (false, false, false),
+            Transform::FlipHorizontal => (false, true, false),
+            Transform::FlipVertical => (false, false, true),
+            Transform::Transpose => (true, false, false),
+            Transform::Transverse => (true, true, true),
+            Transform::Rotate90 => (true, true, false),
+            Transform::Rotate180 => (false, true, true),
+            Transform::Rotate270 => (true, false, true),
+        }
+    }
+}
+
+/// Quantized DCT blocks of one component, in natural (not zigzag) order
+///
+/// Rows of blocks are `width()` blocks wide. Blocks from `Decompress::read_coefficients()` cover
+/// the component exactly, but ones given to callbacks of transformations are padded to whole iMCUs,
+/// so there may be more blocks than needed to cover the image.
+pub struct Blocks {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) blocks: Vec<JBLOCK>,
+}
+
+impl Blocks {
+    pub fn new(width: usize, height: usize) -> Blocks {
+        Blocks {
+            width,
+            height,
+            blocks: vec![[0; 64]; width * height],
+        }
+    }
+
+    /// Number of blocks in a row
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Number of rows of blocks
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// All blocks, row by row
+    pub fn as_slice(&self) -> &[JBLOCK] {
+        &self.blocks
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [JBLOCK] {
+        &mut self.blocks
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&JBLOCK> {
+        if x < self.width && y < self.height {
+            Some(&self.blocks[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut JBLOCK> {
+        if x < self.width && y < self.height {
+            Some(&mut self.blocks[y * self.width + x])
+        } else {
+            None
+        }
+    }
+}
+
+/// Rectangle in pixels of the transformed image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crop {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Which markers of the source file are copied, like `jpegtran -copy`
+///
+/// JFIF and Adobe headers are not markers in this sense; libjpeg writes them itself when needed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopyMarkers {
+    /// Strip all metadata (`-copy none`)
+    None,
+    /// Keep only `COM` markers (`-copy comments`)
+    Comments,
+    /// Keep everything, the default (`-copy all`)
+    All,
+    /// Keep only markers in the list, e.g. `APP(2)` to keep the ICC profile, but not EXIF in `APP(1)`
+    Only(Vec<Marker>),
+}
+
+impl CopyMarkers {
+    pub(crate) fn markers(&self) -> &[Marker] {
+        match *self {
+            CopyMarkers::None => NO_MARKERS,
+            CopyMarkers::Comments => &[Marker::COM],
+            CopyMarkers::All => ALL_MARKERS,
+            CopyMarkers::Only(ref markers) => markers,
+        }
+    }
+}
+
+/// Settings for `transform_mem()`, like `jpegtran` command-line options
+///
+/// Huffman tables of the output are always optimized.
+///
+/// Like `jpegtran`, blocks of partial iMCUs at the right and bottom edges can't be moved
+/// to the opposite edge, so they stay where they were (transposed if needed).
+/// These edges are only transformed correctly in images with sizes that are multiples of the iMCU size
+/// (8 or 16 pixels, depending on chroma subsampling). See `with_trim()` and `with_perfect()` for other edge handling.
+pub struct TransformConfig {
+    transform: Transform,
+    crop: Option<Crop>,
+    perfect: bool,
+    trim: bool,
+    grayscale: bool,
+    progressive: bool,
+    scan_mode: ScanMode,
+    copy_markers: CopyMarkers,
+    wipes: Vec<Crop>,
+    drops: Vec<DropImage>,
+}
+
+/// Another JPEG inserted with `with_drop()`
+struct DropImage {
+    x: usize,
+    y: usize,
+    jpeg: Vec<u8>,
+}
+
+impl TransformConfig {
+    pub fn new(transform: Transform) -> TransformConfig {
+        TransformConfig {
+            transform,
+            crop: None,
+            perfect: false,
+            trim: false,
+            grayscale: false,
+            progressive: true,
+            scan_mode: ScanMode::Auto,
+            copy_markers: CopyMarkers::All,
+            wipes: Vec::new(),
+            drops: Vec::new(),
+        }
+    }
+
+    /// Keep only the luma component, making a smaller grayscale file (`-grayscale`)
+    ///
+    /// The file must be in YCbCr (or already grayscale) color space, with luma not subsampled.
+    pub fn with_grayscale(mut self, grayscale: bool) -> Self {
+        self.grayscale = grayscale;
+        self
+    }
+
+    /// Write a progressive file (the default), or baseline if `false`
+    ///
+    /// The entropy-coded data is rewritten from the same DCT coefficients, so there's no loss of quality.
+    /// This doesn't depend on whether the input file was progressive.
+    pub fn with_progressive(mut self, progressive: bool) -> Self {
+        self.progressive = progressive;
+        self
+    }
+
+    /// How DC coefficients are split into scans of the progressive file
+    pub fn with_scan_mode(mut self, mode: ScanMode) -> Self {
+        self.scan_mode = mode;
+        self
+    }
+
+    /// Choose which markers (EXIF, ICC profile, comments, etc.) are copied to the output
+    pub fn with_copy_markers(mut self, copy: CopyMarkers) -> Self {
+        self.copy_markers = copy;
+        self
+    }
+
+    /// Blank the area to neutral gray (`-wipe`), e.g. for redacting parts of the image
+    ///
+    /// The area is in pixels of the transformed (and cropped) image, and is extended outwards to iMCU boundaries.
+    /// Can be used several times.
+    pub fn with_wipe(mut self, area: Crop) -> Self {
+        self.wipes.push(area);
+        self
+    }
+
+    /// Insert another JPEG file at the given position (`-drop`)
+    ///
+    /// The position is in pixels of the transformed (and cropped) image, and is moved up and left to an iMCU boundary.
+    /// The inserted image must have the same chroma subsampling, or be grayscale (then the color is neutral).
+    /// If its quantization tables differ, its coefficients are requantized to the tables of the main image,
+    /// which loses some of its quality. Can be used several times.
+    pub fn with_drop(mut self, x: usize, y: usize, jpeg: &[u8]) -> Self {
+        self.drops.push(DropImage {
+            x, y,
+            jpeg: jpeg.to_vec(),
+        });
+        self
+    }
+
+    /// Fail if the transformation can't be done for the whole image (`-perfect`)
+    ///
+    /// That's when a partial iMCU would have to be moved to the opposite edge.
+    pub fn with_perfect(mut self, perfect: bool) -> Self {
+        self.perfect = perfect;
+        self
+    }
+
+    /// Drop partial iMCUs that can't be transformed from the edges, making the image slightly smaller (`-trim`)
+    pub fn with_trim(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Crop the transformed image (`-crop`)
+    ///
+    /// Blocks can't be split, so the top-left corner is moved up and left to the nearest iMCU boundary
+    /// (multiple of 8 or 16 pixels), and the size grows by the same amount. The rectangle is clipped to the image.
+    /// Use `actual_crop()` to find the rectangle that will be used.
+    pub fn with_crop(mut self, crop: Crop) -> Self {
+        self.crop = Some(crop);
+        self
+    }
+
+    /// Crop rectangle adjusted to block boundaries of the given file, or `None` if not cropping
+    ///
+    /// Fails if the rectangle is empty or outside the image.
+    pub fn actual_crop(&self, jpeg: &[u8]) -> io::Result<Option<Crop>> {
+        let dinfo = Decompress::new_mem(jpeg)?;
+        let geometry = self.geometry(&dinfo)?;
+        self.aligned_crop(&geometry)
+    }
+
+    /// Size of the transformed image, after checking `perfect` and applying `trim`
+    fn geometry(&self, dinfo: &Decompress) -> io::Result<Geometry> {
+        let (transpose, mirror_x, mirror_y) = self.transform.steps();
+        let mut geometry = Geometry::new(dinfo, transpose);
+        if self.grayscale {
+            let max_sampling = geometry.sampling.iter().fold((1, 1), |a, s| (max(a.0, s.0), max(a.1, s.1)));
+            let luma_only = match dinfo.color_space() {
+                ColorSpace::JCS_YCbCr | ColorSpace::JCS_GRAYSCALE => geometry.sampling[0] == max_sampling,
+                _ => false,
+            };
+            if !luma_only {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Can't convert {:?} to grayscale without decoding", dinfo.color_space())));
+            }
+            geometry.sampling.truncate(1);
+            geometry.sampling[0] = (1, 1);
+            geometry.imcu_width = DCTSIZE;
+            geometry.imcu_height = DCTSIZE;
+        }
+        let partial_x = mirror_x && geometry.width % geometry.imcu_width != 0;
+        let partial_y = mirror_y && geometry.height % geometry.imcu_height != 0;
+        if self.perfect && (partial_x || partial_y) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{:?} of {}x{} image isn't perfect, because size isn't a multiple of {}x{}",
+                    self.transform, geometry.width, geometry.height, geometry.imcu_width, geometry.imcu_height)));
+        }
+        if self.trim {
+            if partial_x && geometry.width > geometry.imcu_width {
+                geometry.width = geometry.width / geometry.imcu_width * geometry.imcu_width;
+            }
+            if partial_y && geometry.height > geometry.imcu_height {
+                geometry.height = geometry.height / geometry.imcu_height * geometry.imcu_height;
+            }
+        }
+        Ok(geometry)
+    }
+
+    fn aligned_crop(&self, geometry: &Geometry) -> io::Result<Option<Crop>> {
+        let crop = match self.crop {
+            Some(crop) => crop,
+            None => return Ok(None),
+        };
+        if crop.width == 0 || crop.height == 0 || crop.x >= geometry.width || crop.y >= geometry.height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Crop {}x{}+{}+{} is outside of the {}x{} image", crop.width, crop.height, crop.x, crop.y, geometry.width, geometry.height)));
+        }
+        let right = min(geometry.width, crop.x + crop.width);
+        let bottom = min(geometry.height, crop.y + crop.height);
+        let x = crop.x / geometry.imcu_width * geometry.imcu_width;
+        let y = crop.y / geometry.imcu_height * geometry.imcu_height;
+        Ok(Some(Crop {
+            x, y,
+            width: right - x,
+            height: bottom - y,
+        }))
+    }
+
+    /// Transform a JPEG file in memory. Markers selected by `with_copy_markers()` are copied as-is.
+    pub fn transform_mem(&self, jpeg: &[u8]) -> io::Result<Vec<u8>> {
+        self.transform_mem_with_callback(jpeg, |_, _| {})
+    }
+
+    /// Like `transform_mem()`, but `callback` can modify DCT coefficients before they're written
+    ///
+    /// It's called once for each component (with its index), after all other operations
+    /// (transform, crop, wipe, drop). Coefficients are quantized with the output file's quantization tables.
+    pub fn transform_mem_with_callback<F: FnMut(usize, &mut Blocks)>(&self, jpeg: &[u8], mut callback: F) -> io::Result<Vec<u8>> {
+        let mut dinfo = Decompress::with_markers(self.copy_markers.markers()).from_mem(jpeg)?;
+        let (transpose, mirror_x, mirror_y) = self.transform.steps();
+        let geometry = self.geometry(&dinfo)?;
+        let crop = self.aligned_crop(&geometry)?.unwrap_or(Crop {
+            x: 0, y: 0,
+            width: geometry.width,
+            height: geometry.height,
+        });
+        let src_blocks = dinfo.read_coefficients()?;
+
+        // Color space is replaced by one from the source
+        let mut comp = Compress::new(ColorSpace::JCS_GRAYSCALE);
+        unsafe {
+            ffi::jpeg_copy_critical_parameters(&dinfo.cinfo, &mut comp.cinfo);
+        }
+        if transpose {
+            transpose_parameters(&mut comp);
+        }
+        if self.grayscale {
+            let luma_qtable = comp.components()[0].quant_tbl_no;
+            comp.set_color_space(ColorSpace::JCS_GRAYSCALE);
+            comp.components_mut()[0].quant_tbl_no = luma_qtable;
+        }
+        comp.set_optimize_coding(true);
+        if self.progressive {
+            comp.set_scan_optimization_mode(self.scan_mode);
+            // The default scan script may have been made for a different number of components
+            comp.set_progressive_mode();
+        } else {
+            comp.set_optimize_scans(false);
+        }
+        comp.cinfo.image_width = crop.width as JDIMENSION;
+        comp.cinfo.image_height = crop.height as JDIMENSION;
+
+        let mut dst_blocks = geometry.sampling.iter().zip(&src_blocks).map(|(&(h, v), src)| {
+            // Blocks in whole iMCUs, which can be moved to the opposite edge
+            let mirror_width = geometry.width / geometry.imcu_width * h;
+            let mirror_height = geometry.height / geometry.imcu_height * v;
+            let x_offset = crop.x / geometry.imcu_width * h;
+            let y_offset = crop.y / geometry.imcu_height * v;
+
+            let blocks_width = div_round_up(crop.width * h, geometry.imcu_width);
+            let blocks_height = div_round_up(crop.height * v, geometry.imcu_height);
+            let mut dst = Blocks::new(round_up(blocks_width, h), round_up(blocks_height, v));
+            for y in 0..dst.height {
+                for x in 0..dst.width {
+                    let (sx, sy) = (x + x_offset, y + y_offset);
+                    let (sx, flip_x) = if mirror_x && sx < mirror_width {(mirror_width - 1 - sx, true)} else {(sx, false)};
+                    let (sy, flip_y) = if mirror_y && sy < mirror_height {(mirror_height - 1 - sy, true)} else {(sy, false)};
+                    let src_block = if transpose {src.get(sy, sx)} else {src.get(sx, sy)};
+                    if let Some(src_block) = src_block {
+                        dst.blocks[y * dst.width + x] = transform_block(src_block, transpose, flip_x, flip_y);
+                    }
+                }
+            }
+            dst
+        }).collect::<Vec<_>>();
+
+        for drop in &self.drops {
+            insert_drop(drop, &geometry, &comp, &mut dst_blocks)?;
+        }
+        for area in &self.wipes {
+            for (&(h, v), dst) in geometry.sampling.iter().zip(&mut dst_blocks) {
+                let x_end = min(dst.width, div_round_up(area.x + area.width, geometry.imcu_width) * h);
+                let y_end = min(dst.height, div_round_up(area.y + area.height, geometry.imcu_height) * v);
+                for y in area.y / geometry.imcu_height * v .. y_end {
+                    for x in area.x / geometry.imcu_width * h .. x_end {
+                        dst.blocks[y * dst.width + x] = [0; 64];
+                    }
+                }
+            }
+        }
+        for (i, dst) in dst_blocks.iter_mut().enumerate() {
+            callback(i, dst);
+        }
+
+        comp.set_mem_dest();
+        {
+            let mut started = comp.start_compress_coefficients(&dst_blocks);
+            started.copy_markers(dinfo.markers());
+            started.finish_compress();
+        }
+        comp.data_to_vec().map_err(|_| io::Error::new(io::ErrorKind::Other, "No data written"))
+    }
+}
+
+/// Size and sampling of the transformed image
+struct Geometry {
+    width: usize,
+    height: usize,
+    imcu_width: usize,
+    imcu_height: usize,
+    /// Per component
+    sampling: Vec<(usize, usize)>,
+}
+
+impl Geometry {
+    fn new(dinfo: &Decompress, transpose: bool) -> Geometry {
+        let (width, height) = dinfo.size();
+        let components = dinfo.components();
+        let mut sampling = components.iter().map(|c| (c.h_samp_factor as usize, c.v_samp_factor as usize)).collect::<Vec<_>>();
+        // A single component isn't interleaved, so every block is an MCU
+        if sampling.len() == 1 {
+            sampling[0] = (1, 1);
+        }
+        if transpose {
+            for s in &mut sampling {
+                *s = (s.1, s.0);
+            }
+        }
+        let max_h = sampling.iter().map(|s| s.0).fold(1, max);
+        let max_v = sampling.iter().map(|s| s.1).fold(1, max);
+        let (width, height) = if transpose {(height, width)} else {(width, height)};
+        Geometry {
+            width, height,
+            imcu_width: max_h * DCTSIZE,
+            imcu_height: max_v * DCTSIZE,
+            sampling,
+        }
+    }
+}
+
+/// Transform a JPEG file in memory without recompressing it
+pub fn transform_mem(jpeg: &[u8], transform: Transform) -> io::Result<Vec<u8>> {
+    TransformConfig::new(transform).transform_mem(jpeg)
+}
+
+/// Make a JPEG file smaller without any loss of quality (like `jpegtran -optimize`)
+///
+/// The pixels stay exactly the same. Only the entropy coding is redone with optimized Huffman tables,
+/// and with progressive scans if `progressive` is `true`. Metadata is kept.
+pub fn optimize_mem(jpeg: &[u8], progressive: bool) -> io::Result<Vec<u8>> {
+    TransformConfig::new(Transform::None).with_progressive(progressive).transform_mem(jpeg)
+}
+
+/// Replace a rectangle of a JPEG file with new pixels, without recompressing the rest of the image
+///
+/// Only the patched area is compressed, with the same quantization tables and subsampling as the file,
+/// and its blocks are inserted as with `TransformConfig::with_drop()`. The rectangle must be aligned
+/// to iMCU boundaries (multiples of 8 or 16 pixels, depending on chroma subsampling),
+/// except where it reaches the right or bottom edge of the image.
+///
+/// `pixels` are tightly packed RGB (or grayscale for grayscale files, CMYK for CMYK and YCCK files).
+pub fn patch_mem(jpeg: &[u8], area: Crop, pixels: &[u8]) -> io::Result<Vec<u8>> {
+    let dinfo = Decompress::new_mem(jpeg)?;
+    let geometry = Geometry::new(&dinfo, false);
+    let aligned = |pos: usize, len: usize, imcu: usize, size: usize| {
+        len > 0 && pos + len <= size && pos % imcu == 0 && (len % imcu == 0 || pos + len == size)
+    };
+    if !aligned(area.x, area.width, geometry.imcu_width, geometry.width) ||
+       !aligned(area.y, area.height, geometry.imcu_height, geometry.height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Patch must be within the image and aligned to iMCU boundaries"));
+    }
+
+    let color_space = dinfo.color_space();
+    let input_color_space = match color_space {
+        ColorSpace::JCS_GRAYSCALE => ColorSpace::JCS_GRAYSCALE,
+        ColorSpace::JCS_CMYK | ColorSpace::JCS_YCCK => ColorSpace::JCS_CMYK,
+        _ => ColorSpace::JCS_RGB,
+    };
+    if pixels.len() != area.width * area.height * input_color_space.num_components() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Pixels don't match size of the patch"));
+    }
+
+    let mut comp = Compress::new(input_color_space);
+    comp.set_color_space(color_space);
+    if comp.components().len() != dinfo.components().len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't patch {:?} files", color_space)));
+    }
+    for (c, src) in comp.components_mut().iter_mut().zip(dinfo.components()) {
+        c.h_samp_factor = src.h_samp_factor;
+        c.v_samp_factor = src.v_samp_factor;
+    }
+    let qtables = dinfo.qtables();
+    if let Some(Some(luma)) = qtables.first() {
+        comp.set_luma_qtable(luma);
+    }
+    if let Some(Some(chroma)) = qtables.get(1) {
+        comp.set_chroma_qtable(chroma);
+    }
+    comp.set_size(area.width, area.height);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(pixels));
+        started.finish_compress();
+    }
+    let patch = comp.data_to_vec().map_err(|_| io::Error::new(io::ErrorKind::Other, "No data written"))?;
+
+    TransformConfig::new(Transform::None).with_drop(area.x, area.y, &patch).transform_mem(jpeg)
+}
+
+/// Copy blocks of another image, see `TransformConfig::with_drop()`
+fn insert_drop(drop: &DropImage, geometry: &Geometry, comp: &Compress, dst_blocks: &mut [Blocks]) -> io::Result<()> {
+    let mut dinfo = Decompress::new_mem(&drop.jpeg)?;
+    let drop_geometry = Geometry::new(&dinfo, false);
+    // Components must have blocks of the same size in pixels
+    let block_size = |g: &Geometry, (h, v): (usize, usize)| (g.imcu_width / h, g.imcu_height / v);
+    let compatible = drop_geometry.sampling.len() <= geometry.sampling.len() &&
+        drop_geometry.sampling.iter().zip(&geometry.sampling)
+            .all(|(&d, &s)| block_size(&drop_geometry, d) == block_size(geometry, s));
+    if !compatible {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Inserted image has incompatible components or subsampling"));
+    }
+
+    let drop_blocks = dinfo.read_coefficients()?;
+    let drop_qtables = dinfo.components().iter().map(|c| c.qtable()).collect::<Vec<_>>();
+    for (ci, (&(h, v), dst)) in geometry.sampling.iter().zip(dst_blocks).enumerate() {
+        let x_offset = drop.x / geometry.imcu_width * h;
+        let y_offset = drop.y / geometry.imcu_height * v;
+        if let Some(src) = drop_blocks.get(ci) {
+            let dst_qtable = unsafe { comp.cinfo.quant_tbl_ptrs[comp.components()[ci].quant_tbl_no as usize].as_ref() };
+            let requant = match (&drop_qtables[ci], dst_qtable) {
+                (Some(src_q), Some(dst_q)) => Some((src_q.as_slice(), &dst_q.quantval[..])),
+                _ => None,
+            };
+            for y in 0..min(src.height, dst.height.saturating_sub(y_offset)) {
+                for x in 0..min(src.width, dst.width.saturating_sub(x_offset)) {
+                    let mut block = src.blocks[y * src.width + x];
+                    if let Some((src_q, dst_q)) = requant {
+                        requantize_block(&mut block, src_q, dst_q);
+                    }
+                    dst.blocks[(y + y_offset) * dst.width + x + x_offset] = block;
+                }
+            }
+        } else {
+            // Neutral color for components missing in a grayscale image
+            let x_end = min(dst.width, x_offset + div_round_up(drop_geometry.width, geometry.imcu_width) * h);
+            let y_end = min(dst.height, y_offset + div_round_up(drop_geometry.height, geometry.imcu_height) * v);
+            for y in y_offset..y_end {
+                for x in x_offset..x_end {
+                    dst.blocks[y * dst.width + x] = [0; 64];
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Same rounding as in libjpeg's `transupp.c`
+fn requantize_block(block: &mut JBLOCK, src_qtable: &[u32], dst_qtable: &[u16]) {
+    for ((coef, &src_q), &dst_q) in block.iter_mut().zip(src_qtable).zip(dst_qtable) {
+        let (src_q, dst_q) = (src_q as i32, dst_q as i32);
+        if src_q != dst_q && dst_q != 0 {
+            let value = *coef as i32 * src_q;
+            let quantized = (value.abs() + dst_q / 2) / dst_q;
+            *coef = (if value < 0 {-quantized} else {quantized}) as i16;
+        }
+    }
+}
+
+/// Swap width with height, and horizontal with vertical sampling and quantization
+fn transpose_parameters(comp: &mut Compress) {
+    ::std::mem::swap(&mut comp.cinfo.image_width, &mut comp.cinfo.image_height);
+    for c in comp.components_mut() {
+        ::std::mem::swap(&mut c.h_samp_factor, &mut c.v_samp_factor);
+    }
+    for &qtable in comp.cinfo.quant_tbl_ptrs.iter() {
+        if let Some(qtable) = unsafe { qtable.as_mut() } {
+            for i in 0..DCTSIZE {
+                for j in 0..i {
+                    qtable.quantval.swap(i * DCTSIZE + j, j * DCTSIZE + i);
+                }
+            }
+        }
+    }
+}
+
+/// Mirroring pixels in a block flips the sign of odd frequencies
+fn transform_block(src: &JBLOCK, transpose: bool, flip_x: bool, flip_y: bool) -> JBLOCK {
+    let mut out = [0; 64];
+    for row in 0..DCTSIZE {
+        for col in 0..DCTSIZE {
+            let coef = if transpose {src[col * DCTSIZE + row]} else {src[row * DCTSIZE + col]};
+            let negate = (flip_x && col % 2 == 1) != (flip_y && row % 2 == 1);
+            out[row * DCTSIZE + col] = if negate {-coef} else {coef};
+        }
+    }
+    out
+}
+
+fn div_round_up(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
+fn round_up(a: usize, b: usize) -> usize {
+    div_round_up(a, b) * b
+}
+
+#[test]
+fn block_ops() {
+    let block = {
+        let mut b = [0; 64];
+        for (i, c) in b.iter_mut().enumerate() {
+            *c = i as i16;
+        }
+        b
+    };
+    assert_eq!(&block[..], &transform_block(&block, false, false, false)[..]);
+    assert_eq!(8, transform_block(&block, true, false, false)[1]);
+    assert_eq!(-1, transform_block(&block, false, true, false)[1]);
+    assert_eq!(8, transform_block(&block, false, true, false)[8]);
+    assert_eq!(-8, transform_block(&block, false, false, true)[8]);
+    assert_eq!(9, transform_block(&block, false, true, true)[9]);
+}
@@ -1,4 +1,24 @@
 extern crate mozjpeg;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "image")]
+extern crate image;
+#[cfg(feature = "rgb")]
+extern crate rgb;
+#[cfg(feature = "imgref")]
+extern crate imgref;
+#[cfg(feature = "ndarray")]
+extern crate ndarray;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "futures")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 use mozjpeg::CompInfoExt;
 
 pub fn decompress_jpeg(jpeg: &[u8]) -> Vec<Vec<u8>> {
@@ -26,18 +46,39 @@ fn color_jpeg() {
         comp.set_scan_optimization_mode(mozjpeg::ScanMode::AllComponentsTogether);
         comp.set_size(size, size);
         comp.set_mem_dest();
-        comp.start_compress();
+        let mut started = comp.start_compress();
 
         let lines = vec![128; size*size*3];
-        assert!(comp.write_scanlines(&lines[..]));
+        assert!(started.write_scanlines(&lines[..]));
 
-        comp.finish_compress();
+        started.finish_compress();
         let jpeg = comp.data_to_vec().unwrap();
 
         decompress_jpeg(&jpeg);
     }
 }
 
+#[test]
+fn gray_jpeg() {
+    for size in 1..64 {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+
+        comp.set_size(size, size);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+
+        let lines = vec![128; size*size];
+        assert!(started.write_gray_scanlines(&lines[..]));
+
+        started.finish_compress();
+        let jpeg = comp.data_to_vec().unwrap();
+
+        let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+        assert_eq!(mozjpeg::ColorSpace::JCS_GRAYSCALE, decomp.color_space());
+        assert_eq!(1, decompress_jpeg(&jpeg).len());
+    }
+}
+
 #[test]
 fn raw_jpeg() {
     for size in 1..64 {
@@ -49,16 +90,2757 @@ fn raw_jpeg() {
         comp.set_size(size, size);
 
         comp.set_mem_dest();
-        comp.start_compress();
+        let mut started = comp.start_compress();
 
         let rounded_size = (size+7)/8*8;
         let t = vec![128; rounded_size*rounded_size];
         let components = vec![&t[..], &t[..], &t[..]];
-        comp.write_raw_data(&components[..]);
+        started.write_raw_data(&components[..]);
+
+        started.finish_compress();
+        let jpeg = comp.data_to_vec().unwrap();
+
+        decompress_jpeg(&jpeg);
+    }
+}
+
+#[test]
+fn cmyk_jpeg() {
+    for &jpeg_color_space in &[mozjpeg::ColorSpace::JCS_CMYK, mozjpeg::ColorSpace::JCS_YCCK] {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_CMYK);
 
-        comp.finish_compress();
+        comp.set_color_space(jpeg_color_space);
+        comp.set_size(16, 16);
+        comp.set_quality(95.);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+
+        let lines = [10u8, 60, 120, 200].iter().cloned().cycle().take(16*16*4).collect::<Vec<_>>();
+        assert!(started.write_scanlines(&lines[..]));
+
+        started.finish_compress();
         let jpeg = comp.data_to_vec().unwrap();
 
+        let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+        assert_eq!(jpeg_color_space, decomp.color_space());
+
+        let mut decomp = decomp.cmyk().unwrap();
+        assert_eq!(mozjpeg::ColorSpace::JCS_CMYK, decomp.color_space());
+        let pixels: Vec<[u8; 4]> = decomp.read_pixels().unwrap();
+        assert_eq!(16*16, pixels.len());
+        for px in pixels {
+            for (&c, &expected) in px.iter().zip(&[10u8, 60, 120, 200]) {
+                assert!((c as i16 - expected as i16).abs() < 8, "{:?}", px);
+            }
+        }
+        assert!(decomp.finish_decompress());
+    }
+}
+
+#[test]
+fn unknown_jpeg() {
+    let mut comp = mozjpeg::Compress::new_unknown(2);
+
+    comp.set_size(16, 8);
+    comp.set_quality(95.);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+
+    let lines = [30u8, 220].iter().cloned().cycle().take(16*8*2).collect::<Vec<_>>();
+    assert!(started.write_scanlines(&lines[..]));
+
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!(mozjpeg::ColorSpace::JCS_UNKNOWN, decomp.color_space());
+    assert_eq!(2, decomp.components().len());
+
+    let mut decomp = decomp.passthrough().unwrap();
+    let pixels: Vec<[u8; 2]> = decomp.read_pixels().unwrap();
+    assert_eq!(16*8, pixels.len());
+    for px in pixels {
+        assert!((px[0] as i16 - 30).abs() < 4 && (px[1] as i16 - 220).abs() < 4, "{:?}", px);
+    }
+    assert!(decomp.finish_decompress());
+}
+
+#[test]
+fn stride_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+
+    comp.set_size(10, 4);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+
+    // 10 pixels of image data followed by 6 bytes of padding, last row unpadded
+    let mut lines = Vec::new();
+    for _ in 0..3 {
+        lines.extend_from_slice(&[200; 10]);
+        lines.extend_from_slice(&[0; 6]);
+    }
+    lines.extend_from_slice(&[200; 10]);
+    assert!(started.write_scanlines_with_stride(&lines[..], 16));
+
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let mut decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap().grayscale().unwrap();
+    let pixels: Vec<u8> = decomp.read_scanlines_flat().unwrap().0;
+    assert_eq!(10*4, pixels.len());
+    assert!(pixels.iter().all(|&px| (px as i16 - 200).abs() < 4), "{:?}", pixels);
+}
+
+#[test]
+fn bottom_up_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+
+    comp.set_size(8, 16);
+    comp.set_bottom_up(true);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+
+    // bottom half black, top half white
+    let mut lines = vec![0; 8*8];
+    lines.extend_from_slice(&[255; 8*8]);
+    assert!(started.write_scanlines(&lines[..]));
+
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let mut decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap().grayscale().unwrap();
+    let pixels: Vec<u8> = decomp.read_scanlines_flat().unwrap().0;
+    assert!(pixels[0] > 200);
+    assert!(pixels[pixels.len()-1] < 50);
+}
+
+#[test]
+fn rows_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+
+    comp.set_size(7, 33);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+
+    let row = [100u8; 7*3];
+    assert!(started.write_rows((0..33).map(|_| &row[..])));
+
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!((7, 33), decomp.size());
+}
+
+#[test]
+fn reader_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+
+    comp.set_size(20, 50);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+
+    let pixels = vec![77u8; 20*50*3];
+    started.write_scanlines_from_reader(&pixels[..]).unwrap();
+
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!((20, 50), decomp.size());
+
+    // Rows that were read would be lost if the destination suspended
+    comp.set_buffer_dest(100);
+    let mut started = comp.start_compress();
+    let err = started.write_scanlines_from_reader(&pixels[..]).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn reuse_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(16, 16);
+    comp.set_quality(60.);
+
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&vec![50; 16*3*8][..]));
+    started.abort_compress();
+    assert!(comp.data_to_vec().is_err());
+
+    // Abort after libjpeg has reallocated its buffer
+    let mut big = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    big.set_fastest_defaults();
+    big.set_optimize_coding(false);
+    big.set_size(256, 256);
+    big.set_mem_dest();
+    let noise = (0..256*128*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let mut started = big.start_compress();
+    assert!(started.write_scanlines(&noise));
+    drop(started);
+    assert!(big.data_to_vec().is_err());
+
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&vec![50; 16*16*3][..]));
+        started.finish_compress();
+        outputs.push(comp.data_to_vec().unwrap());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    decompress_jpeg(&outputs[1]);
+}
+
+fn has_marker(jpeg: &[u8], marker: u8) -> bool {
+    jpeg.windows(2).any(|w| w[0] == 0xFF && w[1] == marker)
+}
+
+#[test]
+fn abbreviated_jpeg() {
+    const DQT: u8 = 0xDB;
+    const SOF0: u8 = 0xC0;
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_fastest_defaults();
+    comp.set_size(16, 16);
+
+    comp.set_mem_dest();
+    comp.write_tables();
+    let tables = comp.data_to_vec().unwrap();
+    assert!(has_marker(&tables, DQT));
+    assert!(!has_marker(&tables, SOF0));
+
+    comp.set_mem_dest();
+    let mut started = comp.start_compress_abbreviated();
+    assert!(started.write_scanlines(&vec![50; 16*16*3][..]));
+    started.finish_compress();
+    let frame = comp.data_to_vec().unwrap();
+    assert!(!has_marker(&frame, DQT));
+    assert!(has_marker(&frame, SOF0));
+}
+
+#[test]
+fn target_size_jpeg() {
+    let pixels = (0..64*64*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(64, 64);
+
+    let (large, large_q) = comp.target_size(&pixels, 8000).unwrap();
+    let (small, small_q) = comp.target_size(&pixels, 3000).unwrap();
+    assert!(large.len() <= 8000);
+    assert!(small.len() <= 3000);
+    assert!(small_q < large_q);
+    decompress_jpeg(&small);
+
+    assert!(comp.target_size(&pixels, 10).is_none());
+}
+
+#[test]
+fn quality_ladder_jpeg() {
+    let pixels = (0..64*64*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(64, 64);
+
+    let outputs = comp.quality_ladder(&pixels, &[40., 60., 80.]);
+    assert_eq!(3, outputs.len());
+    assert!(outputs[0].len() < outputs[1].len());
+    assert!(outputs[1].len() < outputs[2].len());
+    for data in &outputs {
+        decompress_jpeg(data);
+    }
+}
+
+#[test]
+#[cfg(feature = "metrics")]
+fn metrics_jpeg() {
+    use mozjpeg::metrics;
+
+    let original = std::fs::read("tests/test.jpg").unwrap();
+    let (pixels, width, height) = mozjpeg::decode_rgb(&original).unwrap();
+    let high = mozjpeg::encode_rgb(&pixels, width, height, 95.).unwrap();
+    let low = mozjpeg::encode_rgb(&pixels, width, height, 20.).unwrap();
+
+    let same = metrics::compare_jpegs(&original, &original).unwrap();
+    assert_eq!(f64::INFINITY, same.psnr);
+    assert_eq!(1., same.ssim);
+    let high = metrics::compare_jpegs(&original, &high).unwrap();
+    let low = metrics::compare_jpegs(&original, &low).unwrap();
+    assert!(high.psnr > low.psnr && high.ssim > low.ssim, "{:?} {:?}", high, low);
+    assert!(high.dssim() < low.dssim());
+
+    let small = mozjpeg::encode_rgb(&pixels[..width * 3 * 10], width, 10, 90.).unwrap();
+    assert!(metrics::compare_jpegs(&original, &small).is_err());
+}
+
+#[test]
+#[cfg(feature = "perceptual")]
+fn perceptual_jpeg() {
+    let pixels = (0..64*64*3).map(|i| ((i / 3) % 64 * 4) as u8).collect::<Vec<_>>();
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(64, 64);
+
+    let (_, strict_q) = comp.perceptual_quality(&pixels, 0.0001);
+    let (_, loose_q) = comp.perceptual_quality(&pixels, 0.05);
+    assert!(loose_q < strict_q);
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn tune_jpeg() {
+    let pixels = (0..32*32*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let mut sizes = Vec::new();
+    for &tune in &[mozjpeg::Tune::Psnr, mozjpeg::Tune::Ssim, mozjpeg::Tune::MsSsim, mozjpeg::Tune::HvsPsnr] {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_tune(tune);
+        comp.set_quality(75.);
+        comp.set_size(32, 32);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        let jpeg = comp.data_to_vec().unwrap();
         decompress_jpeg(&jpeg);
+        sizes.push(jpeg.len());
+    }
+    assert!(sizes[0] != sizes[3]);
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn profile_jpeg() {
+    let pixels = (0..64*64*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let sizes = [mozjpeg::CompressProfile::Fastest, mozjpeg::CompressProfile::MaxCompression].iter().map(|&profile| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_compress_profile(profile);
+        comp.set_size(64, 64);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        comp.data_to_vec().unwrap().len()
+    }).collect::<Vec<_>>();
+    assert!(sizes[1] < sizes[0]);
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn qtables_scaled_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(8, 8);
+    comp.set_force_baseline(true);
+    comp.set_default_qtables_scaled(50, 200);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[128; 8*8*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+    let luma = decomp.components()[0].qtable().unwrap();
+    let chroma = decomp.components()[1].qtable().unwrap();
+    assert_eq!(luma, mozjpeg::qtable::NRobidoux.scaled(75., 75.));
+    assert_eq!(chroma, mozjpeg::qtable::NRobidoux.scaled(25., 25.));
+}
+
+#[test]
+fn force_baseline_jpeg() {
+    for &force_baseline in &[false, true] {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+        comp.set_size(8, 8);
+        comp.set_force_baseline(force_baseline);
+        comp.set_quality(1.);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[128; 8*8]));
+        started.finish_compress();
+        let jpeg = comp.data_to_vec().unwrap();
+
+        let decomp = mozjpeg::Decompress::new_mem(&jpeg).unwrap().grayscale().unwrap();
+        let max_coef = decomp.components()[0].qtable().unwrap().as_slice().iter().cloned().max().unwrap();
+        assert_eq!(force_baseline, max_coef <= 255);
+    }
+
+    // Explicit tables are limited unless asked otherwise
+    let low_quality = |configure: &dyn Fn(&mut mozjpeg::Compress)| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+        comp.set_size(8, 8);
+        configure(&mut comp);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[128; 8*8]));
+        started.finish_compress();
+        let jpeg = comp.data_to_vec().unwrap();
+        let table = mozjpeg::Decompress::new_mem(&jpeg).unwrap().qtables().remove(0).unwrap();
+        table
+    };
+    let large = low_quality(&|comp| comp.set_quality(1.));
+    assert!(large.as_slice().iter().any(|&q| q > 255));
+    let limited = low_quality(&|comp| comp.set_luma_qtable(&large));
+    assert!(limited.as_slice().iter().all(|&q| q <= 255));
+    let unlimited = low_quality(&|comp| { comp.set_force_baseline(false); comp.set_luma_qtable(&large) });
+    assert_eq!(large, unlimited);
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn dc_scan_mode_jpeg() {
+    const SOS: u8 = 0xDA;
+
+    let pixels = (0..32*32*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let scans = [mozjpeg::ScanMode::AllComponentsTogether, mozjpeg::ScanMode::ScanPerComponent].iter().map(|&mode| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(32, 32);
+        comp.set_optimize_scans(false);
+        comp.set_progressive_mode();
+        comp.set_scan_optimization_mode(mode);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        let jpeg = comp.data_to_vec().unwrap();
+        jpeg.windows(2).filter(|w| w[0] == 0xFF && w[1] == SOS).count()
+    }).collect::<Vec<_>>();
+    assert!(scans[0] < scans[1], "{:?}", scans);
+
+    // Doesn't replace a custom script
+    let script = mozjpeg::ScanScript::new()
+        .add_scan(&[0, 1, 2], 0..=0, 0, 0)
+        .add_scan(&[0], 1..=63, 0, 0)
+        .add_scan(&[1], 1..=63, 0, 0)
+        .add_scan(&[2], 1..=63, 0, 0);
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_scan_script(&script).unwrap();
+    comp.set_scan_optimization_mode(mozjpeg::ScanMode::ScanPerComponent);
+    assert_eq!(Some(script), comp.scan_script());
+}
+
+#[test]
+fn progress_jpeg() {
+    use std::cell::Cell;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    let calls = Rc::new(Cell::new(0));
+    let calls2 = calls.clone();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(16, 16);
+    comp.set_progress_callback(move |progress| {
+        assert!(progress.fraction() >= 0. && progress.fraction() <= 1.);
+        calls2.set(calls2.get() + 1);
+        calls2.get() < 5
+    });
+
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        started.write_scanlines(&[0; 16*16*3]);
+        started.finish_compress();
+    }));
+    assert!(res.unwrap_err().downcast::<mozjpeg::Cancelled>().is_ok());
+    assert_eq!(5, calls.get());
+
+    comp.set_progress_callback(|_| true);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[0; 16*16*3]));
+    started.finish_compress();
+    decompress_jpeg(&comp.data_to_vec().unwrap());
+
+    // A panic of the callback is raised once libjpeg returns
+    comp.set_progress_callback(|_| panic!("callback panicked"));
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        started.write_scanlines(&[0; 16*16*3]);
+        started.finish_compress();
+    }));
+    assert_eq!(Some(&"callback panicked"), res.unwrap_err().downcast_ref::<&str>());
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn suspending_jpeg() {
+    let pixels = (0..64*64).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let compress = || {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+        comp.set_fastest_defaults();
+        comp.set_size(64, 64);
+        comp
+    };
+
+    let mut comp = compress();
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let expected = comp.data_to_vec().unwrap();
+
+    let mut comp = compress();
+    comp.set_buffer_dest(100);
+    let mut started = comp.start_compress();
+    let mut output = started.flush();
+    let mut suspensions = 0;
+    while started.next_scanline() < 64 {
+        let start = started.next_scanline() * 64;
+        let end = (start + 8*64).min(pixels.len());
+        if !started.write_scanlines(&pixels[start..end]) {
+            suspensions += 1;
+        }
+        output.extend(started.flush());
+    }
+    started.finish_compress();
+    output.extend(comp.flush());
+
+    assert!(suspensions > 0);
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn deterministic_jpeg() {
+    fn encode() -> Vec<u8> {
+        encode_with(false)
+    }
+    fn encode_with(deterministic: bool) -> Vec<u8> {
+        let pixels = (0..64*64*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_deterministic(deterministic);
+        assert_eq!(deterministic, comp.deterministic());
+        comp.set_size(64, 64);
+        comp.set_quality(80.);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    }
+
+    let expected = encode();
+    assert_eq!(expected, encode());
+    let threads = (0..4).map(|_| std::thread::spawn(encode)).collect::<Vec<_>>();
+    for t in threads {
+        assert_eq!(expected, t.join().unwrap());
+    }
+    // The default DCT is already the integer one
+    assert_eq!(expected, encode_with(true));
+}
+
+#[test]
+fn config_error_jpeg() {
+    use mozjpeg::ConfigError;
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    assert_eq!(Err(ConfigError::InvalidSize { width: 0, height: 0 }), comp.validate());
+    comp.set_size(16, 16);
+    assert_eq!(Ok(()), comp.validate());
+
+    comp.set_color_space(mozjpeg::ColorSpace::JCS_CMYK);
+    assert!(comp.validate().is_err());
+    comp.set_color_space(mozjpeg::ColorSpace::JCS_YCbCr);
+    assert_eq!(Ok(()), comp.validate());
+
+    comp.components_mut()[0].h_samp_factor = 5;
+    assert!(matches!(comp.validate(), Err(ConfigError::InvalidSamplingFactor { component: 0, .. })));
+    comp.components_mut()[0].h_samp_factor = 3;
+    comp.components_mut()[1].h_samp_factor = 2;
+    assert_eq!(Err(ConfigError::FractionalSampling { component: 1 }), comp.validate());
+    comp.components_mut()[0].h_samp_factor = 4;
+    comp.components_mut()[0].v_samp_factor = 4;
+    assert_eq!(Err(ConfigError::TooManyBlocksInMcu { blocks: 19 }), comp.validate());
+    assert!(comp.try_start_compress().is_err());
+
+    comp.components_mut()[0].h_samp_factor = 2;
+    comp.components_mut()[0].v_samp_factor = 2;
+    comp.components_mut()[1].h_samp_factor = 1;
+    comp.set_mem_dest();
+    let mut started = comp.try_start_compress().unwrap();
+    assert!(started.write_scanlines(&[0; 16*16*3]));
+    started.finish_compress();
+    decompress_jpeg(&comp.data_to_vec().unwrap());
+}
+
+#[test]
+fn transform_jpeg() {
+    use mozjpeg::transform::{transform_mem, Transform};
+
+    let (width, height) = (32, 16);
+    let pixels = (0..width*height).map(|i| ((i % width) * 7 + (i / width) * 3) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    comp.set_size(width, height);
+    comp.set_quality(98.);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+    let decode = |jpeg: &[u8]| {
+        let mut decomp = mozjpeg::Decompress::new_mem(jpeg).unwrap().grayscale().unwrap();
+        let size = (decomp.width(), decomp.height());
+        (size, decomp.read_scanlines_flat().unwrap().0)
+    };
+    let (_, original) = decode(&jpeg);
+    let at = |x: usize, y: usize| original[y * width + x] as i32;
+
+    // Pixel of the original image expected at (x, y) of the transformed one
+    type Expected<'a> = &'a dyn Fn(usize, usize) -> i32;
+    let cases: [(Transform, Expected); 8] = [
+        (Transform::None, &|x, y| at(x, y)),
+        (Transform::FlipHorizontal, &|x, y| at(width - 1 - x, y)),
+        (Transform::FlipVertical, &|x, y| at(x, height - 1 - y)),
+        (Transform::Transpose, &|x, y| at(y, x)),
+        (Transform::Transverse, &|x, y| at(width - 1 - y, height - 1 - x)),
+        (Transform::Rotate90, &|x, y| at(y, height - 1 - x)),
+        (Transform::Rotate180, &|x, y| at(width - 1 - x, height - 1 - y)),
+        (Transform::Rotate270, &|x, y| at(width - 1 - y, x)),
+    ];
+    for &(transform, expected) in &cases {
+        let transformed = transform_mem(&jpeg, transform).unwrap();
+        let ((w, h), pixels) = decode(&transformed);
+        assert_eq!(width * height, w * h);
+        assert_eq!(w == height, w != width, "{:?}", transform);
+        for (i, &px) in pixels.iter().enumerate() {
+            assert!((px as i32 - expected(i % w, i / w)).abs() <= 2, "{:?} at {},{}", transform, i % w, i / w);
+        }
+    }
+
+    let rgb = vec![100; 40*24*3];
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(40, 24);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&rgb));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+    let rotated = transform_mem(&jpeg, Transform::Rotate90).unwrap();
+    let decomp = mozjpeg::Decompress::new_mem(&rotated).unwrap();
+    assert_eq!((24, 40), decomp.size());
+    let pixels: Vec<[u8; 3]> = decomp.rgb().unwrap().read_pixels().unwrap();
+    assert!(pixels.iter().all(|px| px.iter().all(|&c| (c as i32 - 100).abs() <= 2)));
+}
+
+#[test]
+fn crop_jpeg() {
+    use mozjpeg::transform::{Crop, Transform, TransformConfig};
+
+    let (width, height) = (32, 24);
+    let pixels = (0..width*height).map(|i| ((i % width) * 7 + (i / width) * 3) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    comp.set_size(width, height);
+    comp.set_quality(98.);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let config = TransformConfig::new(Transform::None).with_crop(Crop { x: 10, y: 3, width: 12, height: 30 });
+    let crop = config.actual_crop(&jpeg).unwrap().unwrap();
+    assert_eq!(Crop { x: 8, y: 0, width: 14, height: 24 }, crop);
+
+    let cropped = config.transform_mem(&jpeg).unwrap();
+    let mut decomp = mozjpeg::Decompress::new_mem(&cropped).unwrap().grayscale().unwrap();
+    assert_eq!((14, 24), (decomp.width(), decomp.height()));
+    let cropped_pixels = decomp.read_scanlines_flat().unwrap().0;
+    for (i, &px) in cropped_pixels.iter().enumerate() {
+        let original = pixels[(i / 14) * width + 8 + i % 14];
+        assert!((px as i32 - original as i32).abs() <= 3);
+    }
+
+    let rotated = TransformConfig::new(Transform::Rotate90).with_crop(Crop { x: 0, y: 17, width: 24, height: 8 });
+    assert_eq!(Crop { x: 0, y: 16, width: 24, height: 9 }, rotated.actual_crop(&jpeg).unwrap().unwrap());
+    let rotated = rotated.transform_mem(&jpeg).unwrap();
+    assert_eq!((24, 9), mozjpeg::Decompress::new_mem(&rotated).unwrap().size());
+
+    let outside = TransformConfig::new(Transform::None).with_crop(Crop { x: 40, y: 0, width: 8, height: 8 });
+    assert!(outside.actual_crop(&jpeg).is_err());
+    assert!(outside.transform_mem(&jpeg).is_err());
+}
+
+#[test]
+fn perfect_trim_jpeg() {
+    use mozjpeg::transform::{Transform, TransformConfig};
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(36, 32);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&vec![100; 36*32*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    for &(transform, perfect) in &[(Transform::FlipHorizontal, false), (Transform::FlipVertical, true),
+                                   (Transform::Rotate90, true), (Transform::Rotate270, false)] {
+        let res = TransformConfig::new(transform).with_perfect(true).transform_mem(&jpeg);
+        assert_eq!(perfect, res.is_ok(), "{:?}", transform);
+
+        let trimmed = TransformConfig::new(transform).with_trim(true).transform_mem(&jpeg).unwrap();
+        let size = mozjpeg::Decompress::new_mem(&trimmed).unwrap().size();
+        let expected = match transform {
+            Transform::FlipHorizontal => (32, 32),
+            Transform::FlipVertical => (36, 32),
+            _ => (32, if perfect {36} else {32}),
+        };
+        assert_eq!(expected, size, "{:?}", transform);
+    }
+}
+
+#[test]
+fn wipe_drop_jpeg() {
+    use mozjpeg::transform::{Crop, Transform, TransformConfig};
+
+    let encode = |size: usize, value: u8, quality: f32| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(size, size);
+        comp.set_quality(quality);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&vec![value; size*size*3]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    };
+    let decode = |jpeg: &[u8]| -> Vec<[u8; 3]> {
+        mozjpeg::Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_pixels().unwrap()
+    };
+    let background = encode(48, 250, 90.);
+    let foreground = encode(16, 10, 70.);
+
+    let result = TransformConfig::new(Transform::None)
+        .with_drop(16, 20, &foreground)
+        .with_wipe(Crop { x: 40, y: 0, width: 2, height: 2 })
+        .transform_mem(&background).unwrap();
+    let pixels = decode(&result);
+    assert_eq!(48*48, pixels.len());
+    let at = |x: usize, y: usize| pixels[y * 48 + x][1] as i32;
+    assert!((at(0, 0) - 250).abs() < 4);
+    assert!((at(20, 20) - 10).abs() < 4);
+    assert!((at(40, 8) - 128).abs() < 4);
+    assert!((at(35, 35) - 250).abs() < 4);
+
+    let mut gray = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    gray.set_size(8, 8);
+    gray.set_mem_dest();
+    let mut started = gray.start_compress();
+    assert!(started.write_scanlines(&[0; 64]));
+    started.finish_compress();
+    let gray = gray.data_to_vec().unwrap();
+    let result = TransformConfig::new(Transform::None).with_drop(0, 0, &gray).transform_mem(&background).unwrap();
+    let pixels = decode(&result);
+    assert!(pixels[0].iter().all(|&c| c < 4));
+}
+
+#[test]
+fn grayscale_transform_jpeg() {
+    use mozjpeg::transform::{Transform, TransformConfig};
+
+    let pixels = (0..32*16).flat_map(|i| vec![(i % 32 * 8) as u8, 50, 200]).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(32, 16);
+    comp.set_quality(95.);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let gray = TransformConfig::new(Transform::Rotate180).with_grayscale(true).transform_mem(&jpeg).unwrap();
+    assert!(gray.len() < jpeg.len());
+    let decomp = mozjpeg::Decompress::new_mem(&gray).unwrap();
+    assert_eq!(mozjpeg::ColorSpace::JCS_GRAYSCALE, decomp.color_space());
+    assert_eq!((32, 16), decomp.size());
+
+    let luma: Vec<u8> = mozjpeg::Decompress::new_mem(&jpeg).unwrap().grayscale().unwrap().read_scanlines_flat().unwrap().0;
+    let gray_pixels: Vec<u8> = decomp.grayscale().unwrap().read_scanlines_flat().unwrap().0;
+    for (i, &px) in gray_pixels.iter().enumerate() {
+        assert!((px as i32 - luma[32*16 - 1 - i] as i32).abs() <= 2);
+    }
+}
+
+#[test]
+fn progressive_transform_jpeg() {
+    use mozjpeg::transform::{Transform, TransformConfig};
+
+    fn is_progressive(jpeg: &[u8]) -> bool {
+        jpeg.windows(2).any(|w| w == [0xFF, 0xC2])
+    }
+
+    let pixels = (0..48*32).flat_map(|i| vec![(i % 48 * 5) as u8, (i / 48 * 7) as u8, 90]).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(48, 32);
+    comp.set_progressive_mode();
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let progressive = comp.data_to_vec().unwrap();
+    assert!(is_progressive(&progressive));
+
+    let decode = |jpeg: &[u8]| -> Vec<[u8; 3]> {
+        mozjpeg::Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_pixels().unwrap()
+    };
+    let expected = decode(&progressive);
+
+    let baseline = TransformConfig::new(Transform::None).with_progressive(false).transform_mem(&progressive).unwrap();
+    assert!(!is_progressive(&baseline));
+    assert_eq!(expected, decode(&baseline));
+
+    let back = TransformConfig::new(Transform::None)
+        .with_scan_mode(mozjpeg::ScanMode::ScanPerComponent)
+        .transform_mem(&baseline).unwrap();
+    assert!(is_progressive(&back));
+    assert_eq!(expected, decode(&back));
+}
+
+#[test]
+fn optimize_jpeg() {
+    let pixels = (0..64*48).flat_map(|i| vec![(i % 64 * 4) as u8, (i / 64 * 5) as u8, (i * 7 % 256) as u8]).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_fastest_defaults();
+    comp.set_optimize_coding(false);
+    comp.set_size(64, 48);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let decode = |jpeg: &[u8]| -> Vec<[u8; 3]> {
+        mozjpeg::Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_pixels().unwrap()
+    };
+
+    let baseline = mozjpeg::transform::optimize_mem(&jpeg, false).unwrap();
+    let progressive = mozjpeg::transform::optimize_mem(&jpeg, true).unwrap();
+    assert!(baseline.len() < jpeg.len());
+    assert!(progressive.len() < jpeg.len());
+    assert_eq!(decode(&jpeg), decode(&baseline));
+    assert_eq!(decode(&jpeg), decode(&progressive));
+}
+
+#[test]
+fn copy_markers_jpeg() {
+    use mozjpeg::Marker;
+    use mozjpeg::transform::{CopyMarkers, Transform, TransformConfig};
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(16, 16);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    started.write_marker(Marker::APP(1), b"Exif\0\0secret");
+    started.write_marker(Marker::APP(2), b"ICC_PROFILE\0profile");
+    started.write_marker(Marker::COM, b"hello");
+    assert!(started.write_scanlines(&[128; 16*16*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let copied = |copy: CopyMarkers| -> Vec<Marker> {
+        let out = TransformConfig::new(Transform::None).with_copy_markers(copy).transform_mem(&jpeg).unwrap();
+        let dinfo = mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_mem(&out).unwrap();
+        dinfo.markers().map(|m| m.marker).filter(|&m| m != Marker::APP(0)).collect()
+    };
+
+    assert_eq!(vec![Marker::APP(1), Marker::APP(2), Marker::COM], copied(CopyMarkers::All));
+    assert_eq!(vec![Marker::COM], copied(CopyMarkers::Comments));
+    assert_eq!(Vec::<Marker>::new(), copied(CopyMarkers::None));
+    assert_eq!(vec![Marker::APP(2)], copied(CopyMarkers::Only(vec![Marker::APP(2)])));
+}
+
+#[test]
+fn coefficient_callback_jpeg() {
+    use mozjpeg::transform::{Transform, TransformConfig};
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    comp.set_size(24, 16);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[30; 24*16]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let mut components = Vec::new();
+    let out = TransformConfig::new(Transform::None).transform_mem_with_callback(&jpeg, |c, blocks| {
+        components.push(c);
+        assert_eq!((3, 2), (blocks.width(), blocks.height()));
+        assert_eq!(6, blocks.as_slice().len());
+        blocks.get_mut(0, 0).unwrap()[0] = 0;
+    }).unwrap();
+    assert_eq!(vec![0], components);
+
+    let pixels: Vec<u8> = mozjpeg::Decompress::new_mem(&out).unwrap().grayscale().unwrap().read_scanlines_flat().unwrap().0;
+    for (i, &px) in pixels.iter().enumerate() {
+        let expected = if i % 24 < 8 && i / 24 < 8 { 128 } else { 30 };
+        assert!((px as i32 - expected).abs() <= 2);
+    }
+}
+
+#[test]
+fn match_quality_jpeg() {
+    fn encode(quality: f32, source: Option<&[u8]>) -> Vec<u8> {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(16, 16);
+        comp.set_quality(quality);
+        if let Some(source) = source {
+            comp.set_qtables_from(&mozjpeg::Decompress::new_mem(source).unwrap());
+        }
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[100; 16*16*3]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
     }
+    let qtables = |jpeg: &[u8]| mozjpeg::Decompress::new_mem(jpeg).unwrap().qtables();
+
+    let original = encode(50., None);
+    let original_tables = qtables(&original);
+    assert_eq!(3, original_tables.len());
+    assert!(original_tables.iter().all(|t| t.is_some()));
+
+    assert_eq!(original_tables, qtables(&encode(95., Some(&original))));
+    assert_eq!(qtables(&encode(10., None)), qtables(&encode(10., Some(&original))));
+}
+
+#[test]
+fn optimize_pipeline_jpeg() {
+    use mozjpeg::{Marker, OptimizeOptions};
+
+    let pixels = (0..64*64).flat_map(|i| vec![(i % 64 * 4) as u8, (i / 64 * 4) as u8, (i * 13 % 256) as u8]).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_fastest_defaults();
+    comp.set_optimize_coding(false);
+    comp.set_quality(95.);
+    comp.set_size(64, 64);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    started.write_marker(Marker::APP(1), b"Exif\0\0data");
+    started.write_marker(Marker::APP(2), b"ICC_PROFILE\0profile");
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let markers = |jpeg: &[u8]| -> Vec<Marker> {
+        let dinfo = mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_mem(jpeg).unwrap();
+        dinfo.markers().map(|m| m.marker).filter(|&m| m != Marker::APP(0)).collect()
+    };
+
+    let lossless = mozjpeg::optimize(&jpeg, &OptimizeOptions::default()).unwrap();
+    assert!(lossless.len() < jpeg.len());
+    assert_eq!(vec![Marker::APP(1), Marker::APP(2)], markers(&lossless));
+
+    let lossy = mozjpeg::optimize(&jpeg, &OptimizeOptions {
+        strip_metadata: true,
+        max_quality: Some(60.),
+        ..OptimizeOptions::default()
+    }).unwrap();
+    assert!(lossy.len() < lossless.len());
+    assert_eq!(vec![Marker::APP(2)], markers(&lossy));
+
+    let small = mozjpeg::optimize(&jpeg, &OptimizeOptions {
+        target_size: Some(lossy.len()),
+        ..OptimizeOptions::default()
+    }).unwrap();
+    assert!(small.len() <= lossy.len());
+    assert_eq!((64, 64), mozjpeg::Decompress::new_mem(&small).unwrap().size());
+
+    assert!(mozjpeg::optimize(&jpeg, &OptimizeOptions {
+        target_size: Some(10),
+        ..OptimizeOptions::default()
+    }).is_err());
+}
+
+#[test]
+fn copy_exif_icc_jpeg() {
+    use mozjpeg::Marker;
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(16, 16);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    started.write_marker(Marker::APP(1), b"Exif\0\0data");
+    started.write_marker(Marker::APP(1), b"http://ns.adobe.com/xap/1.0/\0xmp");
+    started.write_marker(Marker::APP(2), b"ICC_PROFILE\0\x01\x01profile");
+    started.write_marker(Marker::COM, b"comment");
+    assert!(started.write_scanlines(&[50; 16*16*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let mut dinfo = mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_mem(&jpeg).unwrap().rgb().unwrap();
+    let pixels: Vec<[u8; 3]> = dinfo.read_pixels().unwrap();
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(16, 16);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    started.copy_exif_and_icc(dinfo.markers());
+    assert!(started.write_scanlines(&pixels.iter().flat_map(|p| p.iter().cloned()).collect::<Vec<_>>()));
+    started.finish_compress();
+    let recompressed = comp.data_to_vec().unwrap();
+    dinfo.finish_decompress();
+
+    let dinfo = mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_mem(&recompressed).unwrap();
+    let markers = dinfo.markers().filter(|m| m.marker != Marker::APP(0)).map(|m| (m.marker, m.data.to_vec())).collect::<Vec<_>>();
+    assert_eq!(vec![
+        (Marker::APP(1), b"Exif\0\0data".to_vec()),
+        (Marker::APP(2), b"ICC_PROFILE\0\x01\x01profile".to_vec()),
+    ], markers);
+}
+
+#[test]
+fn patch_jpeg() {
+    use mozjpeg::transform::{patch_mem, Crop};
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(48, 40);
+    comp.set_quality(95.);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[40; 48*40*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let area = Crop { x: 16, y: 16, width: 32, height: 24 };
+    let patch = [200u8, 30, 30].iter().cloned().cycle().take(32*24*3).collect::<Vec<_>>();
+    let patched = patch_mem(&jpeg, area, &patch).unwrap();
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&patched).unwrap().rgb().unwrap();
+    assert_eq!((48, 40), (dinfo.width(), dinfo.height()));
+    let pixels: Vec<[u8; 3]> = dinfo.read_pixels().unwrap();
+    let close = |a: [u8; 3], b: [u8; 3]| a.iter().zip(&b).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 8);
+    assert!(close([40, 40, 40], pixels[0]));
+    assert!(close([40, 40, 40], pixels[10 * 48 + 40]));
+    assert!(close([40, 40, 40], pixels[30 * 48 + 8]));
+    assert!(close([200, 30, 30], pixels[24 * 48 + 32]));
+    assert!(close([200, 30, 30], pixels[39 * 48 + 47]));
+
+    let unaligned = Crop { x: 8, y: 16, width: 16, height: 16 };
+    assert!(patch_mem(&jpeg, unaligned, &patch[..16*16*3]).is_err());
+}
+
+#[test]
+fn scan_index_jpeg() {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(32, 32);
+    comp.set_progressive_mode();
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[77; 32*32*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let scans = mozjpeg::scan_index(&jpeg).unwrap();
+    assert!(scans.len() > 1);
+    for scan in &scans {
+        assert_eq!(&[0xFF, 0xDA], &jpeg[scan.range.start..scan.range.start + 2]);
+        assert_eq!(1, scan.intervals.len());
+        assert_eq!(scan.range.end, scan.intervals[0].end);
+    }
+    assert_eq!(jpeg.len() - 2, scans.last().unwrap().range.end);
+
+    let prefix = &jpeg[..scans[0].range.end];
+    assert_eq!(1, mozjpeg::scan_index(prefix).unwrap().len());
+}
+
+#[test]
+fn restart_markers_jpeg() {
+    let encode = |configure: &dyn Fn(&mut mozjpeg::Compress)| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_fastest_defaults();
+        comp.set_size(64, 32);
+        configure(&mut comp);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[120; 64*32*3]));
+        started.finish_compress();
+        comp.data_to_vec_with_restarts().unwrap()
+    };
+
+    let (jpeg, offsets) = encode(&|_| {});
+    assert!(offsets.is_empty());
+    assert_eq!(1, mozjpeg::scan_index(&jpeg).unwrap()[0].intervals.len());
+
+    // 4x2 MCUs of 16x16 pixels
+    let (jpeg, offsets) = encode(&|comp| comp.set_restart_interval(3));
+    assert_eq!(2, offsets.len());
+    for (n, &offset) in offsets.iter().enumerate() {
+        assert_eq!([0xFF, 0xD0 + n as u8], [jpeg[offset], jpeg[offset + 1]]);
+    }
+    let pixels: Vec<[u8; 3]> = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap().read_pixels().unwrap();
+    assert!(pixels.iter().all(|p| p.iter().all(|&c| (c as i32 - 120).abs() <= 2)));
+
+    let (_, offsets) = encode(&|comp| comp.set_restart_in_rows(1));
+    assert_eq!(1, offsets.len());
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn decode_parallel_jpeg() {
+    let (width, height) = (80, 200);
+    let pixels = (0..width*height).flat_map(|i| vec![(i % width * 3) as u8, (i / width) as u8, 100]).collect::<Vec<_>>();
+    let encode = |restart_rows: u16| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_fastest_defaults();
+        comp.set_size(width, height);
+        comp.set_restart_in_rows(restart_rows);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    };
+    let decode = |jpeg: &[u8]| -> Vec<[u8; 3]> {
+        mozjpeg::Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_pixels().unwrap()
+    };
+
+    // Bands are split by the number of threads
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    for &restart_rows in &[0, 1, 3] {
+        let jpeg = encode(restart_rows);
+        let expected = decode(&jpeg);
+        let (w, h, decoded) = pool.install(|| mozjpeg::decode_parallel(&jpeg, mozjpeg::ColorSpace::JCS_RGB)).unwrap();
+        assert_eq!((width, height), (w, h));
+        assert_eq!(expected.concat(), decoded);
+    }
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn encode_parallel_jpeg() {
+    let (width, height) = (72, 150);
+    let pixels = (0..width*height).flat_map(|i| vec![(i % width * 3) as u8, (i / width) as u8, (i * 7 % 256) as u8]).collect::<Vec<_>>();
+    let configure = |comp: &mut mozjpeg::Compress| comp.set_quality(85.);
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    let jpeg = pool.install(|| mozjpeg::encode_parallel(mozjpeg::ColorSpace::JCS_RGB, width, height, &pixels, configure)).unwrap();
+    let err = mozjpeg::encode_parallel(mozjpeg::ColorSpace::JCS_RGB, width, height + 1, &pixels, configure).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+    let err = mozjpeg::encode_parallel(mozjpeg::ColorSpace::JCS_RGB, width, height, &pixels, |comp| {
+        comp.set_limits(mozjpeg::Limits { max_width: Some(10), ..mozjpeg::Limits::default() });
+    }).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    configure(&mut comp);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let expected = comp.data_to_vec().unwrap();
+    assert!(jpeg.len() < expected.len() * 11 / 10);
+
+    let decode = |jpeg: &[u8]| -> Vec<[u8; 3]> {
+        mozjpeg::Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_pixels().unwrap()
+    };
+    let (expected, decoded) = (decode(&expected), decode(&jpeg));
+    assert_eq!(expected.len(), decoded.len());
+
+    // Bands don't depend on the number of threads
+    let deterministic = |comp: &mut mozjpeg::Compress| {
+        comp.set_quality(85.);
+        comp.set_deterministic(true);
+    };
+    let encode = |threads| {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| mozjpeg::encode_parallel(mozjpeg::ColorSpace::JCS_RGB, width, height, &pixels, deterministic)).unwrap()
+    };
+    assert_eq!(encode(1), encode(4));
+    // Bands are quantized independently, but the image is the same
+    let diff = expected.iter().zip(&decoded)
+        .map(|(e, d)| e.iter().zip(d).map(|(&e, &d)| (e as i32 - d as i32).abs()).sum::<i32>())
+        .sum::<i32>() as f64 / (width * height) as f64;
+    assert!(diff < 2., "{}", diff);
+}
+
+#[test]
+fn batch_jpeg() {
+    use mozjpeg::batch;
+
+    let mut inputs = (1..6).map(|i| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(i * 8, 8);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&vec![i as u8 * 40; i * 8 * 8 * 3]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    }).collect::<Vec<_>>();
+    inputs.push(b"not a jpeg".to_vec());
+
+    let results = batch::run(inputs, 3, |worker, dinfo| {
+        let (width, height) = dinfo.size();
+        let mut dinfo = dinfo.rgb()?;
+        let pixels: Vec<[u8; 3]> = dinfo.read_pixels().unwrap();
+        dinfo.finish_decompress();
+
+        let comp = worker.compress(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(width, height);
+        comp.set_quality(50.);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels.iter().flat_map(|p| p.iter().cloned()).collect::<Vec<_>>()));
+        started.finish_compress();
+        Ok((width, comp.data_to_vec().unwrap()))
+    });
+
+    let mut results = results.collect::<Vec<_>>();
+    results.sort_by_key(|r| r.0);
+    assert_eq!((0..6).collect::<Vec<_>>(), results.iter().map(|r| r.0).collect::<Vec<_>>());
+    for (i, result) in &results[..5] {
+        let (width, ref jpeg) = *result.as_ref().unwrap();
+        assert_eq!((i + 1) * 8, width);
+        assert_eq!((width, 8), mozjpeg::Decompress::new_mem(jpeg).unwrap().size());
+    }
+    assert!(results[5].1.is_err());
+}
+
+#[test]
+fn buffer_pool_jpeg() {
+    let pool = mozjpeg::BufferPool::new(4);
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(40, 24);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[99; 40*24*3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    for _ in 0..3 {
+        let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+        let mut pixels = pool.for_scanlines(&dinfo);
+        let capacity = pixels.capacity();
+        assert!(dinfo.read_scanlines_into(&mut pixels));
+        assert_eq!(40*24*3, pixels.len());
+        assert_eq!(capacity, pixels.capacity());
+        assert!(pixels.iter().all(|&c| (c as i32 - 99).abs() <= 2));
+        dinfo.finish_decompress();
+    }
+    assert_eq!(1, pool.len());
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().raw().unwrap();
+    let mut planes = pool.for_planes(dinfo.components());
+    assert!(pool.is_empty());
+    let capacities = planes.iter().map(|p| p.capacity()).collect::<Vec<_>>();
+    dinfo.read_raw_data(&mut planes.iter_mut().map(|p| &mut **p).collect::<Vec<_>>());
+    assert_eq!(capacities, planes.iter().map(|p| p.capacity()).collect::<Vec<_>>());
+    drop(planes);
+    assert_eq!(3, pool.len());
+}
+
+#[test]
+fn read_pixels_jpeg() {
+    let mut dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap().rgb().unwrap();
+    let pixels = dinfo.read_pixels::<3>().unwrap();
+    assert_eq!(45 * 30, pixels.len());
+    dinfo.finish_decompress();
+
+    let mut dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap().rgb().unwrap();
+    let (flat, _) = dinfo.read_scanlines_flat().unwrap();
+    assert_eq!(flat, pixels.iter().flat_map(|px| px.iter().cloned()).collect::<Vec<u8>>());
+
+    let mut dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap().grayscale().unwrap();
+    assert_eq!(45 * 30, dinfo.read_pixels::<1>().unwrap().len());
+    assert!(std::panic::catch_unwind(|| {
+        mozjpeg::Decompress::new_path("tests/test.jpg").unwrap().grayscale().unwrap().read_pixels::<3>()
+    }).is_err());
+}
+
+#[test]
+fn imcu_rows_jpeg() {
+    let mut dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap().raw().unwrap();
+    assert_eq!(16, dinfo.imcu_height());
+    assert_eq!(2, dinfo.total_imcu_rows());
+    let luma_row_len = dinfo.components()[0].row_stride() * 16;
+
+    let mut planes = vec![Vec::new(), Vec::new(), Vec::new()];
+    let mut rows = 0;
+    while dinfo.read_next_imcu_row(&mut planes.iter_mut().collect::<Vec<_>>()) {
+        rows += 1;
+        assert_eq!(rows, dinfo.imcu_row());
+        assert_eq!(rows * luma_row_len, planes[0].len());
+    }
+    assert_eq!(2, rows);
+    assert!(!dinfo.read_next_imcu_row(&mut planes.iter_mut().collect::<Vec<_>>()));
+
+    let mut dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap().raw().unwrap();
+    let mut all = vec![Vec::new(), Vec::new(), Vec::new()];
+    dinfo.read_raw_data(&mut all.iter_mut().collect::<Vec<_>>());
+    assert_eq!(all, planes);
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn codec_pool_jpeg() {
+    let pixels = (0..32*16*3).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+    let encode = |comp: &mut mozjpeg::Compress| {
+        comp.set_size(32, 16);
+        comp.set_mem_dest();
+        {
+            let mut started = comp.start_compress();
+            assert!(started.write_scanlines(&pixels));
+            started.finish_compress();
+        }
+        comp.data_to_vec().unwrap()
+    };
+    let fresh = encode(&mut mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB));
+
+    let encoders = mozjpeg::Pool::<mozjpeg::Compress>::new(2);
+    {
+        let mut comp = encoders.compress(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_quality(20.);
+        comp.set_fastest_defaults();
+        comp.set_buffer_dest(100);
+        comp.set_progress_callback(|_| true);
+    }
+    assert_eq!(1, encoders.len());
+    let mut jpegs = Vec::new();
+    for _ in 0..3 {
+        let mut comp = encoders.compress(mozjpeg::ColorSpace::JCS_RGB);
+        assert!(encoders.is_empty());
+        let jpeg = encode(&mut comp);
+        // Settings of the previous user don't leak
+        assert_eq!(mozjpeg::Decompress::new_mem(&fresh).unwrap().qtables(),
+                   mozjpeg::Decompress::new_mem(&jpeg).unwrap().qtables());
+        assert!(jpeg.len() < fresh.len() * 11 / 10);
+        jpegs.push(jpeg);
+    }
+    assert_eq!(1, encoders.len());
+
+    let decoders = mozjpeg::Pool::<mozjpeg::Decompress>::new(2);
+    for jpeg in &jpegs {
+        let dinfo = decoders.decompress_mem_with_markers(mozjpeg::ALL_MARKERS, jpeg).unwrap();
+        assert_eq!((32, 16), dinfo.size());
+        assert_eq!(1, dinfo.markers().count());
+        let mut dinfo = dinfo.rgb().unwrap();
+        assert_eq!(32*16, dinfo.read_pixels::<3>().unwrap().len());
+        dinfo.finish_decompress();
+        assert_eq!(1, decoders.len());
+    }
+    let dinfo = decoders.decompress_mem(&jpegs[0]).unwrap();
+    assert_eq!(0, dinfo.markers().count());
+    // A decoder dropped while unwinding goes back too
+    let _ = std::panic::catch_unwind(|| {
+        decoders.decompress_mem(&jpegs[0]).unwrap().rgb().unwrap().read_pixels::<4>()
+    });
+    drop(dinfo);
+    assert_eq!(2, decoders.len());
+}
+
+#[test]
+fn owned_source_jpeg() {
+    fn open(path: &str) -> mozjpeg::Decompress<'static> {
+        let data = std::fs::read(path).unwrap();
+        mozjpeg::Decompress::new_vec(data).unwrap()
+    }
+    let dinfo = open("tests/test.jpg");
+    assert_eq!((45, 30), dinfo.size());
+    let mut dinfo = dinfo.rgb().unwrap();
+    let pixels = dinfo.read_pixels::<3>().unwrap();
+    assert!(dinfo.finish_decompress());
+
+    let shared: std::sync::Arc<[u8]> = std::fs::read("tests/test.jpg").unwrap().into();
+    let decoders = (0..2).map(|_| {
+        mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_owned(shared.clone()).unwrap()
+    }).collect::<Vec<_>>();
+    assert_eq!(3, std::sync::Arc::strong_count(&shared));
+    for dinfo in decoders {
+        let mut dinfo = dinfo.rgb().unwrap();
+        assert_eq!(pixels, dinfo.read_pixels::<3>().unwrap());
+        assert!(dinfo.finish_decompress());
+    }
+    assert_eq!(1, std::sync::Arc::strong_count(&shared));
+
+    let boxed: Box<[u8]> = std::fs::read("tests/test.jpg").unwrap().into_boxed_slice();
+    let mut dinfo = mozjpeg::Decompress::new_owned(boxed).unwrap().rgb().unwrap();
+    assert_eq!(pixels, dinfo.read_pixels::<3>().unwrap());
+}
+
+#[test]
+#[cfg(feature = "memmap2")]
+fn mmap_source_jpeg() {
+    let mut dinfo = mozjpeg::Decompress::new_mmap("tests/test.jpg").unwrap().rgb().unwrap();
+    assert_eq!((45, 30), (dinfo.width(), dinfo.height()));
+    let pixels = dinfo.read_pixels::<3>().unwrap();
+    assert!(dinfo.finish_decompress());
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    assert_eq!(pixels, dinfo.read_pixels::<3>().unwrap());
+}
+
+#[test]
+fn file_source_jpeg() {
+    let pixels = (0..300*200*3).map(|i| (i * 13 % 256) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(300, 200);
+    comp.set_quality(100.);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        // Skipped without reading, since it's larger than the source's buffer
+        for _ in 0..3 {
+            started.write_marker(mozjpeg::Marker::APP(9), &[9; 65000]);
+        }
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = comp.data_to_vec().unwrap();
+    assert!(jpeg.len() > 250_000);
+    let expected = {
+        let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+        dinfo.read_pixels::<3>().unwrap()
+    };
+
+    let path = std::env::temp_dir().join(format!("mozjpeg-file-source-{}.jpg", std::process::id()));
+    std::fs::write(&path, &jpeg).unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_path(&path).unwrap().rgb().unwrap();
+    assert_eq!(expected, dinfo.read_pixels::<3>().unwrap());
+    assert!(dinfo.finish_decompress());
+
+    let dinfo = mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_path(&path).unwrap();
+    assert_eq!(3, dinfo.markers().filter(|m| m.data.len() == 65000).count());
+
+    // Truncated files are decoded as far as possible
+    std::fs::write(&path, &jpeg[..jpeg.len() - 1000]).unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_file(std::fs::File::open(&path).unwrap()).unwrap().rgb().unwrap();
+    assert_eq!(300 * 200, dinfo.read_pixels::<3>().unwrap().len());
+
+    // Starts from the current position, e.g. of JPEG data embedded in another file
+    use std::io::{Seek, SeekFrom};
+    let mut container = b"header".to_vec();
+    container.extend_from_slice(&jpeg);
+    std::fs::write(&path, &container).unwrap();
+    let mut file = std::fs::File::open(&path).unwrap();
+    file.seek(SeekFrom::Start(6)).unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_file(file).unwrap().rgb().unwrap();
+    assert_eq!(expected, dinfo.read_pixels::<3>().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    // Read errors are returned, not thrown through libjpeg
+    #[cfg(unix)]
+    assert!(mozjpeg::Decompress::new_file(std::fs::File::open("tests").unwrap()).is_err());
+}
+
+#[test]
+fn planar_jpeg() {
+    use mozjpeg::planar;
+    let (width, height) = (33, 17);
+    let pixels = (0..width * height * 3).map(|i| (i * 5 % 256) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    let planes = decompress_jpeg(&jpeg);
+    let i420 = planar::raw_to_i420(&planes, dinfo.components(), width, height);
+    assert_eq!(width * height + 2 * 17 * 9, i420.len());
+    assert_eq!(&planes[0][..width], &i420[..width]);
+    let nv12 = planar::i420_to_nv12(&i420, width, height);
+    assert_eq!(i420, planar::nv12_to_i420(&nv12, width, height));
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_YCbCr);
+    comp.set_raw_data_in(true);
+    comp.set_size(width, height);
+    comp.set_quality(100.);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        let raw = planar::i420_to_raw(&i420, started.components(), width, height);
+        assert!(started.write_raw_data(&raw.iter().map(|p| &p[..]).collect::<Vec<_>>()));
+        started.finish_compress();
+    }
+    let reencoded = comp.data_to_vec().unwrap();
+    let dinfo = mozjpeg::Decompress::new_mem(&reencoded).unwrap();
+    let i420_again = planar::raw_to_i420(&decompress_jpeg(&reencoded), dinfo.components(), width, height);
+    assert!(i420.iter().zip(&i420_again).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 3));
+}
+
+#[test]
+fn thumbnail_jpeg() {
+    let pixels = (0..300*200*3).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(300, 200);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let (thumb, width, height) = mozjpeg::thumbnail(&jpeg, 40).unwrap();
+    assert_eq!((75, 50), (width, height));
+    assert_eq!(75 * 50 * 3, thumb.len());
+    assert_eq!((38, 25), {
+        let (_, w, h) = mozjpeg::thumbnail(&jpeg, 38).unwrap();
+        (w, h)
+    });
+    assert_eq!((300, 200), {
+        let (_, w, h) = mozjpeg::thumbnail(&jpeg, 1000).unwrap();
+        (w, h)
+    });
+}
+
+#[test]
+fn smart_thumbnail_jpeg() {
+    use mozjpeg::ThumbnailSource;
+
+    let encode = |width: usize, height: usize, exif: Option<&[u8]>| {
+        let pixels = (0..width*height*3).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(width, height);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        if let Some(exif) = exif {
+            started.write_marker(mozjpeg::Marker::APP(1), exif);
+        }
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    };
+
+    // Little-endian TIFF with an empty IFD0, and IFD1 pointing to the thumbnail right after it
+    let with_thumb = |thumb: &[u8]| {
+        let mut exif = b"Exif\0\0II*\0\x08\0\0\0\0\0\x0e\0\0\0\x02\0".to_vec();
+        exif.extend_from_slice(&[0x01, 0x02, 4, 0, 1, 0, 0, 0, 44, 0, 0, 0]);
+        exif.extend_from_slice(&[0x02, 0x02, 4, 0, 1, 0, 0, 0]);
+        exif.extend_from_slice(&(thumb.len() as u32).to_le_bytes());
+        exif.extend_from_slice(&[0; 4]);
+        exif.extend_from_slice(thumb);
+        encode(300, 200, Some(&exif))
+    };
+    let jpeg = with_thumb(&encode(150, 100, None));
+
+    let t = mozjpeg::smart_thumbnail(&jpeg, 100).unwrap();
+    assert_eq!((100, 67, ThumbnailSource::Exif), (t.width, t.height, t.source));
+    assert_eq!(100 * 67 * 3, t.pixels.len());
+    let t = mozjpeg::smart_thumbnail(&jpeg, 200).unwrap();
+    assert_eq!((200, 133, ThumbnailSource::ScaledDecode { scale: 6 }), (t.width, t.height, t.source));
+    let t = mozjpeg::smart_thumbnail(&jpeg, 290).unwrap();
+    assert_eq!((290, 193, ThumbnailSource::FullDecode), (t.width, t.height, t.source));
+    let t = mozjpeg::smart_thumbnail(&jpeg, 1000).unwrap();
+    assert_eq!((300, 200, ThumbnailSource::FullDecode), (t.width, t.height, t.source));
+
+    // A corrupt thumbnail is skipped
+    let t = mozjpeg::smart_thumbnail(&with_thumb(&[0xFF, 0xD8, 0xFF, 0xDB, 0, 1, 0, 0]), 100).unwrap();
+    assert_eq!((100, 67, ThumbnailSource::ScaledDecode { scale: 3 }), (t.width, t.height, t.source));
+
+    // Without EXIF
+    let t = mozjpeg::smart_thumbnail(&encode(300, 200, None), 37).unwrap();
+    assert_eq!((37, 25, ThumbnailSource::ScaledDecode { scale: 1 }), (t.width, t.height, t.source));
+}
+
+#[test]
+fn tiles_jpeg() {
+    let (width, height) = (300, 200);
+    let pixels = (0..width*height*3).map(|i| (i * 7 % 253) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = comp.data_to_vec().unwrap();
+    let full = {
+        let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+        dinfo.read_pixels::<3>().unwrap()
+    };
+    let full_tile = |x: usize, y: usize, w: usize, h: usize| {
+        (y..y + h).flat_map(|row| full[row * width + x..row * width + x + w].iter().flat_map(|px| px.iter().cloned()).collect::<Vec<_>>()).collect::<Vec<u8>>()
+    };
+
+    let mut tiles = Vec::new();
+    mozjpeg::decode_tiles(&jpeg, mozjpeg::ColorSpace::JCS_RGB, 128, |tile| tiles.push(tile)).unwrap();
+    assert_eq!(6, tiles.len());
+    assert_eq!((256, 128, 44, 72), (tiles[5].x, tiles[5].y, tiles[5].width, tiles[5].height));
+    for tile in &tiles {
+        assert_eq!(full_tile(tile.x, tile.y, tile.width, tile.height), tile.pixels);
+    }
+
+    let region = mozjpeg::decode_region(&jpeg, mozjpeg::ColorSpace::JCS_RGB, 37, 50, 100, 60).unwrap();
+    assert_eq!((100, 60), (region.width, region.height));
+    // Upsampling at region edges may differ slightly
+    let expected = full_tile(37, 50, 100, 60);
+    assert!(region.pixels.iter().zip(&expected).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 8));
+    assert!(mozjpeg::decode_region(&jpeg, mozjpeg::ColorSpace::JCS_RGB, 250, 0, 100, 10).is_err());
+
+    let mut started = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+    let crop = started.crop(37, 100);
+    assert!(crop.x <= 37 && crop.x + crop.skip == 37);
+    assert!(crop.x + crop.width >= 137);
+    assert_eq!(crop.width, started.width());
+    let lines = started.read_pixels::<3>().unwrap();
+    let row = crop.trim(&lines[50 * crop.width..51 * crop.width], 1);
+    assert_eq!(100, row.len());
+    assert!(region.pixels[..3].iter().zip(&row[0]).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 8));
+}
+
+#[test]
+fn pyramid_jpeg() {
+    let (width, height) = (301, 203);
+    let pixels = (0..width*height*3).map(|i| (i / 3 % 200) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let levels = mozjpeg::pyramid(&jpeg, mozjpeg::ColorSpace::JCS_RGB, &[8, 1, 4]).unwrap();
+    assert_eq!(vec![8, 1, 4], levels.iter().map(|l| l.denominator).collect::<Vec<_>>());
+    assert_eq!(vec![(38, 26), (301, 203), (76, 51)], levels.iter().map(|l| (l.width, l.height)).collect::<Vec<_>>());
+    for level in &levels {
+        assert_eq!(level.width * level.height * 3, level.pixels.len());
+    }
+
+    // Same size as libjpeg's own scaling
+    let levels = mozjpeg::pyramid(&jpeg, mozjpeg::ColorSpace::JCS_GRAYSCALE, &[2, 8]).unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    dinfo.scale(1);
+    let dinfo = dinfo.grayscale().unwrap();
+    assert_eq!((dinfo.width(), dinfo.height()), (levels[1].width, levels[1].height));
+
+    // libjpeg decodes subsampled chroma at a larger scale, instead of upsampling it
+    use mozjpeg::CompInfoExt;
+    let mut dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap();
+    dinfo.scale(4);
+    let dinfo = dinfo.rgb().unwrap();
+    let geometry = dinfo.components().iter().map(|c| (c.dct_scaled_size(), c.downsampled_size())).collect::<Vec<_>>();
+    assert_eq!(vec![(4, (23, 15)), (8, (23, 15)), (8, (23, 15))], geometry);
+    assert!(mozjpeg::pyramid(&jpeg, mozjpeg::ColorSpace::JCS_RGB, &[]).unwrap().is_empty());
+}
+
+#[test]
+fn decode_to_writer_jpeg() {
+    let (width, height) = (123, 77);
+    let pixels = (0..width*height*3).map(|i| (i * 3 % 256) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = comp.data_to_vec().unwrap();
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+    let expected = dinfo.read_pixels::<3>().unwrap().iter().flat_map(|px| px.iter().cloned()).collect::<Vec<_>>();
+
+    let mut written = Vec::new();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+    dinfo.read_scanlines_to_writer(&mut written).unwrap();
+    assert!(dinfo.finish_decompress());
+    assert_eq!(expected, written);
+
+    // Compressing straight from the decoder's output
+    let mut reader = &written[..];
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    started.write_scanlines_from_reader(&mut reader).unwrap();
+    started.finish_compress();
+}
+
+#[test]
+#[cfg(feature = "memmap2")]
+fn mmap_dest_jpeg() {
+    let (width, height) = (200, 150);
+    let pixels = (0..width*height*3).map(|i| (i * 11 % 256) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let expected = comp.data_to_vec().unwrap();
+
+    let path = std::env::temp_dir().join(format!("mozjpeg-mmap-dest-{}.jpg", std::process::id()));
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    // Too small, so it has to grow
+    comp.set_mmap_dest(&file, 100).unwrap();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    assert_eq!(expected, std::fs::read(&path).unwrap());
+
+    // Switching back works
+    comp.set_mem_dest();
+    {
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    assert!(comp.data_to_vec().is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn histogram_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    dinfo.collect_histogram();
+    let pixels = dinfo.read_pixels::<3>().unwrap();
+    let histogram = dinfo.histogram().unwrap().clone();
+    assert!(dinfo.finish_decompress());
+
+    assert_eq!(45 * 30, histogram.pixels());
+    for c in 0..3 {
+        let mut counts = [0u32; 256];
+        for px in &pixels {
+            counts[px[c] as usize] += 1;
+        }
+        assert_eq!(&counts[..], &histogram.channels[c][..]);
+        assert_eq!(pixels.iter().map(|px| px[c]).min(), histogram.min(c));
+        assert_eq!(pixels.iter().map(|px| px[c]).max(), histogram.max(c));
+    }
+
+    let dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().grayscale().unwrap();
+    assert!(dinfo.histogram().is_none());
+}
+
+#[test]
+fn hash_rows_jpeg() {
+    use std::hash::Hasher;
+    let data = std::fs::read("tests/test.jpg").unwrap();
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let mut rows = 0;
+    let pixels = {
+        let mut dinfo = mozjpeg::Decompress::new_vec(data.clone()).unwrap().rgb().unwrap();
+        dinfo.hash_rows(&mut hasher);
+        dinfo.inspect_rows(|row| {
+            assert_eq!(45 * 3, row.len());
+            rows += 1;
+        });
+        let pixels = dinfo.read_pixels::<3>().unwrap();
+        assert!(dinfo.finish_decompress());
+        pixels
+    };
+    assert_eq!(30, rows);
+
+    let mut expected = std::collections::hash_map::DefaultHasher::new();
+    for row in pixels.chunks(45) {
+        expected.write(&row.iter().flat_map(|px| px.iter().cloned()).collect::<Vec<_>>());
+    }
+    assert_eq!(expected.finish(), hasher.finish());
+}
+
+#[test]
+fn arena_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut arena = mozjpeg::Arena::new();
+    let mut capacity = 0;
+    for i in 0..3 {
+        arena.reset();
+        let mut dinfo = mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS).from_mem(&data).unwrap().rgb().unwrap();
+        arena.copy_markers(dinfo.markers());
+        let num_markers = dinfo.markers().count();
+        {
+            let pixels = arena.read_scanlines(&mut dinfo).unwrap();
+            assert_eq!(45 * 30 * 3, pixels.len());
+        }
+        assert!(dinfo.finish_decompress());
+        assert_eq!(num_markers, arena.markers().count());
+
+        let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().raw().unwrap();
+        let num_planes = dinfo.components().len();
+        assert_eq!(num_planes, arena.read_raw_data(&mut dinfo).len());
+        assert!(dinfo.finish_decompress());
+
+        // Nothing is allocated once the arena has grown
+        if i > 0 {
+            assert_eq!(capacity, arena.capacity());
+        }
+        capacity = arena.capacity();
+    }
+}
+
+#[test]
+fn bounded_memory_jpeg() {
+    let pixels = vec![128u8; 64 * 40 * 3];
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    cinfo.set_size(64, 40);
+    cinfo.set_mem_dest();
+    cinfo.set_bounded_memory(true);
+    assert_eq!(Ok(()), cinfo.validate());
+
+    cinfo.set_progressive_mode();
+    assert_eq!(Err(mozjpeg::ConfigError::NeedsWholeImageBuffer { option: "Progressive mode" }), cinfo.validate());
+    cinfo.set_bounded_memory(true);
+    cinfo.set_optimize_coding(true);
+    assert!(cinfo.try_start_compress().is_err());
+    cinfo.set_optimize_coding(false);
+
+    {
+        let mut started = cinfo.try_start_compress().unwrap();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    }
+    let jpeg = cinfo.data_to_vec().unwrap();
+    let dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!((64, 40), dinfo.size());
+    assert_eq!(1, mozjpeg::scan_index(&jpeg).unwrap().len());
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn image_decoder_jpeg() {
+    use image::ImageDecoder;
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let decoder = mozjpeg::JpegDecoder::new(&data).unwrap();
+    assert_eq!((45, 30), decoder.dimensions());
+    assert_eq!(image::ColorType::Rgb8, decoder.color_type());
+    let mut buf = vec![0; decoder.total_bytes() as usize];
+    decoder.read_image(&mut buf).unwrap();
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let expected = dinfo.read_pixels::<3>().unwrap();
+    assert_eq!(expected.iter().flat_map(|px| px.iter().cloned()).collect::<Vec<_>>(), buf);
+
+    assert!(mozjpeg::JpegDecoder::new(b"not a jpeg").is_err());
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn image_encoder_jpeg() {
+    use image::ImageEncoder;
+    let pixels = (0..32 * 16 * 3).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let mut jpeg = Vec::new();
+    mozjpeg::JpegEncoder::new_with_quality(&mut jpeg, 90.).write_image(&pixels, 32, 16, image::ColorType::Rgb8).unwrap();
+    let dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!((32, 16), dinfo.size());
+
+    let mut out = Vec::new();
+    assert!(mozjpeg::JpegEncoder::new(&mut out).write_image(&pixels, 32, 15, image::ColorType::Rgb8).is_err());
+    assert!(mozjpeg::JpegEncoder::new(&mut out).write_image(&pixels, 16, 16, image::ColorType::Rgb16).is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn pixel_types_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().start_as::<u8>().unwrap();
+    let gray = dinfo.read_scanlines_as::<u8>().unwrap();
+    assert_eq!(45 * 30, gray.len());
+    assert!(dinfo.finish_decompress());
+
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    cinfo.set_size(45, 30);
+    cinfo.set_mem_dest();
+    let mut started = cinfo.start_compress();
+    assert!(started.write_scanlines_as(&gray));
+    started.finish_compress();
+}
+
+#[cfg(feature = "rgb")]
+#[test]
+fn rgb_pixel_types_jpeg() {
+    use rgb::{RGB8, RGBA8};
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().start_as::<RGB8>().unwrap();
+    let rgb = dinfo.read_scanlines_as::<RGB8>().unwrap();
+    assert!(dinfo.finish_decompress());
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let expected = dinfo.read_pixels::<3>().unwrap();
+    assert_eq!(expected.iter().map(|px| RGB8::new(px[0], px[1], px[2])).collect::<Vec<_>>(), rgb);
+
+    let rgba = rgb.iter().map(|px| RGBA8 { r: px.r, g: px.g, b: px.b, a: 255 }).collect::<Vec<RGBA8>>();
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_EXT_RGBA);
+    cinfo.set_size(45, 30);
+    cinfo.set_mem_dest();
+    let mut started = cinfo.start_compress();
+    assert!(started.write_scanlines_as(&rgba));
+    started.finish_compress();
+    assert_eq!((45, 30), mozjpeg::Decompress::new_mem(&cinfo.data_to_vec().unwrap()).unwrap().size());
+}
+
+#[cfg(feature = "rgb")]
+#[test]
+#[should_panic]
+fn rgb_pixel_type_mismatch_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgba().unwrap();
+    dinfo.read_scanlines_as::<rgb::RGB8>();
+}
+
+#[cfg(feature = "imgref")]
+#[test]
+fn imgref_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let img = mozjpeg::Decompress::new_mem(&data).unwrap().to_img::<u8>().unwrap();
+    assert_eq!((45, 30), (img.width(), img.height()));
+
+    // A sub-image in a larger buffer, with padding at the end of the buffer
+    let stride = 50;
+    let mut padded = vec![0u8; stride * 31];
+    for (y, row) in img.as_ref().rows().enumerate() {
+        padded[y * stride..][..45].copy_from_slice(row);
+    }
+    let sub = imgref::ImgRef::new_stride(&padded[..], 45, 30, stride);
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    let jpeg = cinfo.compress_img(sub).unwrap();
+
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE);
+    assert_eq!(jpeg, cinfo.compress_img(img.as_ref()).unwrap());
+    let decoded = mozjpeg::Decompress::new_mem(&jpeg).unwrap().to_img::<u8>().unwrap();
+    assert_eq!((45, 30), (decoded.width(), decoded.height()));
+}
+
+#[cfg(feature = "ndarray")]
+#[test]
+fn ndarray_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let array = dinfo.read_array().unwrap();
+    assert!(dinfo.finish_decompress());
+    assert_eq!((30, 45, 3), array.dim());
+
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    let jpeg = cinfo.compress_array(array.view()).unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().grayscale().unwrap();
+    assert_eq!((30, 45, 1), dinfo.read_array().unwrap().dim());
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn buf_source_jpeg() {
+    use bytes::Buf;
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let expected = dinfo.read_pixels::<3>().unwrap();
+
+    // Chunks split in the middle of markers and entropy-coded data
+    for &split in &[1, 20, 700, 2000] {
+        let (a, b) = data.split_at(split);
+        let buf = bytes::Bytes::from(a.to_vec()).chain(bytes::Bytes::from(b.to_vec()));
+        let mut dinfo = mozjpeg::Decompress::new_buf(buf).unwrap().rgb().unwrap();
+        assert_eq!(expected, dinfo.read_pixels::<3>().unwrap());
+        assert!(dinfo.finish_decompress());
+    }
+}
+
+#[test]
+fn row_bands_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let mut expected = Vec::new();
+    assert!(dinfo.read_scanlines_into(&mut expected));
+
+    let dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let bands = dinfo.row_bands(8).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(vec![(0, 8), (8, 8), (16, 8), (24, 6)], bands.iter().map(|b| (b.y, b.height)).collect::<Vec<_>>());
+    assert_eq!(expected, bands.iter().flat_map(|b| b.pixels.iter().cloned()).collect::<Vec<_>>());
+}
+
+/// Waker for polling futures in a loop, without an executor
+#[cfg(any(feature = "futures", feature = "async"))]
+fn noop_waker() -> std::task::Waker {
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker { noop_raw_waker() }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    unsafe { Waker::from_raw(noop_raw_waker()) }
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn row_bands_stream_jpeg() {
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut bands = mozjpeg::Decompress::new_mem(&data).unwrap().grayscale().unwrap().row_bands(16);
+    let mut lines = 0;
+    while let Poll::Ready(Some(band)) = Pin::new(&mut bands).poll_next(&mut cx) {
+        let band = band.unwrap();
+        assert_eq!(band.height * 45, band.pixels.len());
+        lines += band.height;
+    }
+    assert_eq!(30, lines);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn flush_to_async_write_jpeg() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Accepts at most 100 bytes at a time, and isn't ready every other time
+    struct SlowWriter {
+        data: Vec<u8>,
+        ready: bool,
+    }
+    impl tokio::io::AsyncWrite for SlowWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Poll::Pending;
+            }
+            let len = buf.len().min(100);
+            self.data.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+    fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(out) = Pin::new(&mut future).poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    let (width, height) = (64, 64);
+    let pixels = (0..width * height * 3).map(|i| (i * i * 7 % 251) as u8).collect::<Vec<u8>>();
+    let mut socket = SlowWriter { data: Vec::new(), ready: false };
+
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    cinfo.set_size(width, height);
+    cinfo.set_bounded_memory(true);
+    cinfo.set_buffer_dest(512);
+    let mut flushes = 0;
+    {
+        let mut started = cinfo.start_compress();
+        while started.next_scanline() < height {
+            let next = started.next_scanline();
+            let end = (next + 16).min(height);
+            started.write_scanlines(&pixels[next * width * 3..end * width * 3]);
+            // A dropped future leaves unwritten data in the buffer for the next one
+            let waker = noop_waker();
+            let _ = Pin::new(&mut started.flush_to(&mut socket)).poll(&mut Context::from_waker(&waker));
+            block_on(started.flush_to(&mut socket)).unwrap();
+            flushes += 1;
+        }
+        started.finish_compress();
+    }
+    block_on(cinfo.flush_to(&mut socket)).unwrap();
+    assert!(flushes > 1, "{} bytes in {} flushes", socket.data.len(), flushes);
+
+    let dinfo = mozjpeg::Decompress::new_mem(&socket.data).unwrap();
+    assert_eq!((width, height), dinfo.size());
+
+    cinfo.set_mem_dest();
+    let mut started = cinfo.start_compress();
+    started.write_scanlines(&pixels);
+    started.finish_compress();
+    assert_eq!(cinfo.data_to_vec().unwrap(), socket.data);
+}
+
+#[test]
+fn decode_rgb_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let (pixels, width, height) = mozjpeg::decode_rgb(&data).unwrap();
+    assert_eq!((45, 30), (width, height));
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let mut expected = Vec::new();
+    assert!(dinfo.read_scanlines_into(&mut expected));
+    assert_eq!(expected, pixels);
+
+    assert_eq!((pixels, width, height), mozjpeg::decode_path_rgb("tests/test.jpg").unwrap());
+    assert!(mozjpeg::decode_rgb(b"not a jpeg").is_err());
+    assert!(mozjpeg::decode_path_rgb("tests/missing.jpg").is_err());
+}
+
+#[test]
+fn encode_rgb_jpeg() {
+    let (pixels, width, height) = mozjpeg::decode_path_rgb("tests/test.jpg").unwrap();
+    let jpeg = mozjpeg::encode_rgb(&pixels, width, height, 90.).unwrap();
+    let (decoded, w, h) = mozjpeg::decode_rgb(&jpeg).unwrap();
+    assert_eq!((width, height), (w, h));
+    assert_eq!(pixels.len(), decoded.len());
+
+    let gray = pixels.chunks(3).map(|px| px[1]).collect::<Vec<u8>>();
+    let jpeg = mozjpeg::encode_gray(&gray, width, height, 50.).unwrap();
+    let dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!(mozjpeg::ColorSpace::JCS_GRAYSCALE, dinfo.color_space());
+
+    assert!(mozjpeg::encode_rgb(&pixels, width + 1, height, 90.).is_err());
+    assert!(mozjpeg::encode_gray(&[], 0, 0, 90.).is_err());
+}
+
+#[test]
+fn prelude_jpeg() {
+    use mozjpeg::prelude::*;
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let dinfo: Decompress = DecompressConfig::new().with_markers(ALL_MARKERS).from_mem(&data).unwrap();
+    assert_eq!(3, dinfo.color_space().num_components());
+    assert!(dinfo.components().iter().all(|c| c.row_stride() > 0));
+    let mut started: DecompressStarted = dinfo.grayscale().unwrap();
+    let pixels = started.read_scanlines_as::<u8>().unwrap();
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    cinfo.set_size(45, 30);
+    assert_eq!(Ok(()), cinfo.validate().map_err(|e: ConfigError| e));
+    cinfo.set_mem_dest();
+    let mut started: CompressStarted = cinfo.start_compress();
+    started.write_marker(Marker::COM, b"prelude");
+    assert!(started.write_scanlines_as(&pixels));
+    started.finish_compress();
+}
+
+#[test]
+fn debug_jpeg() {
+    let dinfo = mozjpeg::Decompress::new_path("tests/test.jpg").unwrap();
+    let debug = format!("{:?}", dinfo);
+    assert!(debug.starts_with("Decompress {"), "{}", debug);
+    assert!(debug.contains("width: 45") && debug.contains("height: 30"), "{}", debug);
+    assert!(debug.contains("JCS_YCbCr") && debug.contains("multiple_scans: true"), "{}", debug);
+    let mut started = dinfo.rgb().unwrap();
+    assert!(format!("{:?}", started).contains("output_scanline: 0"));
+    started.read_pixels::<3>().unwrap();
+    assert!(format!("{:?}", started).contains("output_scanline: 30"));
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(16, 8);
+    let debug = format!("{:?}", comp);
+    assert!(debug.contains("in_color_space: JCS_RGB") && debug.contains("subsampling: [(2, 2), (1, 1), (1, 1)]"), "{}", debug);
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[0; 16 * 3 * 8]));
+    assert!(format!("{:?}", started).contains("next_scanline: 8"));
+    started.finish_compress();
+
+    // Several sequential scans aren't progressive
+    comp.set_scan_layout(mozjpeg::ScanLayout::NonInterleaved);
+    assert!(format!("{:?}", comp).contains("progressive: false"));
+    comp.set_progressive_mode();
+    assert!(format!("{:?}", comp).contains("progressive: true"));
+}
+
+#[test]
+fn settings_jpeg() {
+    let (pixels, width, height) = mozjpeg::decode_path_rgb("tests/test.jpg").unwrap();
+    let compress = |settings: &mozjpeg::CompressSettings| {
+        let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.apply_settings(settings);
+        cinfo.set_mem_dest();
+        let mut started = cinfo.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        cinfo.data_to_vec().unwrap()
+    };
+
+    let settings = mozjpeg::CompressSettings {
+        quality: 90.,
+        chroma_subsampling: (1, 1),
+        progressive: false,
+        ..Default::default()
+    };
+    let jpeg = compress(&settings);
+    assert_eq!(1, mozjpeg::scan_index(&jpeg).unwrap().len());
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert!(dinfo.components().iter().all(|c| (c.h_samp_factor, c.v_samp_factor) == (1, 1)));
+
+    dinfo.apply_settings(&mozjpeg::DecompressSettings { scale: 4, ..Default::default() });
+    let dinfo = dinfo.rgb().unwrap();
+    assert_eq!((23, 15), (dinfo.width(), dinfo.height()));
+
+    let jpeg = compress(&mozjpeg::CompressSettings::default());
+    assert!(mozjpeg::scan_index(&jpeg).unwrap().len() > 1);
+    let dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert_eq!((2, 2), (dinfo.components()[0].h_samp_factor, dinfo.components()[0].v_samp_factor));
+
+    // CMYK has no chroma, and isn't subsampled
+    let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_CMYK);
+    cinfo.set_size(8, 8);
+    cinfo.apply_settings(&mozjpeg::CompressSettings::default());
+    assert!(cinfo.components().iter().all(|c| (c.h_samp_factor, c.v_samp_factor) == (1, 1)));
+}
+
+#[test]
+fn presets_jpeg() {
+    use mozjpeg::Preset;
+
+    let (pixels, width, height) = mozjpeg::decode_path_rgb("tests/test.jpg").unwrap();
+    let compress = |preset| {
+        let mut cinfo = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.apply_preset(preset);
+        cinfo.set_mem_dest();
+        let mut started = cinfo.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+        cinfo.data_to_vec().unwrap()
+    };
+    let sampling = |jpeg: &[u8]| {
+        let dinfo = mozjpeg::Decompress::new_mem(jpeg).unwrap();
+        let c = &dinfo.components()[0];
+        (c.h_samp_factor, c.v_samp_factor)
+    };
+
+    let web = compress(Preset::Web85);
+    assert!(mozjpeg::scan_index(&web).unwrap().len() > 1);
+    assert_eq!((2, 2), sampling(&web));
+
+    let archival = compress(Preset::ArchivalHigh);
+    assert_eq!(1, mozjpeg::scan_index(&archival).unwrap().len());
+    assert_eq!((1, 1), sampling(&archival));
+    assert!(archival.len() > web.len());
+
+    let preview = compress(Preset::FastPreview);
+    assert_eq!(1, mozjpeg::scan_index(&preview).unwrap().len());
+    assert_eq!((2, 2), sampling(&preview));
+    assert_eq!(75., mozjpeg::CompressSettings::from(Preset::FastPreview).quality);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn settings_serde() {
+    fn assert_serde<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+    assert_serde::<mozjpeg::CompressSettings>();
+    assert_serde::<mozjpeg::DecompressSettings>();
+    assert_serde::<mozjpeg::Preset>();
+    assert_serde::<mozjpeg::OptimizeOptions>();
+    assert_serde::<mozjpeg::Marker>();
+
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(value, serde_json::from_str(&json).unwrap());
+    }
+    round_trip(mozjpeg::Preset::ArchivalHigh.settings());
+    round_trip(mozjpeg::Limits { max_width: Some(1000), max_scans: Some(10), ..mozjpeg::Limits::default() });
+    round_trip(mozjpeg::OptimizeOptions { strip_metadata: true, max_quality: Some(80.), ..mozjpeg::OptimizeOptions::default() });
+
+    // Missing fields of partial configs are defaults
+    let settings: mozjpeg::CompressSettings = serde_json::from_str(r#"{"quality": 90.0, "tune": "Ssim"}"#).unwrap();
+    assert_eq!(mozjpeg::CompressSettings { quality: 90., tune: Some(mozjpeg::Tune::Ssim), ..mozjpeg::CompressSettings::default() }, settings);
+    let limits: mozjpeg::Limits = serde_json::from_str(r#"{"max_pixels": 1000000}"#).unwrap();
+    assert_eq!(mozjpeg::Limits { max_pixels: Some(1000000), ..mozjpeg::Limits::default() }, limits);
+    let options: mozjpeg::OptimizeOptions = serde_json::from_str(r#"{"target_size": 50000}"#).unwrap();
+    assert_eq!(mozjpeg::OptimizeOptions { target_size: Some(50000), ..mozjpeg::OptimizeOptions::default() }, options);
+    assert_eq!(mozjpeg::CompressSettings::default(), serde_json::from_str("{}").unwrap());
+}
+
+#[test]
+fn try_from_jpeg() {
+    use std::convert::TryFrom;
+    use std::path::Path;
+
+    fn size<T>(src: T) -> std::io::Result<(usize, usize)> where mozjpeg::Decompress<'static>: TryFrom<T, Error = std::io::Error> {
+        Ok(mozjpeg::Decompress::try_from(src)?.size())
+    }
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    assert_eq!((45, 30), mozjpeg::Decompress::try_from(&data[..]).unwrap().size());
+    assert_eq!((45, 30), mozjpeg::Decompress::try_from(Path::new("tests/test.jpg")).unwrap().size());
+    assert_eq!((45, 30), size(data).unwrap());
+    assert!(mozjpeg::Decompress::try_from(Path::new("tests/missing.jpg")).is_err());
+}
+
+#[test]
+fn pixel_reader_jpeg() {
+    use std::io::Read;
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let (expected, _, _) = mozjpeg::decode_rgb(&data).unwrap();
+
+    let mut reader = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap().into_reader();
+    let mut small = [0u8; 7];
+    let len = reader.read(&mut small).unwrap();
+    assert_eq!(&expected[..len], &small[..len]);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(&expected[len..], &rest[..]);
+    assert_eq!(0, reader.read(&mut small).unwrap());
+
+    let mut copied = Vec::new();
+    let reader = mozjpeg::Decompress::new_mem(&data).unwrap().rgb().unwrap().into_reader();
+    assert_eq!(expected.len() as u64, std::io::copy(&mut { reader }, &mut copied).unwrap());
+    assert_eq!(expected, copied);
+}
+
+#[test]
+fn reader_and_writer_jpeg() {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    /// Returns at most a few bytes per call, so the data spans many `fill_input_buffer()` calls
+    struct Trickle(io::Cursor<Vec<u8>>);
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(100);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let (expected, width, height) = mozjpeg::decode_rgb(&data).unwrap();
+
+    let dinfo = mozjpeg::Decompress::new_reader(Trickle(io::Cursor::new(data.clone()))).unwrap();
+    assert_eq!((width, height), dinfo.size());
+    let mut dinfo = dinfo.rgb().unwrap();
+    let mut pixels = Vec::new();
+    assert!(dinfo.read_scanlines_into(&mut pixels));
+    assert_eq!(expected, pixels);
+    assert!(dinfo.finish_decompress());
+
+    // A truncated stream is a warning, and the rest of the image is filled in
+    let truncated = data[..data.len() / 2].to_vec();
+    let mut dinfo = mozjpeg::Decompress::new_reader(io::Cursor::new(truncated)).unwrap().rgb().unwrap();
+    pixels.clear();
+    assert!(dinfo.read_scanlines_into(&mut pixels));
+    assert_eq!(expected.len(), pixels.len());
+
+    let encode = |to_writer: Option<Shared>| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(width, height);
+        match to_writer {
+            Some(ref writer) => comp.set_writer_dest(writer.clone()),
+            None => comp.set_mem_dest(),
+        }
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&expected));
+        started.finish_compress();
+        comp.data_to_vec().ok()
+    };
+    let in_memory = encode(None).unwrap();
+    let output = Shared(Rc::new(RefCell::new(Vec::new())));
+    assert!(encode(Some(output.clone())).is_none());
+    assert_eq!(in_memory, *output.0.borrow());
+
+    // I/O errors don't unwind through libjpeg, they're raised when it returns
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "failed"))
+        }
+    }
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "failed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    assert!(mozjpeg::Decompress::new_reader(Failing).is_err());
+    let truncated = io::Cursor::new(data[..data.len() - 100].to_vec());
+    // The file is progressive, so it's read in full when decompression starts
+    assert!(mozjpeg::Decompress::new_reader(truncated.chain(Failing)).unwrap().rgb().is_err());
+    assert!(std::panic::catch_unwind(|| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(width, height);
+        comp.set_writer_dest(Failing);
+        let mut started = comp.start_compress();
+        started.write_scanlines(&expected);
+        started.finish_compress();
+    }).is_err());
+}
+
+#[test]
+#[cfg(feature = "capi")]
+fn capi_jpeg() {
+    use mozjpeg::capi::*;
+    use std::ptr;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    unsafe {
+        let (mut width, mut height, mut len) = (0, 0, 0);
+        let pixels = mozjpeg_decode_rgb(data.as_ptr(), data.len(), &mut width, &mut height, &mut len);
+        assert!(!pixels.is_null());
+        assert_eq!((45, 30, 45 * 30 * 3), (width, height, len));
+        assert_eq!(mozjpeg::decode_rgb(&data).unwrap().0, std::slice::from_raw_parts(pixels, len));
+
+        let mut jpeg_len = 0;
+        let jpeg = mozjpeg_encode_rgb(pixels, width, height, 80., &mut jpeg_len);
+        assert!(!jpeg.is_null());
+        assert_eq!((45, 30), mozjpeg::Decompress::new_mem(std::slice::from_raw_parts(jpeg, jpeg_len)).unwrap().size());
+
+        let mut optimized_len = 0;
+        let optimized = mozjpeg_optimize(jpeg, jpeg_len, 0., 0, &mut optimized_len);
+        assert!(!optimized.is_null());
+        assert_eq!((45, 30), mozjpeg::Decompress::new_mem(std::slice::from_raw_parts(optimized, optimized_len)).unwrap().size());
+        mozjpeg_free(optimized, optimized_len);
+        mozjpeg_free(jpeg, jpeg_len);
+        mozjpeg_free(pixels, len);
+
+        assert!(mozjpeg_decode_rgb(b"not a jpeg".as_ptr(), 10, &mut width, &mut height, &mut len).is_null());
+        assert!(mozjpeg_encode_gray(ptr::null(), 10, 10, 80., &mut len).is_null());
+        mozjpeg_free(ptr::null_mut(), 0);
+    }
+}
+
+#[test]
+fn dct_blocks_jpeg() {
+    use mozjpeg::dct;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap();
+    let blocks = dinfo.read_coefficients().unwrap();
+    assert_eq!((6, 4), (blocks[0].width(), blocks[0].height()));
+    let luma_qtable = dinfo.qtables().swap_remove(dinfo.components()[0].quant_tbl_no as usize).unwrap();
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&data).unwrap();
+    dinfo.dct_method(mozjpeg::DctMethod::Float);
+    let mut gray = dinfo.grayscale().unwrap();
+    let width = gray.width();
+    let luma = gray.read_scanlines_flat().unwrap().0;
+
+    let pixels = dct::block_to_pixels(blocks[0].get(1, 1).unwrap(), &luma_qtable);
+    for y in 0..8 {
+        for x in 0..8 {
+            let decoded = luma[(8 + y) * width + 8 + x] as i32;
+            assert!((decoded - pixels[y * 8 + x] as i32).abs() <= 1, "{} {}", decoded, pixels[y * 8 + x]);
+        }
+    }
+    // Pixels are rounded, so coefficients of a high-quality file come back only approximately
+    let requantized = dct::pixels_to_block(&pixels, &luma_qtable);
+    assert!(blocks[0].get(1, 1).unwrap().iter().zip(requantized.iter()).all(|(&a, &b)| (a - b).abs() <= 1));
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn scan_script_jpeg() {
+    use mozjpeg::ScanScript;
+
+    let script = ScanScript::new()
+        .add_scan(&[0, 1, 2], 0..=0, 0, 1)
+        .add_scan(&[0], 1..=5, 0, 0)
+        .add_scan(&[2], 1..=63, 0, 0)
+        .add_scan(&[1], 1..=63, 0, 0)
+        .add_scan(&[0], 6..=63, 0, 0)
+        .add_scan(&[0, 1, 2], 0..=0, 1, 0);
+
+    let pixels = (0..32*32*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(32, 32);
+    assert!(comp.set_scan_script(&ScanScript::new().add_scan(&[0, 3], 0..=63, 0, 0)).is_err());
+    comp.set_scan_script(&script).unwrap();
+    assert_eq!(Some(script.clone()), comp.scan_script());
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+
+    assert_eq!(script, ScanScript::from_jpeg(&jpeg).unwrap());
+    assert_eq!(6, mozjpeg::scan_index(&jpeg).unwrap().len());
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+    assert_eq!(32 * 32 * 3, dinfo.read_pixels::<3>().unwrap().len() * 3);
+
+    // Scripts chosen by mozjpeg itself follow the same rules
+    let optimized = ScanScript::from_jpeg(&mozjpeg::encode_gray(&[128; 64], 8, 8, 80.).unwrap()).unwrap();
+    assert!(!optimized.scans().is_empty());
+    assert_eq!(Ok(()), optimized.validate(1));
+}
+
+#[test]
+fn component_setters_jpeg() {
+    use mozjpeg::ConfigError;
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(32, 32);
+    assert_eq!(Ok(()), comp.set_sampling_factors(0, 1, 1));
+    assert_eq!(Err(ConfigError::InvalidSamplingFactor { component: 1, h_samp_factor: 5, v_samp_factor: 1 }), comp.set_sampling_factors(1, 5, 1));
+    assert_eq!(Err(ConfigError::InvalidComponent { component: 3, num_components: 3 }), comp.set_sampling_factors(3, 1, 1));
+    assert_eq!(Ok(()), comp.set_quant_table_slot(1, 0));
+    assert_eq!(Err(ConfigError::InvalidTableSlot { component: 1, table: "quant", slot: 3 }), comp.set_quant_table_slot(1, 3));
+    assert_eq!(Err(ConfigError::InvalidTableSlot { component: 2, table: "ac", slot: 4 }), comp.set_huffman_table_slots(2, 0, 4));
+    assert_eq!(Ok(()), comp.set_huffman_table_slots(2, 0, 0));
+    assert_eq!(Ok(()), comp.validate());
+
+    comp.components_mut()[2].quant_tbl_no = 2;
+    assert_eq!(Err(ConfigError::InvalidTableSlot { component: 2, table: "quant", slot: 2 }), comp.validate());
+    comp.components_mut()[2].quant_tbl_no = 1;
+
+    comp.set_optimize_coding(false);
+    assert_eq!(Ok(()), comp.set_huffman_table_slots(2, 2, 0));
+    assert_eq!(Err(ConfigError::InvalidTableSlot { component: 2, table: "dc", slot: 2 }), comp.validate());
+    comp.set_optimize_coding(true);
+    assert_eq!(Ok(()), comp.validate());
+    assert_eq!(Ok(()), comp.set_huffman_table_slots(2, 0, 0));
+
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    assert!(started.write_scanlines(&[77; 32 * 32 * 3]));
+    started.finish_compress();
+    let jpeg = comp.data_to_vec().unwrap();
+    let dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap();
+    assert!(dinfo.components().iter().all(|c| (c.h_samp_factor, c.v_samp_factor) == (1, 1)));
+    assert_eq!(0, dinfo.components()[1].quant_tbl_no);
+}
+
+#[test]
+fn limits_jpeg() {
+    use mozjpeg::{ConfigError, Limits};
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let small = Limits { max_width: Some(40), ..Limits::default() };
+    let err = mozjpeg::Decompress::with_limits(small).from_mem(&data).err().unwrap();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    assert!(mozjpeg::Decompress::with_limits(Limits { max_pixels: Some(45 * 30), ..Limits::default() }).from_mem(&data).is_ok());
+    assert!(mozjpeg::Decompress::with_markers(mozjpeg::ALL_MARKERS)
+        .with_limits(Limits { max_marker_bytes: Some(0), ..Limits::default() }).from_mem(&data).is_err());
+
+    let pixels = (0..64*64*3).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(64, 64);
+    comp.set_limits(Limits { max_height: Some(32), ..Limits::default() });
+    assert_eq!(Err(ConfigError::LimitExceeded { limit: "max_height", value: 64, max: 32 }), comp.validate());
+    comp.set_limits(Limits { max_scans: Some(100), max_marker_bytes: Some(10), ..Limits::default() });
+    comp.set_progressive_mode();
+    assert_eq!(Ok(()), comp.validate());
+    comp.set_mem_dest();
+    let mut started = comp.start_compress();
+    started.write_marker(mozjpeg::Marker::COM, b"0123456789");
+    assert!(started.write_scanlines(&pixels));
+    started.finish_compress();
+    let progressive = comp.data_to_vec().unwrap();
+    let scans = mozjpeg::scan_index(&progressive).unwrap().len();
+    assert!(scans > 1);
+
+    // Markers are saved only up to the limit, but their full length counts
+    let with_marker_limit = |max| mozjpeg::Decompress::with_markers(&[mozjpeg::Marker::COM])
+        .with_limits(Limits { max_marker_bytes: Some(max), ..Limits::default() }).from_mem(&progressive);
+    assert!(with_marker_limit(9).is_err());
+    assert_eq!(&b"0123456789"[..], with_marker_limit(10).unwrap().markers().next().unwrap().data);
+
+    let decode = |limits| -> std::io::Result<usize> {
+        let dinfo = mozjpeg::Decompress::with_limits(limits).from_mem(&progressive)?;
+        let mut dinfo = dinfo.rgb()?;
+        Ok(dinfo.read_pixels::<3>().unwrap().len())
+    };
+    assert_eq!(64 * 64, decode(Limits { max_scans: Some(scans), ..Limits::default() }).unwrap());
+    let err = decode(Limits { max_scans: Some(scans - 1), ..Limits::default() }).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+    // Progressive mode needs a buffer for the whole image
+    assert_eq!(64 * 64, decode(Limits { max_memory: Some(64 * 64 * 3 * 2), ..Limits::default() }).unwrap());
+    let err = decode(Limits { max_memory: Some(1000), ..Limits::default() }).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    let encoded = std::panic::catch_unwind(|| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(64, 64);
+        comp.set_limits(Limits { max_memory: Some(1000), ..Limits::default() });
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&pixels));
+        started.finish_compress();
+    });
+    assert!(encoded.is_err());
+}
+
+#[test]
+fn thread_defaults() {
+    use mozjpeg::{Defaults, Limits};
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let defaults = Defaults {
+        limits: Limits { max_width: Some(40), ..Limits::default() },
+        progressive: Some(false),
+        ..Defaults::default()
+    };
+    let decoders = mozjpeg::Pool::<mozjpeg::Decompress>::new(1);
+    drop(decoders.decompress_mem(&data).unwrap());
+    assert_eq!(1, decoders.len());
+    let sequential = mozjpeg::with_thread_defaults(defaults, || {
+        assert_eq!(Some(40), mozjpeg::defaults().limits.max_width);
+        let err = mozjpeg::Decompress::new_mem(&data).err().unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        let err = decoders.decompress_mem(&data).err().unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        assert_eq!(Some(40), comp.limits().max_width);
+        comp.set_limits(Limits::default());
+        comp.set_size(32, 32);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[128; 32 * 32 * 3]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    });
+    assert_eq!(1, mozjpeg::scan_index(&sequential).unwrap().len());
+
+    // Restored after the closure
+    assert_eq!(None, mozjpeg::defaults().limits.max_width);
+    assert!(mozjpeg::Decompress::new_mem(&data).is_ok());
+    assert!(decoders.decompress_mem(&data).is_ok());
+}
+
+#[test]
+fn flavor_jpeg() {
+    use mozjpeg::Marker;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let flavor = mozjpeg::Decompress::new_mem(&data).unwrap().flavor();
+    assert!(flavor.jfif && !flavor.exif);
+    assert_eq!(None, flavor.adobe_transform);
+
+    // RGB and CMYK files have an Adobe marker instead of JFIF
+    let encode = |color_space: mozjpeg::ColorSpace, adobe: bool, exif: bool| {
+        let mut comp = mozjpeg::Compress::new(color_space);
+        comp.set_fastest_defaults();
+        comp.set_size(8, 8);
+        comp.set_color_space(color_space);
+        comp.set_write_adobe_marker(adobe);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        if exif {
+            started.write_marker(Marker::APP(1), b"Exif\0\0MM");
+        }
+        assert!(started.write_scanlines(&vec![128; 8 * 8 * mozjpeg::ColorSpaceExt::num_components(&color_space)]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    };
+
+    let cmyk = encode(mozjpeg::ColorSpace::JCS_CMYK, true, false);
+    let cmyk = mozjpeg::Decompress::new_mem(&cmyk).unwrap();
+    assert_eq!(Some(0), cmyk.flavor().adobe_transform);
+    assert!(!cmyk.flavor().jfif);
+
+    let raw = encode(mozjpeg::ColorSpace::JCS_RGB, false, false);
+    assert!(mozjpeg::Decompress::new_mem(&raw).unwrap().flavor().is_raw());
+
+    // Not visible in markers() unless requested
+    let exif = encode(mozjpeg::ColorSpace::JCS_RGB, false, true);
+    let dinfo = mozjpeg::Decompress::new_mem(&exif).unwrap();
+    assert!(dinfo.flavor().exif);
+    assert_eq!(0, dinfo.markers().count());
+    let dinfo = mozjpeg::Decompress::with_markers(&[Marker::APP(1)]).from_mem(&exif).unwrap();
+    assert!(dinfo.flavor().exif);
+    assert_eq!(b"Exif\0\0MM", dinfo.markers().next().unwrap().data);
+}
+
+#[test]
+fn output_components_jpeg() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    for &(rgba, components) in &[(false, 3), (true, 4)] {
+        let dinfo = mozjpeg::Decompress::new_mem(&data).unwrap();
+        let mut dinfo = if rgba { dinfo.rgba() } else { dinfo.rgb() }.unwrap();
+        assert_eq!(components, dinfo.output_components());
+        assert_eq!(components, dinfo.out_color_components());
+        let rows = dinfo.rec_outbuf_height();
+        assert!((1..=4).contains(&rows));
+        let (pixels, _) = dinfo.read_scanlines_flat().unwrap();
+        assert_eq!(dinfo.width() * dinfo.height() * dinfo.output_components(), pixels.len());
+    }
+}
+
+#[test]
+fn jfif_thumbnail_jpeg() {
+    use mozjpeg::{JfifThumbnail, Marker};
+
+    let encode = |color_space, thumbnail: &JfifThumbnail| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(32, 32);
+        comp.set_color_space(color_space);
+        comp.set_jfif_thumbnail(Some(thumbnail.clone())).unwrap();
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[128; 32 * 32 * 3]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    };
+
+    let thumbnail = JfifThumbnail::encode_rgb(&[50; 4 * 4 * 3], 4, 4, 90.).unwrap();
+    let jpeg = encode(mozjpeg::ColorSpace::JCS_YCbCr, &thumbnail);
+    let dinfo = mozjpeg::Decompress::with_markers(&[Marker::APP(0)]).from_mem(&jpeg).unwrap();
+    let app0 = dinfo.markers().map(|m| m.data).collect::<Vec<_>>();
+    assert_eq!(2, app0.len());
+    assert_eq!(&[1, 2], &app0[0][5..7], "JFIF 1.02");
+    assert_eq!(b"JFXX\0\x10", &app0[1][..6]);
+
+    let mut small = mozjpeg::Decompress::new_mem(&app0[1][6..]).unwrap().rgb().unwrap();
+    assert_eq!((4, 4), (small.width(), small.height()));
+    let (pixels, _) = small.read_scanlines_flat().unwrap();
+    assert!((pixels[0] as i32 - 50).abs() <= 2);
+
+    // Not JFIF, so there's nowhere to put it
+    let rgb = encode(mozjpeg::ColorSpace::JCS_RGB, &JfifThumbnail::Rgb { width: 1, height: 1, pixels: vec![1, 2, 3] });
+    let dinfo = mozjpeg::Decompress::with_markers(&[Marker::APP(0)]).from_mem(&rgb).unwrap();
+    assert_eq!(0, dinfo.markers().count());
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    let err = comp.set_jfif_thumbnail(Some(JfifThumbnail::Rgb { width: 2, height: 2, pixels: vec![0; 3] }));
+    assert!(matches!(err, Err(mozjpeg::ConfigError::InvalidThumbnail { .. })));
+
+    // Counts towards the marker limit, and it's checked before compression starts
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(32, 32);
+    comp.set_jfif_thumbnail(Some(JfifThumbnail::Rgb { width: 2, height: 2, pixels: vec![0; 12] })).unwrap();
+    comp.set_limits(mozjpeg::Limits { max_marker_bytes: Some(19), ..Default::default() });
+    assert_eq!(Err(mozjpeg::ConfigError::LimitExceeded { limit: "max_marker_bytes", value: 20, max: 19 }), comp.validate());
+    comp.set_limits(mozjpeg::Limits { max_marker_bytes: Some(20), ..Default::default() });
+    assert_eq!(Ok(()), comp.validate());
+    comp.set_color_space(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_limits(mozjpeg::Limits { max_marker_bytes: Some(19), ..Default::default() });
+    assert_eq!(Ok(()), comp.validate(), "not written without a JFIF header");
+}
+
+#[test]
+#[cfg_attr(feature = "libjpeg-turbo", ignore)]
+fn scan_layout_jpeg() {
+    use mozjpeg::{ScanLayout, ScanScript};
+
+    let encode = |layout| {
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        comp.set_size(24, 16);
+        comp.set_scan_layout(layout);
+        comp.set_mem_dest();
+        let mut started = comp.start_compress();
+        assert!(started.write_scanlines(&[90; 24 * 16 * 3]));
+        started.finish_compress();
+        comp.data_to_vec().unwrap()
+    };
+
+    let interleaved = ScanScript::from_jpeg(&encode(ScanLayout::Interleaved)).unwrap();
+    assert_eq!(1, interleaved.scans().len());
+    assert_eq!(vec![0, 1, 2], interleaved.scans()[0].components);
+
+    let jpeg = encode(ScanLayout::NonInterleaved);
+    let script = ScanScript::from_jpeg(&jpeg).unwrap();
+    assert!(!script.is_progressive());
+    assert_eq!(ScanScript::non_interleaved(3), script);
+
+    let mut dinfo = mozjpeg::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap();
+    let (pixels, _) = dinfo.read_scanlines_flat().unwrap();
+    assert!(pixels.iter().all(|&p| (p as i32 - 90).abs() <= 2));
 }